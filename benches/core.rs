@@ -0,0 +1,147 @@
+// Benchmarks for the hot paths: command parsing, cache access under contention,
+// broadcast fan-out and Arrow snapshot writing. Run with `cargo bench`.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+
+use p2p_rust::protocol::command::parse_command;
+use p2p_rust::replication::broadcast_set;
+use p2p_rust::storage::persistence::write_cache_to_arrow;
+use p2p_rust::transport::TcpTransport;
+use p2p_rust::{Cache, CacheValue, PeerList, SharedCache};
+
+const REQUESTS: &[(&str, &str)] = &[
+    ("get", "GET key731"),
+    ("set", "SET key1001=value1001"),
+    ("set_typed", "SET counter=42 TYPE=int"),
+    ("scan_where", "SCAN PREFIX user: COUNT 100 WHERE $.category = 'books' AND $.price < 20"),
+    ("agg", "AGG SUM $.amount PREFIX order: GROUP BY PREFIX :"),
+    ("json_set", "JSON.SET user:1 $.address.city \"Prague\""),
+    ("zadd", "ZADD leaderboard 42.5 alice"),
+    ("unknown", "NOT_A_COMMAND with some arguments"),
+];
+
+fn filled_cache(size: usize) -> SharedCache {
+    let mut cache = Cache::new();
+    for i in 0..size {
+        cache.insert(format!("key{}", i), CacheValue::Str(format!("value{}", i)));
+    }
+    Arc::new(Mutex::new(cache))
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_command");
+    for (name, request) in REQUESTS {
+        group.bench_with_input(BenchmarkId::from_parameter(name), request, |b, request| {
+            b.iter(|| parse_command(std::hint::black_box(request)))
+        });
+    }
+    group.finish();
+}
+
+// Mixed 90% GET / 10% SET from `tasks` concurrent tasks sharing one cache
+fn bench_contention(c: &mut Criterion) {
+    const OPS_PER_TASK: usize = 1_000;
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let mut group = c.benchmark_group("cache_contention");
+    for tasks in [1, 4, 16, 64] {
+        group.throughput(Throughput::Elements((tasks * OPS_PER_TASK) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(tasks), &tasks, |b, &tasks| {
+            let cache = filled_cache(10_000);
+            b.to_async(&runtime).iter(|| {
+                let cache = Arc::clone(&cache);
+                async move {
+                    let handles: Vec<_> = (0..tasks)
+                        .map(|t| {
+                            let cache = Arc::clone(&cache);
+                            tokio::spawn(async move {
+                                for i in 0..OPS_PER_TASK {
+                                    let key = format!("key{}", (t * OPS_PER_TASK + i) % 10_000);
+                                    if i % 10 == 0 {
+                                        cache.lock().await.insert(key, CacheValue::Int(i as i64));
+                                    } else {
+                                        std::hint::black_box(cache.lock().await.get(&key).cloned());
+                                    }
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.await.unwrap();
+                    }
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+// Local TCP peers that accept and drain every message
+fn spawn_peers(runtime: &Runtime, count: usize) -> PeerList {
+    let addrs = runtime.block_on(async {
+        let mut addrs = HashSet::new();
+        for _ in 0..count {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.insert(listener.local_addr().unwrap().to_string());
+            tokio::spawn(async move {
+                loop {
+                    if let Ok((mut socket, _)) = listener.accept().await {
+                        tokio::spawn(async move {
+                            let mut buffer = Vec::new();
+                            let _ = socket.read_to_end(&mut buffer).await;
+                        });
+                    }
+                }
+            });
+        }
+        addrs
+    });
+    Arc::new(Mutex::new(addrs))
+}
+
+fn bench_fanout(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let mut group = c.benchmark_group("broadcast_fanout");
+    for peers in [1, 4, 16] {
+        let peer_list = spawn_peers(&runtime, peers);
+        group.throughput(Throughput::Elements(peers as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(peers), &peer_list, |b, peer_list| {
+            b.to_async(&runtime).iter(|| {
+                broadcast_set(
+                    Arc::new(TcpTransport),
+                    Arc::clone(peer_list),
+                    "key1".to_string(),
+                    CacheValue::Str("value1".to_string()),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_snapshot(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let path = std::env::temp_dir().join(format!("p2p-rust-bench-{}.arrow", std::process::id()));
+    let path = path.to_string_lossy().into_owned();
+    let mut group = c.benchmark_group("arrow_snapshot");
+    group.sample_size(10);
+    for size in [1_000, 10_000, 100_000] {
+        let cache = filled_cache(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &cache, |b, cache| {
+            b.to_async(&runtime).iter(|| async {
+                write_cache_to_arrow(Arc::clone(cache), &path).await.unwrap();
+            })
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, bench_parse, bench_contention, bench_fanout, bench_snapshot);
+criterion_main!(benches);
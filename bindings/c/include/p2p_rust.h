@@ -0,0 +1,103 @@
+/*
+ * C client for p2p-rust nodes. Every call blocks until the node answers and returns a
+ * P2pStatus; on failure p2p_last_error() describes the error. A handle may be shared
+ * between threads. Pointer arguments must be NULL or valid and strings NUL-terminated;
+ * don't use a handle after p2p_client_free or free a string twice.
+ */
+
+#ifndef P2P_RUST_H
+#define P2P_RUST_H
+
+/* Generated by cbindgen from src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of every call; negative values are errors.
+ */
+typedef enum P2pStatus {
+  P2P_STATUS_OK = 0,
+  /**
+   * p2p_get: the key does not exist.
+   */
+  P2P_STATUS_NOT_FOUND = 1,
+  /**
+   * A null pointer, invalid UTF-8 or a key the protocol can't carry.
+   */
+  P2P_STATUS_INVALID_ARGUMENT = -1,
+  /**
+   * No node could be reached.
+   */
+  P2P_STATUS_CONNECT = -2,
+  /**
+   * The node did not answer in time.
+   */
+  P2P_STATUS_TIMEOUT = -3,
+  /**
+   * The connection broke mid-request.
+   */
+  P2P_STATUS_IO = -4,
+  /**
+   * The node rejected the request.
+   */
+  P2P_STATUS_SERVER = -5,
+  /**
+   * The node sent something unexpected.
+   */
+  P2P_STATUS_PROTOCOL = -6,
+  /**
+   * A bug in the library; the call had no effect on the handle.
+   */
+  P2P_STATUS_INTERNAL = -7,
+} P2pStatus;
+
+/**
+ * Opaque client handle.
+ */
+typedef struct P2pClient P2pClient;
+
+/**
+ * Connect to the node at `addr` ("host:port") and store a new handle in `*out`.
+ * `timeout_ms` bounds connecting and each request; 0 keeps the defaults (5s / 10s).
+ * Free the handle with p2p_client_free.
+ */
+enum P2pStatus p2p_client_connect(const char *addr, uint32_t timeout_ms, struct P2pClient **out);
+
+/**
+ * Close a handle from p2p_client_connect; NULL is ignored.
+ */
+void p2p_client_free(struct P2pClient *client);
+
+/**
+ * Look up `key`. On P2P_STATUS_OK `*value_out` receives a string to release with
+ * p2p_string_free; on P2P_STATUS_NOT_FOUND it is set to NULL.
+ */
+enum P2pStatus p2p_get(const struct P2pClient *client, const char *key, char **value_out);
+
+/**
+ * Store a string value; the node replicates it to its peers.
+ */
+enum P2pStatus p2p_set(const struct P2pClient *client, const char *key, const char *value);
+
+/**
+ * Delete `key` everywhere. `existed` may be NULL; otherwise it receives whether the
+ * key existed on the node that answered.
+ */
+enum P2pStatus p2p_del(const struct P2pClient *client, const char *key, bool *existed);
+
+/**
+ * Release a string returned by this library; NULL is ignored.
+ */
+void p2p_string_free(char *value);
+
+/**
+ * Message for the last failed call on this thread, or NULL if it succeeded.
+ * Valid until the next call on the same thread.
+ */
+const char *p2p_last_error(void);
+
+#endif  /* P2P_RUST_H */
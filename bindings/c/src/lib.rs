@@ -0,0 +1,224 @@
+//! C bindings for the p2p-rust client, see include/p2p_rust.h.
+//!
+//! A `P2pClient` is an opaque handle around [`p2p_rust::Client`] and may be
+//! used from several threads at once. Every call blocks on one shared tokio
+//! runtime and returns a `P2pStatus`; on failure `p2p_last_error` describes
+//! what went wrong on the calling thread. Doc comments on the exported items
+//! end up in the generated header.
+//!
+//! Safety, for every function: pointer arguments are NULL or valid for the
+//! access the function documents; strings are NUL-terminated; a handle is
+//! not used after p2p_client_free, and strings are freed once.
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use p2p_rust::{Client, ClientError};
+use tokio::runtime::Runtime;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Result of every call; negative values are errors.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum P2pStatus {
+    Ok = 0,
+    /// p2p_get: the key does not exist.
+    NotFound = 1,
+    /// A null pointer, invalid UTF-8 or a key the protocol can't carry.
+    InvalidArgument = -1,
+    /// No node could be reached.
+    Connect = -2,
+    /// The node did not answer in time.
+    Timeout = -3,
+    /// The connection broke mid-request.
+    Io = -4,
+    /// The node rejected the request.
+    Server = -5,
+    /// The node sent something unexpected.
+    Protocol = -6,
+    /// A bug in the library; the call had no effect on the handle.
+    Internal = -7,
+}
+
+/// Opaque client handle.
+pub struct P2pClient {
+    inner: Client,
+}
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start the tokio runtime")
+    })
+}
+
+struct Failure {
+    status: P2pStatus,
+    message: String,
+}
+
+impl Failure {
+    fn invalid(message: impl Into<String>) -> Self {
+        Failure {
+            status: P2pStatus::InvalidArgument,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ClientError> for Failure {
+    fn from(e: ClientError) -> Self {
+        let status = match e {
+            ClientError::Connect { .. } => P2pStatus::Connect,
+            ClientError::Timeout(_) => P2pStatus::Timeout,
+            ClientError::Io(_) => P2pStatus::Io,
+            ClientError::Server(_) => P2pStatus::Server,
+            ClientError::Protocol(_) => P2pStatus::Protocol,
+            ClientError::InvalidRequest(_) => P2pStatus::InvalidArgument,
+        };
+        Failure {
+            status,
+            message: e.to_string(),
+        }
+    }
+}
+
+// Run an exported call, recording the error message and keeping panics from crossing into C
+fn call(body: impl FnOnce() -> Result<P2pStatus, Failure>) -> P2pStatus {
+    let failure = match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(status)) => {
+            set_last_error(None);
+            return status;
+        }
+        Ok(Err(failure)) => failure,
+        Err(_) => Failure {
+            status: P2pStatus::Internal,
+            message: "internal error (panic)".to_string(),
+        },
+    };
+    set_last_error(Some(failure.message));
+    failure.status
+}
+
+fn set_last_error(message: Option<String>) {
+    // Messages never contain NUL, but don't lose the error if one ever does
+    let message = message.map(|m| CString::new(m.replace('\0', " ")).unwrap_or_default());
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+unsafe fn string_arg<'a>(value: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    if value.is_null() {
+        return Err(Failure::invalid(format!("{} is null", name)));
+    }
+    CStr::from_ptr(value)
+        .to_str()
+        .map_err(|_| Failure::invalid(format!("{} is not valid UTF-8", name)))
+}
+
+unsafe fn client_arg<'a>(client: *const P2pClient) -> Result<&'a Client, Failure> {
+    client
+        .as_ref()
+        .map(|client| &client.inner)
+        .ok_or_else(|| Failure::invalid("client is null"))
+}
+
+/// Connect to the node at `addr` ("host:port") and store a new handle in `*out`.
+/// `timeout_ms` bounds connecting and each request; 0 keeps the defaults (5s / 10s).
+/// Free the handle with p2p_client_free.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_client_connect(addr: *const c_char, timeout_ms: u32, out: *mut *mut P2pClient) -> P2pStatus {
+    call(|| {
+        if out.is_null() {
+            return Err(Failure::invalid("out is null"));
+        }
+        let mut builder = Client::builder(string_arg(addr, "addr")?);
+        if timeout_ms > 0 {
+            let timeout = Duration::from_millis(timeout_ms.into());
+            builder = builder.connect_timeout(timeout).request_timeout(timeout);
+        }
+        let inner = runtime().block_on(builder.connect())?;
+        *out = Box::into_raw(Box::new(P2pClient { inner }));
+        Ok(P2pStatus::Ok)
+    })
+}
+
+/// Close a handle from p2p_client_connect; NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_client_free(client: *mut P2pClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Look up `key`. On P2P_STATUS_OK `*value_out` receives a string to release with
+/// p2p_string_free; on P2P_STATUS_NOT_FOUND it is set to NULL.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_get(client: *const P2pClient, key: *const c_char, value_out: *mut *mut c_char) -> P2pStatus {
+    call(|| {
+        if value_out.is_null() {
+            return Err(Failure::invalid("value_out is null"));
+        }
+        *value_out = ptr::null_mut();
+        let client = client_arg(client)?;
+        match runtime().block_on(client.get(string_arg(key, "key")?))? {
+            Some(value) => {
+                let value = CString::new(value).map_err(|_| Failure {
+                    status: P2pStatus::Protocol,
+                    message: "value contains a NUL byte".to_string(),
+                })?;
+                *value_out = value.into_raw();
+                Ok(P2pStatus::Ok)
+            }
+            None => Ok(P2pStatus::NotFound),
+        }
+    })
+}
+
+/// Store a string value; the node replicates it to its peers.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_set(client: *const P2pClient, key: *const c_char, value: *const c_char) -> P2pStatus {
+    call(|| {
+        let client = client_arg(client)?;
+        runtime().block_on(client.set(string_arg(key, "key")?, string_arg(value, "value")?))?;
+        Ok(P2pStatus::Ok)
+    })
+}
+
+/// Delete `key` everywhere. `existed` may be NULL; otherwise it receives whether the
+/// key existed on the node that answered.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_del(client: *const P2pClient, key: *const c_char, existed: *mut bool) -> P2pStatus {
+    call(|| {
+        let client = client_arg(client)?;
+        let removed = runtime().block_on(client.del(string_arg(key, "key")?))?;
+        if !existed.is_null() {
+            *existed = removed;
+        }
+        Ok(P2pStatus::Ok)
+    })
+}
+
+/// Release a string returned by this library; NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn p2p_string_free(value: *mut c_char) {
+    if !value.is_null() {
+        drop(CString::from_raw(value));
+    }
+}
+
+/// Message for the last failed call on this thread, or NULL if it succeeded.
+/// Valid until the next call on the same thread.
+#[no_mangle]
+pub extern "C" fn p2p_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
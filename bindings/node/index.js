@@ -0,0 +1,95 @@
+'use strict';
+
+// Promise-based client for p2p-rust nodes; subscriptions are EventEmitters
+// emitting 'set' (key, value), 'del' (key), 'lagged' (missed), 'error' and 'end'.
+
+const { EventEmitter } = require('node:events');
+const native = require('./p2p_rust_client.node');
+
+// Native errors read "<code>: <message>"; expose the code the Node.js way
+function withCode(error) {
+  const match = /^(P2P_[A-Z_]+): ([\s\S]*)$/.exec(error && error.message);
+  if (match) {
+    error.code = match[1];
+    error.message = match[2];
+  }
+  return error;
+}
+
+function call(promise) {
+  return promise.catch((error) => {
+    throw withCode(error);
+  });
+}
+
+class Subscription extends EventEmitter {
+  constructor(inner) {
+    super();
+    this.inner = inner;
+    this.closed = false;
+    // Let listeners attach before the first event is emitted
+    setImmediate(() => this.pump());
+  }
+
+  async pump() {
+    try {
+      for (;;) {
+        const event = await call(this.inner.next());
+        if (event === null || this.closed) break;
+        if (event.kind === 'set') this.emit('set', event.key, event.value);
+        else if (event.kind === 'del') this.emit('del', event.key);
+        else this.emit('lagged', event.missed);
+      }
+    } catch (error) {
+      if (!this.closed) this.emit('error', error);
+    }
+    this.emit('end');
+  }
+
+  close() {
+    this.closed = true;
+    this.inner.close();
+  }
+}
+
+class Client {
+  // connect('127.0.0.1:8080') or connect([...nodes], { requestTimeoutMs, connectTimeoutMs, poolSize, discoverMs })
+  static async connect(addrs, options) {
+    return new Client(await call(native.connect(addrs, options)));
+  }
+
+  constructor(inner) {
+    this.inner = inner;
+  }
+
+  get addr() {
+    return this.inner.addr;
+  }
+
+  get(key) {
+    return call(this.inner.get(key));
+  }
+
+  set(key, value) {
+    return call(this.inner.set(key, value));
+  }
+
+  del(key) {
+    return call(this.inner.del(key));
+  }
+
+  // Up to `count` (100) { key, value } pairs under `prefix` in key order, starting after `after`
+  scan(prefix = '', { after, count } = {}) {
+    return call(this.inner.scan(prefix, after, count));
+  }
+
+  request(command) {
+    return call(this.inner.request(command));
+  }
+
+  async subscribe(prefix = '') {
+    return new Subscription(await call(this.inner.subscribe(prefix)));
+  }
+}
+
+module.exports = { Client, Subscription };
@@ -0,0 +1,196 @@
+//! Node.js bindings for the p2p-rust client, loaded by index.js.
+//!
+//! Every method returns a Promise that runs on napi's tokio runtime. Failures
+//! reject with a message of the form `<code>: <description>`; index.js moves
+//! the code into `error.code`. A subscription reads changes in a background
+//! task and index.js pumps `next()` into an EventEmitter.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use p2p_rust::client::{Event, Subscription as RustSubscription};
+use p2p_rust::{Client as RustClient, ClientBuilder, ClientError};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::AbortHandle;
+use tokio::time::Duration;
+
+// Changes buffered between the reading task and JavaScript
+const EVENT_BUFFER: usize = 256;
+
+fn to_js_error(e: ClientError) -> Error {
+    let (status, code) = match e {
+        ClientError::Connect { .. } => (Status::GenericFailure, "P2P_CONNECT"),
+        ClientError::Timeout(_) => (Status::GenericFailure, "P2P_TIMEOUT"),
+        ClientError::Io(_) => (Status::GenericFailure, "P2P_IO"),
+        ClientError::Server(_) => (Status::GenericFailure, "P2P_SERVER"),
+        ClientError::Protocol(_) => (Status::GenericFailure, "P2P_PROTOCOL"),
+        ClientError::InvalidRequest(_) => (Status::InvalidArg, "P2P_INVALID_REQUEST"),
+    };
+    Error::new(status, format!("{}: {}", code, e))
+}
+
+#[napi(object)]
+pub struct ConnectOptions {
+    pub connect_timeout_ms: Option<u32>,
+    // Time allowed for sending a request and reading the whole response
+    pub request_timeout_ms: Option<u32>,
+    // Persistent connections kept per node, 0 (the default) connects per request
+    pub pool_size: Option<u32>,
+    // Learn the cluster from PEERS and health-check nodes at this interval
+    pub discover_ms: Option<u32>,
+}
+
+#[napi(object)]
+pub struct Pair {
+    pub key: String,
+    pub value: String,
+}
+
+// kind is "set", "del" or "lagged"
+#[napi(object)]
+pub struct ChangeEvent {
+    pub kind: String,
+    pub key: Option<String>,
+    pub value: Option<String>,
+    // Changes skipped because the subscriber fell behind, for "lagged"
+    pub missed: Option<i64>,
+}
+
+impl From<Event> for ChangeEvent {
+    fn from(event: Event) -> Self {
+        let (kind, key, value, missed) = match event {
+            Event::Set { key, value } => ("set", Some(key), Some(value), None),
+            Event::Del { key } => ("del", Some(key), None, None),
+            Event::Lagged(missed) => ("lagged", None, None, Some(missed as i64)),
+        };
+        ChangeEvent { kind: kind.to_string(), key, value, missed }
+    }
+}
+
+// connect("127.0.0.1:8080") or connect(["127.0.0.1:8080", "127.0.0.1:8081"], { poolSize: 4 })
+#[napi]
+pub async fn connect(addrs: Either<String, Vec<String>>, options: Option<ConnectOptions>) -> Result<Client> {
+    let addrs = match addrs {
+        Either::A(addr) => vec![addr],
+        Either::B(addrs) => addrs,
+    };
+    let mut builder = ClientBuilder::with_nodes(addrs);
+    if let Some(options) = options {
+        let millis = |ms: u32| Duration::from_millis(ms.into());
+        if let Some(ms) = options.connect_timeout_ms {
+            builder = builder.connect_timeout(millis(ms));
+        }
+        if let Some(ms) = options.request_timeout_ms {
+            builder = builder.request_timeout(millis(ms));
+        }
+        if let Some(size) = options.pool_size {
+            builder = builder.pool_size(size as usize);
+        }
+        if let Some(ms) = options.discover_ms {
+            builder = builder.discover(millis(ms));
+        }
+    }
+    let inner = builder.connect().await.map_err(to_js_error)?;
+    Ok(Client { inner })
+}
+
+#[napi]
+pub struct Client {
+    inner: RustClient,
+}
+
+#[napi]
+impl Client {
+    // The node that answered the last request
+    #[napi(getter)]
+    pub fn addr(&self) -> String {
+        self.inner.addr()
+    }
+
+    // Resolves to null for a missing key
+    #[napi]
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.get(&key).await.map_err(to_js_error)
+    }
+
+    #[napi]
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.inner.set(&key, &value).await.map_err(to_js_error)
+    }
+
+    // Resolves to whether the key existed on the node that answered
+    #[napi]
+    pub async fn del(&self, key: String) -> Result<bool> {
+        self.inner.del(&key).await.map_err(to_js_error)
+    }
+
+    #[napi]
+    pub async fn scan(&self, prefix: Option<String>, after: Option<String>, count: Option<u32>) -> Result<Vec<Pair>> {
+        let count = count.map_or(100, |count| count as usize);
+        let pairs = self
+            .inner
+            .scan(prefix.as_deref().unwrap_or(""), after.as_deref(), count)
+            .await
+            .map_err(to_js_error)?;
+        Ok(pairs.into_iter().map(|(key, value)| Pair { key, value }).collect())
+    }
+
+    // A raw protocol command, resolving to the node's whole response
+    #[napi]
+    pub async fn request(&self, command: String) -> Result<String> {
+        self.inner.request(&command).await.map_err(to_js_error)
+    }
+
+    #[napi]
+    pub async fn subscribe(&self, prefix: Option<String>) -> Result<Subscription> {
+        let subscription = self.inner.subscribe(prefix.as_deref().unwrap_or("")).await.map_err(to_js_error)?;
+        Ok(Subscription::forward(subscription))
+    }
+}
+
+type Events = mpsc::Receiver<std::result::Result<Event, ClientError>>;
+
+#[napi]
+pub struct Subscription {
+    events: Mutex<Events>,
+    task: AbortHandle,
+}
+
+impl Subscription {
+    // Read the subscription in the background until it ends, fails or is closed
+    fn forward(mut subscription: RustSubscription) -> Self {
+        let (sender, events) = mpsc::channel(EVENT_BUFFER);
+        let task = tokio::spawn(async move {
+            while let Some(event) = subscription.next().await.transpose() {
+                let failed = event.is_err();
+                if sender.send(event).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Subscription {
+            events: Mutex::new(events),
+            task: task.abort_handle(),
+        }
+    }
+}
+
+#[napi]
+impl Subscription {
+    // Resolves to the next change, or null once the subscription has ended
+    #[napi]
+    pub async fn next(&self) -> Result<Option<ChangeEvent>> {
+        let event = self.events.lock().await.recv().await;
+        event.transpose().map(|event| event.map(ChangeEvent::from)).map_err(to_js_error)
+    }
+
+    #[napi]
+    pub fn close(&self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,421 @@
+//! Python bindings for the p2p-rust client, importable as `p2p_rust_client`.
+//!
+//! `Client` blocks the calling thread (with the GIL released) and `AsyncClient`
+//! returns asyncio awaitables; both run on one shared tokio runtime. A
+//! subscription reads changes in a background task and hands them over through
+//! a channel, so Ctrl-C or a cancelled `await` never loses a half-read change.
+//!
+//! Awaitables are resolved from runtime threads through
+//! `loop.call_soon_threadsafe`. An `atexit` hook stops new resolutions and
+//! waits for running ones, because a thread that still holds the GIL once the
+//! interpreter starts finalizing crashes the process.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use p2p_rust::client::{Event, Subscription as RustSubscription};
+use p2p_rust::{Client as RustClient, ClientBuilder, ClientError as RustError};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyStopAsyncIteration, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyCFunction;
+use pyo3::IntoPyObjectExt;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+// How often a blocking iteration wakes up to let Python handle signals
+const SIGNAL_CHECK: Duration = Duration::from_millis(100);
+// Changes buffered between the reading task and Python
+const EVENT_BUFFER: usize = 256;
+
+// Set by the atexit hook; awaitables that finish later are dropped unresolved
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+// Runtime threads currently resolving an awaitable
+static RESOLVING: AtomicUsize = AtomicUsize::new(0);
+
+create_exception!(p2p_rust_client, ClientError, PyException, "Base class for client errors.");
+create_exception!(p2p_rust_client, ConnectError, ClientError, "No node could be reached.");
+create_exception!(p2p_rust_client, TimeoutError, ClientError, "The node did not answer in time.");
+create_exception!(p2p_rust_client, ServerError, ClientError, "The node rejected the request.");
+create_exception!(p2p_rust_client, ProtocolError, ClientError, "The node sent something unexpected.");
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start the tokio runtime")
+    })
+}
+
+fn to_py_err(e: RustError) -> PyErr {
+    let message = e.to_string();
+    match e {
+        RustError::Connect { .. } => ConnectError::new_err(message),
+        RustError::Timeout(_) => TimeoutError::new_err(message),
+        RustError::Server(_) => ServerError::new_err(message),
+        RustError::Protocol(_) => ProtocolError::new_err(message),
+        RustError::InvalidRequest(_) => PyValueError::new_err(message),
+        RustError::Io(_) => ClientError::new_err(message),
+    }
+}
+
+// Run a client call to completion without holding the GIL
+fn block<T: Send>(py: Python<'_>, call: impl Future<Output = Result<T, RustError>> + Send) -> PyResult<T> {
+    py.detach(|| runtime().block_on(call)).map_err(to_py_err)
+}
+
+// Run `call` on the runtime and return an asyncio future for its result; cancelling the
+// future cancels the call
+fn awaitable<T>(py: Python<'_>, call: impl Future<Output = PyResult<T>> + Send + 'static) -> PyResult<Bound<'_, PyAny>>
+where
+    T: for<'py> IntoPyObject<'py> + Send + 'static,
+{
+    let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+    let future = event_loop.call_method0("create_future")?;
+    let (loop_ref, future_ref) = (event_loop.unbind(), future.clone().unbind());
+    let task = runtime().spawn(async move {
+        let outcome = call.await;
+        RESOLVING.fetch_add(1, Ordering::SeqCst);
+        if !SHUTTING_DOWN.load(Ordering::SeqCst) {
+            // Fails if the loop has already been closed, and then nobody is waiting
+            let _ = Python::attach(|py| -> PyResult<()> {
+                let (value, failed) = match outcome.and_then(|value| value.into_py_any(py)) {
+                    Ok(value) => (value, false),
+                    Err(e) => (e.into_value(py).into_any(), true),
+                };
+                let resolve = wrap_pyfunction!(resolve, py)?;
+                loop_ref.bind(py).call_method1("call_soon_threadsafe", (resolve, future_ref, value, failed))?;
+                Ok(())
+            });
+        }
+        RESOLVING.fetch_sub(1, Ordering::SeqCst);
+    });
+    let abort = task.abort_handle();
+    let cancel = PyCFunction::new_closure(py, None, None, move |_, _| abort.abort())?;
+    future.call_method1("add_done_callback", (cancel,))?;
+    Ok(future)
+}
+
+// Runs on the event loop; the future is already done if it was cancelled meanwhile
+#[pyfunction]
+fn resolve(future: &Bound<'_, PyAny>, value: &Bound<'_, PyAny>, failed: bool) -> PyResult<()> {
+    if future.call_method0("done")?.is_truthy()? {
+        return Ok(());
+    }
+    let method = if failed { "set_exception" } else { "set_result" };
+    future.call_method1(method, (value,))?;
+    Ok(())
+}
+
+// Registered with atexit, which runs before the interpreter starts finalizing
+#[pyfunction]
+fn stop_resolving(py: Python<'_>) {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    py.detach(|| {
+        while RESOLVING.load(Ordering::SeqCst) > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    });
+}
+
+fn seconds(value: f64) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(value).map_err(|_| PyValueError::new_err(format!("invalid duration: {}", value)))
+}
+
+// `addrs` is one "host:port" string or a list of them
+fn builder(
+    addrs: &Bound<'_, PyAny>,
+    connect_timeout: Option<f64>,
+    request_timeout: Option<f64>,
+    pool_size: usize,
+    discover: Option<f64>,
+) -> PyResult<ClientBuilder> {
+    let addrs: Vec<String> = match addrs.extract::<String>() {
+        Ok(addr) => vec![addr],
+        Err(_) => addrs.extract()?,
+    };
+    let mut builder = ClientBuilder::with_nodes(addrs).pool_size(pool_size);
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connect_timeout(seconds(connect_timeout)?);
+    }
+    if let Some(request_timeout) = request_timeout {
+        builder = builder.request_timeout(seconds(request_timeout)?);
+    }
+    if let Some(interval) = discover {
+        builder = builder.discover(seconds(interval)?);
+    }
+    Ok(builder)
+}
+
+// A change yielded by a subscription: kind is "set", "del" or "lagged"
+#[pyclass(name = "Event", frozen, get_all)]
+struct ChangeEvent {
+    kind: String,
+    key: Option<String>,
+    value: Option<String>,
+    // Changes skipped because the subscriber fell behind, for "lagged"
+    missed: Option<u64>,
+}
+
+#[pymethods]
+impl ChangeEvent {
+    fn __repr__(&self) -> String {
+        match (&self.key, &self.value, self.missed) {
+            (Some(key), Some(value), _) => format!("Event(set, {:?}={:?})", key, value),
+            (Some(key), None, _) => format!("Event(del, {:?})", key),
+            (_, _, missed) => format!("Event(lagged, {})", missed.unwrap_or(0)),
+        }
+    }
+}
+
+impl From<Event> for ChangeEvent {
+    fn from(event: Event) -> Self {
+        let (kind, key, value, missed) = match event {
+            Event::Set { key, value } => ("set", Some(key), Some(value), None),
+            Event::Del { key } => ("del", Some(key), None, None),
+            Event::Lagged(missed) => ("lagged", None, None, Some(missed)),
+        };
+        ChangeEvent { kind: kind.to_string(), key, value, missed }
+    }
+}
+
+type Events = mpsc::Receiver<Result<Event, RustError>>;
+
+// Read the subscription in the background until it ends, fails or the receiver is dropped
+fn forward(mut subscription: RustSubscription) -> (Events, JoinHandle<()>) {
+    let (sender, events) = mpsc::channel(EVENT_BUFFER);
+    let task = runtime().spawn(async move {
+        while let Some(event) = subscription.next().await.transpose() {
+            let failed = event.is_err();
+            if sender.send(event).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    (events, task)
+}
+
+// Iterator over changes; the loop ends when the node closes the connection
+#[pyclass]
+struct Subscription {
+    events: Mutex<Events>,
+    task: JoinHandle<()>,
+}
+
+#[pymethods]
+impl Subscription {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<ChangeEvent>> {
+        loop {
+            let received = py.detach(|| {
+                runtime().block_on(async {
+                    let mut events = self.events.lock().await;
+                    tokio::time::timeout(SIGNAL_CHECK, events.recv()).await
+                })
+            });
+            match received {
+                Ok(Some(Ok(event))) => return Ok(Some(event.into())),
+                Ok(Some(Err(e))) => return Err(to_py_err(e)),
+                Ok(None) => return Ok(None),
+                Err(_) => py.check_signals()?,
+            }
+        }
+    }
+
+    // Unsubscribe; also happens when the object is garbage collected
+    fn close(&self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// Async iterator over changes, for `async for event in subscription`
+#[pyclass]
+struct AsyncSubscription {
+    events: Arc<Mutex<Events>>,
+    task: JoinHandle<()>,
+}
+
+#[pymethods]
+impl AsyncSubscription {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let events = Arc::clone(&self.events);
+        awaitable(py, async move {
+            match events.lock().await.recv().await {
+                Some(Ok(event)) => Ok(ChangeEvent::from(event)),
+                Some(Err(e)) => Err(to_py_err(e)),
+                None => Err(PyStopAsyncIteration::new_err(())),
+            }
+        })
+    }
+
+    fn close(&self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for AsyncSubscription {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// Blocking client, e.g. Client("127.0.0.1:8080", request_timeout=2.0, pool_size=4)
+#[pyclass]
+struct Client {
+    inner: RustClient,
+}
+
+#[pymethods]
+impl Client {
+    #[new]
+    #[pyo3(signature = (addrs, *, connect_timeout=None, request_timeout=None, pool_size=0, discover=None))]
+    fn new(
+        py: Python<'_>,
+        addrs: &Bound<'_, PyAny>,
+        connect_timeout: Option<f64>,
+        request_timeout: Option<f64>,
+        pool_size: usize,
+        discover: Option<f64>,
+    ) -> PyResult<Self> {
+        let builder = builder(addrs, connect_timeout, request_timeout, pool_size, discover)?;
+        let inner = block(py, builder.connect())?;
+        Ok(Client { inner })
+    }
+
+    // The node that answered the last request
+    #[getter]
+    fn addr(&self) -> String {
+        self.inner.addr()
+    }
+
+    fn get(&self, py: Python<'_>, key: &str) -> PyResult<Option<String>> {
+        block(py, self.inner.get(key))
+    }
+
+    fn set(&self, py: Python<'_>, key: &str, value: &str) -> PyResult<()> {
+        block(py, self.inner.set(key, value))
+    }
+
+    // Whether the key existed on the node that answered
+    fn delete(&self, py: Python<'_>, key: &str) -> PyResult<bool> {
+        block(py, self.inner.del(key))
+    }
+
+    #[pyo3(signature = (prefix="", after=None, count=100))]
+    fn scan(&self, py: Python<'_>, prefix: &str, after: Option<&str>, count: usize) -> PyResult<Vec<(String, String)>> {
+        block(py, self.inner.scan(prefix, after, count))
+    }
+
+    // A raw protocol command, returning the node's whole response
+    fn request(&self, py: Python<'_>, command: &str) -> PyResult<String> {
+        block(py, self.inner.request(command))
+    }
+
+    #[pyo3(signature = (prefix=""))]
+    fn subscribe(&self, py: Python<'_>, prefix: &str) -> PyResult<Subscription> {
+        let (events, task) = forward(block(py, self.inner.subscribe(prefix))?);
+        Ok(Subscription { events: Mutex::new(events), task })
+    }
+}
+
+// asyncio client, created with `await AsyncClient.connect(...)`; every method returns an awaitable
+#[pyclass]
+struct AsyncClient {
+    inner: RustClient,
+}
+
+#[pymethods]
+impl AsyncClient {
+    #[staticmethod]
+    #[pyo3(signature = (addrs, *, connect_timeout=None, request_timeout=None, pool_size=0, discover=None))]
+    fn connect<'py>(
+        py: Python<'py>,
+        addrs: &Bound<'py, PyAny>,
+        connect_timeout: Option<f64>,
+        request_timeout: Option<f64>,
+        pool_size: usize,
+        discover: Option<f64>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let builder = builder(addrs, connect_timeout, request_timeout, pool_size, discover)?;
+        awaitable(py, async move {
+            let inner = builder.connect().await.map_err(to_py_err)?;
+            Ok(AsyncClient { inner })
+        })
+    }
+
+    #[getter]
+    fn addr(&self) -> String {
+        self.inner.addr()
+    }
+
+    fn get<'py>(&self, py: Python<'py>, key: String) -> PyResult<Bound<'py, PyAny>> {
+        let client = self.inner.clone();
+        awaitable(py, async move { client.get(&key).await.map_err(to_py_err) })
+    }
+
+    fn set<'py>(&self, py: Python<'py>, key: String, value: String) -> PyResult<Bound<'py, PyAny>> {
+        let client = self.inner.clone();
+        awaitable(py, async move { client.set(&key, &value).await.map_err(to_py_err) })
+    }
+
+    fn delete<'py>(&self, py: Python<'py>, key: String) -> PyResult<Bound<'py, PyAny>> {
+        let client = self.inner.clone();
+        awaitable(py, async move { client.del(&key).await.map_err(to_py_err) })
+    }
+
+    #[pyo3(signature = (prefix=String::new(), after=None, count=100))]
+    fn scan<'py>(&self, py: Python<'py>, prefix: String, after: Option<String>, count: usize) -> PyResult<Bound<'py, PyAny>> {
+        let client = self.inner.clone();
+        awaitable(py, async move {
+            client.scan(&prefix, after.as_deref(), count).await.map_err(to_py_err)
+        })
+    }
+
+    fn request<'py>(&self, py: Python<'py>, command: String) -> PyResult<Bound<'py, PyAny>> {
+        let client = self.inner.clone();
+        awaitable(py, async move { client.request(&command).await.map_err(to_py_err) })
+    }
+
+    #[pyo3(signature = (prefix=String::new()))]
+    fn subscribe<'py>(&self, py: Python<'py>, prefix: String) -> PyResult<Bound<'py, PyAny>> {
+        let client = self.inner.clone();
+        awaitable(py, async move {
+            let (events, task) = forward(client.subscribe(&prefix).await.map_err(to_py_err)?);
+            Ok(AsyncSubscription { events: Arc::new(Mutex::new(events)), task })
+        })
+    }
+}
+
+#[pymodule]
+fn p2p_rust_client(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Client>()?;
+    m.add_class::<AsyncClient>()?;
+    m.add_class::<Subscription>()?;
+    m.add_class::<AsyncSubscription>()?;
+    m.add_class::<ChangeEvent>()?;
+    let py = m.py();
+    py.import("atexit")?.call_method1("register", (wrap_pyfunction!(stop_resolving, m)?,))?;
+    m.add("ClientError", py.get_type::<ClientError>())?;
+    m.add("ConnectError", py.get_type::<ConnectError>())?;
+    m.add("TimeoutError", py.get_type::<TimeoutError>())?;
+    m.add("ServerError", py.get_type::<ServerError>())?;
+    m.add("ProtocolError", py.get_type::<ProtocolError>())?;
+    Ok(())
+}
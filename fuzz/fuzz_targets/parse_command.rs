@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use p2p_rust::protocol::command::parse_command;
+
+// Requests arrive as raw bytes and are decoded lossily, exactly as in handle_connection
+fuzz_target!(|data: &[u8]| {
+    let request = String::from_utf8_lossy(data);
+    let _ = parse_command(&request);
+});
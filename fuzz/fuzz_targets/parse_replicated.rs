@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use p2p_rust::protocol::command::parse_replicated;
+
+// Replicated operations must also round-trip through their canonical text
+fuzz_target!(|data: &[u8]| {
+    let command = String::from_utf8_lossy(data);
+    if let Ok(op) = parse_replicated(&command) {
+        let reparsed = parse_replicated(&op.to_string()).expect("canonical form parses");
+        assert_eq!(op.to_string(), reparsed.to_string());
+    }
+});
@@ -0,0 +1,232 @@
+//! Interactive shell for talking to a node, started with `p2p-rust cli [addr]`.
+//!
+//! Lines are sent as raw protocol commands over a pooled [`Client`]
+//! connection. GET_ALL and SCAN results are printed as a numbered, aligned
+//! list with JSON values pretty-printed, and SUBSCRIBE streams changes until
+//! Ctrl-C. Lines starting with `:` are handled by the shell itself, e.g.
+//! `:connect <addr>` switches to another node. Commands and known node
+//! addresses complete with Tab; history is kept in `~/.p2p_rust_history`.
+
+use std::path::PathBuf;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::history::FileHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use tokio::time::Duration;
+
+use crate::client::{Event, RetryPolicy};
+use crate::Client;
+
+const HISTORY_FILE: &str = ".p2p_rust_history";
+
+// Protocol commands offered by Tab completion
+const COMMANDS: &[&str] = &[
+    "ADDPEER", "AGG", "APPEND", "BACKUP", "CID", "CLUSTER", "COMPACT", "COUNT", "CREATE_INDEX", "DEBUG", "DEL", "DEL_MATCH", "DEL_PREFIX", "DIFF", "DROP_INDEX", "EXPIREAT", "EXPORT", "FIND",
+    "FLUSHALL", "GET", "GET_ALL", "GET_LEN", "GETEX", "GETHIST", "HDEL", "HGET", "HGETALL", "HSET", "IMPORT", "INCR", "JSON.GET", "JSON.SET",
+    "LIST_INDEXES", "LPOP", "LPUSH", "LRANGE", "MEMORY", "METRICS", "OUTBOX", "PEERS", "PERSIST", "PING", "REMOVEPEER", "RESTORE", "RPOP", "RPUSH", "SCAN", "SESSION", "SET", "SIZES", "STATS",
+    "SUBSCRIBE", "SYNC", "TTL", "TYPE", "XADD", "XCOMMIT", "XLEN", "XREAD", "ZADD", "ZRANGEBYSCORE", "ZREM", "ZSCORE",
+];
+
+const META_COMMANDS: &[&str] = &[":connect", ":help", ":quit"];
+
+const HELP: &str = "\
+Type protocol commands as you would over nc, e.g. GET key or SCAN PREFIX user: COUNT 10.
+  :connect <addr>  switch to another node (Tab completes the current node's peers)
+  :help            show this help
+  :quit            leave the shell (or Ctrl-D)
+SUBSCRIBE <prefix> streams changes until Ctrl-C.";
+
+type ReplEditor = Editor<ReplHelper, FileHistory>;
+
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ReplHelper {
+    // The connected node and its peers, for completing :connect
+    nodes: Vec<String>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let candidates = match line.split_once(char::is_whitespace) {
+            None if line.starts_with(':') => matching(META_COMMANDS.iter().copied(), line),
+            None => matching(COMMANDS.iter().copied(), &line.to_uppercase()),
+            Some((":connect", rest)) if !rest.trim_start().contains(char::is_whitespace) => {
+                let word = rest.trim_start();
+                return Ok((pos - word.len(), matching(self.nodes.iter().map(String::as_str), word)));
+            }
+            Some(_) => Vec::new(),
+        };
+        Ok((0, candidates))
+    }
+}
+
+fn matching<'a>(options: impl Iterator<Item = &'a str>, prefix: &str) -> Vec<String> {
+    options.filter(|option| option.starts_with(prefix)).map(str::to_string).collect()
+}
+
+// Run the shell against `addr` until the user quits
+pub async fn run(addr: &str) -> Result<(), ReadlineError> {
+    let mut editor = ReplEditor::new()?;
+    editor.set_helper(Some(ReplHelper { nodes: Vec::new() }));
+    let history = history_path();
+    // A missing history file just means a first run
+    let _ = editor.load_history(&history);
+
+    println!("p2p-rust cli, :help for help");
+    let mut client = connect(&mut editor, addr).await;
+    loop {
+        let prompt = match &client {
+            Some(client) => format!("{}> ", client.addr()),
+            None => "(disconnected)> ".to_string(),
+        };
+        let (returned, line) = tokio::task::spawn_blocking(move || {
+            let line = editor.readline(&prompt);
+            (editor, line)
+        })
+        .await
+        .expect("readline task panicked");
+        editor = returned;
+
+        let line = match line {
+            Ok(line) => line,
+            // Ctrl-C clears the line, Ctrl-D leaves
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match name {
+            ":quit" | ":exit" => break,
+            ":help" => println!("{}", HELP),
+            ":connect" if args.trim().is_empty() => println!("(error) usage: :connect <addr>"),
+            ":connect" => {
+                if let Some(connected) = connect(&mut editor, args.trim()).await {
+                    client = Some(connected);
+                }
+            }
+            _ if name.starts_with(':') => println!("(error) unknown shell command {}, see :help", name),
+            _ => match &client {
+                Some(client) if name.eq_ignore_ascii_case("SUBSCRIBE") => subscribe(client, args.trim()).await,
+                // Command names are case-sensitive on the node
+                Some(client) => match client.request(&format!("{} {}", name.to_uppercase(), args)).await {
+                    Ok(response) => println!("{}", format_response(name, &response)),
+                    Err(e) => println!("(error) {}", e),
+                },
+                None => println!("(error) not connected, use :connect <addr>"),
+            },
+        }
+    }
+
+    if let Err(e) = editor.save_history(&history) {
+        eprintln!("Failed to save history to {}: {}", history.display(), e);
+    }
+    Ok(())
+}
+
+fn history_path() -> PathBuf {
+    std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(HISTORY_FILE)
+}
+
+// Connect to `addr` and remember its peers for completion; errors are printed
+async fn connect(editor: &mut ReplEditor, addr: &str) -> Option<Client> {
+    let connected = Client::builder(addr)
+        .pool_size(1)
+        .connect_timeout(Duration::from_secs(2))
+        .retry(RetryPolicy::none())
+        .connect()
+        .await;
+    let client = match connected {
+        Ok(client) => client,
+        Err(e) => {
+            println!("(error) {}", e);
+            return None;
+        }
+    };
+    let mut nodes = vec![addr.to_string()];
+    if let Ok(peers) = client.request("PEERS").await {
+        nodes.extend(peers.lines().map(str::to_string));
+    }
+    if let Some(helper) = editor.helper_mut() {
+        helper.nodes = nodes;
+    }
+    Some(client)
+}
+
+// Print changes under `prefix` until Ctrl-C or the node closes the subscription
+async fn subscribe(client: &Client, prefix: &str) {
+    let mut subscription = match client.subscribe(prefix).await {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            println!("(error) {}", e);
+            return;
+        }
+    };
+    println!("Subscribed to {:?}, Ctrl-C to stop", prefix);
+    loop {
+        tokio::select! {
+            event = subscription.next() => match event {
+                Ok(Some(Event::Set { key, value })) => println!("SET {} = {}", key, value),
+                Ok(Some(Event::Del { key })) => println!("DEL {}", key),
+                Ok(Some(Event::Lagged(missed))) => println!("(lagged, {} changes skipped)", missed),
+                Ok(None) => {
+                    println!("(subscription closed by the node)");
+                    break;
+                }
+                Err(e) => {
+                    println!("(error) {}", e);
+                    break;
+                }
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+}
+
+// GET_ALL and SCAN answer with key=value lines; list them numbered with aligned values
+fn format_response(command: &str, response: &str) -> String {
+    if response.is_empty() {
+        return "(empty)".to_string();
+    }
+    let command = command.to_uppercase();
+    if command != "GET_ALL" && command != "SCAN" {
+        return pretty_json(response).unwrap_or_else(|| response.to_string());
+    }
+    let mut pairs: Vec<(&str, &str)> = Vec::new();
+    for line in response.lines() {
+        match line.split_once('=') {
+            Some(pair) => pairs.push(pair),
+            // Not a listing, e.g. an error message
+            None => return response.to_string(),
+        }
+    }
+    // GET_ALL comes in cache order, SCAN is already sorted
+    pairs.sort_by(|a, b| a.0.cmp(b.0));
+
+    let number_width = pairs.len().to_string().len();
+    let key_width = pairs.iter().map(|(key, _)| key.chars().count()).max().unwrap_or(0);
+    let indent = " ".repeat(number_width + 2 + key_width + 3);
+    let mut output = String::new();
+    for (i, (key, value)) in pairs.iter().enumerate() {
+        let value = pretty_json(value).unwrap_or_else(|| value.to_string()).replace('\n', &format!("\n{}", indent));
+        output.push_str(&format!("{:>number_width$}) {:<key_width$} = {}\n", i + 1, key, value));
+    }
+    output.push_str(&format!("({} {})", pairs.len(), if pairs.len() == 1 { "pair" } else { "pairs" }));
+    output
+}
+
+// Multi-line rendering of JSON objects and arrays; other values print as they are
+fn pretty_json(value: &str) -> Option<String> {
+    if !value.starts_with(['{', '[']) {
+        return None;
+    }
+    let json: serde_json::Value = serde_json::from_str(value).ok()?;
+    serde_json::to_string_pretty(&json).ok()
+}
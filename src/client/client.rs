@@ -1,29 +1,81 @@
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use arrow::ipc::reader::FileReader;
 use arrow::array::Array;
+use hdrhistogram::Histogram;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use p2p_rust::{Client, ClientBuilder};
+use serde_json::{json, Map, Value};
+use tokio::runtime::Runtime;
 use std::collections::HashMap;
 
 type SharedCache = Arc<Mutex<HashMap<String, String>>>;
 
+// Latencies are recorded in microseconds, from 1µs up to an hour with 3 significant digits
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, 3_600_000_000, 3).unwrap()
+}
+
+fn record_latency(histogram: &mut Histogram<u64>, latency: Duration) {
+    histogram.saturating_record(latency.as_micros() as u64);
+}
+
+fn print_percentiles(name: &str, histogram: &Histogram<u64>) {
+    let at = |quantile: f64| Duration::from_micros(histogram.value_at_quantile(quantile));
+    println!(
+        "{} Latency: p50={:?} p90={:?} p99={:?} p999={:?} max={:?}",
+        name,
+        at(0.5),
+        at(0.9),
+        at(0.99),
+        at(0.999),
+        Duration::from_micros(histogram.max())
+    );
+}
+
 fn send_request(node: &str, request: &str) -> Option<String> {
-    match TcpStream::connect(node) {
-        Ok(mut stream) => {
-            stream.write_all(request.as_bytes()).unwrap();
-            let mut buffer = [0; 1024];
-            let bytes_read = stream.read(&mut buffer).unwrap();
-            Some(String::from_utf8_lossy(&buffer[..bytes_read]).to_string())
-        }
+    let mut stream = match TcpStream::connect(node) {
+        Ok(stream) => stream,
         Err(e) => {
             eprintln!("Failed to connect to {}: {}", node, e);
+            return None;
+        }
+    };
+    let mut buffer = [0; 1024];
+    match stream.write_all(request.as_bytes()).and_then(|_| stream.read(&mut buffer)) {
+        Ok(bytes_read) => Some(String::from_utf8_lossy(&buffer[..bytes_read]).to_string()),
+        Err(e) => {
+            eprintln!("Request to {} failed: {}", node, e);
             None
         }
     }
 }
 
+// Ask a node to IMPORT a file and print its progress until the import finishes
+fn import(node: &str, file_path: &str, options: &[String]) {
+    // The node reads the file itself, so resolve the path relative to where the CLI runs
+    let file_path = std::fs::canonicalize(file_path)
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| file_path.to_string());
+    let request = format!("IMPORT {} {}\n", file_path, options.join(" "));
+
+    match TcpStream::connect(node) {
+        Ok(mut stream) => {
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut output = String::new();
+            if let Err(e) = stream.read_to_string(&mut output) {
+                eprintln!("Failed to read IMPORT response from {}: {}", node, e);
+            }
+            println!("{}", output.trim_end());
+        }
+        Err(e) => eprintln!("Failed to connect to {}: {}", node, e),
+    }
+}
+
 fn get_from_arrow(file_path: &str, key: &str) -> Option<String> {
     // Open the Arrow file
     let file = File::open(file_path).ok()?;
@@ -46,12 +98,633 @@ fn get_from_arrow(file_path: &str, key: &str) -> Option<String> {
 }
 
 
-fn benchmark_write(cache: SharedCache, write_node: &str, num_requests: usize) {
-    let mut total_time = Duration::ZERO;
+// Nodes read at most this many bytes of a request
+const MAX_REQUEST_SIZE: usize = 1024;
+// Distinct random payloads generated up front for --value-size
+const PAYLOADS: usize = 1024;
+// How long observers keep waiting for writes after the last one was sent
+const CONVERGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Which key index in 0..key_space operation i touches
+enum KeyChooser {
+    // i modulo the key space, every key in turn
+    Sequential,
+    Uniform,
+    // Skewed towards low indices, so a few hot keys take most of the operations
+    Zipfian(Zipfian),
+}
+
+impl KeyChooser {
+    fn parse(name: &str, key_space: usize, theta: f64) -> Result<KeyChooser, String> {
+        match name {
+            "sequential" => Ok(KeyChooser::Sequential),
+            "uniform" => Ok(KeyChooser::Uniform),
+            "zipfian" => Ok(KeyChooser::Zipfian(Zipfian::new(key_space, theta))),
+            _ => Err(format!("Unknown distribution {}, use sequential, uniform or zipfian", name)),
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            KeyChooser::Sequential => "sequential".to_string(),
+            KeyChooser::Uniform => "uniform".to_string(),
+            KeyChooser::Zipfian(zipfian) => format!("zipfian({})", zipfian.theta),
+        }
+    }
+
+    fn index(&self, i: usize, key_space: usize) -> usize {
+        match self {
+            KeyChooser::Sequential => i % key_space,
+            KeyChooser::Uniform => rand::thread_rng().gen_range(0..key_space),
+            KeyChooser::Zipfian(zipfian) => zipfian.sample(&mut rand::thread_rng()),
+        }
+    }
+}
+
+// Zipfian generator from YCSB (Gray et al., "Quickly generating billion-record synthetic
+// databases"); index 0 is the most popular
+struct Zipfian {
+    items: usize,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(items: usize, theta: f64) -> Zipfian {
+        let zeta = |n: usize| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(items);
+        Zipfian {
+            items,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zetan),
+        }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> usize {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.items - 1);
+        }
+        let index = (self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as usize;
+        index.min(self.items - 1)
+    }
+}
+
+// Share of GETs and PUTs in the mixed benchmark
+struct Workload {
+    name: String,
+    read_percent: u32,
+    // Writes go through the key space in order, adding keys rather than overwriting hot ones
+    inserts: bool,
+}
+
+impl Workload {
+    fn parse(name: &str) -> Result<Workload, String> {
+        let (read_percent, inserts) = match name {
+            "read-mostly" => (95, false),
+            "balanced" => (50, false),
+            "insert-heavy" => (10, true),
+            // <read>/<write> percentages, e.g. 80/20
+            _ => match name.split_once('/').map(|(r, w)| (r.parse::<u32>(), w.parse::<u32>())) {
+                Some((Ok(read), Ok(write))) if read + write == 100 => (read, false),
+                _ => return Err(format!("Unknown workload {}, use read-mostly, balanced, insert-heavy or <read>/<write>", name)),
+            },
+        };
+        Ok(Workload {
+            name: name.to_string(),
+            read_percent,
+            inserts,
+        })
+    }
+}
 
-    for i in 0..num_requests {
-        let key = format!("key{}", i);
-        let value = format!("value{}", i);
+// 60s, 500ms, 2m or plain seconds
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(at) => value.split_at(at),
+        None => (value, "s"),
+    };
+    let number: f64 = number.parse().ok()?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Read,
+    Write,
+    // A write showing up on an observer node
+    Converge,
+}
+
+impl Op {
+    const ALL: [Op; 3] = [Op::Read, Op::Write, Op::Converge];
+
+    // Name in results files
+    fn name(self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Converge => "converge",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Op::Read => "Read",
+            Op::Write => "Write",
+            Op::Converge => "Convergence",
+        }
+    }
+}
+
+// Flags shared by the write and read benchmarks
+struct Options {
+    // Worker threads issuing operations in parallel
+    concurrency: usize,
+    // Target operations per second across all workers; None runs closed-loop, each
+    // worker starting its next operation as soon as the previous one finishes
+    rate: Option<f64>,
+    // Random alphanumeric values of this many bytes instead of value<i>
+    value_size: Option<usize>,
+    // Operation i uses key <key_prefix><index>, with the index picked by `keys`
+    key_space: usize,
+    key_prefix: String,
+    keys: KeyChooser,
+    // Mix for the mixed benchmark, read-mostly unless --workload says otherwise
+    workload: Option<Workload>,
+    // Run for this long instead of num_requests operations
+    duration: Option<Duration>,
+    // Operations in this first stretch run but are not measured
+    warmup: Duration,
+    // Results file, CSV if it ends in .csv and JSON otherwise
+    output: Option<String>,
+    // JSON results of an earlier run to print deltas against
+    compare: Option<String>,
+    // Nodes for the tcp and mixed modes instead of write_node/read_node, and the observers
+    // for converge
+    nodes: Vec<String>,
+    // Send those requests through the pooled p2p_rust::Client instead of a connection each
+    pooled: bool,
+}
+
+impl Options {
+    fn parse(args: &[String], num_requests: usize) -> Result<Options, String> {
+        let mut options = Options {
+            concurrency: 1,
+            rate: None,
+            value_size: None,
+            key_space: num_requests.max(1),
+            key_prefix: "key".to_string(),
+            keys: KeyChooser::Sequential,
+            workload: None,
+            duration: None,
+            warmup: Duration::ZERO,
+            output: None,
+            compare: None,
+            nodes: Vec::new(),
+            pooled: false,
+        };
+        let mut distribution = "sequential".to_string();
+        let mut theta = 0.99;
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+            let positive = |name: &str| value.parse().ok().filter(|n: &usize| *n > 0).ok_or(format!("Invalid {}", name));
+            match flag.as_str() {
+                "--concurrency" => options.concurrency = positive(flag)?,
+                "--rate" => options.rate = Some(value.parse().ok().filter(|r: &f64| *r > 0.0).ok_or("Invalid --rate")?),
+                "--value-size" => options.value_size = Some(positive(flag)?),
+                "--key-space" => options.key_space = positive(flag)?,
+                "--key-prefix" => {
+                    if value.contains(char::is_whitespace) || value.contains('=') {
+                        return Err("Invalid --key-prefix".to_string());
+                    }
+                    options.key_prefix = value.clone();
+                }
+                "--distribution" => distribution = value.clone(),
+                "--zipf-theta" => {
+                    theta = value.parse().ok().filter(|t: &f64| *t > 0.0 && *t < 1.0).ok_or("Invalid --zipf-theta, use 0 < theta < 1")?
+                }
+                "--workload" => options.workload = Some(Workload::parse(value)?),
+                "--duration" => options.duration = Some(parse_duration(value).filter(|d| !d.is_zero()).ok_or("Invalid --duration")?),
+                "--warmup" => options.warmup = parse_duration(value).ok_or("Invalid --warmup")?,
+                "--output" => options.output = Some(value.clone()),
+                "--compare" => options.compare = Some(value.clone()),
+                "--nodes" => options.nodes = value.split(',').filter(|n| !n.is_empty()).map(str::to_string).collect(),
+                "--transport" => {
+                    options.pooled = match value.as_str() {
+                        "raw" => false,
+                        "pooled" => true,
+                        _ => return Err(format!("Unknown transport {}, use raw or pooled", value)),
+                    }
+                }
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+
+        // SET <key>=<value>\n for the longest key has to fit in one request
+        let longest_key = options.key_prefix.len() + (options.key_space - 1).to_string().len();
+        let longest_value = options.value_size.unwrap_or("value".len() + num_requests.to_string().len());
+        if "SET =\n".len() + longest_key + longest_value > MAX_REQUEST_SIZE {
+            return Err(format!("Keys and values must fit in a {} byte request", MAX_REQUEST_SIZE));
+        }
+        options.keys = KeyChooser::parse(&distribution, options.key_space, theta)?;
+        Ok(options)
+    }
+
+    fn key(&self, i: usize) -> String {
+        format!("{}{}", self.key_prefix, self.keys.index(i, self.key_space))
+    }
+
+    // The next key to insert, for insert-heavy workloads
+    fn new_key(&self, i: usize) -> String {
+        format!("{}{}", self.key_prefix, i % self.key_space)
+    }
+
+    // What a results file records about the run
+    fn config(&self, mode: &str, num_requests: usize) -> Value {
+        let default_workload = if mode == "mixed" { Some("read-mostly") } else { None };
+        json!({
+            "mode": mode,
+            "num_requests": num_requests,
+            "concurrency": self.concurrency,
+            "rate": self.rate,
+            "value_size": self.value_size,
+            "key_space": self.key_space,
+            "key_prefix": self.key_prefix,
+            "distribution": self.keys.name(),
+            "workload": self.workload.as_ref().map(|w| w.name.as_str()).or(default_workload),
+            "duration_secs": self.duration.map(|d| d.as_secs_f64()),
+            "warmup_secs": self.warmup.as_secs_f64(),
+            "nodes": self.nodes,
+            "transport": if self.pooled { "pooled" } else { "raw" },
+        })
+    }
+
+    // Values for the write benchmark, indexed by operation modulo their count
+    fn values(&self, num_requests: usize) -> Vec<String> {
+        match self.value_size {
+            Some(size) => (0..PAYLOADS.min(num_requests.max(1)))
+                .map(|_| rand::thread_rng().sample_iter(&Alphanumeric).take(size).map(char::from).collect())
+                .collect(),
+            None => (0..num_requests).map(|i| format!("value{}", i)).collect(),
+        }
+    }
+}
+
+// Quantiles kept in results files, with their JSON/CSV names
+const QUANTILES: &[(&str, f64)] = &[("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p999", 0.999)];
+
+struct Report {
+    name: String,
+    // Indexed by Op
+    latencies: [Histogram<u64>; 3],
+    // Operations that failed to connect, broke off or were rejected by the node
+    errors: u64,
+    // Wall-clock time from the first operation to the last
+    elapsed: Duration,
+}
+
+impl Report {
+    fn requests(&self) -> u64 {
+        self.latencies.iter().map(Histogram::len).sum()
+    }
+
+    fn throughput(&self) -> f64 {
+        self.requests() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    // Per-operation histograms that saw any operations
+    fn ops(&self) -> impl Iterator<Item = (Op, &Histogram<u64>)> {
+        Op::ALL.into_iter().zip(&self.latencies).filter(|(_, h)| !h.is_empty())
+    }
+
+    fn print(&self, options: &Options) {
+        let mut latencies = new_histogram();
+        for (_, histogram) in self.ops() {
+            latencies.add(histogram).unwrap();
+        }
+        let mean = Duration::from_secs_f64(latencies.mean() / 1_000_000.0);
+        println!(
+            "{} Benchmark Complete: {} requests, Concurrency: {}, Total Time: {:?}, Avg Time per Request: {:?}, Throughput: {:.0} ops/s, Errors: {}",
+            self.name,
+            self.requests(),
+            options.concurrency,
+            self.elapsed,
+            mean,
+            self.throughput(),
+            self.errors
+        );
+        let mixed = self.ops().count() > 1;
+        for (op, latencies) in self.ops() {
+            if mixed {
+                print_percentiles(&format!("{} ({} ops)", op.label(), latencies.len()), latencies);
+            } else {
+                print_percentiles(op.label(), latencies);
+            }
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut latency = Map::new();
+        for (op, histogram) in self.ops() {
+            let mut stats = Map::new();
+            stats.insert("ops".to_string(), json!(histogram.len()));
+            stats.insert("mean".to_string(), json!(histogram.mean()));
+            for (name, quantile) in QUANTILES {
+                stats.insert(name.to_string(), json!(histogram.value_at_quantile(*quantile)));
+            }
+            stats.insert("max".to_string(), json!(histogram.max()));
+            latency.insert(op.name().to_string(), Value::Object(stats));
+        }
+        json!({
+            "name": self.name,
+            "requests": self.requests(),
+            "errors": self.errors,
+            "elapsed_secs": self.elapsed.as_secs_f64(),
+            "throughput": self.throughput(),
+            "latency_us": latency,
+        })
+    }
+}
+
+// Write the run's config and reports to `path`, one CSV row per benchmark and operation
+// or a single JSON document that --compare can read back
+fn write_results(path: &str, config: &Value, reports: &[Report]) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    if !path.ends_with(".csv") {
+        let benchmarks: Vec<Value> = reports.iter().map(Report::to_json).collect();
+        let results = json!({ "config": config, "benchmarks": benchmarks });
+        return writeln!(file, "{}", serde_json::to_string_pretty(&results)?);
+    }
+
+    let config = config.as_object().expect("config is an object");
+    let cell = |value: &Value| match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    let mut header: Vec<String> = config.keys().cloned().collect();
+    header.extend(["benchmark", "op", "requests", "errors", "elapsed_secs", "throughput", "ops", "mean_us"].map(String::from));
+    header.extend(QUANTILES.iter().map(|(name, _)| format!("{}_us", name)));
+    header.push("max_us".to_string());
+    writeln!(file, "{}", header.join(","))?;
+    for report in reports {
+        let results = report.to_json();
+        for (op, stats) in results["latency_us"].as_object().into_iter().flatten() {
+            let mut row: Vec<String> = config.values().map(cell).collect();
+            row.extend([report.name.clone(), op.clone()]);
+            row.extend(["requests", "errors", "elapsed_secs", "throughput"].iter().map(|field| cell(&results[field])));
+            row.extend(["ops", "mean"].iter().map(|field| cell(&stats[field])));
+            row.extend(QUANTILES.iter().map(|(name, _)| cell(&stats[name])));
+            row.push(cell(&stats["max"]));
+            writeln!(file, "{}", row.join(","))?;
+        }
+    }
+    Ok(())
+}
+
+// Print how this run's reports moved against the same benchmarks in an earlier JSON results file
+fn compare_results(path: &str, config: &Value, reports: &[Report]) {
+    let previous: Value = match std::fs::read_to_string(path).map(|text| serde_json::from_str(&text)) {
+        Ok(Ok(previous)) => previous,
+        Ok(Err(e)) => return eprintln!("Failed to parse {}: {}", path, e),
+        Err(e) => return eprintln!("Failed to read {}: {}", path, e),
+    };
+    let change = |before: f64, after: f64| match before {
+        0.0 => "n/a".to_string(),
+        _ => format!("{:+.1}%", (after - before) / before * 100.0),
+    };
+
+    println!("Compared with {}:", path);
+    for (name, before) in config.as_object().into_iter().flatten() {
+        if previous["config"][name] != *before {
+            println!("  config {} differs: {} -> {}", name, previous["config"][name], before);
+        }
+    }
+    for report in reports {
+        let current = report.to_json();
+        let Some(before) = previous["benchmarks"].as_array().and_then(|b| b.iter().find(|b| b["name"] == current["name"])) else {
+            println!("  {}: not in {}", report.name, path);
+            continue;
+        };
+        let number = |value: &Value| value.as_f64().unwrap_or(0.0);
+        println!(
+            "  {} Throughput: {:.0} -> {:.0} ops/s ({}), Errors: {} -> {}",
+            report.name,
+            number(&before["throughput"]),
+            report.throughput(),
+            change(number(&before["throughput"]), report.throughput()),
+            before["errors"],
+            report.errors
+        );
+        for (op, stats) in current["latency_us"].as_object().into_iter().flatten() {
+            let was = &before["latency_us"][op];
+            if was.is_null() {
+                continue;
+            }
+            let deltas: Vec<String> = QUANTILES
+                .iter()
+                .map(|(name, _)| *name)
+                .chain(["max"])
+                .map(|name| {
+                    let (a, b) = (number(&was[name]), number(&stats[name]));
+                    format!("{}={:?}->{:?} ({})", name, Duration::from_micros(a as u64), Duration::from_micros(b as u64), change(a, b))
+                })
+                .collect();
+            println!("  {} Latency ({}): {}", report.name, op, deltas.join(" "));
+        }
+    }
+}
+
+// Run `operation(i)` for i = 0, 1, ... spread over the workers, until num_requests
+// operations have been measured or --duration is up; it returns which kind of operation
+// it was, so reads and writes are reported apart, and whether it succeeded. Operations
+// during --warmup are not measured. With a target rate each operation has a scheduled
+// start and its latency is measured from then, so time spent queued behind a slow
+// operation counts (no coordinated omission).
+fn run_workers(name: &str, options: &Options, num_requests: usize, operation: impl Fn(usize) -> (Op, bool) + Sync) -> Report {
+    let start = Instant::now();
+    let measure_from = start + options.warmup;
+    let deadline = options.duration.map(|duration| measure_from + duration);
+    let workers = match options.duration {
+        Some(_) => options.concurrency,
+        None => options.concurrency.min(num_requests.max(1)),
+    };
+    // Each worker runs every workers-th operation, so its share of the rate is 1/workers
+    let interval = options.rate.map(|rate| Duration::from_secs_f64(workers as f64 / rate));
+    let mut latencies = Op::ALL.map(|_| new_histogram());
+    let mut errors = 0;
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|worker| {
+                let operation = &operation;
+                scope.spawn(move || {
+                    let mut latencies = Op::ALL.map(|_| new_histogram());
+                    // This worker's part of num_requests
+                    let share = (worker..num_requests).step_by(workers).len();
+                    let mut measured = 0;
+                    let mut errors = 0;
+                    for n in 0.. {
+                        let done = match deadline {
+                            Some(deadline) => Instant::now() >= deadline,
+                            None => measured == share,
+                        };
+                        if done {
+                            break;
+                        }
+                        let i = worker + n * workers;
+                        let scheduled = match interval {
+                            Some(interval) => {
+                                let scheduled = start + interval * n as u32;
+                                // Behind schedule: start right away, the delay still counts
+                                if let Some(wait) = scheduled.checked_duration_since(Instant::now()) {
+                                    std::thread::sleep(wait);
+                                }
+                                scheduled
+                            }
+                            None => Instant::now(),
+                        };
+                        let (op, ok) = operation(i);
+                        if scheduled >= measure_from {
+                            record_latency(&mut latencies[op as usize], scheduled.elapsed());
+                            measured += 1;
+                            errors += u64::from(!ok);
+                        }
+                    }
+                    (latencies, errors)
+                })
+            })
+            .collect();
+        for handle in handles {
+            let (worker_latencies, worker_errors) = handle.join().unwrap();
+            for (merged, worker) in latencies.iter_mut().zip(worker_latencies) {
+                merged.add(worker).unwrap();
+            }
+            errors += worker_errors;
+        }
+    });
+
+    Report {
+        name: name.to_string(),
+        latencies,
+        errors,
+        elapsed: measure_from.elapsed(),
+    }
+}
+
+// Where the tcp and mixed modes send SETs and GETs
+enum Target {
+    // A new connection per request, operation i going to nodes[i % len]
+    Raw { write_nodes: Vec<String>, read_nodes: Vec<String> },
+    // One p2p_rust::Client with a connection per worker, driven from the worker threads
+    Pooled { client: Client, runtime: Runtime },
+}
+
+impl Target {
+    fn new(write_node: &str, read_node: &str, options: &Options) -> Result<Target, String> {
+        let (write_nodes, read_nodes) = if options.nodes.is_empty() {
+            (vec![write_node.to_string()], vec![read_node.to_string()])
+        } else {
+            (options.nodes.clone(), options.nodes.clone())
+        };
+        if !options.pooled {
+            return Ok(Target::Raw { write_nodes, read_nodes });
+        }
+
+        let mut nodes = write_nodes;
+        for node in read_nodes {
+            if !nodes.contains(&node) {
+                nodes.push(node);
+            }
+        }
+        let runtime = Runtime::new().map_err(|e| format!("Failed to start the tokio runtime: {}", e))?;
+        let client = runtime
+            .block_on(ClientBuilder::with_nodes(nodes).pool_size(options.concurrency).connect())
+            .map_err(|e| e.to_string())?;
+        Ok(Target::Pooled { client, runtime })
+    }
+
+    fn set(&self, i: usize, key: &str, value: &str) -> bool {
+        match self {
+            Target::Raw { write_nodes, .. } => {
+                let response = send_request(&write_nodes[i % write_nodes.len()], &format!("SET {}={}\n", key, value));
+                if let Some(response) = &response {
+                    println!("Write Response: {}", response);
+                }
+                set_ok(&response)
+            }
+            Target::Pooled { client, runtime } => match runtime.block_on(client.set(key, value)) {
+                Ok(()) => {
+                    println!("Write Response: key={} set", key);
+                    true
+                }
+                Err(e) => {
+                    eprintln!("SET {} failed: {}", key, e);
+                    false
+                }
+            },
+        }
+    }
+
+    fn get(&self, i: usize, key: &str) -> bool {
+        match self {
+            Target::Raw { read_nodes, .. } => {
+                let response = send_request(&read_nodes[i % read_nodes.len()], &format!("GET {}\n", key));
+                if let Some(response) = &response {
+                    println!("Read Response: {}", response);
+                }
+                get_ok(&response)
+            }
+            Target::Pooled { client, runtime } => match runtime.block_on(client.get(key)) {
+                Ok(Some(value)) => {
+                    println!("Read Response: key={}, value={}", key, value);
+                    true
+                }
+                Ok(None) => {
+                    println!("Read Response: key={} not found", key);
+                    true
+                }
+                Err(e) => {
+                    eprintln!("GET {} failed: {}", key, e);
+                    false
+                }
+            },
+        }
+    }
+}
+
+// Whether a node answered a SET with success
+fn set_ok(response: &Option<String>) -> bool {
+    response.as_deref().is_some_and(|response| response.starts_with("OK"))
+}
+
+// Whether a node answered a GET, found or not, rather than rejecting it
+fn get_ok(response: &Option<String>) -> bool {
+    response.as_deref().is_some_and(|response| !response.starts_with("Invalid"))
+}
+
+fn benchmark_write(cache: SharedCache, write_node: &str, num_requests: usize, options: &Options) -> Report {
+    // Generated before timing starts
+    let values = options.values(num_requests);
+    let report = run_workers("Write", options, num_requests, |i| {
+        let key = options.key(i);
+        let value = &values[i % values.len()];
 
         // Update local cache
         {
@@ -61,29 +734,21 @@ fn benchmark_write(cache: SharedCache, write_node: &str, num_requests: usize) {
 
         // Send SET request to write node
         let request = format!("SET {}={}\n", key, value);
-        let start = Instant::now();
-        if let Some(response) = send_request(write_node, &request) {
+        let response = send_request(write_node, &request);
+        if let Some(response) = &response {
             println!("Write Response: {}", response);
         }
-        total_time += start.elapsed();
-    }
-
-    println!(
-        "Write Benchmark Complete: {} requests, Total Time: {:?}, Avg Time per Request: {:?}",
-        num_requests,
-        total_time,
-        total_time / num_requests as u32
-    );
+        (Op::Write, set_ok(&response))
+    });
+    report.print(options);
+    report
 }
 
-fn benchmark_read(cache: SharedCache, _: &str, file_path: &str, num_requests: usize) {
-    let mut total_time = Duration::ZERO;
-
-    for i in 0..num_requests {
-        let key = format!("key{}", i);
+fn benchmark_read(cache: SharedCache, _: &str, file_path: &str, num_requests: usize, options: &Options) -> Report {
+    let report = run_workers("Read", options, num_requests, |i| {
+        let key = options.key(i);
 
         // Attempt to get from cache first
-        let start = Instant::now();
         let value = {
             let cache = cache.lock().unwrap();
             cache.get(&key).cloned()
@@ -102,22 +767,177 @@ fn benchmark_read(cache: SharedCache, _: &str, file_path: &str, num_requests: us
         } else {
             println!("Read Response: key={} not found", key);
         }
-        total_time += start.elapsed();
+        (Op::Read, true)
+    });
+    report.print(options);
+    report
+}
+
+// SETs and then GETs of the same keys, both over the node protocol, unlike write/read
+// which read back from the local cache
+fn benchmark_tcp(target: &Target, num_requests: usize, options: &Options) -> Vec<Report> {
+    let values = options.values(num_requests);
+    let writes = run_workers("TCP Write", options, num_requests, |i| {
+        (Op::Write, target.set(i, &options.key(i), &values[i % values.len()]))
+    });
+    writes.print(options);
+    let reads = run_workers("TCP Read", options, num_requests, |i| (Op::Read, target.get(i, &options.key(i))));
+    reads.print(options);
+    vec![writes, reads]
+}
+
+// GETs interleaved with SETs in the --workload ratio. Read-only keys come back as not
+// found, so load the key space with a write run first.
+fn benchmark_mixed(target: &Target, num_requests: usize, options: &Options) -> Report {
+    let default = Workload::parse("read-mostly").unwrap();
+    let workload = options.workload.as_ref().unwrap_or(&default);
+    let values = options.values(num_requests);
+    let report = run_workers("Mixed", options, num_requests, |i| {
+        if rand::thread_rng().gen_range(0..100) < workload.read_percent {
+            (Op::Read, target.get(i, &options.key(i)))
+        } else {
+            let key = if workload.inserts { options.new_key(i) } else { options.key(i) };
+            (Op::Write, target.set(i, &key, &values[i % values.len()]))
+        }
+    });
+    report.print(options);
+    report
+}
+
+// SUBSCRIBE to `prefix` on `node`, returning the stream once the node has confirmed
+fn subscribe(node: &str, prefix: &str) -> Result<BufReader<TcpStream>, String> {
+    let mut stream = TcpStream::connect(node).map_err(|e| format!("Failed to connect to {}: {}", node, e))?;
+    stream.write_all(format!("SUBSCRIBE {}", prefix).as_bytes()).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    if line.trim_end() != "OK: SUBSCRIBED" {
+        return Err(format!("{} refused SUBSCRIBE: {}", node, line.trim_end()));
     }
+    // Wake up now and then to check whether the run is over
+    reader.get_ref().set_read_timeout(Some(Duration::from_millis(100))).map_err(|e| e.to_string())?;
+    Ok(reader)
+}
 
-    println!(
-        "Read Benchmark Complete: {} requests, Total Time: {:?}, Avg Time per Request: {:?}",
-        num_requests,
-        total_time,
-        total_time / num_requests as u32
-    );
+// SETs to the write node while every observer node is subscribed to the written keys; each
+// write's convergence time runs from just before it is sent until the observer sees it
+fn benchmark_converge(write_node: &str, observers: &[String], num_requests: usize, options: &Options) -> Vec<Report> {
+    // Keys unique to this run, so nothing left from an earlier run is mistaken for a write
+    let run = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let prefix = format!("{}{}-", options.key_prefix, run);
+    let subscriptions: Vec<_> = match observers.iter().map(|node| subscribe(node, &prefix)).collect() {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Vec::new();
+        }
+    };
+
+    let values = options.values(num_requests);
+    // When each measured write was sent, by operation
+    let sent: Mutex<HashMap<usize, Instant>> = Mutex::new(HashMap::new());
+    let measure_from = Instant::now() + options.warmup;
+    // Set once the writer is done: when it finished and how many writes to wait for
+    let finished: OnceLock<(Instant, usize)> = OnceLock::new();
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = subscriptions
+            .into_iter()
+            .map(|mut reader| {
+                let (sent, finished, prefix) = (&sent, &finished, &prefix);
+                scope.spawn(move || {
+                    let mut latencies = new_histogram();
+                    let mut last_seen = measure_from;
+                    let mut line = String::new();
+                    loop {
+                        if let Some((at, writes)) = finished.get() {
+                            if latencies.len() as usize >= *writes || at.elapsed() > CONVERGE_TIMEOUT {
+                                break;
+                            }
+                        }
+                        line.clear();
+                        match reader.read_line(&mut line) {
+                            Ok(0) => break,
+                            Ok(_) => {}
+                            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+                            Err(_) => break,
+                        }
+                        let now = Instant::now();
+                        let change = match line.strip_prefix("CID ").and_then(|rest| rest.split_once(' ')) {
+                            Some((_, change)) => change,
+                            None => line.as_str(),
+                        };
+                        let Some(i) = change
+                            .strip_prefix("SET ")
+                            .and_then(|change| change.strip_prefix(prefix.as_str()))
+                            .and_then(|change| change.split_once('='))
+                            .and_then(|(i, _)| i.parse::<usize>().ok())
+                        else {
+                            continue;
+                        };
+                        if let Some(sent) = sent.lock().unwrap().get(&i) {
+                            record_latency(&mut latencies, now - *sent);
+                            last_seen = now;
+                        }
+                    }
+                    (latencies, last_seen)
+                })
+            })
+            .collect();
+
+        let writes = run_workers("Converge Write", options, num_requests, |i| {
+            let key = format!("{}{}", prefix, i);
+            let now = Instant::now();
+            if now >= measure_from {
+                sent.lock().unwrap().insert(i, now);
+            }
+            let response = send_request(write_node, &format!("SET {}={}\n", key, &values[i % values.len()]));
+            (Op::Write, set_ok(&response))
+        });
+        writes.print(options);
+        let written = sent.lock().unwrap().len();
+        finished.set((Instant::now(), written)).unwrap();
+
+        let mut reports = vec![writes];
+        for (observer, handle) in observers.iter().zip(handles) {
+            let (converged, last_seen) = handle.join().unwrap();
+            println!(
+                "Convergence on {}: {} of {} writes seen within {:?} of the last write",
+                observer,
+                converged.len(),
+                written,
+                CONVERGE_TIMEOUT
+            );
+            if !converged.is_empty() {
+                print_percentiles("Convergence", &converged);
+            }
+            let mut latencies = Op::ALL.map(|_| new_histogram());
+            let missing = written as u64 - converged.len();
+            latencies[Op::Converge as usize] = converged;
+            reports.push(Report {
+                name: format!("Convergence {}", observer),
+                latencies,
+                errors: missing,
+                elapsed: last_seen.saturating_duration_since(measure_from),
+            });
+        }
+        reports
+    })
 }
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+    if args.len() >= 4 && args[1] == "import" {
+        import(&args[2], &args[3], &args[4..]);
+        return;
+    }
     if args.len() < 6 {
         eprintln!(
-            "Usage: {} <write_node> <read_node> <file_path> <mode: write|read|both> <num_requests>",
+            "Usage: {} <write_node> <read_node> <file_path> <mode: write|read|both|tcp|mixed|converge> <num_requests> [--concurrency n] [--rate ops_per_sec] [--value-size bytes] [--key-space n] [--key-prefix p] [--distribution sequential|uniform|zipfian] [--zipf-theta t] [--workload read-mostly|balanced|insert-heavy|<read>/<write>] [--duration 60s] [--warmup 10s] [--output results.json|results.csv] [--compare previous.json] [--nodes a,b,..] [--transport raw|pooled]",
+            args[0]
+        );
+        eprintln!(
+            "       {} import <node> <file_path> [--format csv|jsonl|arrow] [--batch-size n] [--throttle-ms n] [--no-replicate]",
             args[0]
         );
         return;
@@ -128,16 +948,59 @@ fn main() {
     let file_path = &args[3];
     let mode = &args[4];
     let num_requests: usize = args[5].parse().unwrap_or(100);
+    let options = match Options::parse(&args[6..], num_requests) {
+        Ok(options) if options.workload.is_some() && mode != "mixed" => {
+            eprintln!("--workload only applies to mode mixed");
+            return;
+        }
+        Ok(options) if options.pooled && mode != "tcp" && mode != "mixed" => {
+            eprintln!("--transport only applies to modes tcp and mixed");
+            return;
+        }
+        Ok(options) if !options.nodes.is_empty() && !["tcp", "mixed", "converge"].contains(&mode.as_str()) => {
+            eprintln!("--nodes only applies to modes tcp, mixed and converge");
+            return;
+        }
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
 
     let cache: SharedCache = Arc::new(Mutex::new(HashMap::new()));
 
-    match mode.as_str() {
-        "write" => benchmark_write(cache, write_node, num_requests),
-        "read" => benchmark_read(cache, read_node, file_path, num_requests),
-        "both" => {
-            benchmark_write(Arc::clone(&cache), write_node, num_requests);
-            benchmark_read(Arc::clone(&cache), read_node, file_path, num_requests);
+    let reports = match mode.as_str() {
+        "write" => vec![benchmark_write(cache, write_node, num_requests, &options)],
+        "read" => vec![benchmark_read(cache, read_node, file_path, num_requests, &options)],
+        "both" => vec![
+            benchmark_write(Arc::clone(&cache), write_node, num_requests, &options),
+            benchmark_read(Arc::clone(&cache), read_node, file_path, num_requests, &options),
+        ],
+        "tcp" | "mixed" => {
+            let target = match Target::new(write_node, read_node, &options) {
+                Ok(target) => target,
+                Err(e) => return eprintln!("{}", e),
+            };
+            match mode.as_str() {
+                "tcp" => benchmark_tcp(&target, num_requests, &options),
+                _ => vec![benchmark_mixed(&target, num_requests, &options)],
+            }
+        }
+        // Observers are --nodes, or just the read node
+        "converge" if options.nodes.is_empty() => benchmark_converge(write_node, std::slice::from_ref(read_node), num_requests, &options),
+        "converge" => benchmark_converge(write_node, &options.nodes, num_requests, &options),
+        _ => return eprintln!("Invalid mode. Use 'write', 'read', 'both', 'tcp', 'mixed' or 'converge'."),
+    };
+
+    let config = options.config(mode, num_requests);
+    if let Some(path) = &options.compare {
+        compare_results(path, &config, &reports);
+    }
+    if let Some(path) = &options.output {
+        match write_results(path, &config, &reports) {
+            Ok(()) => println!("Results written to {}", path),
+            Err(e) => eprintln!("Failed to write results to {}: {}", path, e),
         }
-        _ => eprintln!("Invalid mode. Use 'write', 'read', or 'both'."),
     }
 }
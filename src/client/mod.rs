@@ -0,0 +1,654 @@
+//! Async client for the node's TCP protocol.
+//!
+//! [`Client`] wraps the line-based commands in typed calls with connect and
+//! request timeouts, failing over between nodes per [`RetryPolicy`] and
+//! routing by node health and latency (see `topology`). The
+//! node answers one request per connection and closes it, so by default each
+//! call opens a fresh connection; [`ClientBuilder::pool_size`] keeps
+//! persistent connections instead (see `pool`). [`Client::subscribe`] always uses a connection of its own and
+//! yields changes as they happen, and [`Client::near_cache`] caches reads until the node
+//! invalidates them (see `near_cache`).
+
+mod near_cache;
+mod pipeline;
+mod pool;
+mod retry;
+mod session;
+mod topology;
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use log::{debug, warn};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::protocol::{format_assignment, MAX_REQUEST_SIZE};
+use crate::storage::CacheValue;
+use crate::transport::{BoxConnection, SharedTransport, TcpTransport};
+pub use near_cache::NearCache;
+pub use pipeline::{Pipeline, Reply};
+pub use retry::RetryPolicy;
+pub use session::Session;
+use retry::retryable;
+pub use topology::NodeStatus;
+use topology::{NodeEntry, Topology};
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum ClientError {
+    // The node could not be reached
+    Connect { addr: String, source: io::Error },
+    // The connection broke while sending the request or reading the response
+    Io(io::Error),
+    // No connection or no complete response within the configured timeout
+    Timeout(Duration),
+    // The node rejected the request, e.g. "Invalid SET command"
+    Server(String),
+    // The request can't be expressed in the protocol, e.g. a key containing whitespace
+    InvalidRequest(String),
+    // The node answered with something that isn't a response to the request
+    Protocol(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Connect { addr, source } => write!(f, "failed to connect to {}: {}", addr, source),
+            ClientError::Io(e) => write!(f, "connection error: {}", e),
+            ClientError::Timeout(after) => write!(f, "timed out after {:?}", after),
+            ClientError::Server(message) => write!(f, "server error: {}", message),
+            ClientError::InvalidRequest(message) => write!(f, "invalid request: {}", message),
+            ClientError::Protocol(message) => write!(f, "unexpected response: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Connect { source, .. } => Some(source),
+            ClientError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Configures and connects a Client
+pub struct ClientBuilder {
+    addrs: Vec<String>,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    transport: SharedTransport,
+    pool_size: usize,
+    retry: RetryPolicy,
+    discover: Option<Duration>,
+}
+
+impl ClientBuilder {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self::with_nodes([addr])
+    }
+
+    // Talk to any of several nodes, starting with the first reachable one and failing
+    // over to the others (see RetryPolicy)
+    pub fn with_nodes<I: IntoIterator<Item = impl Into<String>>>(addrs: I) -> Self {
+        ClientBuilder {
+            addrs: addrs.into_iter().map(Into::into).collect(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            transport: Arc::new(TcpTransport),
+            pool_size: 0,
+            retry: RetryPolicy::default(),
+            discover: None,
+        }
+    }
+
+    // Keep up to `size` persistent connections per node and reuse them across requests;
+    // 0 (the default) opens a connection per request
+    pub fn pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    // Time allowed for sending a request and reading the whole response
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // Learn the rest of the cluster from the configured nodes' PEERS on connect, and
+    // refresh the node list and health checks every `refresh_interval`
+    pub fn discover(mut self, refresh_interval: Duration) -> Self {
+        self.discover = Some(refresh_interval);
+        self
+    }
+
+    // Network to connect over, e.g. the sim::SimNetwork a test cluster runs on
+    pub fn transport(mut self, transport: SharedTransport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    // Check that at least one node is reachable and return the client
+    pub async fn connect(self) -> Result<Client, ClientError> {
+        if self.addrs.is_empty() {
+            return Err(ClientError::InvalidRequest("no node addresses given".to_string()));
+        }
+        let client = Client {
+            topology: Arc::new(Topology::new(self.addrs, self.pool_size)),
+            config: Arc::new(Config {
+                connect_timeout: self.connect_timeout,
+                request_timeout: self.request_timeout,
+                transport: self.transport,
+                retry: self.retry,
+            }),
+        };
+
+        let mut reachable = false;
+        let mut last_error = None;
+        for node in client.topology.nodes().iter() {
+            match client.open(&node.addr).await {
+                Ok(_) => {
+                    client.topology.set_current(&node.addr);
+                    reachable = true;
+                    break;
+                }
+                Err(e) => {
+                    debug!("Node {} unreachable: {}", node.addr, e);
+                    node.record_failure();
+                    last_error = Some(e);
+                }
+            }
+        }
+        if !reachable {
+            return Err(last_error.expect("at least one node was tried"));
+        }
+
+        if let Some(interval) = self.discover {
+            topology::refresh(&client).await;
+            tokio::spawn(topology::refresh_periodically(
+                Arc::downgrade(&client.topology),
+                Arc::clone(&client.config),
+                interval,
+            ));
+        }
+        Ok(client)
+    }
+}
+
+// A change streamed by SUBSCRIBE
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    // The key was written; the value is formatted as GET returns it
+    Set { key: String, value: String },
+    Del { key: String },
+    // The subscriber fell behind and this many changes were skipped
+    Lagged(u64),
+}
+
+// How a request may be routed and retried
+#[derive(Clone, Copy, PartialEq)]
+enum RequestKind {
+    // Idempotent and read-only: goes to the fastest healthy node
+    Read,
+    // Idempotent write: stays on the current node
+    Write,
+    // Anything else: only moved to another node when the current one can't be reached
+    Raw,
+}
+
+pub(crate) struct Config {
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    transport: SharedTransport,
+    retry: RetryPolicy,
+}
+
+// Cheap to clone; clones share the node list, connection pools and health information
+#[derive(Clone)]
+pub struct Client {
+    topology: Arc<Topology>,
+    config: Arc<Config>,
+}
+
+impl Client {
+    // Connect with the default timeouts; see ClientBuilder for the options
+    pub async fn connect(addr: impl Into<String>) -> Result<Client, ClientError> {
+        ClientBuilder::new(addr).connect().await
+    }
+
+    pub fn builder(addr: impl Into<String>) -> ClientBuilder {
+        ClientBuilder::new(addr)
+    }
+
+    // The node that answered the last request
+    pub fn addr(&self) -> String {
+        self.topology.current()
+    }
+
+    // Every known node with its health and latency
+    pub fn nodes(&self) -> Vec<NodeStatus> {
+        self.topology.status()
+    }
+
+    async fn open(&self, addr: &str) -> Result<BoxConnection, ClientError> {
+        let connect_timeout = self.config.connect_timeout;
+        match timeout(connect_timeout, self.config.transport.connect(addr.to_string())).await {
+            Ok(Ok(connection)) => Ok(connection),
+            Ok(Err(source)) => Err(ClientError::Connect { addr: addr.to_string(), source }),
+            Err(_) => Err(ClientError::Timeout(connect_timeout)),
+        }
+    }
+
+    // Send a raw protocol command and return the node's whole response. The command may
+    // not be idempotent, so it only moves to another node if the current one can't be reached.
+    pub async fn request(&self, command: &str) -> Result<String, ClientError> {
+        self.send(command, RequestKind::Raw).await
+    }
+
+    async fn send(&self, command: &str, kind: RequestKind) -> Result<String, ClientError> {
+        let mut responses = self.send_batch(&[command.to_string()], kind).await?;
+        Ok(responses.remove(0))
+    }
+
+    // Run `commands` in order on one node and return their responses, trying the nodes in
+    // Topology::order as the retry policy allows
+    async fn send_batch(&self, commands: &[String], kind: RequestKind) -> Result<Vec<String>, ClientError> {
+        if let Some(command) = commands.iter().find(|command| command.len() > MAX_REQUEST_SIZE) {
+            return Err(ClientError::InvalidRequest(format!(
+                "request is {} bytes, the node reads at most {}",
+                command.len(),
+                MAX_REQUEST_SIZE
+            )));
+        }
+        let nodes = self.topology.order(kind == RequestKind::Read);
+        let retry = &self.config.retry;
+        let mut attempt = 0;
+        loop {
+            let node = &nodes[attempt % nodes.len()];
+            let started = Instant::now();
+            let result = self.exchange(node, commands).await;
+            attempt += 1;
+            match result {
+                Ok(responses) => {
+                    // A pipeline's time says little about the node's latency
+                    if commands.len() == 1 {
+                        node.record_success(started.elapsed());
+                    }
+                    self.topology.set_current(&node.addr);
+                    return Ok(responses);
+                }
+                Err(e) => {
+                    if retryable(&e, true) {
+                        node.record_failure();
+                    }
+                    if attempt >= retry.max_attempts || !retryable(&e, kind != RequestKind::Raw) {
+                        return Err(e);
+                    }
+                    let next = &nodes[attempt % nodes.len()].addr;
+                    warn!("Request to {} failed ({}), retrying on {}", node.addr, e, next);
+                    tokio::time::sleep(retry.delay(attempt, nodes.len())).await;
+                }
+            }
+        }
+    }
+
+    // Run `commands` on `node` once; a batch of more than one command needs a persistent connection
+    pub(crate) async fn exchange(&self, node: &NodeEntry, commands: &[String]) -> Result<Vec<String>, ClientError> {
+        match (&node.pool, commands) {
+            (Some(pool), _) => pool.run(self, &node.addr, commands).await,
+            (None, [command]) => self.request_once(&node.addr, command).await.map(|response| vec![response]),
+            (None, _) => pool::run_unpooled(self, &node.addr, commands).await,
+        }
+    }
+
+    // One request on a connection of its own, which the node closes after responding
+    async fn request_once(&self, addr: &str, command: &str) -> Result<String, ClientError> {
+        let response = self.request_bytes(addr, command).await?;
+        Ok(String::from_utf8_lossy(&response).into_owned())
+    }
+
+    async fn request_bytes(&self, addr: &str, command: &str) -> Result<Vec<u8>, ClientError> {
+        let mut connection = self.open(addr).await?;
+        let exchange = async {
+            connection.write_all(command.as_bytes()).await?;
+            let mut response = Vec::new();
+            connection.read_to_end(&mut response).await?;
+            Ok(response)
+        };
+        let request_timeout = self.config.request_timeout;
+        match timeout(request_timeout, exchange).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => Err(ClientError::Io(e)),
+            Err(_) => Err(ClientError::Timeout(request_timeout)),
+        }
+    }
+
+    // Queue several requests and send them together on one connection
+    pub fn pipeline(&self) -> Pipeline<'_> {
+        Pipeline::new(self)
+    }
+
+    // GETs cached in this process for up to `capacity` keys, each until the node reports it
+    // changed; the tracking connection is opened by the first read
+    pub fn near_cache(&self, capacity: usize) -> NearCache {
+        NearCache::new(self.clone(), capacity)
+    }
+
+    // Reads and writes that always see the session's own writes, whichever node answers
+    pub fn session(&self) -> Session {
+        Session::new(self.clone())
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, ClientError> {
+        let response = self.send(&get_command(key)?, RequestKind::Read).await?;
+        Ok(parse_get(response))
+    }
+
+    // GET along with how long the node says the value may be reused without asking again
+    // (zero on nodes without leases, see the node's lease.rs)
+    pub async fn get_leased(&self, key: &str) -> Result<(Option<String>, Duration), ClientError> {
+        let response = self.send(&format!("{} LEASE", get_command(key)?), RequestKind::Read).await?;
+        let Some((value, max_age)) = response.rsplit_once("\nMAX-AGE ") else {
+            return Err(ClientError::Server(response));
+        };
+        let max_age = max_age.trim().parse().map_err(|_| ClientError::Server(response.clone()))?;
+        Ok((parse_get(value.to_string()), Duration::from_millis(max_age)))
+    }
+
+    // GET that also makes the key expire `ttl` from now (in whole seconds, at least one), for
+    // sliding expiration: every read keeps the key alive for `ttl` more
+    pub async fn get_ex(&self, key: &str, ttl: Duration) -> Result<Option<String>, ClientError> {
+        check_key(key)?;
+        let seconds = ttl.as_millis().div_ceil(1000).max(1);
+        let response = self.send(&format!("GETEX {} TTL={}", key, seconds), RequestKind::Write).await?;
+        Ok(parse_get(response))
+    }
+
+    // Store a string value; the node replicates it to its peers
+    pub async fn set(&self, key: &str, value: &str) -> Result<(), ClientError> {
+        expect_ok(self.send(&set_command(key, value)?, RequestKind::Write).await?)
+    }
+
+    // Delete a key everywhere; returns whether it existed on the node that answered
+    pub async fn del(&self, key: &str) -> Result<bool, ClientError> {
+        parse_del(self.send(&del_command(key)?, RequestKind::Write).await?)
+    }
+
+    // Up to `count` pairs under `prefix` in key order, starting after `after`; pass the
+    // last key of a page as `after` to fetch the next one
+    pub async fn scan(&self, prefix: &str, after: Option<&str>, count: usize) -> Result<Vec<(String, String)>, ClientError> {
+        let response = self.send(&scan_command(prefix, after, count)?, RequestKind::Read).await?;
+        if response.is_empty() {
+            return Ok(Vec::new());
+        }
+        response
+            .lines()
+            .map(|line| {
+                line.split_once('=')
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .ok_or_else(|| ClientError::Server(response.clone()))
+            })
+            .collect()
+    }
+
+    // Like scan, but as Arrow record batches with key, value and type columns, which keeps
+    // values with newlines or '=' intact
+    pub async fn scan_arrow(&self, prefix: &str, after: Option<&str>, count: usize) -> Result<Vec<RecordBatch>, ClientError> {
+        self.request_arrow(&format!("{} FORMAT ARROW", scan_command(prefix, after, count)?)).await
+    }
+
+    // Send GET_ALL/SCAN .. FORMAT ARROW or EXPORT - and decode the Arrow IPC stream the node
+    // answers with. Uses a connection of its own, on the first node that can be reached.
+    pub async fn request_arrow(&self, command: &str) -> Result<Vec<RecordBatch>, ClientError> {
+        if command.len() > MAX_REQUEST_SIZE {
+            return Err(ClientError::InvalidRequest(format!(
+                "request is {} bytes, the node reads at most {}",
+                command.len(),
+                MAX_REQUEST_SIZE
+            )));
+        }
+        let mut last_error = None;
+        for node in self.topology.order(true) {
+            match self.request_bytes(&node.addr, command).await {
+                Err(e @ ClientError::Connect { .. }) => {
+                    debug!("Node {} unreachable for an Arrow request: {}", node.addr, e);
+                    node.record_failure();
+                    last_error = Some(e);
+                }
+                result => return decode_arrow_stream(result?),
+            }
+        }
+        Err(last_error.expect("at least one node was tried"))
+    }
+
+    // Bulk load record batches with key, value and (optionally) type columns through IMPORT -,
+    // on the first node that can be reached, which replicates them unless `replicate` is false;
+    // returns how many keys were imported. The node acknowledges every batch, so only a node
+    // making no progress for the request timeout fails it, however large the import.
+    pub async fn import_arrow(&self, batches: &[RecordBatch], replicate: bool) -> Result<usize, ClientError> {
+        let Some(first) = batches.first() else {
+            return Ok(0);
+        };
+        let encode = || -> Result<Vec<u8>, arrow::error::ArrowError> {
+            let mut writer = StreamWriter::try_new(Vec::new(), &first.schema())?;
+            for batch in batches {
+                writer.write(batch)?;
+            }
+            writer.into_inner()
+        };
+        let stream = encode().map_err(|e| ClientError::InvalidRequest(e.to_string()))?;
+        let command = if replicate { "IMPORT -\n" } else { "IMPORT - --no-replicate\n" };
+        let mut last_error = None;
+        for node in self.topology.order(true) {
+            match self.import_on(&node.addr, command, &stream).await {
+                Err(e @ ClientError::Connect { .. }) => {
+                    debug!("Node {} unreachable for IMPORT -: {}", node.addr, e);
+                    node.record_failure();
+                    last_error = Some(e);
+                }
+                result => return result,
+            }
+        }
+        Err(last_error.expect("at least one node was tried"))
+    }
+
+    async fn import_on(&self, addr: &str, command: &str, stream: &[u8]) -> Result<usize, ClientError> {
+        let (reader, mut writer) = tokio::io::split(self.open(addr).await?);
+        let request_timeout = self.config.request_timeout;
+        let send = async {
+            writer.write_all(command.as_bytes()).await?;
+            writer.write_all(stream).await?;
+            writer.shutdown().await
+        };
+        let receive = async {
+            let mut lines = BufReader::new(reader).lines();
+            let mut last = String::new();
+            loop {
+                match timeout(request_timeout, lines.next_line()).await {
+                    Ok(Ok(Some(line))) => last = line,
+                    Ok(Ok(None)) => return Ok(last),
+                    Ok(Err(e)) => return Err(ClientError::Io(e)),
+                    Err(_) => return Err(ClientError::Timeout(request_timeout)),
+                }
+            }
+        };
+        let (mut send, mut receive) = (std::pin::pin!(send), std::pin::pin!(receive));
+        let mut sent = false;
+        // A node rejecting the stream answers and closes before reading all of it, so its
+        // answer counts rather than the failed send
+        let outcome = loop {
+            tokio::select! {
+                result = &mut send, if !sent => {
+                    if let Err(e) = result {
+                        debug!("IMPORT - to {} stopped sending: {}", addr, e);
+                    }
+                    sent = true;
+                }
+                outcome = &mut receive => break outcome?,
+            }
+        };
+        outcome
+            .strip_prefix("OK: imported ")
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|keys| keys.parse().ok())
+            .ok_or(ClientError::Server(outcome))
+    }
+
+    // Stream changes to keys under `prefix` (every key if empty) until the subscription is
+    // dropped; subscribes on the first node that can be reached
+    pub async fn subscribe(&self, prefix: &str) -> Result<Subscription, ClientError> {
+        if !prefix.is_empty() {
+            check_key(prefix)?;
+        }
+        let mut last_error = None;
+        for node in self.topology.order(true) {
+            match self.subscribe_on(&node.addr, prefix).await {
+                Err(e @ ClientError::Connect { .. }) => {
+                    debug!("Node {} unreachable for SUBSCRIBE: {}", node.addr, e);
+                    node.record_failure();
+                    last_error = Some(e);
+                }
+                result => return result,
+            }
+        }
+        Err(last_error.expect("at least one node was tried"))
+    }
+
+    async fn subscribe_on(&self, addr: &str, prefix: &str) -> Result<Subscription, ClientError> {
+        let mut connection = self.open(addr).await?;
+        let handshake = async {
+            connection.write_all(format!("SUBSCRIBE {}", prefix).as_bytes()).await?;
+            let mut reader = BufReader::new(connection);
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            Ok::<_, io::Error>((reader, line))
+        };
+        let request_timeout = self.config.request_timeout;
+        let (reader, line) = match timeout(request_timeout, handshake).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => return Err(ClientError::Io(e)),
+            Err(_) => return Err(ClientError::Timeout(request_timeout)),
+        };
+        match line.trim_end() {
+            "OK: SUBSCRIBED" => Ok(Subscription { reader, correlation_id: None }),
+            other => Err(ClientError::Server(other.to_string())),
+        }
+    }
+}
+
+// Open SUBSCRIBE connection; dropping it unsubscribes
+pub struct Subscription {
+    reader: BufReader<BoxConnection>,
+    correlation_id: Option<String>,
+}
+
+impl Subscription {
+    // Wait for the next change; None once the node closes the connection
+    pub async fn next(&mut self) -> Result<Option<Event>, ClientError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await.map_err(ClientError::Io)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (correlation_id, change) = match line.strip_prefix("CID ").and_then(|rest| rest.split_once(' ')) {
+            Some((id, change)) => (Some(id.to_string()), change),
+            None => (None, line),
+        };
+        self.correlation_id = correlation_id;
+        let event = match change.split_once(' ') {
+            Some(("SET", assignment)) => assignment
+                .split_once('=')
+                .map(|(key, value)| Event::Set { key: key.to_string(), value: value.to_string() }),
+            Some(("DEL", key)) => Some(Event::Del { key: key.to_string() }),
+            Some(("LAGGED", missed)) => missed.parse().ok().map(Event::Lagged),
+            _ => None,
+        };
+        event.map(Some).ok_or_else(|| ClientError::Protocol(line.to_string()))
+    }
+
+    // Correlation ID of the request behind the last event, for tracing it across nodes
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+}
+
+// Keys travel as single words, and SET splits on the first '='
+fn check_key(key: &str) -> Result<(), ClientError> {
+    if key.is_empty() || key.contains(char::is_whitespace) || key.contains('=') {
+        return Err(ClientError::InvalidRequest(format!("invalid key: {:?}", key)));
+    }
+    Ok(())
+}
+
+fn get_command(key: &str) -> Result<String, ClientError> {
+    check_key(key)?;
+    Ok(format!("GET {}", key))
+}
+
+fn scan_command(prefix: &str, after: Option<&str>, count: usize) -> Result<String, ClientError> {
+    let mut command = String::from("SCAN");
+    if !prefix.is_empty() {
+        check_key(prefix)?;
+        command.push_str(&format!(" PREFIX {}", prefix));
+    }
+    if let Some(after) = after {
+        check_key(after)?;
+        command.push_str(&format!(" AFTER {}", after));
+    }
+    command.push_str(&format!(" COUNT {}", count));
+    Ok(command)
+}
+
+fn set_command(key: &str, value: &str) -> Result<String, ClientError> {
+    check_key(key)?;
+    Ok(format!("SET {}", format_assignment(key, &CacheValue::Str(value.to_string()))))
+}
+
+fn del_command(key: &str) -> Result<String, ClientError> {
+    check_key(key)?;
+    Ok(format!("DEL {}", key))
+}
+
+fn parse_get(response: String) -> Option<String> {
+    (response != "Not Found").then_some(response)
+}
+
+// DEL answers with the number of keys removed
+fn parse_del(response: String) -> Result<bool, ClientError> {
+    let removed: usize = response.parse().map_err(|_| ClientError::Server(response))?;
+    Ok(removed > 0)
+}
+
+// Errors come back as text, while an Arrow stream starts with the 0xFFFFFFFF continuation marker
+fn decode_arrow_stream(response: Vec<u8>) -> Result<Vec<RecordBatch>, ClientError> {
+    if !response.starts_with(&[0xff; 4]) {
+        return Err(ClientError::Server(String::from_utf8_lossy(&response).into_owned()));
+    }
+    let reader = StreamReader::try_new(io::Cursor::new(response), None).map_err(|e| ClientError::Protocol(e.to_string()))?;
+    reader.collect::<Result<_, _>>().map_err(|e| ClientError::Protocol(e.to_string()))
+}
+
+fn expect_ok(response: String) -> Result<(), ClientError> {
+    if response.starts_with("OK") {
+        Ok(())
+    } else {
+        Err(ClientError::Server(response))
+    }
+}
@@ -0,0 +1,191 @@
+//! Client-side caching kept correct by the node: GETs go over one `PERSIST TRACKING`
+//! connection, so the node remembers the keys read and pushes `INVALIDATE <key>` lines on it
+//! when they change anywhere in the cluster (see the node's serve_persistent). Values are
+//! cached until then, so repeated reads of a key don't leave the process.
+//!
+//! The connection's reader caches a value before handing it over, and the node sends a key's
+//! invalidation only after the response that read it, so a change can't be missed between
+//! the two. If the connection is lost the cache is emptied, since invalidations may have been
+//! missed, and the next read opens a new one.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use log::debug;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::transport::BoxConnection;
+use super::{get_command, parse_get, Client, ClientError};
+
+// GETs sent on a tracking connection and not answered yet, in order
+type Pending = Arc<Mutex<VecDeque<(String, oneshot::Sender<String>)>>>;
+
+struct Tracking {
+    // Tells a lost connection's reader from the current one's
+    id: u64,
+    writer: WriteHalf<BoxConnection>,
+    pending: Pending,
+}
+
+struct Shared {
+    // Cached GET results, None for a missing key
+    entries: Mutex<HashMap<String, Option<String>>>,
+    connection: tokio::sync::Mutex<Option<Tracking>>,
+}
+
+// Created by Client::near_cache
+pub struct NearCache {
+    client: Client,
+    capacity: usize,
+    shared: Arc<Shared>,
+    next_id: std::sync::atomic::AtomicU64,
+}
+
+impl NearCache {
+    pub(crate) fn new(client: Client, capacity: usize) -> Self {
+        NearCache {
+            client,
+            capacity: capacity.max(1),
+            shared: Arc::new(Shared { entries: Mutex::new(HashMap::new()), connection: tokio::sync::Mutex::new(None) }),
+            next_id: Default::default(),
+        }
+    }
+
+    // From the cache if the key was read before and hasn't changed since, from a node otherwise
+    pub async fn get(&self, key: &str) -> Result<Option<String>, ClientError> {
+        let command = get_command(key)?;
+        if let Some(value) = self.shared.entries.lock().unwrap().get(key) {
+            return Ok(value.clone());
+        }
+        let answer = {
+            let mut connection = self.shared.connection.lock().await;
+            if connection.is_none() {
+                *connection = Some(self.connect().await?);
+            }
+            let tracking = connection.as_mut().expect("connected above");
+            let (sender, answer) = oneshot::channel();
+            tracking.pending.lock().unwrap().push_back((key.to_string(), sender));
+            if let Err(e) = tracking.writer.write_all(format!("{}\n", command).as_bytes()).await {
+                *connection = None;
+                return Err(ClientError::Io(e));
+            }
+            answer
+        };
+        let request_timeout = self.client.config.request_timeout;
+        match timeout(request_timeout, answer).await {
+            Ok(Ok(response)) => Ok(parse_get(response)),
+            Ok(Err(_)) => Err(ClientError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "tracking connection lost"))),
+            Err(_) => Err(ClientError::Timeout(request_timeout)),
+        }
+    }
+
+    // Writes go to the cluster as usual; the node invalidates the cached value
+    pub async fn set(&self, key: &str, value: &str) -> Result<(), ClientError> {
+        self.client.set(key, value).await
+    }
+
+    pub async fn del(&self, key: &str) -> Result<bool, ClientError> {
+        self.client.del(key).await
+    }
+
+    // Keys currently cached
+    pub fn len(&self) -> usize {
+        self.shared.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Open a tracking connection on the first node that can be reached and start its reader
+    async fn connect(&self) -> Result<Tracking, ClientError> {
+        let mut last_error = None;
+        for node in self.client.topology.order(true) {
+            match self.open_tracking(&node.addr).await {
+                Err(e @ ClientError::Connect { .. }) => {
+                    debug!("Node {} unreachable for a tracking connection: {}", node.addr, e);
+                    node.record_failure();
+                    last_error = Some(e);
+                }
+                result => return result,
+            }
+        }
+        Err(last_error.expect("at least one node was tried"))
+    }
+
+    async fn open_tracking(&self, addr: &str) -> Result<Tracking, ClientError> {
+        let mut connection = self.client.open(addr).await?;
+        let handshake = async {
+            connection.write_all(b"PERSIST TRACKING\n").await?;
+            let (reader, writer) = tokio::io::split(connection);
+            let mut reader = BufReader::new(reader);
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            Ok::<_, io::Error>((reader, writer, line))
+        };
+        let request_timeout = self.client.config.request_timeout;
+        let (reader, writer, line) = match timeout(request_timeout, handshake).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => return Err(ClientError::Io(e)),
+            Err(_) => return Err(ClientError::Timeout(request_timeout)),
+        };
+        if line.trim_end() != "OK: PERSIST TRACKING" {
+            return Err(ClientError::Server(line.trim_end().to_string()));
+        }
+        let id = self.next_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let pending = Pending::default();
+        tokio::spawn(read_tracking(reader, id, Arc::clone(&pending), Arc::clone(&self.shared), self.capacity));
+        Ok(Tracking { id, writer, pending })
+    }
+}
+
+// Cache responses and apply invalidations as they arrive, until the connection is lost
+async fn read_tracking(mut reader: BufReader<ReadHalf<BoxConnection>>, id: u64, pending: Pending, shared: Arc<Shared>, capacity: usize) {
+    let result: io::Result<()> = async {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end();
+            if let Some(key) = line.strip_prefix("INVALIDATE ") {
+                let mut entries = shared.entries.lock().unwrap();
+                match key {
+                    "*" => entries.clear(),
+                    key => drop(entries.remove(key)),
+                }
+                continue;
+            }
+            let length: usize = line.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid frame header: {}", line)))?;
+            let mut response = vec![0; length];
+            reader.read_exact(&mut response).await?;
+            let response = String::from_utf8_lossy(&response).into_owned();
+            let Some((key, sender)) = pending.lock().unwrap().pop_front() else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "response to no request"));
+            };
+            let mut entries = shared.entries.lock().unwrap();
+            // Full: make room with whichever key comes first; the node merely goes on tracking it
+            if entries.len() >= capacity && !entries.contains_key(&key) {
+                if let Some(evicted) = entries.keys().next().cloned() {
+                    entries.remove(&evicted);
+                }
+            }
+            entries.insert(key, parse_get(response.clone()));
+            drop(entries);
+            let _ = sender.send(response);
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        debug!("Tracking connection lost: {}", e);
+    }
+    // Invalidations may have been missed
+    shared.entries.lock().unwrap().clear();
+    let mut connection = shared.connection.lock().await;
+    if connection.as_ref().is_some_and(|tracking| tracking.id == id) {
+        *connection = None;
+    }
+}
@@ -0,0 +1,115 @@
+//! Request pipelining: several requests written to one connection before
+//! any response is read, saving a round trip per request.
+
+use super::{del_command, expect_ok, get_command, parse_del, parse_get, set_command, Client, ClientError, RequestKind};
+
+// Response to one pipelined request
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    // SET succeeded
+    Ok,
+    // GET result, None for a missing key
+    Value(Option<String>),
+    // DEL result: whether the key existed
+    Deleted(bool),
+    // Response to a raw request
+    Raw(String),
+    // The node rejected this request; the rest of the pipeline still ran
+    Error(String),
+}
+
+enum Expect {
+    Ok,
+    Value,
+    Deleted,
+    Raw,
+}
+
+// Requests queued by Client::pipeline, sent in order by execute
+pub struct Pipeline<'a> {
+    client: &'a Client,
+    commands: Vec<String>,
+    expected: Vec<Expect>,
+    // First request that couldn't be queued; execute reports it without sending anything
+    invalid: Option<ClientError>,
+    // Only pipelines of GET/SET/DEL are retried after a timeout or broken connection
+    kind: RequestKind,
+}
+
+impl<'a> Pipeline<'a> {
+    pub(crate) fn new(client: &'a Client) -> Self {
+        Pipeline {
+            client,
+            commands: Vec::new(),
+            expected: Vec::new(),
+            invalid: None,
+            kind: RequestKind::Write,
+        }
+    }
+
+    fn push(mut self, command: Result<String, ClientError>, expect: Expect) -> Self {
+        match command {
+            Ok(command) => {
+                self.commands.push(command);
+                self.expected.push(expect);
+            }
+            Err(e) => {
+                self.invalid.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    pub fn get(self, key: &str) -> Self {
+        self.push(get_command(key), Expect::Value)
+    }
+
+    pub fn set(self, key: &str, value: &str) -> Self {
+        self.push(set_command(key, value), Expect::Ok)
+    }
+
+    pub fn del(self, key: &str) -> Self {
+        self.push(del_command(key), Expect::Deleted)
+    }
+
+    // A raw protocol command on a single line, answered with Reply::Raw
+    pub fn request(mut self, command: &str) -> Self {
+        self.kind = RequestKind::Raw;
+        self.push(Ok(command.to_string()), Expect::Raw)
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    // Send every queued request on one connection and return the replies in order
+    pub async fn execute(self) -> Result<Vec<Reply>, ClientError> {
+        if let Some(e) = self.invalid {
+            return Err(e);
+        }
+        if self.commands.is_empty() {
+            return Ok(Vec::new());
+        }
+        let responses = self.client.send_batch(&self.commands, self.kind).await?;
+        Ok(responses
+            .into_iter()
+            .zip(self.expected)
+            .map(|(response, expect)| {
+                let reply = match expect {
+                    Expect::Ok => expect_ok(response).map(|_| Reply::Ok),
+                    Expect::Value => Ok(Reply::Value(parse_get(response))),
+                    Expect::Deleted => parse_del(response).map(Reply::Deleted),
+                    Expect::Raw => Ok(Reply::Raw(response)),
+                };
+                reply.unwrap_or_else(|e| match e {
+                    ClientError::Server(message) => Reply::Error(message),
+                    other => Reply::Error(other.to_string()),
+                })
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,178 @@
+//! Persistent connections for pooled and pipelined requests.
+//!
+//! A persistent connection starts with `PERSIST`, after which the node reads
+//! one request per line and answers each with `<length>\n<response>`, in
+//! order. A batch of requests is written while its responses are read, so a
+//! long pipeline can't stall on full socket buffers. Pooled requests check a
+//! connection out and put it back once the responses have been read; a
+//! connection that broke while idle is replaced by a fresh one.
+
+use std::io;
+use log::debug;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+use crate::protocol::MAX_REQUEST_SIZE;
+use crate::transport::BoxConnection;
+use super::{Client, ClientError};
+
+pub(crate) struct PersistentConnection {
+    reader: BufReader<ReadHalf<BoxConnection>>,
+    writer: WriteHalf<BoxConnection>,
+}
+
+pub(crate) struct Pool {
+    idle: std::sync::Mutex<Vec<PersistentConnection>>,
+    // One permit per connection the pool may have open
+    permits: Semaphore,
+}
+
+impl Pool {
+    pub(crate) fn new(size: usize) -> Self {
+        Pool {
+            idle: std::sync::Mutex::new(Vec::with_capacity(size)),
+            permits: Semaphore::new(size),
+        }
+    }
+
+    // Run a batch of requests on a pooled connection, waiting for a free one if all are in use
+    pub(crate) async fn run(&self, client: &Client, addr: &str, commands: &[String]) -> Result<Vec<String>, ClientError> {
+        check_lines(commands)?;
+        let checkout = async {
+            let _permit = self.permits.acquire().await.expect("pool semaphore is never closed");
+            loop {
+                let idle = self.idle.lock().unwrap().pop();
+                let reused = idle.is_some();
+                let mut connection = match idle {
+                    Some(connection) => connection,
+                    None => open_persistent(client, addr).await?,
+                };
+                match exchange(&mut connection, commands).await {
+                    Ok(responses) => {
+                        self.idle.lock().unwrap().push(connection);
+                        return Ok(responses);
+                    }
+                    // The node closed an idle connection (e.g. it restarted) without answering,
+                    // so it never ran the requests and it is safe to send them again on a new one
+                    Err(e) if reused && is_stale(&e) => {
+                        debug!("Replacing broken pooled connection to {}: {}", addr, e);
+                    }
+                    Err(e) => return Err(ClientError::Io(e)),
+                }
+            }
+        };
+        match timeout(client.config.request_timeout, checkout).await {
+            Ok(result) => result,
+            Err(_) => Err(ClientError::Timeout(client.config.request_timeout)),
+        }
+    }
+}
+
+// Run a batch on a persistent connection of its own, for clients without a pool
+pub(crate) async fn run_unpooled(client: &Client, addr: &str, commands: &[String]) -> Result<Vec<String>, ClientError> {
+    check_lines(commands)?;
+    let batch = async {
+        let mut connection = open_persistent(client, addr).await?;
+        exchange(&mut connection, commands).await.map_err(ClientError::Io)
+    };
+    match timeout(client.config.request_timeout, batch).await {
+        Ok(result) => result,
+        Err(_) => Err(ClientError::Timeout(client.config.request_timeout)),
+    }
+}
+
+// Each request goes out as a line, so its newline counts towards MAX_REQUEST_SIZE
+fn check_lines(commands: &[String]) -> Result<(), ClientError> {
+    if commands.iter().any(|command| command.contains('\n')) {
+        return Err(ClientError::InvalidRequest(
+            "requests on a persistent connection must fit on one line".to_string(),
+        ));
+    }
+    if let Some(command) = commands.iter().find(|command| command.len() >= MAX_REQUEST_SIZE) {
+        return Err(ClientError::InvalidRequest(format!(
+            "request is {} bytes, the node reads at most {} on a persistent connection",
+            command.len(),
+            MAX_REQUEST_SIZE - 1
+        )));
+    }
+    Ok(())
+}
+
+// Connect and switch the connection to framed mode
+async fn open_persistent(client: &Client, addr: &str) -> Result<PersistentConnection, ClientError> {
+    let mut connection = client.open(addr).await?;
+    connection.write_all(b"PERSIST\n").await.map_err(ClientError::Io)?;
+    let (reader, writer) = tokio::io::split(connection);
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    reader.read_line(&mut line).await.map_err(ClientError::Io)?;
+    match line.trim_end() {
+        "OK: PERSIST" => Ok(PersistentConnection { reader, writer }),
+        other => Err(ClientError::Server(other.to_string())),
+    }
+}
+
+async fn exchange(connection: &mut PersistentConnection, commands: &[String]) -> io::Result<Vec<String>> {
+    let batch: String = commands.iter().map(|command| format!("{}\n", command)).collect();
+    let write = async {
+        connection.writer.write_all(batch.as_bytes()).await?;
+        connection.writer.flush().await
+    };
+    let read = async {
+        let mut responses = Vec::with_capacity(commands.len());
+        for _ in commands {
+            match read_frame(&mut connection.reader).await {
+                Ok(response) => responses.push(response),
+                Err(e) if responses.is_empty() => return Err(e),
+                // Some requests already ran, so this is no longer a stale connection
+                Err(e) => {
+                    let message = format!("connection lost after {} of {} responses: {}", responses.len(), commands.len(), e);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+            }
+        }
+        Ok(responses)
+    };
+    let (written, responses) = tokio::join!(write, read);
+    let responses = responses?;
+    written?;
+    Ok(responses)
+}
+
+async fn read_frame(reader: &mut BufReader<ReadHalf<BoxConnection>>) -> io::Result<String> {
+    let mut header = String::new();
+    if reader.read_line(&mut header).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let length: usize = header
+        .trim_end()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid response header: {:?}", header)))?;
+    let mut response = vec![0; length];
+    reader
+        .read_exact(&mut response)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("truncated response: {}", e)))?;
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+// Errors that mean the connection was already dead before the first response started
+fn is_stale(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_leave_room_for_their_newline() {
+        assert!(check_lines(&["x".repeat(MAX_REQUEST_SIZE - 1)]).is_ok());
+        assert!(matches!(check_lines(&["x".repeat(MAX_REQUEST_SIZE)]), Err(ClientError::InvalidRequest(_))));
+        assert!(matches!(check_lines(&["GET a\nGET b".to_string()]), Err(ClientError::InvalidRequest(_))));
+    }
+}
@@ -0,0 +1,58 @@
+//! Retry policy for failing over between nodes.
+
+use tokio::time::Duration;
+
+use super::ClientError;
+
+// How often and how patiently a Client retries a failed request. Each retry goes to
+// the next configured node; once every node has failed in a round the client backs
+// off, doubling the delay up to `max_backoff`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    // Total attempts per request, including the first one
+    pub max_attempts: usize,
+    // Delay before the second round over the nodes
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Fail on the first error
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    // Delay before retry number `retry` (1-based) with `nodes` nodes to rotate through
+    pub(crate) fn delay(&self, retry: usize, nodes: usize) -> Duration {
+        let nodes = nodes.max(1);
+        if !retry.is_multiple_of(nodes) {
+            return Duration::ZERO;
+        }
+        let round = (retry / nodes).min(16) as u32;
+        self.backoff.saturating_mul(1 << (round - 1)).min(self.max_backoff)
+    }
+}
+
+// Whether a request that failed with `error` may be sent again. A failed connect never
+// reached the node, so any request can be retried; after a timeout or a broken connection
+// the node may already have applied it, which only idempotent requests tolerate.
+pub(crate) fn retryable(error: &ClientError, idempotent: bool) -> bool {
+    match error {
+        ClientError::Connect { .. } => true,
+        ClientError::Timeout(_) | ClientError::Io(_) => idempotent,
+        ClientError::Server(_) | ClientError::InvalidRequest(_) | ClientError::Protocol(_) => false,
+    }
+}
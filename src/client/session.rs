@@ -0,0 +1,60 @@
+//! Read-your-writes sessions: every write through a [`Session`] returns a token naming it,
+//! and reads carry the tokens so the node answering them has those writes (see the node's
+//! session.rs). Reads still go to the fastest healthy node.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::{del_command, expect_ok, get_command, parse_del, parse_get, set_command, Client, ClientError, RequestKind};
+
+// Created by Client::session; uses the client's nodes and connections
+pub struct Session {
+    client: Client,
+    // Latest token per origin node, as the nodes wrote them
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+impl Session {
+    pub(crate) fn new(client: Client) -> Self {
+        Session { client, tokens: Mutex::new(HashMap::new()) }
+    }
+
+    // The tokens reads are sent with, comma separated; None before the first write
+    pub fn tokens(&self) -> Option<String> {
+        let tokens = self.tokens.lock().unwrap();
+        let mut tokens: Vec<&str> = tokens.values().map(String::as_str).collect();
+        tokens.sort_unstable();
+        (!tokens.is_empty()).then(|| tokens.join(","))
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, ClientError> {
+        Ok(parse_get(self.read(&get_command(key)?).await?))
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<(), ClientError> {
+        expect_ok(self.write(&set_command(key, value)?).await?)
+    }
+
+    pub async fn del(&self, key: &str) -> Result<bool, ClientError> {
+        parse_del(self.write(&del_command(key)?).await?)
+    }
+
+    async fn read(&self, command: &str) -> Result<String, ClientError> {
+        match self.tokens() {
+            Some(tokens) => self.client.send(&format!("SESSION {} {}", tokens, command), RequestKind::Read).await,
+            None => self.client.send(command, RequestKind::Read).await,
+        }
+    }
+
+    // Run a write and keep the token it was answered with
+    async fn write(&self, command: &str) -> Result<String, ClientError> {
+        let response = self.client.send(&format!("SESSION {}", command), RequestKind::Write).await?;
+        let Some((response, token)) = response.rsplit_once("\nTOKEN ") else {
+            return Ok(response);
+        };
+        // Tokens are `<origin>/<boot>/<seq>`, and a later write on the same origin supersedes
+        let origin = token.split('/').next().unwrap_or_default().to_string();
+        self.tokens.lock().unwrap().insert(origin, token.trim().to_string());
+        Ok(response.to_string())
+    }
+}
@@ -0,0 +1,204 @@
+//! The nodes a Client knows about and how healthy they are.
+//!
+//! Every request records how long its node took to answer, or that it
+//! failed. Reads go to the fastest healthy node, writes stick to the node
+//! that answered last, and failed nodes are only tried once the healthy ones
+//! have been. With [`ClientBuilder::discover`](super::ClientBuilder::discover)
+//! the client also asks a node for its `PEERS` and `PING`s every node on an
+//! interval, picking up nodes that join and bringing recovered ones back.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use futures::future::join_all;
+use log::debug;
+use tokio::time::{Duration, Instant};
+
+use super::pool::Pool;
+use super::{Client, Config};
+
+// Weight of the newest sample in a node's latency average
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+pub(crate) struct NodeEntry {
+    pub(crate) addr: String,
+    pub(crate) pool: Option<Pool>,
+    // Smoothed round-trip time in microseconds, 0 until the node first answers
+    latency_micros: AtomicU64,
+    // Failures since the node last answered
+    failures: AtomicU32,
+}
+
+impl NodeEntry {
+    fn new(addr: String, pool_size: usize) -> Self {
+        NodeEntry {
+            addr,
+            pool: (pool_size > 0).then(|| Pool::new(pool_size)),
+            latency_micros: AtomicU64::new(0),
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    pub(crate) fn record_success(&self, latency: Duration) {
+        let sample = (latency.as_micros() as u64).max(1);
+        let previous = self.latency_micros.load(Ordering::Relaxed);
+        let smoothed = if previous == 0 {
+            sample
+        } else {
+            (previous as f64 * (1.0 - LATENCY_SMOOTHING) + sample as f64 * LATENCY_SMOOTHING) as u64
+        };
+        self.latency_micros.store(smoothed.max(1), Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn healthy(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) == 0
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        match self.latency_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    fn status(&self) -> NodeStatus {
+        NodeStatus {
+            addr: self.addr.clone(),
+            healthy: self.healthy(),
+            latency: self.latency(),
+        }
+    }
+}
+
+// What the client currently knows about one node, see Client::nodes
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeStatus {
+    pub addr: String,
+    // False after a failed request or health check, until the node answers again
+    pub healthy: bool,
+    // Smoothed round-trip time, None before the first answer
+    pub latency: Option<Duration>,
+}
+
+pub(crate) struct Topology {
+    // Replaced wholesale on refresh, so requests work on a consistent snapshot
+    nodes: RwLock<Arc<Vec<Arc<NodeEntry>>>>,
+    // Node that answered the last request; writes start there
+    current: Mutex<String>,
+    // Addresses the client was configured with, kept even when no node reports them
+    seeds: Vec<String>,
+    pool_size: usize,
+}
+
+impl Topology {
+    pub(crate) fn new(seeds: Vec<String>, pool_size: usize) -> Self {
+        let nodes = seeds.iter().map(|addr| Arc::new(NodeEntry::new(addr.clone(), pool_size))).collect();
+        Topology {
+            nodes: RwLock::new(Arc::new(nodes)),
+            current: Mutex::new(seeds[0].clone()),
+            seeds,
+            pool_size,
+        }
+    }
+
+    pub(crate) fn nodes(&self) -> Arc<Vec<Arc<NodeEntry>>> {
+        Arc::clone(&self.nodes.read().unwrap())
+    }
+
+    pub(crate) fn status(&self) -> Vec<NodeStatus> {
+        self.nodes().iter().map(|node| node.status()).collect()
+    }
+
+    pub(crate) fn current(&self) -> String {
+        self.current.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_current(&self, addr: &str) {
+        let mut current = self.current.lock().unwrap();
+        if *current != addr {
+            *current = addr.to_string();
+        }
+    }
+
+    // Nodes in the order a request should try them: healthy before failed, then reads by
+    // latency and writes starting from the current node
+    pub(crate) fn order(&self, read: bool) -> Vec<Arc<NodeEntry>> {
+        let current = self.current();
+        let mut nodes: Vec<Arc<NodeEntry>> = self.nodes().iter().cloned().collect();
+        nodes.sort_by_key(|node| {
+            let preferred = read || node.addr == current;
+            (!node.healthy(), !preferred, node.latency().unwrap_or(Duration::MAX))
+        });
+        nodes
+    }
+
+    // Switch to `addrs` plus the seeds, keeping the pools and health of nodes already known
+    fn update(&self, addrs: Vec<String>) {
+        let mut nodes = self.nodes.write().unwrap();
+        let mut updated: Vec<Arc<NodeEntry>> = Vec::new();
+        for addr in self.seeds.iter().chain(addrs.iter()) {
+            if updated.iter().any(|node| node.addr == *addr) {
+                continue;
+            }
+            match nodes.iter().find(|node| node.addr == *addr) {
+                Some(node) => updated.push(Arc::clone(node)),
+                None => {
+                    debug!("Discovered node {}", addr);
+                    updated.push(Arc::new(NodeEntry::new(addr.clone(), self.pool_size)));
+                }
+            }
+        }
+        *nodes = Arc::new(updated);
+    }
+}
+
+// Learn the cluster from the first node that answers PEERS, then health-check every node
+pub(crate) async fn refresh(client: &Client) {
+    for node in client.topology.order(true) {
+        match client.exchange(&node, &["PEERS".to_string()]).await {
+            Ok(responses) => {
+                let mut addrs: Vec<String> = responses[0]
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| line.contains(':') && !line.contains(char::is_whitespace))
+                    .map(str::to_string)
+                    .collect();
+                addrs.push(node.addr.clone());
+                client.topology.update(addrs);
+                break;
+            }
+            Err(e) => {
+                debug!("PEERS from {} failed: {}", node.addr, e);
+                node.record_failure();
+            }
+        }
+    }
+
+    let nodes = client.topology.nodes();
+    join_all(nodes.iter().map(|node| async move {
+        let started = Instant::now();
+        match client.exchange(node, &["PING".to_string()]).await {
+            Ok(_) => node.record_success(started.elapsed()),
+            Err(e) => {
+                debug!("Health check of {} failed: {}", node.addr, e);
+                node.record_failure();
+            }
+        }
+    }))
+    .await;
+}
+
+// Refresh until every Client sharing the topology has been dropped
+pub(crate) async fn refresh_periodically(topology: Weak<Topology>, config: Arc<Config>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(topology) = topology.upgrade() else {
+            break;
+        };
+        refresh(&Client { topology, config: Arc::clone(&config) }).await;
+    }
+}
@@ -0,0 +1,60 @@
+//! Node time source.
+//!
+//! Timers and deadlines use `tokio::time`, so a paused runtime
+//! (`tokio::time::pause`, `#[tokio::test(start_paused = true)]`) drives them
+//! virtually. Wall-clock readings go through a [`Clock`] so they can follow
+//! the same virtual time in simulations.
+
+use std::sync::Arc;
+use tokio::time::Instant;
+
+pub trait Clock: Send + Sync {
+    // Monotonic time for deadlines and expiry
+    fn now(&self) -> Instant;
+
+    // Wall-clock time in milliseconds since the Unix epoch
+    fn unix_millis(&self) -> u64;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_millis(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default()
+    }
+}
+
+// Unix millis of a UTC time like 2024-05-01T12:00:00Z or 2024-05-01T12:00:00.250Z
+pub fn parse_utc(time: &str) -> Option<u64> {
+    let (date, time) = time.strip_suffix('Z')?.split_once('T')?;
+    let date: Vec<i64> = date.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let (time, millis) = match time.split_once('.') {
+        Some((time, fraction)) if !fraction.is_empty() && fraction.len() <= 3 => (time, format!("{:0<3}", fraction).parse::<u64>().ok()?),
+        Some(_) => return None,
+        None => (time, 0),
+    };
+    let time: Vec<u64> = time.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let (&[year, month, day], &[hour, minute, second]) = (date.as_slice(), time.as_slice()) else {
+        return None;
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    // Days since 1970-01-01 in the proleptic Gregorian calendar
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146097 + day_of_era - 719468).ok()?;
+    Some(((days * 24 + hour) * 60 + minute) * 60_000 + second * 1000 + millis)
+}
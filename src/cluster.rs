@@ -0,0 +1,177 @@
+//! Cluster-wide views gathered from every peer (the CLUSTER variants of commands)
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use futures::future::join_all;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
+use log::{debug, warn};
+use serde_json::Value;
+
+use crate::discovery::PeerList;
+use crate::node::NodeContext;
+use crate::protocol::command::parse_command;
+use crate::protocol::{execute, parse_assignment, request_id};
+use crate::storage::{CacheValue, SharedCache};
+use crate::transport::SharedTransport;
+
+// Time allowed for each peer to answer a cluster query
+pub(crate) const CLUSTER_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Send `command` to every peer at once and collect each peer's whole response
+pub(crate) async fn query_peers(transport: &SharedTransport, peers: &PeerList, command: &str) -> Vec<(String, io::Result<String>)> {
+    let peers_snapshot = peers.lock().await.clone();
+    let queries = peers_snapshot.into_iter().map(|peer| {
+        let transport = Arc::clone(transport);
+        async move {
+            let query = async {
+                let mut stream = transport.connect(peer.clone()).await?;
+                stream.write_all(format!("{}\n", command).as_bytes()).await?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await?;
+                Ok(response)
+            };
+            let response = match timeout(CLUSTER_QUERY_TIMEOUT, query).await {
+                Ok(response) => response,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "peer did not answer in time")),
+            };
+            (peer, response)
+        }
+    });
+    join_all(queries).await
+}
+
+// Send `command` to one peer and return its whole response
+pub(crate) async fn ask(context: &NodeContext, peer: &str, command: &str) -> Result<String, String> {
+    let query = async {
+        let mut stream = context.transport.connect(peer.to_string()).await?;
+        stream.write_all(format!("{}\n", command).as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    match timeout(CLUSTER_QUERY_TIMEOUT, query).await {
+        Ok(response) => response.map_err(|e| e.to_string()),
+        Err(_) => Err("peer did not answer in time".to_string()),
+    }
+}
+
+// Every key in the cluster with its most recently changed value, by modification time;
+// peers that can't be reached are left out. A key deleted on some nodes but not yet on
+// others still shows up.
+pub(crate) async fn gather_entries(cache: &SharedCache, peers: &PeerList, context: &NodeContext) -> HashMap<String, (u64, CacheValue)> {
+    let mut merged: HashMap<String, (u64, CacheValue)> = {
+        let cache = cache.lock().await;
+        cache
+            .iter()
+            .map(|(key, value)| (key.clone(), (cache.modified(key).unwrap_or(0), value.clone())))
+            .collect()
+    };
+
+    for (peer, response) in query_peers(&context.transport, peers, "ENTRIES").await {
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Leaving {} out of the cluster view: {}", peer, e);
+                continue;
+            }
+        };
+        let mut received = 0;
+        for line in response.lines().filter(|line| !line.is_empty()) {
+            let Ok(entry) = serde_json::from_str::<Value>(line) else {
+                warn!("Invalid ENTRIES line from {}: {}", peer, line);
+                continue;
+            };
+            let Some(Ok((key, value))) = entry["entry"].as_str().map(parse_assignment) else {
+                warn!("Invalid ENTRIES line from {}: {}", peer, line);
+                continue;
+            };
+            let modified = entry["modified"].as_u64().unwrap_or(0);
+            // Ties keep what is already there, so the local value wins them
+            if merged.get(&key).is_none_or(|(current, _)| modified > *current) {
+                merged.insert(key, (modified, value));
+            }
+            received += 1;
+        }
+        debug!("Merged {} keys from {}", received, peer);
+    }
+    merged
+}
+
+// Run `command` on this node and every peer; each line of a node's response comes back
+// as `[node] line`, this node first, followed by a count of the nodes that failed
+pub(crate) async fn exec_on_cluster(command: &str, client: &str, cache: &SharedCache, peers: &PeerList, context: &NodeContext) -> String {
+    let local = match parse_command(command) {
+        // Boxed because execute is what called us
+        Ok(parsed) => Box::pin(execute(parsed, &mut tokio::io::sink(), client, cache, peers, context)).await,
+        Err(e) => e,
+    };
+    let mut results = vec![(context.sequences.origin().to_string(), Ok(local))];
+    let mut remote = query_peers(&context.transport, peers, &format!("CID {} {}", request_id(), command)).await;
+    remote.sort_by(|a, b| a.0.cmp(&b.0));
+    results.extend(remote);
+
+    let mut output = Vec::new();
+    let mut failed = 0;
+    for (node, result) in results.iter() {
+        match result {
+            Ok(response) if response.is_empty() => output.push(format!("[{}] (empty)", node)),
+            Ok(response) => output.extend(response.lines().map(|line| format!("[{}] {}", node, line))),
+            Err(e) => {
+                warn!("CLUSTER EXEC {} failed on {}: {}", command, node, e);
+                failed += 1;
+                output.push(format!("[{}] (error) {}", node, e));
+            }
+        }
+    }
+    output.push(format!("({} nodes, {} failed)", results.len(), failed));
+    output.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Duration;
+    use crate::testing::TestCluster;
+
+    #[tokio::test(start_paused = true)]
+    async fn cluster_reads_keep_the_latest_value_of_each_key() {
+        let cluster = TestCluster::start_simulated(3).await.unwrap();
+        // Values each node holds alone, as if replication hadn't reached the others
+        cluster.request(0, "BROADCAST b=1").await.unwrap();
+        cluster.request(1, "BROADCAST a=old").await.unwrap();
+        cluster.advance(Duration::from_secs(1)).await;
+        cluster.request(2, "BROADCAST a=new").await.unwrap();
+
+        assert_eq!(cluster.request(0, "GET_ALL").await.unwrap().trim_end(), "b=1");
+        assert_eq!(cluster.request(0, "GET_ALL CLUSTER").await.unwrap().trim_end(), "a=new\nb=1");
+        assert_eq!(cluster.request(1, "GET_ALL CLUSTER WHERE value = new").await.unwrap().trim_end(), "a=new");
+        assert_eq!(cluster.request(0, "GET_LEN").await.unwrap().trim_end(), "1");
+        assert_eq!(cluster.request(0, "GET_LEN CLUSTER").await.unwrap().trim_end(), "2");
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cluster_exec_runs_on_every_node_and_counts_failures() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        let (a, b) = (cluster.addr(0), cluster.addr(1));
+        let created = cluster.request(1, "CLUSTER EXEC CREATE_INDEX owner $.owner").await.unwrap();
+        assert_eq!(
+            created.trim_end(),
+            format!("[{b}] OK: index owner created (0 keys indexed)\n[{a}] OK: index owner created (0 keys indexed)\n(2 nodes, 0 failed)")
+        );
+        assert_eq!(cluster.request(0, "LIST_INDEXES").await.unwrap().trim_end(), "owner=$.owner");
+
+        // A peer that can't be reached is reported, and the rest still run the command
+        let down = "127.0.0.1:1";
+        cluster.request(0, &format!("ADDPEER {}", down)).await.unwrap();
+        let dropped = cluster.request(0, "CLUSTER EXEC DROP_INDEX owner").await.unwrap();
+        let lines: Vec<&str> = dropped.lines().collect();
+        assert_eq!(lines[0], format!("[{a}] OK: index owner dropped"));
+        assert!(lines[1].starts_with(&format!("[{down}] (error) ")), "{}", dropped);
+        assert_eq!(lines[2], format!("[{b}] OK: index owner dropped"));
+        assert_eq!(lines[3], "(3 nodes, 1 failed)");
+        assert_eq!(cluster.request(1, "LIST_INDEXES").await.unwrap().trim_end(), "");
+        cluster.shutdown().await;
+    }
+}
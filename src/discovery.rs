@@ -0,0 +1,468 @@
+//! Peer discovery over UDP broadcast on DISCOVERY_PORT, from DNS, or from the instances a
+//! cloud provider lists by tag (through its `aws` or `gcloud` CLI, which brings the credentials)
+//!
+//! Broadcast announcements are JSON objects (see [`Announcement`]) carrying a format version.
+//! Fields a node doesn't know are ignored and missing ones take defaults, so later versions
+//! can add fields without breaking older nodes; the plain `ANNOUNCE <addr>` of nodes from
+//! before versioning is still understood.
+//!
+//! Peers added with ADDPEER stay in the list whatever discovery finds, and are never expired;
+//! peers removed with REMOVEPEER are kept out of it until they are added again, and banned
+//! peers (see health.rs) until their ban is over.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::StreamExt;
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::time::timeout;
+use tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use socket2::{Socket, Domain, Type};
+use log::{debug, error, info, warn};
+
+use crate::node::{NodeContext, SharedContext};
+
+pub type PeerList = Arc<Mutex<HashSet<String>>>;
+
+// Peers an operator added or removed with ADDPEER/REMOVEPEER
+#[derive(Debug, Default)]
+pub struct ManualPeers {
+    peers: std::sync::Mutex<(HashSet<String>, HashSet<String>)>,
+}
+
+impl ManualPeers {
+    // Put `peer` in the list for good; false if it already was there
+    pub(crate) async fn add(&self, peers: &PeerList, peer: &str) -> bool {
+        let mut peers = peers.lock().await;
+        let mut manual = self.peers.lock().unwrap();
+        manual.1.remove(peer);
+        manual.0.insert(peer.to_string());
+        peers.insert(peer.to_string())
+    }
+
+    // Take `peer` out of the list and keep discovery from adding it back; false if it wasn't there
+    pub(crate) async fn remove(&self, peers: &PeerList, peer: &str) -> bool {
+        let mut peers = peers.lock().await;
+        let mut manual = self.peers.lock().unwrap();
+        manual.0.remove(peer);
+        manual.1.insert(peer.to_string());
+        peers.remove(peer)
+    }
+
+    pub(crate) fn added(&self, peer: &str) -> bool {
+        self.peers.lock().unwrap().0.contains(peer)
+    }
+
+    pub(crate) fn removed(&self, peer: &str) -> bool {
+        self.peers.lock().unwrap().1.contains(peer)
+    }
+
+    // What discovery found, with the manual changes applied
+    fn apply(&self, mut found: HashSet<String>) -> HashSet<String> {
+        let manual = self.peers.lock().unwrap();
+        found.retain(|peer| !manual.1.contains(peer));
+        found.extend(manual.0.iter().cloned());
+        found
+    }
+}
+
+// Whether discovery may put `peer` in the list: neither removed with REMOVEPEER nor banned
+pub(crate) fn admits(context: &NodeContext, peer: &str) -> bool {
+    !context.manual_peers.removed(peer) && !context.health.banned(peer, context.clock.now())
+}
+
+// What discovery found, as far as it may go in the list, and the peers added with ADDPEER
+fn admit(context: &NodeContext, mut found: HashSet<String>) -> HashSet<String> {
+    let now = context.clock.now();
+    found.retain(|peer| !context.health.banned(peer, now));
+    context.manual_peers.apply(found)
+}
+
+pub const DISCOVERY_PORT: u16 = 9000;
+
+// Version of the Announcement format this node sends
+pub const DISCOVERY_VERSION: u32 = 1;
+
+// Protocol features this build speaks, as announced
+pub const CAPABILITIES: &[&str] = &["seq", "resync", "cid", "snapshot", "digest", "sync_ack"];
+
+// What a node broadcasts about itself
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Announcement {
+    // Format version; 0 for a legacy `ANNOUNCE <addr>`
+    pub v: u32,
+    // Random per run of a node
+    pub node_id: String,
+    // Where peers connect to, as NodeBuilder::advertise sets it
+    pub addr: String,
+    pub port: u16,
+    pub role: String,
+    // Bumped on every restart of the node (its boot time)
+    pub epoch: u64,
+    pub capabilities: Vec<String>,
+}
+
+impl Announcement {
+    // A received datagram, structured or legacy; None if it isn't an announcement
+    pub fn parse(message: &str) -> Option<Announcement> {
+        let announcement = match message.strip_prefix("ANNOUNCE") {
+            Some(addr) => Announcement { addr: addr.trim().to_string(), ..Announcement::default() },
+            None => serde_json::from_str(message).ok()?,
+        };
+        (!announcement.addr.is_empty()).then_some(announcement)
+    }
+
+    pub fn to_message(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+// How often announced peers are dialed to see if they are still there, how long one has to
+// accept, and how many are dialed at once
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(5);
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(1);
+const LIVENESS_CONCURRENCY: usize = 16;
+
+// How often Discovery::Dns resolves its name again
+const DNS_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Which cloud API lists the instances
+#[derive(Clone, Debug, PartialEq)]
+pub enum CloudProvider {
+    // EC2 DescribeInstances, filtering on a tag
+    Aws,
+    // Compute Engine instances.list, filtering on a label
+    Gce,
+}
+
+// Running instances tagged `tag_key=tag_value`, each a peer on `port`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloudDiscovery {
+    pub provider: CloudProvider,
+    pub tag_key: String,
+    pub tag_value: String,
+    pub port: u16,
+    // AWS region, or the CLI's configured one
+    pub region: Option<String>,
+    // Cloud APIs are rate limited, so they are asked less often than DNS
+    pub interval: Duration,
+}
+
+impl CloudDiscovery {
+    // e.g. "provider=aws,tag=cluster:p2p,region=eu-west-1" or "provider=gce,tag=cluster:p2p,interval_s=60";
+    // peers listen on `port` unless the spec sets port=
+    pub fn parse(spec: &str, port: u16) -> Result<CloudDiscovery, String> {
+        let (mut provider, mut tag, mut region) = (None, None, None);
+        let mut interval = Duration::from_secs(30);
+        let mut port = port;
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid cloud discovery setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "provider" => {
+                    provider = Some(match value {
+                        "aws" => CloudProvider::Aws,
+                        "gce" => CloudProvider::Gce,
+                        _ => return Err(invalid()),
+                    })
+                }
+                "tag" => tag = Some(value.split_once(':').filter(|(key, _)| !key.is_empty()).ok_or_else(invalid)?),
+                "port" => port = value.parse().map_err(|_| invalid())?,
+                "region" => region = Some(value.to_string()),
+                "interval_s" => interval = Duration::from_secs(value.parse().ok().filter(|s| *s > 0).ok_or_else(invalid)?),
+                _ => return Err(format!("Unknown cloud discovery setting: {}", name)),
+            }
+        }
+        let provider = provider.ok_or("Cloud discovery needs provider=aws or provider=gce")?;
+        let (tag_key, tag_value) = tag.ok_or("Cloud discovery needs tag=<key>:<value>")?;
+        Ok(CloudDiscovery {
+            provider,
+            tag_key: tag_key.to_string(),
+            tag_value: tag_value.to_string(),
+            port,
+            region,
+            interval,
+        })
+    }
+
+    // The CLI invocation printing the private IPs of the matching running instances
+    fn command(&self) -> tokio::process::Command {
+        match self.provider {
+            CloudProvider::Aws => {
+                let mut command = tokio::process::Command::new("aws");
+                command.args(["ec2", "describe-instances", "--filters"]);
+                command.arg(format!("Name=tag:{},Values={}", self.tag_key, self.tag_value));
+                command.arg("Name=instance-state-name,Values=running");
+                command.args(["--query", "Reservations[].Instances[].PrivateIpAddress", "--output", "text"]);
+                if let Some(region) = &self.region {
+                    command.args(["--region", region]);
+                }
+                command
+            }
+            CloudProvider::Gce => {
+                let mut command = tokio::process::Command::new("gcloud");
+                command.args(["compute", "instances", "list"]);
+                command.arg(format!("--filter=labels.{}={} AND status=RUNNING", self.tag_key, self.tag_value));
+                command.arg("--format=value(networkInterfaces[0].networkIP)");
+                command
+            }
+        }
+    }
+
+    // Private IPs of the matching instances
+    async fn list(&self) -> Result<Vec<IpAddr>, String> {
+        let output = self.command().output().await.map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .filter(|ip| *ip != "None")
+            .map(|ip| ip.parse().map_err(|_| format!("unexpected output: {}", ip)))
+            .collect()
+    }
+}
+
+// How a node finds its peers
+#[derive(Clone, Debug)]
+pub enum Discovery {
+    // UDP broadcast announcements on DISCOVERY_PORT
+    Broadcast,
+    // A fixed list of peer addresses, e.g. "127.0.0.1:8081"
+    Static(Vec<String>),
+    // Every address `name` resolves to, on `port`; for a Kubernetes headless service, where
+    // broadcast doesn't cross pods, e.g. "p2p.default.svc.cluster.local"
+    Dns { name: String, port: u16 },
+    // Instances listed by a cloud provider's API, for autoscaling groups
+    Cloud(CloudDiscovery),
+    // No peers; writes stay local
+    None,
+}
+
+// Listen for announcements; `self_addr` is what this node announces itself as
+pub async fn discovery_service(peers: PeerList, context: SharedContext) {
+    let self_addr = context.sequences.origin();
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+    socket.set_reuse_address(true).unwrap();
+    #[cfg(unix)]
+    socket.set_reuse_port(true).unwrap();
+    socket.bind(&"0.0.0.0:9000".parse::<std::net::SocketAddr>().unwrap().into()).unwrap();
+    // Tokio requires the std socket to be non-blocking, otherwise recv_from stalls a worker thread
+    socket.set_nonblocking(true).unwrap();
+
+    let socket = UdpSocket::from_std(socket.into()).unwrap();
+    debug!("Discovery service listening on UDP port {}", DISCOVERY_PORT);
+
+    let mut buf = [0u8; 1024];
+    loop {
+        if let Ok((len, _)) = socket.recv_from(&mut buf).await {
+            let message = String::from_utf8_lossy(&buf[..len]);
+            if let Some(announcement) = Announcement::parse(&message) {
+                // Add the peer to the peer list. Our own announcements come back to us;
+                // replicating to ourselves would apply operations twice.
+                if announcement.addr != self_addr && admits(&context, &announcement.addr) {
+                    debug!("Discovered peer: {} ({:?})", announcement.addr, announcement);
+                    peers.lock().await.insert(announcement.addr);
+                }
+            }
+        }
+    }
+}
+
+// Drop announced peers that stop accepting connections, every LIVENESS_INTERVAL; they are
+// added back when they announce themselves again
+pub async fn expire_peers(peers: PeerList, context: SharedContext) {
+    loop {
+        tokio::time::sleep(LIVENESS_INTERVAL).await;
+        check_for_expired_peers(peers.clone(), &context).await;
+    }
+}
+
+// Keep the peer list to what `name` resolves to: pods that appear are added, pods gone are dropped
+pub async fn dns_discovery(peers: PeerList, name: String, port: u16, context: SharedContext) {
+    let node_port = context.node_port;
+    loop {
+        match lookup_host((name.as_str(), port)).await {
+            Ok(addrs) => {
+                let found = addrs.filter(|addr| !(addr.port() == node_port && is_local(addr.ip()))).map(|addr| addr.to_string());
+                replace_peers(&peers, admit(&context, found.collect()), &name).await;
+            }
+            // Keep the peers we have; DNS may only be briefly unavailable
+            Err(e) => warn!("Failed to resolve {}: {}", name, e),
+        }
+        tokio::time::sleep(DNS_REFRESH_INTERVAL).await;
+    }
+}
+
+// Keep the peer list to the instances `cloud` lists, without this one
+pub async fn cloud_discovery(peers: PeerList, cloud: CloudDiscovery, context: SharedContext) {
+    let node_port = context.node_port;
+    let source = format!("{:?} tag {}:{}", cloud.provider, cloud.tag_key, cloud.tag_value);
+    loop {
+        match cloud.list().await {
+            Ok(ips) => {
+                let found = ips
+                    .into_iter()
+                    .filter(|ip| !(cloud.port == node_port && is_local(*ip)))
+                    .map(|ip| std::net::SocketAddr::new(ip, cloud.port).to_string());
+                replace_peers(&peers, admit(&context, found.collect()), &source).await;
+            }
+            // Keep the peers we have; the API may only be briefly unavailable
+            Err(e) => warn!("Failed to list instances by {}: {}", source, e),
+        }
+        tokio::time::sleep(cloud.interval).await;
+    }
+}
+
+// Make `found` the peer list, logging who joined and who left
+async fn replace_peers(peers: &PeerList, found: HashSet<String>, source: &str) {
+    let mut peers = peers.lock().await;
+    for peer in found.difference(&peers) {
+        info!("Discovered peer {} from {}", peer, source);
+    }
+    for peer in peers.difference(&found) {
+        warn!("Removed peer {}, no longer in {}", peer, source);
+    }
+    *peers = found;
+}
+
+// Whether `ip` is one of this host's addresses, which a headless service lists alongside the others
+fn is_local(ip: IpAddr) -> bool {
+    ip.is_loopback() || std::net::UdpSocket::bind((ip, 0)).is_ok()
+}
+
+// Dial every peer, LIVENESS_CONCURRENCY at a time and without holding the peer list
+pub(crate) async fn check_for_expired_peers(peers: PeerList, context: &NodeContext) {
+    let snapshot: Vec<String> = peers.lock().await.iter().filter(|peer| !context.manual_peers.added(peer)).cloned().collect();
+    let expired_peers: Vec<String> = futures::stream::iter(snapshot)
+        .map(|peer| async move {
+            let alive = matches!(timeout(LIVENESS_TIMEOUT, TcpStream::connect(&peer)).await, Ok(Ok(_)));
+            (!alive).then_some(peer)
+        })
+        .buffer_unordered(LIVENESS_CONCURRENCY)
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+
+    let mut peers = peers.lock().await;
+    let now = context.clock.now();
+    for peer in expired_peers {
+        context.health.record_check_failed(&peer, now);
+        peers.remove(&peer);
+        warn!("Removed expired peer: {}", peer);
+    }
+}
+
+// async fn check_for_expired_peers(peers: PeerList) {
+//     let mut peers = peers.lock().await;
+//     let mut expired_peers = Vec::new();
+//     for peer in peers.iter() {
+//         if let Err(_) = TcpStream::connect(peer).await {
+//             expired_peers.push(peer.clone());
+//         }
+//     }
+
+//     for peer in expired_peers {
+//         peers.remove(&peer);
+//         warn!("Removed expired peer: {}", peer);
+//     }
+// }
+
+// Broadcast `announcement` every 10s
+pub async fn announce_self(announcement: Announcement) { //peers: PeerList,
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await.unwrap();
+    socket.set_broadcast(true).unwrap();
+
+    let broadcast_address = "255.255.255.255:9000";
+
+    loop {
+        let message = announcement.to_message();
+        debug!("Broadcasting: {}", message);
+        if let Err(e) = socket.send_to(message.as_bytes(), broadcast_address).await {
+            error!("Failed to broadcast: {}", e);
+        }
+
+        // {
+        //     let peers_snapshot = peers.lock().await;
+        //     trace!("Known peers: {:?}", peers_snapshot);
+        // }
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cloud_discovery_settings() {
+        let aws = CloudDiscovery::parse("provider=aws,tag=cluster:p2p,region=eu-west-1", 8080).unwrap();
+        assert_eq!((aws.provider, aws.tag_key.as_str(), aws.tag_value.as_str()), (CloudProvider::Aws, "cluster", "p2p"));
+        assert_eq!((aws.port, aws.region.as_deref(), aws.interval), (8080, Some("eu-west-1"), Duration::from_secs(30)));
+
+        let gce = CloudDiscovery::parse("provider=gce,tag=role:cache,port=9090,interval_s=60", 8080).unwrap();
+        assert_eq!((&gce.provider, gce.port, gce.region.as_deref(), gce.interval), (&CloudProvider::Gce, 9090, None, Duration::from_secs(60)));
+        let args: Vec<_> = gce.command().as_std().get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+        assert!(args.contains(&"--filter=labels.role=cache AND status=RUNNING".to_string()), "{:?}", args);
+
+        let error = |spec| CloudDiscovery::parse(spec, 8080).unwrap_err();
+        assert_eq!(error("tag=cluster:p2p"), "Cloud discovery needs provider=aws or provider=gce");
+        assert_eq!(error("provider=aws"), "Cloud discovery needs tag=<key>:<value>");
+        assert_eq!(error("provider=azure,tag=a:b"), "Invalid value for provider: azure");
+        assert_eq!(error("provider=aws,tag=:b"), "Invalid value for tag: :b");
+        assert_eq!(error("provider=aws,tag=a:b,interval_s=0"), "Invalid value for interval_s: 0");
+    }
+
+    #[test]
+    fn reads_structured_and_legacy_announcements() {
+        let announcement = Announcement {
+            v: DISCOVERY_VERSION,
+            node_id: "n1".to_string(),
+            addr: "10.0.0.1:8080".to_string(),
+            port: 8080,
+            role: "cache".to_string(),
+            epoch: 7,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        };
+        assert_eq!(Announcement::parse(&announcement.to_message()), Some(announcement));
+
+        let legacy = Announcement::parse("ANNOUNCE 10.0.0.2:8080").unwrap();
+        assert_eq!((legacy.v, legacy.addr.as_str()), (0, "10.0.0.2:8080"));
+        // Fields a newer node adds are ignored and missing ones default
+        let newer = Announcement::parse(r#"{"v":2,"addr":"10.0.0.3:8080","zone":"b"}"#).unwrap();
+        assert_eq!((newer.v, newer.epoch, newer.capabilities.len()), (2, 0, 0));
+        assert_eq!(Announcement::parse("ANNOUNCE"), None);
+        assert_eq!(Announcement::parse(r#"{"v":1}"#), None);
+        assert_eq!(Announcement::parse("hello"), None);
+    }
+
+    #[tokio::test]
+    async fn manual_peers_override_what_discovery_finds() {
+        let manual = ManualPeers::default();
+        let peers: PeerList = Arc::new(Mutex::new(HashSet::from(["a:1".to_string()])));
+        assert!(manual.add(&peers, "b:1").await);
+        assert!(!manual.add(&peers, "b:1").await);
+        assert!(manual.remove(&peers, "a:1").await);
+        assert!(!manual.remove(&peers, "c:1").await);
+        assert!(manual.added("b:1") && manual.removed("a:1") && manual.removed("c:1"));
+
+        let found = HashSet::from(["a:1".to_string(), "c:1".to_string(), "d:1".to_string()]);
+        let mut applied: Vec<String> = manual.apply(found.clone()).into_iter().collect();
+        applied.sort();
+        assert_eq!(applied, ["b:1", "d:1"]);
+
+        // Adding a removed peer again lets discovery find it, and removing an added one stops keeping it
+        manual.add(&peers, "a:1").await;
+        manual.remove(&peers, "b:1").await;
+        let mut applied: Vec<String> = manual.apply(found).into_iter().collect();
+        applied.sort();
+        assert_eq!(applied, ["a:1", "d:1"]);
+        assert!(!manual.added("b:1") && !manual.removed("a:1"));
+    }
+}
@@ -0,0 +1,209 @@
+//! Fault injection for chaos testing (`fault-injection` feature).
+//!
+//! [`FaultyTransport`] wraps another transport and applies a [`Fault`] to
+//! outgoing connections by destination address: added latency and jitter
+//! (messages in flight at the same time overtake each other), message loss
+//! (the sender's writes succeed but go nowhere) and connection resets.
+//! Faults can be changed while the node runs, e.g. to partition a peer.
+
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use futures::future::BoxFuture;
+use log::debug;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Duration;
+
+use crate::transport::{BoxConnection, Listener, SharedTransport, Transport};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Fault {
+    // Probability that a message is silently dropped
+    pub loss: f64,
+    // Probability that the connection is reset before the message is delivered
+    pub reset: f64,
+    // Fixed delay before connecting
+    pub latency: Duration,
+    // Random extra delay up to this much, which reorders concurrent messages
+    pub jitter: Duration,
+}
+
+impl Fault {
+    // Parse `loss=0.1,reset=0.05,latency_ms=50,jitter_ms=20` (any subset)
+    pub fn parse(spec: &str) -> Result<Fault, String> {
+        let mut fault = Fault::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid fault setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "loss" => fault.loss = value.parse().map_err(|_| invalid())?,
+                "reset" => fault.reset = value.parse().map_err(|_| invalid())?,
+                "latency_ms" => fault.latency = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+                "jitter_ms" => fault.jitter = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+                _ => return Err(format!("Unknown fault setting: {}", name)),
+            }
+        }
+        if !(0.0..=1.0).contains(&fault.loss) || !(0.0..=1.0).contains(&fault.reset) {
+            return Err("loss and reset must be between 0 and 1".to_string());
+        }
+        Ok(fault)
+    }
+}
+
+struct FaultState {
+    // Destination address -> fault; "*" applies to every destination without its own entry
+    links: HashMap<String, Fault>,
+    rng: StdRng,
+}
+
+pub struct FaultyTransport {
+    inner: SharedTransport,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultyTransport {
+    pub fn new(inner: SharedTransport) -> Self {
+        Self::with_seed(inner, rand::random())
+    }
+
+    // Seeded variant, so a simulated run injects the same faults every time
+    pub fn with_seed(inner: SharedTransport, seed: u64) -> Self {
+        FaultyTransport {
+            inner,
+            state: Arc::new(Mutex::new(FaultState {
+                links: HashMap::new(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    // Parse `<addr|*>=<fault>;...` (see Fault::parse), e.g. `127.0.0.1:8081=loss=1;*=latency_ms=20`
+    pub fn from_spec(inner: SharedTransport, spec: &str) -> Result<Self, String> {
+        let transport = Self::new(inner);
+        for link in spec.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            let (addr, fault) = link.split_once('=').ok_or_else(|| format!("Invalid fault link: {}", link))?;
+            transport.set_fault(addr.trim(), Fault::parse(fault)?);
+        }
+        Ok(transport)
+    }
+
+    // Apply `fault` to connections to `addr`, or to every destination for "*"
+    pub fn set_fault(&self, addr: &str, fault: Fault) {
+        self.state.lock().unwrap().links.insert(addr.to_string(), fault);
+    }
+
+    pub fn clear_fault(&self, addr: &str) {
+        self.state.lock().unwrap().links.remove(addr);
+    }
+
+    pub fn clear_all(&self) {
+        self.state.lock().unwrap().links.clear();
+    }
+}
+
+enum Outcome {
+    Deliver,
+    Drop,
+    Reset,
+}
+
+impl Transport for FaultyTransport {
+    fn bind(&self, port: u16) -> io::Result<Box<dyn Listener>> {
+        self.inner.bind(port)
+    }
+
+    fn connect(&self, addr: String) -> BoxFuture<'static, io::Result<BoxConnection>> {
+        // Roll the dice up front so the outcome only depends on the order of connects
+        let (delay, outcome) = {
+            let mut state = self.state.lock().unwrap();
+            let fault = state.links.get(&addr).or_else(|| state.links.get("*")).cloned().unwrap_or_default();
+            let jitter = if fault.jitter.is_zero() {
+                Duration::ZERO
+            } else {
+                fault.jitter.mul_f64(state.rng.gen::<f64>())
+            };
+            let outcome = if state.rng.gen::<f64>() < fault.loss {
+                Outcome::Drop
+            } else if state.rng.gen::<f64>() < fault.reset {
+                Outcome::Reset
+            } else {
+                Outcome::Deliver
+            };
+            (fault.latency + jitter, outcome)
+        };
+
+        let inner = Arc::clone(&self.inner);
+        Box::pin(async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            match outcome {
+                Outcome::Deliver => inner.connect(addr).await,
+                Outcome::Drop => {
+                    debug!("Fault injection: dropping message to {}", addr);
+                    Ok(Box::new(BlackHole) as BoxConnection)
+                }
+                Outcome::Reset => {
+                    debug!("Fault injection: resetting connection to {}", addr);
+                    Ok(Box::new(ResetConnection) as BoxConnection)
+                }
+            }
+        })
+    }
+}
+
+// Accepts and discards writes, reads hit EOF: the peer never sees the message
+struct BlackHole;
+
+impl AsyncRead for BlackHole {
+    fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for BlackHole {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Fails every read and write with ConnectionReset
+struct ResetConnection;
+
+fn reset<T>() -> Poll<io::Result<T>> {
+    Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by fault injection")))
+}
+
+impl AsyncRead for ResetConnection {
+    fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        reset()
+    }
+}
+
+impl AsyncWrite for ResetConnection {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<io::Result<usize>> {
+        reset()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        reset()
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        reset()
+    }
+}
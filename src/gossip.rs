@@ -0,0 +1,196 @@
+//! Digest gossip with bucket-level repair of keys that differ between peers
+
+use std::collections::HashSet;
+use rand::seq::IteratorRandom;
+use serde_json::Value;
+use tokio::time::Duration;
+use log::{debug, info, warn};
+
+use crate::cluster::ask;
+use crate::discovery::PeerList;
+use crate::node::SharedContext;
+use crate::protocol::{format_assignment, parse_assignment};
+use crate::replication::from_peer_clock;
+use crate::storage::{Cache, CacheValue, SharedCache};
+
+// How often and at what granularity nodes compare digests
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gossip {
+    pub interval: Duration,
+    pub buckets: usize,
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Gossip { interval: Duration::from_secs(5), buckets: 64 }
+    }
+}
+
+impl Gossip {
+    // "on" for the defaults, or e.g. "interval_s=2,buckets=256"
+    pub fn parse(spec: &str) -> Result<Gossip, String> {
+        let mut gossip = Gossip::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty() && *s != "on") {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid gossip setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "interval_s" => gossip.interval = Duration::from_secs(value.parse().ok().filter(|s| *s > 0).ok_or_else(invalid)?),
+                "buckets" => gossip.buckets = value.parse().ok().filter(|n| (1..=MAX_BUCKETS).contains(n)).ok_or_else(invalid)?,
+                _ => return Err(format!("Unknown gossip setting: {}", name)),
+            }
+        }
+        Ok(gossip)
+    }
+}
+
+// Upper bound on DIGEST buckets, which keeps the digest line small
+pub(crate) const MAX_BUCKETS: usize = 4096;
+
+// 64-bit FNV-1a, stable across builds and platforms unlike std's hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+pub(crate) fn bucket_of(key: &str, buckets: usize) -> usize {
+    (fnv1a(key.as_bytes()) % buckets as u64) as usize
+}
+
+// Key count and XOR of entry hashes per bucket
+pub(crate) fn digest(cache: &Cache, buckets: usize) -> Vec<(u64, u64)> {
+    let mut digest = vec![(0, 0); buckets];
+    for (key, value) in cache.iter() {
+        let bucket = &mut digest[bucket_of(key, buckets)];
+        bucket.0 += 1;
+        bucket.1 ^= fnv1a(format_assignment(key, value).as_bytes());
+    }
+    digest
+}
+
+// The DIGEST answer: `count:hash` per bucket, in order, space separated
+pub(crate) fn format_digest(digest: &[(u64, u64)]) -> String {
+    digest.iter().map(|(count, hash)| format!("{}:{:x}", count, hash)).collect::<Vec<_>>().join(" ")
+}
+
+fn parse_digest(line: &str) -> Option<Vec<(u64, u64)>> {
+    line.split_whitespace()
+        .map(|bucket| {
+            let (count, hash) = bucket.split_once(':')?;
+            Some((count.parse().ok()?, u64::from_str_radix(hash, 16).ok()?))
+        })
+        .collect()
+}
+
+// Compare digests with a random peer every `gossip.interval` and repair the buckets that differ
+pub async fn gossip_with_peers(cache: SharedCache, peers: PeerList, context: SharedContext, gossip: Gossip) {
+    loop {
+        tokio::time::sleep(gossip.interval).await;
+        let Some(peer) = peers.lock().await.iter().choose(&mut rand::thread_rng()).cloned() else {
+            continue;
+        };
+        match gossip_round(&cache, &peer, &context, gossip.buckets).await {
+            Ok((0, _)) => debug!("Digest matches {}", peer),
+            Ok((buckets, repaired)) => info!("Repaired {} keys in {} buckets that differed from {}", repaired, buckets, peer),
+            Err(e) => debug!("Gossip with {} failed: {}", peer, e),
+        }
+    }
+}
+
+// One exchange with `peer`; returns how many buckets differed and how many keys were taken
+async fn gossip_round(cache: &SharedCache, peer: &str, context: &SharedContext, buckets: usize) -> Result<(usize, usize), String> {
+    let theirs = ask(context, peer, &format!("DIGEST {}", buckets)).await?;
+    let theirs = parse_digest(theirs.trim()).filter(|d| d.len() == buckets).ok_or("invalid DIGEST answer")?;
+    let ours = digest(&*cache.lock().await, buckets);
+    let differing: Vec<String> = (0..buckets).filter(|&i| ours[i] != theirs[i]).map(|i| i.to_string()).collect();
+    if differing.is_empty() {
+        return Ok((0, 0));
+    }
+
+    let entries = ask(context, peer, &format!("ENTRIES BUCKETS {} {}", buckets, differing.join(" "))).await?;
+    let mut repaired = 0;
+    let mut cache = cache.lock().await;
+    for line in entries.lines().filter(|line| !line.is_empty()) {
+        let Some((key, value, modified, expires)) = parse_entry(line) else {
+            warn!("Invalid ENTRIES line from {}: {}", peer, line);
+            continue;
+        };
+        // Equal values and ties are left alone, so two nodes never keep swapping a key
+        let stale = match cache.get(&key) {
+            None => true,
+            Some(current) => *current != value && cache.modified(&key).is_none_or(|local| local < modified),
+        };
+        if stale {
+            cache.insert(key.clone(), value);
+            cache.set_expiry(&key, expires.map(|at| from_peer_clock(context, peer, at)));
+            repaired += 1;
+        }
+    }
+    Ok((differing.len(), repaired))
+}
+
+// Key, value, modification time (0 if unknown) and expiry in the sender's clock of an ENTRIES line
+pub(crate) fn parse_entry(line: &str) -> Option<(String, CacheValue, u64, Option<u64>)> {
+    let entry = serde_json::from_str::<Value>(line).ok()?;
+    let (key, value) = parse_assignment(entry["entry"].as_str()?).ok()?;
+    Some((key, value, entry["modified"].as_u64().unwrap_or(0), entry["expires"].as_u64()))
+}
+
+// Parse the bucket list of `ENTRIES BUCKETS <buckets> <i>..`
+pub(crate) fn parse_buckets(args: &str) -> Option<(usize, HashSet<usize>)> {
+    let mut words = args.split_whitespace();
+    let buckets: usize = words.next()?.parse().ok().filter(|n| (1..=MAX_BUCKETS).contains(n))?;
+    let wanted = words.map(|i| i.parse().ok().filter(|i| *i < buckets)).collect::<Option<HashSet<usize>>>()?;
+    Some((buckets, wanted))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::node::NodeContext;
+    use crate::testing::TestCluster;
+
+    #[test]
+    fn digests_do_not_depend_on_write_order() {
+        let mut a = Cache::new();
+        let mut b = Cache::new();
+        for (key, value) in [("x", 1), ("y", 2), ("z", 3)] {
+            a.insert(key.to_string(), CacheValue::Int(value));
+        }
+        for (key, value) in [("z", 3), ("x", 1), ("y", 2)] {
+            b.insert(key.to_string(), CacheValue::Int(value));
+        }
+        assert_eq!(digest(&a, 8), digest(&b, 8));
+        assert_eq!(parse_digest(&format_digest(&digest(&a, 8))), Some(digest(&a, 8)));
+
+        b.insert("y".to_string(), CacheValue::Int(20));
+        let differing: Vec<usize> = (0..8).filter(|&i| digest(&a, 8)[i] != digest(&b, 8)[i]).collect();
+        assert_eq!(differing, [bucket_of("y", 8)]);
+        assert_eq!(parse_buckets("8 1 7"), Some((8, HashSet::from([1, 7]))));
+        assert_eq!(parse_buckets("8 8"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gossip_repairs_the_buckets_that_differ_keeping_the_newer_value() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        // Values each node holds alone, as if replication had been lost
+        cluster.request(1, "BROADCAST a=old").await.unwrap();
+        cluster.advance(Duration::from_secs(1)).await;
+        cluster.request(0, "BROADCAST a=new").await.unwrap();
+        cluster.request(1, "BROADCAST b=1").await.unwrap();
+        cluster.request(1, "GETEX b TTL=30").await.unwrap();
+
+        let context = |i: usize| Arc::new(NodeContext::new(cluster.node(i).port(), cluster.node(i).transport(), Arc::new(SystemClock)));
+        let (first, second) = (cluster.node(0).cache(), cluster.node(1).cache());
+        assert_eq!(gossip_round(&first, &cluster.addr(1), &context(0), 64).await, Ok((2, 1)));
+        assert_eq!(gossip_round(&second, &cluster.addr(0), &context(1), 64).await, Ok((1, 1)));
+        assert_eq!(digest(&*first.lock().await, 64), digest(&*second.lock().await, 64));
+        assert_eq!(cluster.request(1, "GET a").await.unwrap().trim_end(), "new");
+        // Keys taken keep their expiry
+        assert_eq!(cluster.request(0, "TTL b").await.unwrap().trim_end(), "30");
+        assert_eq!(gossip_round(&first, &cluster.addr(1), &context(0), 64).await, Ok((0, 0)));
+        cluster.shutdown().await;
+    }
+}
@@ -0,0 +1,548 @@
+//! Per-peer health, the replication circuit breaker, heartbeats and peer bans
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::future::join_all;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout, Instant};
+use log::{debug, info, warn};
+
+use crate::discovery::PeerList;
+use crate::node::SharedContext;
+
+// Weight of the newest sample in the smoothed error rate and latency
+const SMOOTHING: f64 = 0.2;
+
+// Sends to a peer before its error rate or latency can open the circuit
+const MIN_SAMPLES: u64 = 10;
+
+// How often peers are PINGed, and how long each has to answer
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Thresholds for skipping an unhealthy peer
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    // Consecutive failed sends
+    pub failures: u32,
+    // Smoothed share of failed sends, 0 to 1
+    pub error_rate: f64,
+    // Smoothed time to connect and send
+    pub latency: Duration,
+    // How long an open circuit skips the peer
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            failures: 3,
+            error_rate: 0.5,
+            latency: Duration::from_secs(1),
+            cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+impl CircuitBreaker {
+    // "on" for the defaults, or e.g. "failures=5,error_rate=0.3,latency_ms=500,cooldown_ms=5000"
+    pub fn parse(spec: &str) -> Result<CircuitBreaker, String> {
+        let mut breaker = CircuitBreaker::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty() && *s != "on") {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid circuit breaker setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "failures" => breaker.failures = value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?,
+                "error_rate" => breaker.error_rate = value.parse().ok().filter(|r| (0.0..=1.0).contains(r)).ok_or_else(invalid)?,
+                "latency_ms" => breaker.latency = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+                "cooldown_ms" => breaker.cooldown = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+                _ => return Err(format!("Unknown circuit breaker setting: {}", name)),
+            }
+        }
+        Ok(breaker)
+    }
+}
+
+// When a peer that keeps failing is banned, and for how long
+#[derive(Clone, Debug)]
+pub struct PeerBans {
+    // Consecutive failed sends, heartbeats and liveness checks
+    pub failures: u32,
+    // The first ban; each one after it is twice as long
+    pub ban: Duration,
+    pub max_ban: Duration,
+    // Time without a ban that takes a doubling off the next one
+    pub decay: Duration,
+}
+
+impl Default for PeerBans {
+    fn default() -> Self {
+        PeerBans {
+            failures: 5,
+            ban: Duration::from_secs(30),
+            max_ban: Duration::from_secs(3600),
+            decay: Duration::from_secs(600),
+        }
+    }
+}
+
+impl PeerBans {
+    // "on" for the defaults, or e.g. "failures=10,ban_ms=60000,max_ban_ms=600000,decay_ms=300000"
+    pub fn parse(spec: &str) -> Result<PeerBans, String> {
+        let mut bans = PeerBans::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty() && *s != "on") {
+            let (name, value) = setting.split_once('=').ok_or_else(|| format!("Invalid peer ban setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            let millis = || value.parse().map(Duration::from_millis).map_err(|_| invalid());
+            match name {
+                "failures" => bans.failures = value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?,
+                "ban_ms" => bans.ban = millis()?,
+                "max_ban_ms" => bans.max_ban = millis()?,
+                "decay_ms" => bans.decay = millis()?.max(Duration::from_millis(1)),
+                _ => return Err(format!("Unknown peer ban setting: {}", name)),
+            }
+        }
+        Ok(bans)
+    }
+
+    // The `level`th ban in a row (from 1)
+    fn length(&self, level: u32) -> Duration {
+        self.ban.saturating_mul(1 << level.saturating_sub(1).min(31)).min(self.max_ban)
+    }
+}
+
+#[derive(Default)]
+struct Health {
+    sent: u64,
+    failed: u64,
+    consecutive_failures: u32,
+    error_rate: f64,
+    latency_ms: f64,
+    // Set while the circuit is open, including while the probe after the cooldown is out
+    open_until: Option<Instant>,
+    // Smoothed heartbeat round trip, None until one answered
+    rtt_ms: Option<f64>,
+    // Whether the last heartbeat was answered
+    reachable: bool,
+    // Smoothed milliseconds the peer's clock is ahead of ours, None until it told us its clock
+    clock_offset_ms: Option<f64>,
+    // Failed sends, heartbeats and liveness checks since the last that succeeded
+    strikes: u32,
+    // Bans so far, less those decayed since the last one
+    ban_level: u32,
+    banned_at: Option<Instant>,
+    banned_until: Option<Instant>,
+}
+
+#[derive(Default)]
+pub(crate) struct PeerHealth {
+    // None tracks health without ever skipping a peer
+    breaker: Option<CircuitBreaker>,
+    // None never bans a peer
+    bans: Option<PeerBans>,
+    peers: std::sync::Mutex<HashMap<String, Health>>,
+}
+
+impl PeerHealth {
+    pub(crate) fn new(breaker: Option<CircuitBreaker>, bans: Option<PeerBans>) -> Self {
+        PeerHealth { breaker, bans, peers: Default::default() }
+    }
+
+    // Whether `peer` is banned now, and so kept out of the peer list
+    pub(crate) fn banned(&self, peer: &str, now: Instant) -> bool {
+        self.peers.lock().unwrap().get(peer).and_then(|health| health.banned_until).is_some_and(|until| now < until)
+    }
+
+    // A liveness check of `peer` failed
+    pub(crate) fn record_check_failed(&self, peer: &str, now: Instant) {
+        let mut peers = self.peers.lock().unwrap();
+        self.strike(peer, peers.entry(peer.to_string()).or_default(), now);
+    }
+
+    // Lift `peer`'s ban, e.g. for ADDPEER; the next one starts over from the shortest
+    pub(crate) fn unban(&self, peer: &str) {
+        if let Some(health) = self.peers.lock().unwrap().get_mut(peer) {
+            health.strikes = 0;
+            health.ban_level = 0;
+            health.banned_until = None;
+        }
+    }
+
+    // One `<peer> level=.. banned_ms=..` line per peer banned before, with what is left of
+    // its current ban (0 if over), sorted
+    pub(crate) fn bans(&self, now: Instant) -> Vec<String> {
+        let peers = self.peers.lock().unwrap();
+        let mut lines: Vec<String> = peers
+            .iter()
+            .filter(|(_, health)| health.ban_level > 0)
+            .map(|(peer, health)| {
+                let left = health.banned_until.map_or(Duration::ZERO, |until| until.saturating_duration_since(now));
+                format!("{} level={} banned_ms={}", peer, health.ban_level, left.as_millis())
+            })
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    // Count a failure against `peer`, banning it once there were too many in a row
+    fn strike(&self, peer: &str, health: &mut Health, now: Instant) {
+        // What a peer fails while banned doesn't count towards the next ban
+        if health.banned_until.is_some_and(|until| now < until) {
+            return;
+        }
+        health.strikes += 1;
+        let Some(bans) = &self.bans else {
+            return;
+        };
+        if health.strikes < bans.failures {
+            return;
+        }
+        if let Some(at) = health.banned_at {
+            let decayed = (now.saturating_duration_since(at).as_millis() / bans.decay.as_millis()) as u32;
+            health.ban_level = health.ban_level.saturating_sub(decayed);
+        }
+        health.ban_level += 1;
+        let length = bans.length(health.ban_level);
+        warn!("Banned peer {} for {:?} after {} failures in a row (ban {})", peer, length, health.strikes, health.ban_level);
+        health.strikes = 0;
+        health.banned_at = Some(now);
+        health.banned_until = Some(now + length);
+    }
+
+    // Whether to send to `peer` now; false while its circuit is open. The first send after the
+    // cooldown is the probe, and the circuit stays open for everything else until it answers.
+    pub(crate) fn allow(&self, peer: &str, now: Instant) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let (Some(health), Some(breaker)) = (peers.get_mut(peer), &self.breaker) else {
+            return true;
+        };
+        match health.open_until {
+            Some(until) if now < until => false,
+            Some(_) => {
+                health.open_until = Some(now + breaker.cooldown);
+                true
+            }
+            None => true,
+        }
+    }
+
+    // A send to `peer` that took `latency`, or failed with None
+    pub(crate) fn record(&self, peer: &str, now: Instant, latency: Option<Duration>) {
+        let mut peers = self.peers.lock().unwrap();
+        let health = peers.entry(peer.to_string()).or_default();
+        health.sent += 1;
+        let probing = health.open_until.is_some();
+
+        let Some(latency) = latency else {
+            self.strike(peer, health, now);
+            health.failed += 1;
+            health.consecutive_failures += 1;
+            health.error_rate += SMOOTHING * (1.0 - health.error_rate);
+            if let Some(breaker) = &self.breaker {
+                let too_many = health.consecutive_failures >= breaker.failures
+                    || (health.sent >= MIN_SAMPLES && health.error_rate > breaker.error_rate);
+                if probing || too_many {
+                    self.open(peer, health, breaker, now, "failing");
+                }
+            }
+            return;
+        };
+
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        health.consecutive_failures = 0;
+        health.strikes = 0;
+        if probing {
+            // A healthy probe starts the averages over
+            info!("Closed the replication circuit for {}", peer);
+            health.open_until = None;
+            health.error_rate = 0.0;
+            health.latency_ms = latency_ms;
+        } else {
+            health.error_rate -= SMOOTHING * health.error_rate;
+            health.latency_ms += SMOOTHING * (latency_ms - health.latency_ms);
+        }
+        if let Some(breaker) = &self.breaker {
+            let slow = health.latency_ms > breaker.latency.as_secs_f64() * 1000.0;
+            if slow && (probing || health.sent >= MIN_SAMPLES) {
+                self.open(peer, health, breaker, now, "slow");
+            }
+        }
+    }
+
+    // How many milliseconds `peer`'s clock is ahead of this node's, once a heartbeat measured it
+    pub(crate) fn clock_offset(&self, peer: &str) -> Option<i64> {
+        self.peers.lock().unwrap().get(peer).and_then(|health| health.clock_offset_ms).map(|offset| offset.round() as i64)
+    }
+
+    // `peer`'s clock was `offset_ms` ahead of ours when its last heartbeat was answered
+    fn record_clock_offset(&self, peer: &str, offset_ms: f64) {
+        let mut peers = self.peers.lock().unwrap();
+        let health = peers.entry(peer.to_string()).or_default();
+        health.clock_offset_ms = Some(health.clock_offset_ms.map_or(offset_ms, |average| average + SMOOTHING * (offset_ms - average)));
+    }
+
+    // A heartbeat to `peer` answered after `rtt`, or not at all with None
+    pub(crate) fn record_rtt(&self, peer: &str, now: Instant, rtt: Option<Duration>) {
+        let mut peers = self.peers.lock().unwrap();
+        let health = peers.entry(peer.to_string()).or_default();
+        health.reachable = rtt.is_some();
+        match rtt {
+            None => self.strike(peer, health, now),
+            Some(_) => health.strikes = 0,
+        }
+        if let Some(rtt) = rtt {
+            let rtt_ms = rtt.as_secs_f64() * 1000.0;
+            health.rtt_ms = Some(health.rtt_ms.map_or(rtt_ms, |average| average + SMOOTHING * (rtt_ms - average)));
+        }
+    }
+
+    // `peers` in the order reads should try them: healthy ones by round trip (unmeasured
+    // last), then unreachable ones and those with an open circuit
+    pub(crate) fn read_order(&self, mut peers: Vec<String>, now: Instant) -> Vec<String> {
+        let health = self.peers.lock().unwrap();
+        let rank = |peer: &String| match health.get(peer) {
+            Some(h) if !h.reachable || h.open_until.is_some_and(|until| now < until) => (1, f64::INFINITY),
+            Some(h) => (0, h.rtt_ms.unwrap_or(f64::INFINITY)),
+            None => (0, f64::INFINITY),
+        };
+        peers.sort_by(|a, b| rank(a).partial_cmp(&rank(b)).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.cmp(b)));
+        peers
+    }
+
+    // One `<peer> rtt_ms=.. reachable=.. circuit=..` line per peer, in read order
+    pub(crate) fn status(&self, peers: Vec<String>, now: Instant) -> Vec<String> {
+        let ordered = self.read_order(peers, now);
+        let health = self.peers.lock().unwrap();
+        ordered
+            .into_iter()
+            .map(|peer| {
+                let (rtt, reachable, circuit, offset) = match health.get(&peer) {
+                    Some(h) => (
+                        h.rtt_ms.map_or("-".to_string(), |rtt| format!("{:.2}", rtt)),
+                        h.reachable,
+                        circuit_state(h, now),
+                        h.clock_offset_ms.map_or("-".to_string(), |offset| (offset.round() as i64).to_string()),
+                    ),
+                    None => ("-".to_string(), false, "closed", "-".to_string()),
+                };
+                format!("{} rtt_ms={} reachable={} circuit={} clock_offset_ms={}", peer, rtt, reachable, circuit, offset)
+            })
+            .collect()
+    }
+
+    fn open(&self, peer: &str, health: &mut Health, breaker: &CircuitBreaker, now: Instant, why: &str) {
+        warn!(
+            "Opened the replication circuit for {} ({}: error rate {:.2}, latency {:.1}ms), skipping it for {:?}",
+            peer, why, health.error_rate, health.latency_ms, breaker.cooldown
+        );
+        health.open_until = Some(now + breaker.cooldown);
+    }
+
+    // One `<peer> state=.. sent=.. failed=.. error_rate=.. latency_ms=..` line per peer, sorted
+    pub(crate) fn report(&self, now: Instant) -> Vec<String> {
+        let peers = self.peers.lock().unwrap();
+        let mut lines: Vec<String> = peers
+            .iter()
+            .map(|(peer, health)| {
+                format!(
+                    "{} state={} sent={} failed={} error_rate={:.2} latency_ms={:.1}",
+                    peer,
+                    circuit_state(health, now),
+                    health.sent,
+                    health.failed,
+                    health.error_rate,
+                    health.latency_ms
+                )
+            })
+            .collect();
+        lines.sort();
+        lines
+    }
+}
+
+fn circuit_state(health: &Health, now: Instant) -> &'static str {
+    match health.open_until {
+        Some(until) if now < until => "open",
+        Some(_) => "half-open",
+        None => "closed",
+    }
+}
+
+// PING every peer each HEARTBEAT_INTERVAL and record the round trips
+pub async fn heartbeat_peers(peers: PeerList, context: SharedContext) {
+    loop {
+        tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+        let peers_snapshot = peers.lock().await.clone();
+        let pings = peers_snapshot.into_iter().map(|peer| {
+            let context = Arc::clone(&context);
+            async move {
+                let started = context.clock.now();
+                let sent_at = context.clock.unix_millis();
+                let ping = async {
+                    let mut stream = context.transport.connect(peer.clone()).await?;
+                    stream.write_all(format!("{}\n", context.membership.stamp("PING TIME")).as_bytes()).await?;
+                    let mut response = String::new();
+                    stream.read_to_string(&mut response).await?;
+                    Ok::<_, std::io::Error>(response)
+                };
+                let rtt = match timeout(HEARTBEAT_TIMEOUT, ping).await {
+                    // Nodes from before PING TIME answer a plain PONG
+                    Ok(Ok(response)) if response == "PONG" => Some(context.clock.now() - started),
+                    Ok(Ok(response)) if response.starts_with("PONG ") => {
+                        let rtt = context.clock.now() - started;
+                        if let Ok(peer_time) = response["PONG ".len()..].parse::<u64>() {
+                            let offset = peer_time as f64 - (sent_at as f64 + rtt.as_secs_f64() * 500.0);
+                            context.health.record_clock_offset(&peer, offset);
+                        }
+                        Some(rtt)
+                    }
+                    Ok(Ok(response)) => {
+                        debug!("Unexpected heartbeat answer from {}: {}", peer, response);
+                        None
+                    }
+                    Ok(Err(e)) => {
+                        debug!("Heartbeat to {} failed: {}", peer, e);
+                        None
+                    }
+                    Err(_) => {
+                        debug!("Heartbeat to {} timed out", peer);
+                        None
+                    }
+                };
+                context.health.record_rtt(&peer, context.clock.now(), rtt);
+            }
+        });
+        join_all(pings).await;
+
+        let now = context.clock.now();
+        peers.lock().await.retain(|peer| {
+            let banned = context.health.banned(peer, now);
+            if banned {
+                info!("Removed banned peer {} from the peer list", peer);
+            }
+            !banned
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "127.0.0.1:9000";
+    const MS: Duration = Duration::from_millis(1);
+
+    fn breaker() -> PeerHealth {
+        PeerHealth::new(Some(CircuitBreaker::default()), None)
+    }
+
+    #[test]
+    fn parses_circuit_breaker_settings() {
+        let breaker = CircuitBreaker::parse("failures=5,error_rate=0.3,latency_ms=500,cooldown_ms=5000").unwrap();
+        assert_eq!((breaker.failures, breaker.error_rate), (5, 0.3));
+        assert_eq!((breaker.latency, breaker.cooldown), (Duration::from_millis(500), Duration::from_secs(5)));
+        assert_eq!(CircuitBreaker::parse("on").unwrap().failures, 3);
+        assert!(CircuitBreaker::parse("error_rate=2").is_err());
+        assert!(CircuitBreaker::parse("failures=0").is_err());
+        assert!(CircuitBreaker::parse("retries=1").is_err());
+    }
+
+    #[test]
+    fn failing_peers_are_skipped_until_a_probe_answers() {
+        let health = breaker();
+        let now = Instant::now();
+        health.record(PEER, now, None);
+        health.record(PEER, now, None);
+        assert!(health.allow(PEER, now));
+        health.record(PEER, now, None);
+        assert!(!health.allow(PEER, now));
+        assert_eq!(health.report(now), [format!("{} state=open sent=3 failed=3 error_rate=0.49 latency_ms=0.0", PEER)]);
+
+        // After the cooldown one send goes through as the probe, and a failed probe reopens the circuit
+        let later = now + CircuitBreaker::default().cooldown;
+        assert!(health.allow(PEER, later));
+        assert!(!health.allow(PEER, later));
+        health.record(PEER, later, None);
+        assert!(!health.allow(PEER, later + 9_999 * MS));
+
+        let later = later + CircuitBreaker::default().cooldown;
+        assert!(health.allow(PEER, later));
+        health.record(PEER, later, Some(5 * MS));
+        assert!(health.allow(PEER, later));
+        assert_eq!(health.report(later), [format!("{} state=closed sent=5 failed=4 error_rate=0.00 latency_ms=5.0", PEER)]);
+    }
+
+    #[test]
+    fn slow_peers_open_the_circuit_once_there_are_enough_samples() {
+        let health = breaker();
+        let now = Instant::now();
+        for _ in 0..MIN_SAMPLES - 1 {
+            health.record(PEER, now, Some(Duration::from_secs(5)));
+        }
+        assert!(health.allow(PEER, now));
+        health.record(PEER, now, Some(Duration::from_secs(5)));
+        assert!(!health.allow(PEER, now));
+
+        // Without a breaker health is only tracked
+        let health = PeerHealth::new(None, None);
+        for _ in 0..MIN_SAMPLES {
+            health.record(PEER, now, None);
+        }
+        assert!(health.allow(PEER, now));
+    }
+
+    #[test]
+    fn reads_try_reachable_peers_fastest_first() {
+        let health = breaker();
+        let now = Instant::now();
+        let (fast, slow, down, open, new) = ("a:1", "b:1", "c:1", "d:1", "e:1");
+        health.record_rtt(slow, now, Some(20 * MS));
+        health.record_rtt(fast, now, Some(2 * MS));
+        health.record_rtt(down, now, None);
+        health.record_rtt(open, now, Some(MS));
+        for _ in 0..3 {
+            health.record(open, now, None);
+        }
+        let peers = [open, new, down, slow, fast].map(String::from).to_vec();
+        assert_eq!(health.read_order(peers, now), [fast, slow, new, down, open]);
+    }
+
+    #[test]
+    fn peers_that_keep_failing_are_banned_for_longer_each_time() {
+        let bans = PeerBans::parse("failures=2,ban_ms=1000,max_ban_ms=3000,decay_ms=10000").unwrap();
+        let health = PeerHealth::new(None, Some(bans));
+        let mut now = Instant::now();
+        // Sends, heartbeats and liveness checks all count, and a success starts the count over
+        health.record(PEER, now, None);
+        health.record_rtt(PEER, now, Some(MS));
+        health.record_check_failed(PEER, now);
+        assert!(!health.banned(PEER, now));
+        health.record_rtt(PEER, now, None);
+        assert!(health.banned(PEER, now));
+        assert_eq!(health.bans(now), [format!("{} level=1 banned_ms=1000", PEER)]);
+
+        // Failures while banned don't count towards the next ban
+        health.record_check_failed(PEER, now);
+        health.record_check_failed(PEER, now);
+        now += Duration::from_secs(1);
+        assert!(!health.banned(PEER, now));
+        for expected in ["level=2 banned_ms=2000", "level=3 banned_ms=3000", "level=4 banned_ms=3000"] {
+            health.record_check_failed(PEER, now);
+            health.record_check_failed(PEER, now);
+            assert_eq!(health.bans(now), [format!("{} {}", PEER, expected)]);
+            now += Duration::from_secs(3);
+        }
+
+        // Each decay without a ban takes a doubling off the next one
+        now += Duration::from_secs(20);
+        health.record_check_failed(PEER, now);
+        health.record_check_failed(PEER, now);
+        assert_eq!(health.bans(now), [format!("{} level=3 banned_ms=3000", PEER)]);
+        health.unban(PEER);
+        assert!(!health.banned(PEER, now));
+        assert!(health.bans(now).is_empty());
+    }
+}
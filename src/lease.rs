@@ -0,0 +1,91 @@
+//! MAX-AGE lease hints for client caches
+
+use std::time::Duration;
+
+use crate::node::NodeContext;
+
+// How long GET ... LEASE lets values be reused
+#[derive(Clone, Debug)]
+pub struct Leases {
+    // Longest lease handed out
+    pub max: Duration,
+    // Share of the time a key has gone unchanged it is leased for
+    pub fraction: f64,
+}
+
+impl Default for Leases {
+    fn default() -> Self {
+        Leases { max: Duration::from_secs(10), fraction: 0.1 }
+    }
+}
+
+impl Leases {
+    // "on" for the defaults, or e.g. "max_ms=30000,fraction=0.2"
+    pub fn parse(spec: &str) -> Result<Leases, String> {
+        let mut leases = Leases::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty() && *s != "on") {
+            let (name, value) = setting.split_once('=').ok_or_else(|| format!("Invalid lease setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "max_ms" => leases.max = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+                "fraction" => leases.fraction = value.parse().ok().filter(|f| (0.0..=1.0).contains(f)).ok_or_else(invalid)?,
+                _ => return Err(format!("Unknown lease setting: {}", name)),
+            }
+        }
+        Ok(leases)
+    }
+}
+
+// How long a value last changed at `modified` and expiring in `ttl` ms may be reused; zero
+// unless NodeBuilder::leases was set
+pub(crate) fn max_age(modified: Option<u64>, ttl: Option<u64>, context: &NodeContext) -> Duration {
+    let Some(leases) = &context.leases else {
+        return Duration::ZERO;
+    };
+    if context.partitions.any() {
+        return Duration::ZERO;
+    }
+    let Some(modified) = modified else {
+        return Duration::ZERO;
+    };
+    let unchanged = Duration::from_millis(context.clock.unix_millis().saturating_sub(modified));
+    let lease = unchanged.mul_f64(leases.fraction).min(leases.max);
+    // Not past the value's expiry
+    ttl.map_or(lease, |ttl| lease.min(Duration::from_millis(ttl)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use crate::sim::{SimClock, SimNetwork};
+
+    const EPOCH: u64 = 1_735_689_600_000;
+
+    #[test]
+    fn parses_lease_settings() {
+        let leases = Leases::parse("max_ms=30000,fraction=0.2").unwrap();
+        assert_eq!((leases.max, leases.fraction), (Duration::from_secs(30), 0.2));
+        assert_eq!(Leases::parse("on").unwrap().max, Duration::from_secs(10));
+        assert_eq!(Leases::parse("fraction=2").unwrap_err(), "Invalid value for fraction: 2");
+        assert_eq!(Leases::parse("cap=1").unwrap_err(), "Unknown lease setting: cap");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn values_are_leased_for_a_share_of_the_time_they_went_unchanged() {
+        let mut context = NodeContext::new(1, Arc::new(SimNetwork::new()), Arc::new(SimClock::new(EPOCH)));
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert_eq!(max_age(Some(EPOCH), None, &context), Duration::ZERO);
+
+        context.leases = Some(Leases::default());
+        assert_eq!(max_age(Some(EPOCH), None, &context), Duration::from_secs(2));
+        assert_eq!(max_age(Some(EPOCH), Some(500), &context), Duration::from_millis(500));
+        assert_eq!(max_age(None, None, &context), Duration::ZERO);
+        tokio::time::advance(Duration::from_secs(200)).await;
+        assert_eq!(max_age(Some(EPOCH), None, &context), Duration::from_secs(10));
+
+        // Nothing is leased while a peer may be holding writes this node hasn't seen
+        context.partitions.lost("127.0.0.1:2", EPOCH);
+        assert_eq!(max_age(Some(EPOCH), None, &context), Duration::ZERO);
+    }
+}
@@ -0,0 +1,44 @@
+//! Peer-to-peer replicated key/value cache.
+//!
+//! A node keeps an in-memory [`storage::Cache`], discovers peers over UDP
+//! broadcast, serves the line-based TCP protocol and replicates writes to
+//! every known peer. [`NodeBuilder`] creates a [`Node`] that runs inside an
+//! existing tokio runtime; [`node::run`] is what the binary uses. [`Client`]
+//! talks to a running node and [`cli`] is the interactive shell built on it.
+
+pub mod cli;
+pub mod client;
+pub mod clock;
+pub mod cluster;
+pub mod discovery;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+pub mod gossip;
+pub mod health;
+pub mod lease;
+pub mod membership;
+pub mod metrics;
+pub mod node;
+pub mod outbox;
+pub mod partition;
+pub mod protocol;
+pub mod replication;
+pub mod sequence;
+pub mod session;
+#[cfg(any(test, feature = "test-support"))]
+pub mod sim;
+pub mod storage;
+pub mod stun;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+pub mod transfer;
+pub mod transport;
+
+pub use client::{Client, ClientBuilder, ClientError};
+pub use discovery::{Discovery, PeerList};
+pub use health::{CircuitBreaker, PeerBans};
+pub use lease::Leases;
+pub use node::{Node, NodeBuilder, NodeContext, RemoteReads, SharedContext};
+pub use outbox::Outbox;
+pub use storage::persistence::Persistence;
+pub use storage::{Cache, CacheValue, SharedCache};
@@ -0,0 +1,137 @@
+//! Cluster configuration epoch.
+//!
+//! Every change to a node's peer list moves its epoch past the highest one it has seen, and
+//! replicated messages and heartbeats carry it as `EPOCH <n> <message>`. A node receiving a
+//! higher epoch than its own has missed a membership change: before applying the message it
+//! refreshes its view by merging in its peers' lists (PEERS), then takes the epoch on. A
+//! heartbeat only schedules that refresh, so answering it is never held up.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+use tokio::time::Duration;
+use log::{debug, info};
+
+use crate::cluster::query_peers;
+use crate::discovery::{admits, PeerList};
+use crate::node::{NodeContext, SharedContext};
+
+// How often the peer list is checked for changes that move the epoch on
+const MEMBERSHIP_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub(crate) struct Membership {
+    epoch: AtomicU64,
+    // Highest epoch a heartbeat carried, refreshed to by track_membership
+    heard: AtomicU64,
+    heard_newer: Notify,
+    // Serializes refreshes, so a burst of messages with a new epoch triggers only one
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+impl Membership {
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    // `message` with the current epoch attached
+    pub(crate) fn stamp(&self, message: &str) -> String {
+        format!("EPOCH {} {}", self.epoch(), message)
+    }
+
+    // A peer sent `epoch`; refresh the peer list first if it is newer than ours
+    pub(crate) async fn observe(&self, epoch: u64, peers: &PeerList, context: &NodeContext) {
+        if epoch <= self.epoch() {
+            return;
+        }
+        let _refreshing = self.refreshing.lock().await;
+        // Another message with this epoch already refreshed the view
+        if epoch <= self.epoch() {
+            return;
+        }
+        info!("Cluster epoch {} is ahead of ours ({}), refreshing the peer list", epoch, self.epoch());
+        refresh_peers(peers, context).await;
+        self.epoch.fetch_max(epoch, Ordering::SeqCst);
+    }
+
+    // A heartbeat carried `epoch`; refresh in the background if it is newer than ours
+    pub(crate) fn heard(&self, epoch: u64) {
+        if epoch > self.epoch() && self.heard.fetch_max(epoch, Ordering::SeqCst) < epoch {
+            self.heard_newer.notify_one();
+        }
+    }
+
+    // This node's peer list changed
+    fn changed(&self) -> u64 {
+        self.epoch.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+// Add the peers our peers know about and we don't
+async fn refresh_peers(peers: &PeerList, context: &NodeContext) {
+    let mut known = HashSet::new();
+    for (peer, response) in query_peers(&context.transport, peers, "PEERS").await {
+        match response {
+            Ok(response) => known.extend(response.lines().filter(|line| !line.is_empty()).map(str::to_string)),
+            Err(e) => debug!("Could not ask {} for its peers: {}", peer, e),
+        }
+    }
+    known.remove(context.sequences.origin());
+    let mut peers = peers.lock().await;
+    for peer in known.into_iter().filter(|peer| admits(context, peer)) {
+        if peers.insert(peer.clone()) {
+            info!("Added peer {} from a peer's view of the cluster", peer);
+        }
+    }
+}
+
+// Move the epoch on whenever the peer list changes, whatever changed it, and catch up with
+// newer epochs heartbeats brought
+pub async fn track_membership(peers: PeerList, context: SharedContext) {
+    let membership = &context.membership;
+    let mut last = peers.lock().await.clone();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(MEMBERSHIP_POLL) => {}
+            _ = membership.heard_newer.notified() => {
+                membership.observe(membership.heard.load(Ordering::SeqCst), &peers, &context).await;
+            }
+        }
+        let current = peers.lock().await.clone();
+        if current != last {
+            let epoch = membership.changed();
+            debug!("Peer list changed ({} peers), cluster epoch now {}", current.len(), epoch);
+            last = current;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::testing::TestCluster;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_newer_epoch_merges_in_the_peers_peers_first() {
+        let cluster = TestCluster::start_simulated(3).await.unwrap();
+        let peers = cluster.node(0).peers();
+        peers.lock().await.remove(&cluster.addr(2));
+        let context = NodeContext::new(cluster.node(0).port(), cluster.node(0).transport(), Arc::new(SystemClock));
+        let membership = Membership::default();
+
+        membership.observe(0, &peers, &context).await;
+        assert!(!peers.lock().await.contains(&cluster.addr(2)));
+        membership.observe(3, &peers, &context).await;
+        assert!(peers.lock().await.contains(&cluster.addr(2)));
+        assert!(!peers.lock().await.contains(&cluster.addr(0)));
+        assert_eq!(membership.stamp("PING"), "EPOCH 3 PING");
+
+        // An older epoch changes nothing, and a change here moves past the highest seen
+        membership.observe(2, &peers, &context).await;
+        assert_eq!(membership.epoch(), 3);
+        assert_eq!(membership.changed(), 4);
+        cluster.shutdown().await;
+    }
+}
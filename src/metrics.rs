@@ -0,0 +1,116 @@
+//! Per-operation latency histograms and their Prometheus export
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use hdrhistogram::Histogram;
+
+// Bucket bounds exported, in seconds, besides +Inf
+const BUCKETS: [f64; 16] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+struct OpLatency {
+    // Microseconds up to an hour, to 3 significant figures, as the client's benchmark records them
+    histogram: Histogram<u64>,
+    // Exact, where the histogram's values are rounded
+    total: Duration,
+}
+
+#[derive(Default)]
+pub struct Latencies {
+    ops: Mutex<BTreeMap<&'static str, OpLatency>>,
+}
+
+impl Latencies {
+    pub(crate) fn record(&self, op: &'static str, elapsed: Duration) {
+        let mut ops = self.ops.lock().unwrap();
+        let latency = ops.entry(op).or_insert_with(|| OpLatency {
+            histogram: Histogram::new_with_bounds(1, 3_600_000_000, 3).unwrap(),
+            total: Duration::ZERO,
+        });
+        latency.histogram.saturating_record(elapsed.as_micros() as u64);
+        latency.total += elapsed;
+    }
+
+    // Run `future`, recording how long it took under `op`
+    pub(crate) async fn time<F: Future>(&self, op: &'static str, future: F) -> F::Output {
+        let started = Instant::now();
+        let output = future.await;
+        self.record(op, started.elapsed());
+        output
+    }
+
+    // METRICS' answer, in the Prometheus text exposition format
+    pub(crate) fn prometheus(&self) -> String {
+        let mut lines = vec![
+            "# HELP p2p_op_duration_seconds Time taken per operation.".to_string(),
+            "# TYPE p2p_op_duration_seconds histogram".to_string(),
+        ];
+        for (op, latency) in self.ops.lock().unwrap().iter() {
+            let histogram = &latency.histogram;
+            for bound in BUCKETS {
+                let count = histogram.count_between(0, (bound * 1_000_000.0) as u64);
+                lines.push(format!("p2p_op_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}", op, bound, count));
+            }
+            lines.push(format!("p2p_op_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}", op, histogram.len()));
+            lines.push(format!("p2p_op_duration_seconds_sum{{op=\"{}\"}} {}", op, latency.total.as_secs_f64()));
+            lines.push(format!("p2p_op_duration_seconds_count{{op=\"{}\"}} {}", op, histogram.len()));
+        }
+        lines.join("\n") + "\n"
+    }
+}
+
+// `GET /metrics` over HTTP, answered with METRICS' text
+pub(crate) fn http_response(metrics: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        metrics.len(),
+        metrics
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCluster;
+
+    #[test]
+    fn exports_cumulative_buckets_per_operation() {
+        let latencies = Latencies::default();
+        latencies.record("GET", Duration::from_micros(80));
+        latencies.record("GET", Duration::from_millis(3));
+        latencies.record("SET", Duration::from_secs(20));
+        let metrics = latencies.prometheus();
+        let lines: Vec<&str> = metrics.lines().collect();
+        for line in [
+            "p2p_op_duration_seconds_bucket{op=\"GET\",le=\"0.0001\"} 1",
+            "p2p_op_duration_seconds_bucket{op=\"GET\",le=\"0.0025\"} 1",
+            "p2p_op_duration_seconds_bucket{op=\"GET\",le=\"0.005\"} 2",
+            "p2p_op_duration_seconds_bucket{op=\"GET\",le=\"+Inf\"} 2",
+            "p2p_op_duration_seconds_sum{op=\"GET\"} 0.00308",
+            "p2p_op_duration_seconds_count{op=\"GET\"} 2",
+            "p2p_op_duration_seconds_bucket{op=\"SET\",le=\"10\"} 0",
+            "p2p_op_duration_seconds_bucket{op=\"SET\",le=\"+Inf\"} 1",
+        ] {
+            assert!(lines.contains(&line), "{} not in\n{}", line, metrics);
+        }
+        assert_eq!(lines.len(), 2 + 2 * (BUCKETS.len() + 3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nodes_answer_metrics_over_the_protocol_and_http() {
+        let cluster = TestCluster::start_simulated(1).await.unwrap();
+        cluster.request(0, "SET a=1").await.unwrap();
+        let metrics = cluster.request(0, "METRICS").await.unwrap();
+        assert!(metrics.contains("p2p_op_duration_seconds_count{op=\"SET\"} 1"), "{}", metrics);
+
+        let http = cluster.request(0, "GET /metrics HTTP/1.1\r\nHost: node\r\n\r\n").await.unwrap();
+        let (head, body) = http.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}", body.len())), "{}", head);
+        assert!(body.contains("p2p_op_duration_seconds_count{op=\"METRICS\"} 1"), "{}", body);
+        cluster.shutdown().await;
+    }
+}
@@ -0,0 +1,767 @@
+//! Node wiring: snapshot restore, background tasks and the TCP listener
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::task::{JoinHandle, JoinSet};
+use log::{debug, error, info, trace};
+
+use crate::clock::{SharedClock, SystemClock};
+use crate::discovery::{
+    announce_self, cloud_discovery, discovery_service, dns_discovery, expire_peers, Announcement, CloudDiscovery, Discovery, ManualPeers, PeerList,
+    CAPABILITIES, DISCOVERY_VERSION,
+};
+use crate::gossip::{gossip_with_peers, Gossip};
+use crate::health::{heartbeat_peers, CircuitBreaker, PeerBans, PeerHealth};
+use crate::lease::Leases;
+use crate::membership::{track_membership, Membership};
+use crate::metrics::Latencies;
+use crate::partition::Partitions;
+use crate::outbox::{Outbox, Outboxes};
+use crate::protocol::handle_connection;
+use crate::sequence::Sequences;
+use crate::replication::{catch_up_peers, replicate_set, replication_worker, MissedWrites, PeerLanes, ReplicationBatcher, ReplicationQueue, Replicator};
+use crate::storage::persistence::{
+    compact_periodically, load_cache_from_arrow, save_cache_incrementally, save_cache_periodically, write_cache_to_arrow, AppendLog,
+    CompactionSchedule, FsyncPolicy, GroupCommit, Persistence,
+};
+use crate::storage::archive::LogArchive;
+use crate::storage::{expire_periodically, Cache, CacheValue, SharedCache};
+use crate::transfer::{warm_up, WarmUp};
+use crate::transport::{Listener, SharedTransport, TcpTransport};
+
+// Ask peers for keys a GET misses locally, e.g. before replication has caught up
+#[derive(Clone, Debug)]
+pub struct RemoteReads {
+    // Peers queried at once
+    pub concurrency: usize,
+    // Time allowed for each peer to answer
+    pub timeout: Duration,
+}
+
+impl Default for RemoteReads {
+    fn default() -> Self {
+        RemoteReads {
+            concurrency: 4,
+            timeout: Duration::from_millis(200),
+        }
+    }
+}
+
+impl RemoteReads {
+    // "on" for the defaults, or e.g. "concurrency=8,timeout_ms=100"
+    pub fn parse(spec: &str) -> Result<RemoteReads, String> {
+        let mut remote_reads = RemoteReads::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty() && *s != "on") {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid remote read setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "concurrency" => remote_reads.concurrency = value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?,
+                "timeout_ms" => remote_reads.timeout = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+                _ => return Err(format!("Unknown remote read setting: {}", name)),
+            }
+        }
+        Ok(remote_reads)
+    }
+}
+
+// Node-wide settings and admin state shared by connection handlers
+pub struct NodeContext {
+    pub(crate) node_port: u16,
+    // Random per run, as announced
+    pub(crate) node_id: String,
+    pub(crate) pending_flush: Mutex<Option<(String, tokio::time::Instant)>>,
+    pub(crate) transport: SharedTransport,
+    pub(crate) clock: SharedClock,
+    // Off unless NodeBuilder::remote_reads was set
+    pub(crate) remote_reads: Option<RemoteReads>,
+    // GET ... LEASE answers MAX-AGE 0 unless NodeBuilder::leases was set
+    pub(crate) leases: Option<Leases>,
+    // Writes are sent to peers one by one unless NodeBuilder::replication_batch was set
+    pub(crate) batcher: Option<Arc<ReplicationBatcher>>,
+    // Unbatched writes go straight to the peers' lanes unless NodeBuilder::replication_queue was set
+    pub(crate) replicator: Option<Replicator>,
+    // Per-peer queues that send unbatched and SYNC writes in order
+    pub(crate) lanes: PeerLanes,
+    pub(crate) missed: Arc<MissedWrites>,
+    pub(crate) sequences: Arc<Sequences>,
+    pub(crate) health: Arc<PeerHealth>,
+    // Cluster configuration epoch, stamped on replicated messages and heartbeats
+    pub(crate) membership: Arc<Membership>,
+    // Peers split off from this node, and what reconciling after a split found
+    pub(crate) partitions: Arc<Partitions>,
+    // ADDPEER/REMOVEPEER changes to the peer list, which discovery leaves alone
+    pub(crate) manual_peers: Arc<ManualPeers>,
+    // Set with Persistence::ArrowLog, for COMPACT and so writes can wait for their segment
+    pub(crate) append_log: Option<Arc<Mutex<AppendLog>>>,
+    pub(crate) log_commits: Option<Arc<GroupCommit>>,
+    // Per-operation latency histograms for METRICS
+    pub(crate) latencies: Arc<Latencies>,
+}
+
+impl NodeContext {
+    pub fn new(node_port: u16, transport: SharedTransport, clock: SharedClock) -> Self {
+        let boot = clock.unix_millis();
+        NodeContext {
+            node_port,
+            node_id: format!("{:016x}", rand::random::<u64>()),
+            pending_flush: Mutex::new(None),
+            transport,
+            clock,
+            remote_reads: None,
+            leases: None,
+            batcher: None,
+            replicator: None,
+            lanes: PeerLanes::default(),
+            missed: Arc::default(),
+            sequences: Arc::new(Sequences::new(format!("127.0.0.1:{}", node_port), boot)),
+            health: Arc::default(),
+            membership: Arc::default(),
+            partitions: Arc::default(),
+            manual_peers: Arc::default(),
+            append_log: None,
+            log_commits: None,
+            latencies: Arc::default(),
+        }
+    }
+}
+
+pub type SharedContext = Arc<NodeContext>;
+
+// Configures and creates an embeddable Node
+pub struct NodeBuilder {
+    port: u16,
+    discovery: Discovery,
+    persistence: Option<Persistence>,
+    transport: SharedTransport,
+    clock: SharedClock,
+    remote_reads: Option<RemoteReads>,
+    leases: Option<Leases>,
+    key_history: Option<usize>,
+    replication_batch: Option<Duration>,
+    replication_queue: Option<ReplicationQueue>,
+    outbox: Option<Outbox>,
+    circuit_breaker: Option<CircuitBreaker>,
+    peer_bans: Option<PeerBans>,
+    log_fsync: FsyncPolicy,
+    log_archive: Option<LogArchive>,
+    recover_to: Option<u64>,
+    compaction: Option<CompactionSchedule>,
+    warm_up: Option<WarmUp>,
+    advertise: Option<String>,
+    gossip: Option<Gossip>,
+}
+
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NodeBuilder {
+    pub fn new() -> Self {
+        NodeBuilder {
+            port: 8080,
+            discovery: Discovery::Broadcast,
+            persistence: None,
+            transport: Arc::new(TcpTransport),
+            clock: Arc::new(SystemClock),
+            remote_reads: None,
+            leases: None,
+            key_history: None,
+            replication_batch: None,
+            replication_queue: None,
+            outbox: None,
+            circuit_breaker: None,
+            peer_bans: None,
+            log_fsync: FsyncPolicy::default(),
+            log_archive: None,
+            recover_to: None,
+            compaction: None,
+            warm_up: None,
+            advertise: None,
+            gossip: None,
+        }
+    }
+
+    // TCP port to serve on; 0 picks a free port (see Node::port)
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    // Defaults to an Arrow snapshot at node_<port>_cache.arrow
+    pub fn persistence(mut self, persistence: Persistence) -> Self {
+        self.persistence = Some(persistence);
+        self
+    }
+
+    // Network used for the listener and for replication, e.g. sim::SimNetwork in tests
+    pub fn transport(mut self, transport: SharedTransport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Look keys a GET misses up on peers and cache the first hit
+    pub fn remote_reads(mut self, remote_reads: RemoteReads) -> Self {
+        self.remote_reads = Some(remote_reads);
+        self
+    }
+
+    // Answer GET ... LEASE with how long the value may be reused without asking again
+    pub fn leases(mut self, leases: Leases) -> Self {
+        self.leases = Some(leases);
+        self
+    }
+
+    // Keep the last `versions` changes of every key (value, time, origin and writer) for GETHIST
+    pub fn key_history(mut self, versions: usize) -> Self {
+        self.key_history = Some(versions).filter(|versions| *versions > 0);
+        self
+    }
+
+    // Buffer writes for `window` and send each peer one batch, keeping only the last value
+    // written to a key, instead of a connection per write
+    pub fn replication_batch(mut self, window: Duration) -> Self {
+        self.replication_batch = Some(window);
+        self
+    }
+
+    // Send unbatched writes from a bounded queue with a fixed set of workers, so a slow cluster
+    // pushes back on clients (or sheds their writes) instead of piling up tasks
+    pub fn replication_queue(mut self, queue: ReplicationQueue) -> Self {
+        self.replication_queue = Some(queue);
+        self
+    }
+
+    // Keep messages peers could not be sent on disk and replay them when the peer is back
+    pub fn outbox(mut self, outbox: Outbox) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    // Skip peers that keep failing or answer slowly for a cooldown, instead of trying each one on
+    // every write; their health is tracked either way (PEERS HEALTH)
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    // Take peers whose sends, heartbeats and liveness checks keep failing out of the peer list
+    // for a while, longer each time, so nothing waits on their connect timeouts
+    pub fn peer_bans(mut self, bans: PeerBans) -> Self {
+        self.peer_bans = Some(bans);
+        self
+    }
+
+    // When Persistence::ArrowLog writes and fsyncs its segments; FsyncPolicy::Always answers
+    // client writes only once they are on disk
+    pub fn log_fsync(mut self, policy: FsyncPolicy) -> Self {
+        self.log_fsync = policy;
+        self
+    }
+
+    // Keep the segments and snapshots compaction replaces, for recover_to
+    pub fn log_archive(mut self, archive: LogArchive) -> Self {
+        self.log_archive = Some(archive);
+        self
+    }
+
+    // Start from the cache as it was at `unix_millis`, rebuilt from the append log's snapshots
+    // and segments, instead of its latest state; needs Persistence::ArrowLog
+    pub fn recover_to(mut self, unix_millis: u64) -> Self {
+        self.recover_to = Some(unix_millis);
+        self
+    }
+
+    // Also compact the append log on a schedule, rather than only once it outgrows the cache
+    pub fn compaction(mut self, schedule: CompactionSchedule) -> Self {
+        self.compaction = Some(schedule);
+        self
+    }
+
+    // Address peers reach this node at, as announced and in replicated messages; defaults to
+    // 127.0.0.1:<port>. See stun::public_address for nodes behind NAT.
+    pub fn advertise(mut self, addr: impl Into<String>) -> Self {
+        self.advertise = Some(addr.into());
+        self
+    }
+
+    // Compare digests with a random peer every few seconds and pull the keys that differ
+    pub fn gossip(mut self, gossip: Gossip) -> Self {
+        self.gossip = Some(gossip);
+        self
+    }
+
+    // Pull the cache from a peer on start, before listening or announcing this node
+    pub fn warm_up(mut self, warm_up: WarmUp) -> Self {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    // Bind the listening port and restore the snapshot, without starting any tasks
+    pub fn build(self) -> std::io::Result<Node> {
+        let listener = self.transport.bind(self.port)?;
+        let node_port = listener.local_port();
+
+        let persistence = self
+            .persistence
+            .unwrap_or_else(|| Persistence::Arrow(format!("node_{}_cache.arrow", node_port).into()));
+
+        // Shared cache (restored from the last snapshot, if any) and peer list
+        let mut initial_cache = match &persistence {
+            Persistence::Arrow(path) if path.exists() => match load_cache_from_arrow(&path.to_string_lossy()) {
+                Ok(cache) => {
+                    info!("Restored {} keys from {}", cache.len(), path.display());
+                    cache
+                }
+                Err(e) => {
+                    error!("Failed to restore cache from {}: {}", path.display(), e);
+                    Cache::new()
+                }
+            },
+            _ => Cache::new(),
+        };
+
+        let mut log_commits = None;
+        let append_log = match &persistence {
+            Persistence::ArrowLog(path) => {
+                let mut log = AppendLog::open(path, self.log_fsync.clone(), Arc::clone(&self.clock), self.log_archive.clone())
+                    .map_err(std::io::Error::other)?;
+                initial_cache = match self.recover_to {
+                    // Refusing to start beats starting from the latest state by mistake
+                    Some(target) => log.recover(target).map_err(|e| std::io::Error::other(format!("Recovery to {} failed: {}", target, e)))?,
+                    None => match log.load() {
+                        Ok(cache) => {
+                            info!("Restored {} keys from {} and its log", cache.len(), path.display());
+                            cache
+                        }
+                        Err(e) => {
+                            error!("Failed to restore cache from {}: {}", path.display(), e);
+                            Cache::new()
+                        }
+                    },
+                };
+                initial_cache.track_changes();
+                log_commits = Some(log.commits());
+                Some(Arc::new(Mutex::new(log)))
+            }
+            _ if self.recover_to.is_some() => {
+                return Err(std::io::Error::other("Point-in-time recovery needs Persistence::ArrowLog"));
+            }
+            _ => None,
+        };
+
+        initial_cache.set_clock(Arc::clone(&self.clock));
+        // Keys that expired while the node was down; the sweeper takes care of the rest
+        let expired = initial_cache.remove_expired();
+        if expired > 0 {
+            info!("Dropped {} restored keys that expired while the node was down", expired);
+        }
+        // Restored keys start without history
+        if let Some(versions) = self.key_history {
+            initial_cache.keep_history(versions);
+        }
+
+        let outbox = self.outbox.map(|outbox| Outboxes::open(&outbox, node_port)).transpose()?;
+
+        let initial_peers = match &self.discovery {
+            Discovery::Static(addrs) => addrs.iter().cloned().collect(),
+            _ => HashSet::new(),
+        };
+
+        let advertise = self.advertise.unwrap_or_else(|| format!("127.0.0.1:{}", node_port));
+
+        Ok(Node {
+            cache: Arc::new(Mutex::new(initial_cache)),
+            peers: Arc::new(Mutex::new(initial_peers)),
+            context: Arc::new(NodeContext {
+                remote_reads: self.remote_reads,
+                leases: self.leases,
+                batcher: self.replication_batch.map(ReplicationBatcher::new),
+                replicator: self.replication_queue.as_ref().map(Replicator::new),
+                missed: Arc::new(MissedWrites::new(outbox)),
+                health: Arc::new(PeerHealth::new(self.circuit_breaker, self.peer_bans)),
+                append_log: append_log.clone(),
+                log_commits,
+                sequences: Arc::new(Sequences::new(advertise, self.clock.unix_millis())),
+                ..NodeContext::new(node_port, self.transport, self.clock)
+            }),
+            discovery: self.discovery,
+            persistence,
+            compaction: self.compaction,
+            warm_up: self.warm_up,
+            gossip: self.gossip,
+            listener: std::sync::Mutex::new(Some(listener)),
+            tasks: std::sync::Mutex::new(Vec::new()),
+        })
+    }
+}
+
+// Handle to a node running inside the caller's tokio runtime
+pub struct Node {
+    cache: SharedCache,
+    peers: PeerList,
+    context: SharedContext,
+    discovery: Discovery,
+    persistence: Persistence,
+    compaction: Option<CompactionSchedule>,
+    warm_up: Option<WarmUp>,
+    gossip: Option<Gossip>,
+    listener: std::sync::Mutex<Option<Box<dyn Listener>>>,
+    tasks: std::sync::Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Node {
+    pub fn port(&self) -> u16 {
+        self.context.node_port
+    }
+
+    pub fn cache(&self) -> SharedCache {
+        Arc::clone(&self.cache)
+    }
+
+    pub fn peers(&self) -> PeerList {
+        Arc::clone(&self.peers)
+    }
+
+    pub fn transport(&self) -> SharedTransport {
+        Arc::clone(&self.context.transport)
+    }
+
+    // Spawn the listener, discovery and snapshot tasks; must be called from within a tokio runtime
+    pub fn start(&self) -> std::io::Result<()> {
+        let listener = self
+            .listener
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| std::io::Error::other("node already started"))?;
+        let node_port = self.port();
+
+        let advertise = self.context.sequences.origin().to_string();
+        let mut tasks = self.tasks.lock().unwrap();
+
+        match &self.discovery {
+            // Start the discovery service; this node announces itself once it is serving
+            Discovery::Broadcast => {
+                tasks.push(tokio::spawn(discovery_service(Arc::clone(&self.peers), Arc::clone(&self.context))));
+                tasks.push(tokio::spawn(expire_peers(Arc::clone(&self.peers), Arc::clone(&self.context))));
+            }
+            Discovery::Dns { name, port } => {
+                tasks.push(tokio::spawn(dns_discovery(Arc::clone(&self.peers), name.clone(), *port, Arc::clone(&self.context))));
+            }
+            Discovery::Cloud(cloud) => tasks.push(tokio::spawn(cloud_discovery(Arc::clone(&self.peers), cloud.clone(), Arc::clone(&self.context)))),
+            _ => {}
+        }
+
+        // Periodically print current peers
+        let peers_clone = Arc::clone(&self.peers);
+        tasks.push(tokio::spawn(async move {
+            loop {
+                let peers_snapshot = peers_clone.lock().await.clone(); // Don't hold the lock while sleeping
+                trace!("Current peers: {:?}", peers_snapshot);
+                tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+            }
+        }));
+
+        // Periodically save cache to Arrow file
+        if let Persistence::Arrow(path) = &self.persistence {
+            tasks.push(tokio::spawn(save_cache_periodically(Arc::clone(&self.cache), path.to_string_lossy().into_owned(), Arc::clone(&self.context.latencies))));
+        }
+
+        // Or append just the changes, compacting now and then
+        if let Some(log) = &self.context.append_log {
+            tasks.push(tokio::spawn(save_cache_incrementally(Arc::clone(&self.cache), Arc::clone(log), Arc::clone(&self.context.latencies))));
+            if let Some(schedule) = &self.compaction {
+                tasks.push(tokio::spawn(compact_periodically(Arc::clone(&self.cache), Arc::clone(log), schedule.clone(), Arc::clone(&self.context.latencies))));
+            }
+        }
+
+        // Remove keys past their TTL
+        tasks.push(tokio::spawn(expire_periodically(Arc::clone(&self.cache))));
+
+        // Measure round trips to peers for read routing
+        tasks.push(tokio::spawn(heartbeat_peers(Arc::clone(&self.peers), Arc::clone(&self.context))));
+
+        // Move the cluster epoch on as peers come and go
+        tasks.push(tokio::spawn(track_membership(Arc::clone(&self.peers), Arc::clone(&self.context))));
+
+        // Drain the replication queue
+        for _ in 0..self.context.replicator.as_ref().map_or(0, |replicator| replicator.workers) {
+            tasks.push(tokio::spawn(replication_worker(Arc::clone(&self.context))));
+        }
+
+        // Repair what replication and catching up missed
+        if let Some(gossip) = &self.gossip {
+            tasks.push(tokio::spawn(gossip_with_peers(
+                Arc::clone(&self.cache),
+                Arc::clone(&self.peers),
+                Arc::clone(&self.context),
+                gossip.clone(),
+            )));
+        }
+
+        // Send peers that come back the writes they missed
+        tasks.push(tokio::spawn(catch_up_peers(
+            Arc::clone(&self.cache),
+            Arc::clone(&self.peers),
+            Arc::clone(&self.context),
+        )));
+
+        // Start the TCP listener for peer-to-peer communication and announce this node, after
+        // warming the cache up if asked to
+        let broadcast = matches!(self.discovery, Discovery::Broadcast);
+        let announcement = Announcement {
+            v: DISCOVERY_VERSION,
+            node_id: self.context.node_id.clone(),
+            addr: advertise,
+            port: node_port,
+            role: "peer".to_string(),
+            epoch: self.context.sequences.boot(),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        };
+        let serve = node_listener(listener, Arc::clone(&self.peers), Arc::clone(&self.cache), Arc::clone(&self.context));
+        match self.warm_up.clone() {
+            None => {
+                if broadcast {
+                    tasks.push(tokio::spawn(announce_self(announcement)));
+                }
+                tasks.push(tokio::spawn(serve));
+            }
+            Some(settings) => {
+                let (peers, cache, context) = (Arc::clone(&self.peers), Arc::clone(&self.cache), Arc::clone(&self.context));
+                tasks.push(tokio::spawn(async move {
+                    warm_up(&settings, &peers, &cache, &context).await;
+                    if broadcast {
+                        tokio::join!(announce_self(announcement), serve);
+                    } else {
+                        serve.await;
+                    }
+                }));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Stop all node tasks and write a final snapshot
+    pub async fn shutdown(&self) {
+        let tasks: Vec<JoinHandle<()>> = self.tasks.lock().unwrap().drain(..).collect();
+        for task in tasks {
+            task.abort();
+            let _ = task.await;
+        }
+
+        if let Persistence::Arrow(path) = &self.persistence {
+            if let Err(e) = write_cache_to_arrow(Arc::clone(&self.cache), &path.to_string_lossy()).await {
+                error!("Failed to save cache to Arrow file: {}", e);
+            }
+        }
+        if let Some(log) = &self.context.append_log {
+            if let Err(e) = log.lock().await.compact(&self.cache, None).await {
+                error!("Failed to compact the log: {}", e);
+            }
+        }
+        if let Some(outbox) = &self.context.missed.outbox {
+            outbox.saved().await;
+        }
+        info!("Node on TCP port {} shut down", self.port());
+    }
+
+    // Read a key directly from the cache, bypassing TCP
+    pub async fn get(&self, key: &str) -> Option<CacheValue> {
+        self.cache.lock().await.get(key).cloned()
+    }
+
+    // Write a key directly and replicate it to peers, like SET
+    pub async fn set(&self, key: impl Into<String>, value: CacheValue) {
+        let key = key.into();
+        self.cache.lock().await.insert(key.clone(), value.clone());
+        replicate_set(&self.context, &self.peers, key, value).await;
+    }
+}
+
+pub async fn node_listener(mut listener: Box<dyn Listener>, peers: PeerList, cache: SharedCache, context: SharedContext) {
+    info!("Node listening on TCP port {}", context.node_port);
+
+    // Owned by the listener task, so stopping the node also closes persistent and SUBSCRIBE connections
+    let mut connections = JoinSet::new();
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                debug!("New connection from {}", addr);
+                while connections.try_join_next().is_some() {}
+
+                let cache = Arc::clone(&cache);
+                let peers = Arc::clone(&peers);
+                let context = Arc::clone(&context);
+                connections.spawn(async move {
+                    handle_connection(socket, addr, cache, peers, context).await;
+                });
+            }
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+// Run a node on `node_port` until the process exits
+// `recover_to` (Unix millis) starts the node from the cache as it was then, see NodeBuilder::recover_to
+pub async fn run(node_port: u16, recover_to: Option<u64>) {
+    #[allow(unused_mut)]
+    let mut builder = NodeBuilder::new().port(node_port);
+
+    // Chaos testing in staging, e.g. P2P_FAULTS="127.0.0.1:8081=loss=0.2;*=latency_ms=50,jitter_ms=50"
+    #[cfg(feature = "fault-injection")]
+    if let Ok(spec) = std::env::var("P2P_FAULTS") {
+        let transport = crate::fault::FaultyTransport::from_spec(Arc::new(TcpTransport), &spec).unwrap();
+        log::warn!("Fault injection enabled: {}", spec);
+        builder = builder.transport(Arc::new(transport));
+    }
+
+    // Peers from a Kubernetes headless service, e.g. P2P_DISCOVERY_DNS=p2p.default.svc.cluster.local
+    // (peers listen on this node's port) or P2P_DISCOVERY_DNS=p2p.default.svc.cluster.local:8080
+    if let Ok(spec) = std::env::var("P2P_DISCOVERY_DNS") {
+        let (name, port) = match spec.rsplit_once(':') {
+            Some((name, port)) => (name.to_string(), port.parse().expect("P2P_DISCOVERY_DNS port must be a number")),
+            None => (spec.clone(), node_port),
+        };
+        builder = builder.discovery(Discovery::Dns { name, port });
+        info!("DNS discovery enabled: {}", spec);
+    }
+
+    // Peers from cloud instance tags, e.g. P2P_DISCOVERY_CLOUD="provider=aws,tag=cluster:p2p,region=eu-west-1"
+    if let Ok(spec) = std::env::var("P2P_DISCOVERY_CLOUD") {
+        builder = builder.discovery(Discovery::Cloud(CloudDiscovery::parse(&spec, node_port).unwrap()));
+        info!("Cloud discovery enabled: {}", spec);
+    }
+
+    // The address peers reach this node at: P2P_ADVERTISE=203.0.113.7:8080, or P2P_STUN=on (or
+    // P2P_STUN=stun.example.com:3478) for this host's public IP as a STUN server sees it
+    if let Ok(addr) = std::env::var("P2P_ADVERTISE") {
+        builder = builder.advertise(addr);
+    } else if let Ok(server) = std::env::var("P2P_STUN") {
+        let server = if server == "on" { crate::stun::DEFAULT_STUN_SERVER.to_string() } else { server };
+        match crate::stun::public_address(&server).await {
+            Ok(public) => builder = builder.advertise(format!("{}:{}", public.ip(), node_port)),
+            Err(e) => log::warn!("Could not detect the public address with {}, advertising the local one: {}", server, e),
+        }
+    }
+
+    // P2P_PERSISTENCE=log appends changes instead of rewriting the snapshot, none keeps the cache in memory only
+    if let Ok(mode) = std::env::var("P2P_PERSISTENCE") {
+        let path = format!("node_{}_cache.arrow", node_port).into();
+        builder = builder.persistence(match mode.as_str() {
+            "arrow" => Persistence::Arrow(path),
+            "log" => Persistence::ArrowLog(path),
+            "none" => Persistence::None,
+            _ => panic!("P2P_PERSISTENCE must be arrow, log or none"),
+        });
+        info!("Persistence: {}", mode);
+    }
+
+    // e.g. P2P_LOG_FSYNC=always, P2P_LOG_FSYNC=interval_ms=200 or P2P_LOG_FSYNC=batch=500
+    if let Ok(spec) = std::env::var("P2P_LOG_FSYNC") {
+        builder = builder.log_fsync(FsyncPolicy::parse(&spec).unwrap());
+        info!("Log fsync policy: {}", spec);
+    }
+
+    // e.g. P2P_LOG_ARCHIVE=on or P2P_LOG_ARCHIVE=keep_s=86400
+    if let Ok(spec) = std::env::var("P2P_LOG_ARCHIVE") {
+        builder = builder.log_archive(LogArchive::parse(&spec).unwrap());
+        info!("Log archive enabled: {}", spec);
+    }
+
+    if let Some(target) = recover_to {
+        builder = builder.recover_to(target);
+        log::warn!("Recovering the cache as it was at {} (Unix ms)", target);
+    }
+
+    // e.g. P2P_COMPACTION=on or P2P_COMPACTION="interval_s=60,min_rows=1000,rate=50000"
+    if let Ok(spec) = std::env::var("P2P_COMPACTION") {
+        builder = builder.compaction(CompactionSchedule::parse(&spec).unwrap());
+        info!("Scheduled compaction enabled: {}", spec);
+    }
+
+    // e.g. P2P_WARM_UP=on or P2P_WARM_UP="prefix=user:,wait_s=30"
+    if let Ok(spec) = std::env::var("P2P_WARM_UP") {
+        builder = builder.warm_up(WarmUp::parse(&spec).unwrap());
+        info!("Warm-up enabled: {}", spec);
+    }
+
+    // e.g. P2P_GOSSIP=on or P2P_GOSSIP="interval_s=2,buckets=256"
+    if let Ok(spec) = std::env::var("P2P_GOSSIP") {
+        builder = builder.gossip(Gossip::parse(&spec).unwrap());
+        info!("Digest gossip enabled: {}", spec);
+    }
+
+    // e.g. P2P_REMOTE_READS=on or P2P_REMOTE_READS="concurrency=8,timeout_ms=100"
+    if let Ok(spec) = std::env::var("P2P_REMOTE_READS") {
+        builder = builder.remote_reads(RemoteReads::parse(&spec).unwrap());
+        info!("Remote reads enabled: {}", spec);
+    }
+
+    // e.g. P2P_LEASES=on or P2P_LEASES="max_ms=30000,fraction=0.2"
+    if let Ok(spec) = std::env::var("P2P_LEASES") {
+        builder = builder.leases(Leases::parse(&spec).unwrap());
+        info!("Read leases enabled: {}", spec);
+    }
+
+    // e.g. P2P_KEY_HISTORY=10
+    if let Ok(versions) = std::env::var("P2P_KEY_HISTORY") {
+        let versions = versions.parse().expect("P2P_KEY_HISTORY must be a number of versions");
+        builder = builder.key_history(versions);
+        info!("Key history enabled: {} versions per key", versions);
+    }
+
+    // e.g. P2P_OUTBOX=on or P2P_OUTBOX="dir=/var/lib/p2p/outbox,capacity=1000,max_age_s=600"
+    if let Ok(spec) = std::env::var("P2P_OUTBOX") {
+        builder = builder.outbox(Outbox::parse(&spec).unwrap());
+        info!("Replication outbox enabled: {}", spec);
+    }
+
+    // e.g. P2P_CIRCUIT_BREAKER=on or P2P_CIRCUIT_BREAKER="failures=5,cooldown_ms=30000"
+    if let Ok(spec) = std::env::var("P2P_CIRCUIT_BREAKER") {
+        builder = builder.circuit_breaker(CircuitBreaker::parse(&spec).unwrap());
+        info!("Replication circuit breaker enabled: {}", spec);
+    }
+
+    // e.g. P2P_PEER_BANS=on or P2P_PEER_BANS="failures=10,ban_ms=60000,max_ban_ms=600000"
+    if let Ok(spec) = std::env::var("P2P_PEER_BANS") {
+        builder = builder.peer_bans(PeerBans::parse(&spec).unwrap());
+        info!("Peer banning enabled: {}", spec);
+    }
+
+    // e.g. P2P_REPLICATION_QUEUE=on or P2P_REPLICATION_QUEUE="capacity=50000,workers=8,when_full=shed"
+    if let Ok(spec) = std::env::var("P2P_REPLICATION_QUEUE") {
+        builder = builder.replication_queue(ReplicationQueue::parse(&spec).unwrap());
+        info!("Replication queue enabled: {}", spec);
+    }
+
+    // e.g. P2P_REPLICATION_BATCH_MS=5
+    if let Ok(window) = std::env::var("P2P_REPLICATION_BATCH_MS") {
+        let window = window.parse().expect("P2P_REPLICATION_BATCH_MS must be a number of milliseconds");
+        builder = builder.replication_batch(Duration::from_millis(window));
+        info!("Replication batching enabled: {}ms", window);
+    }
+
+    let node = builder.build().unwrap();
+    node.start().unwrap();
+    std::future::pending::<()>().await
+}
@@ -0,0 +1,400 @@
+//! Durable per-peer outbox for replication messages that could not be delivered.
+//!
+//! Each peer's undelivered messages are kept in order in `<dir>/<host>_<port>.pending`
+//! (JSON lines) and replayed by the catch-up loop in `replication` once the peer is back.
+//! Messages that wait longer than `max_age`, or that overflow `capacity`, become dead
+//! letters in `<dir>/<host>_<port>.dead`, where OUTBOX DEAD shows them and OUTBOX PURGE
+//! drops them. Changes are written to disk off the async workers, several at a time: a
+//! file is replaced whole through a temporary file, and lines that don't parse on load
+//! are set aside in `<file>.corrupt` instead of failing startup.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+// Keep undelivered replication messages on disk and retry them
+#[derive(Clone, Debug)]
+pub struct Outbox {
+    // Defaults to node_<port>_outbox
+    pub dir: Option<PathBuf>,
+    // Messages kept per peer, for both pending and dead letters
+    pub capacity: usize,
+    // How long a message is retried before it becomes a dead letter
+    pub max_age: Duration,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox {
+            dir: None,
+            capacity: 10_000,
+            max_age: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl Outbox {
+    // "on" for the defaults, or e.g. "dir=/var/lib/p2p/outbox,capacity=1000,max_age_s=600"
+    pub fn parse(spec: &str) -> Result<Outbox, String> {
+        let mut outbox = Outbox::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty() && *s != "on") {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid outbox setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "dir" => outbox.dir = Some(value.into()),
+                "capacity" => outbox.capacity = value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?,
+                "max_age_s" => outbox.max_age = Duration::from_secs(value.parse().map_err(|_| invalid())?),
+                _ => return Err(format!("Unknown outbox setting: {}", name)),
+            }
+        }
+        Ok(outbox)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Letter {
+    // Unix ms when the message was first queued
+    pub queued: u64,
+    pub message: String,
+    // Why delivery was given up, for dead letters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Default)]
+struct PeerOutbox {
+    pending: VecDeque<Letter>,
+    dead: VecDeque<Letter>,
+}
+
+// Peers whose files are behind their outbox, and whether a writer is catching them up
+#[derive(Default)]
+struct Unsaved {
+    peers: HashSet<String>,
+    writing: bool,
+}
+
+// The outboxes of every peer, mirrored to disk after each change
+pub(crate) struct Outboxes {
+    dir: PathBuf,
+    capacity: usize,
+    max_age: Duration,
+    peers: Arc<Mutex<HashMap<String, PeerOutbox>>>,
+    unsaved: Arc<Mutex<Unsaved>>,
+}
+
+impl Outboxes {
+    // Create the directory if needed and load what earlier runs left behind
+    pub(crate) fn open(config: &Outbox, node_port: u16) -> io::Result<Outboxes> {
+        let dir = config.dir.clone().unwrap_or_else(|| format!("node_{}_outbox", node_port).into());
+        std::fs::create_dir_all(&dir)?;
+
+        let mut peers: HashMap<String, PeerOutbox> = HashMap::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let (Some(stem), Some(kind)) = (path.file_stem().and_then(|s| s.to_str()), path.extension().and_then(|s| s.to_str())) else {
+                continue;
+            };
+            // Leftover .tmp and .corrupt files are not outboxes
+            let (Some((host, port)), "pending" | "dead") = (stem.rsplit_once('_'), kind) else {
+                continue;
+            };
+            let letters = read_letters(&path)?;
+            let outbox = peers.entry(format!("{}:{}", host, port)).or_default();
+            match kind {
+                "pending" => outbox.pending = letters,
+                _ => outbox.dead = letters,
+            }
+        }
+
+        Ok(Outboxes {
+            dir,
+            capacity: config.capacity,
+            max_age: config.max_age,
+            peers: Arc::new(Mutex::new(peers)),
+            unsaved: Arc::default(),
+        })
+    }
+
+    // Queue messages `peer` did not get, turning the oldest into dead letters past capacity
+    pub(crate) fn push(&self, peer: &str, queued: u64, messages: impl IntoIterator<Item = String>) {
+        let mut peers = self.peers.lock().unwrap();
+        let outbox = peers.entry(peer.to_string()).or_default();
+        outbox.pending.extend(messages.into_iter().map(|message| Letter { queued, message, reason: None }));
+        while outbox.pending.len() > self.capacity {
+            let letter = outbox.pending.pop_front().expect("outbox over capacity");
+            self.bury(peer, outbox, letter, "outbox full".to_string());
+        }
+        drop(peers);
+        self.save([peer.to_string()]);
+    }
+
+    // Pending messages for `peer`, oldest first
+    pub(crate) fn pending(&self, peer: &str) -> Vec<String> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .get(peer)
+            .map(|outbox| outbox.pending.iter().map(|letter| letter.message.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    // The first `count` pending messages reached `peer`
+    pub(crate) fn delivered(&self, peer: &str, count: usize) {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(outbox) = peers.get_mut(peer) {
+            outbox.pending.drain(..count.min(outbox.pending.len()));
+            drop(peers);
+            self.save([peer.to_string()]);
+        }
+    }
+
+    // Turn messages queued more than max_age before `now` (unix ms) into dead letters
+    pub(crate) fn expire(&self, now: u64) {
+        let max_age = self.max_age.as_millis() as u64;
+        let mut peers = self.peers.lock().unwrap();
+        let mut expired = Vec::new();
+        for (peer, outbox) in peers.iter_mut() {
+            let mut any = false;
+            while outbox.pending.front().is_some_and(|letter| now.saturating_sub(letter.queued) > max_age) {
+                let letter = outbox.pending.pop_front().expect("checked above");
+                self.bury(peer, outbox, letter, format!("not delivered within {}s", self.max_age.as_secs()));
+                any = true;
+            }
+            if any {
+                expired.push(peer.clone());
+            }
+        }
+        drop(peers);
+        self.save(expired);
+    }
+
+    // Oldest pending message time per peer, so a restarted node knows who is behind
+    pub(crate) fn behind(&self) -> Vec<(String, u64)> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .iter()
+            .filter_map(|(peer, outbox)| outbox.pending.front().map(|letter| (peer.clone(), letter.queued)))
+            .collect()
+    }
+
+    // (peer, pending, dead) for every peer with anything queued, sorted by peer
+    pub(crate) fn counts(&self) -> Vec<(String, usize, usize)> {
+        let peers = self.peers.lock().unwrap();
+        let mut counts: Vec<_> = peers
+            .iter()
+            .filter(|(_, outbox)| !outbox.pending.is_empty() || !outbox.dead.is_empty())
+            .map(|(peer, outbox)| (peer.clone(), outbox.pending.len(), outbox.dead.len()))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    pub(crate) fn dead(&self, peer: &str) -> Vec<Letter> {
+        let peers = self.peers.lock().unwrap();
+        peers.get(peer).map(|outbox| outbox.dead.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    // Drop the dead letters of `peer`, or of every peer, returning how many there were
+    pub(crate) fn purge(&self, peer: Option<&str>) -> usize {
+        let mut peers = self.peers.lock().unwrap();
+        let mut purged = 0;
+        let mut changed = Vec::new();
+        for (name, outbox) in peers.iter_mut().filter(|(name, _)| peer.is_none_or(|peer| peer == name.as_str())) {
+            purged += outbox.dead.len();
+            outbox.dead.clear();
+            changed.push(name.clone());
+        }
+        drop(peers);
+        self.save(changed);
+        purged
+    }
+
+    // Wait until every change so far is on disk
+    pub(crate) async fn saved(&self) {
+        while self.unsaved.lock().unwrap().writing {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    fn bury(&self, peer: &str, outbox: &mut PeerOutbox, mut letter: Letter, reason: String) {
+        warn!("Giving up replicating to {} ({}): {}", peer, reason, letter.message);
+        letter.reason = Some(reason);
+        outbox.dead.push_back(letter);
+        if outbox.dead.len() > self.capacity {
+            outbox.dead.pop_front();
+        }
+    }
+
+    // Mark the peers' files stale and have a blocking task rewrite them, unless one already is:
+    // a peer that stays down costs one rewrite per burst of failed sends, not one per send
+    fn save(&self, changed: impl IntoIterator<Item = String>) {
+        let mut unsaved = self.unsaved.lock().unwrap();
+        unsaved.peers.extend(changed);
+        if unsaved.writing || unsaved.peers.is_empty() {
+            return;
+        }
+        unsaved.writing = true;
+        drop(unsaved);
+
+        let (dir, peers, unsaved) = (self.dir.clone(), Arc::clone(&self.peers), Arc::clone(&self.unsaved));
+        let write = move || write_unsaved(&dir, &peers, &unsaved);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(write)),
+            Err(_) => write(),
+        }
+    }
+}
+
+// Rewrite the files of stale peers until none are left
+fn write_unsaved(dir: &Path, peers: &Mutex<HashMap<String, PeerOutbox>>, unsaved: &Mutex<Unsaved>) {
+    loop {
+        let stale = {
+            let mut unsaved = unsaved.lock().unwrap();
+            if unsaved.peers.is_empty() {
+                unsaved.writing = false;
+                return;
+            }
+            std::mem::take(&mut unsaved.peers)
+        };
+        for peer in stale {
+            let (pending, dead) = {
+                let peers = peers.lock().unwrap();
+                peers.get(&peer).map(|outbox| (outbox.pending.clone(), outbox.dead.clone())).unwrap_or_default()
+            };
+            let stem = peer.replace(':', "_");
+            for (kind, letters) in [("pending", &pending), ("dead", &dead)] {
+                let path = dir.join(format!("{}.{}", stem, kind));
+                if let Err(e) = write_letters(&path, letters) {
+                    error!("Failed to save outbox {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+// Lines that don't parse, say from a crash mid-write, are copied to `<file>.corrupt` and skipped
+fn read_letters(path: &Path) -> io::Result<VecDeque<Letter>> {
+    let contents = std::fs::read(path)?;
+    let mut letters = VecDeque::new();
+    let mut corrupt = String::new();
+    for line in String::from_utf8_lossy(&contents).lines().filter(|line| !line.is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(letter) => letters.push_back(letter),
+            Err(_) => {
+                corrupt.push_str(line);
+                corrupt.push('\n');
+            }
+        }
+    }
+    if !corrupt.is_empty() {
+        let quarantine = path.with_extension(format!("{}.corrupt", path.extension().and_then(|s| s.to_str()).unwrap_or_default()));
+        warn!("Skipping {} unreadable lines of outbox {}, kept in {}", corrupt.lines().count(), path.display(), quarantine.display());
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&quarantine)?;
+        file.write_all(corrupt.as_bytes())?;
+    }
+    Ok(letters)
+}
+
+// An empty outbox leaves no file behind; others are replaced whole, so a crash leaves the old file
+fn write_letters(path: &Path, letters: &VecDeque<Letter>) -> io::Result<()> {
+    if letters.is_empty() {
+        return match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    let mut contents = String::new();
+    for letter in letters {
+        contents.push_str(&serde_json::to_string(letter).map_err(io::Error::other)?);
+        contents.push('\n');
+    }
+    let tmp = path.with_extension(format!("{}.tmp", path.extension().and_then(|s| s.to_str()).unwrap_or_default()));
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(capacity: usize) -> (Outbox, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("p2p-rust-outbox-{}-{}", std::process::id(), rand::random::<u32>()));
+        (Outbox { dir: Some(dir.clone()), capacity, max_age: Duration::from_secs(60) }, dir)
+    }
+
+    fn messages(range: std::ops::Range<u32>) -> impl Iterator<Item = String> {
+        range.map(|i| format!("BROADCAST k{}=v{}", i, i))
+    }
+
+    #[tokio::test]
+    async fn pending_letters_are_replayed_in_order_after_a_restart() {
+        let (config, dir) = config(100);
+        let outboxes = Outboxes::open(&config, 0).unwrap();
+        outboxes.push("127.0.0.1:7001", 1_000, messages(0..3));
+        outboxes.push("127.0.0.1:7001", 2_000, ["SET\nmulti-line".to_string()]);
+        outboxes.delivered("127.0.0.1:7001", 1);
+        outboxes.saved().await;
+
+        let reopened = Outboxes::open(&config, 0).unwrap();
+        assert_eq!(
+            reopened.pending("127.0.0.1:7001"),
+            vec!["BROADCAST k1=v1", "BROADCAST k2=v2", "SET\nmulti-line"]
+        );
+        assert_eq!(reopened.behind(), vec![("127.0.0.1:7001".to_string(), 1_000)]);
+
+        reopened.delivered("127.0.0.1:7001", 3);
+        reopened.saved().await;
+        assert!(Outboxes::open(&config, 0).unwrap().counts().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dead_letters_survive_a_restart_until_purged() {
+        let (config, dir) = config(2);
+        let outboxes = Outboxes::open(&config, 0).unwrap();
+        // Over capacity: the oldest pending letter dies
+        outboxes.push("127.0.0.1:7001", 1_000, messages(0..3));
+        // Past max_age: every pending letter dies, the oldest dead one drops off
+        outboxes.push("127.0.0.1:7002", 1_000, messages(3..4));
+        outboxes.expire(1_000 + 61_000);
+        outboxes.saved().await;
+
+        let reopened = Outboxes::open(&config, 0).unwrap();
+        assert_eq!(reopened.counts(), vec![("127.0.0.1:7001".to_string(), 0, 2), ("127.0.0.1:7002".to_string(), 0, 1)]);
+        let dead = reopened.dead("127.0.0.1:7001");
+        assert_eq!(dead.iter().map(|letter| letter.message.as_str()).collect::<Vec<_>>(), ["BROADCAST k1=v1", "BROADCAST k2=v2"]);
+        assert_eq!(reopened.dead("127.0.0.1:7002")[0].reason.as_deref(), Some("not delivered within 60s"));
+
+        assert_eq!(reopened.purge(Some("127.0.0.1:7001")), 2);
+        reopened.saved().await;
+        let purged = Outboxes::open(&config, 0).unwrap();
+        assert_eq!(purged.counts(), vec![("127.0.0.1:7002".to_string(), 0, 1)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_torn_outbox_file_is_quarantined_instead_of_failing_startup() {
+        let (config, dir) = config(100);
+        let outboxes = Outboxes::open(&config, 0).unwrap();
+        outboxes.push("127.0.0.1:7001", 1_000, messages(0..2));
+        outboxes.saved().await;
+        let path = dir.join("127.0.0.1_7001.pending");
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"queued\":1000,\"mess").unwrap();
+        std::fs::write(dir.join("127.0.0.1_7001.pending.tmp"), "left over").unwrap();
+
+        let reopened = Outboxes::open(&config, 0).unwrap();
+        assert_eq!(reopened.pending("127.0.0.1:7001"), vec!["BROADCAST k0=v0", "BROADCAST k1=v1"]);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("127.0.0.1_7001.pending.corrupt")).unwrap(),
+            "{\"queued\":1000,\"mess\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
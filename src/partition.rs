@@ -0,0 +1,225 @@
+//! Split-brain detection and reconciling with a peer when a partition heals
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use log::{info, warn};
+
+use crate::cluster::ask;
+use crate::gossip::parse_entry;
+use crate::node::NodeContext;
+use crate::protocol::format_assignment;
+use crate::replication::{from_peer_clock, value_messages};
+use crate::storage::SharedCache;
+
+// Conflicts kept for CLUSTER CONFLICTS, oldest dropped first
+const MAX_CONFLICTS: usize = 1000;
+
+// A key both sides of a partition changed
+#[derive(Clone, Debug)]
+pub struct Conflict {
+    // Unix ms the conflict was found
+    pub at: u64,
+    pub peer: String,
+    pub key: String,
+    // Both versions as `format_assignment` writes them, with when each was last changed
+    pub ours: (String, u64),
+    pub theirs: (String, u64),
+    pub kept_ours: bool,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} with {}: ours {} (modified {}), theirs {} (modified {}), kept {}",
+            self.at,
+            self.key,
+            self.peer,
+            self.ours.0,
+            self.ours.1,
+            self.theirs.0,
+            self.theirs.1,
+            if self.kept_ours { "ours" } else { "theirs" }
+        )
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Partitions {
+    // Peer -> unix ms it was last in the peer list, until it is back and reconciled
+    split: std::sync::Mutex<HashMap<String, u64>>,
+    conflicts: std::sync::Mutex<VecDeque<Conflict>>,
+}
+
+impl Partitions {
+    pub(crate) fn lost(&self, peer: &str, since: u64) {
+        let mut split = self.split.lock().unwrap();
+        if !split.contains_key(peer) {
+            warn!("Lost contact with {}; treating it as partitioned from this node until it is back", peer);
+            split.insert(peer.to_string(), since);
+        }
+    }
+
+    // When `peer` split off, if it did and hasn't been reconciled with yet
+    pub(crate) fn split_since(&self, peer: &str) -> Option<u64> {
+        self.split.lock().unwrap().get(peer).copied()
+    }
+
+    // Whether any peer is split off, so this node may be missing writes
+    pub(crate) fn any(&self) -> bool {
+        !self.split.lock().unwrap().is_empty()
+    }
+
+    pub(crate) fn healed(&self, peer: &str) {
+        self.split.lock().unwrap().remove(peer);
+    }
+
+    // `peer since=<ms>` for every peer currently split off, for CLUSTER STATUS
+    pub(crate) fn status(&self) -> Vec<String> {
+        let mut lines: Vec<String> =
+            self.split.lock().unwrap().iter().map(|(peer, since)| format!("{} partitioned since={}", peer, since)).collect();
+        lines.sort();
+        lines
+    }
+
+    pub(crate) fn conflicts(&self) -> Vec<String> {
+        self.conflicts.lock().unwrap().iter().map(Conflict::to_string).collect()
+    }
+
+    fn record(&self, conflict: Conflict) {
+        warn!("Partition conflict on {}", conflict);
+        let mut conflicts = self.conflicts.lock().unwrap();
+        if conflicts.len() == MAX_CONFLICTS {
+            conflicts.pop_front();
+        }
+        conflicts.push_back(conflict);
+    }
+}
+
+// Outcome of reconciling with a peer
+pub(crate) struct Reconciled {
+    // Keys where the peer's version was kept
+    pub taken: usize,
+    // BROADCASTs of our versions the peer should apply
+    pub send: Vec<String>,
+    pub conflicts: usize,
+}
+
+// Merge what `peer` and this node changed since `since`, keeping the later change of each key;
+// the caller sends the peer `send`
+pub(crate) async fn reconcile(cache: &SharedCache, peer: &str, since: u64, context: &NodeContext) -> Result<Reconciled, String> {
+    let entries = ask(context, peer, &format!("ENTRIES SINCE {}", since)).await?;
+    let mut theirs = HashMap::new();
+    for line in entries.lines().filter(|line| !line.is_empty()) {
+        match parse_entry(line) {
+            Some((key, value, modified, expires)) => {
+                theirs.insert(key, (value, modified, expires.map(|at| from_peer_clock(context, peer, at))));
+            }
+            None => warn!("Invalid ENTRIES line from {}: {}", peer, line),
+        }
+    }
+
+    let now = context.clock.unix_millis();
+    let mut reconciled = Reconciled { taken: 0, send: Vec::new(), conflicts: 0 };
+    let mut cache = cache.lock().await;
+    let mut ours = HashSet::new();
+    for (key, value) in cache.iter().filter(|(key, _)| cache.modified(key).is_some_and(|modified| modified >= since)) {
+        if !theirs.contains_key(key) {
+            reconciled.send.extend(value_messages(&cache, key, value));
+        }
+        ours.insert(key.clone());
+    }
+    for (key, (value, modified, expires)) in theirs {
+        let local = cache.get(&key).map(|current| (format_assignment(&key, current), cache.modified(&key).unwrap_or(0)));
+        let remote = format_assignment(&key, &value);
+        let take = match &local {
+            None => true,
+            Some((current, _)) if *current == remote => continue,
+            // Later change wins, ties go to the greater value so both sides pick the same one
+            Some((current, local_modified)) => (modified, &remote) > (*local_modified, current),
+        };
+        if let (Some(local), true) = (&local, ours.contains(&key)) {
+            reconciled.conflicts += 1;
+            context.partitions.record(Conflict {
+                at: now,
+                peer: peer.to_string(),
+                key: key.clone(),
+                ours: local.clone(),
+                theirs: (remote.clone(), modified),
+                kept_ours: !take,
+            });
+        }
+        if take {
+            cache.insert(key.clone(), value);
+            cache.set_expiry(&key, expires);
+            reconciled.taken += 1;
+        } else if let Some(current) = cache.get(&key) {
+            reconciled.send.extend(value_messages(&cache, &key, current));
+        }
+    }
+    info!(
+        "Reconciled with {} after a partition: kept {} of its changes, sending {} of ours, {} conflicts",
+        peer,
+        reconciled.taken,
+        reconciled.send.len(),
+        reconciled.conflicts
+    );
+    Ok(reconciled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::time::Duration;
+    use super::*;
+    use crate::sim::SimClock;
+    use crate::testing::TestCluster;
+
+    const EPOCH: u64 = 1_735_689_600_000;
+
+    // Write `pair` straight to node `i` only, as a write made while split off would be
+    async fn write(cluster: &TestCluster, i: usize, pair: &str) {
+        cluster.request(i, &format!("BROADCAST {}", pair)).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconciling_keeps_the_later_change_of_each_key_and_reports_conflicts() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        write(&cluster, 0, "before=1").await;
+        write(&cluster, 1, "before=1").await;
+        cluster.advance(Duration::from_secs(1)).await;
+        let since = EPOCH + 1000;
+        write(&cluster, 0, "ours=1").await;
+        cluster.request(0, "GETEX ours TTL=60").await.unwrap();
+        write(&cluster, 0, "both=0").await;
+        cluster.advance(Duration::from_secs(1)).await;
+        write(&cluster, 1, "theirs=1").await;
+        cluster.request(1, "GETEX theirs TTL=60").await.unwrap();
+        write(&cluster, 1, "both=1").await;
+        write(&cluster, 1, "late=1").await;
+        cluster.advance(Duration::from_secs(1)).await;
+        write(&cluster, 0, "late=0").await;
+
+        let context = NodeContext::new(cluster.node(0).port(), cluster.node(0).transport(), Arc::new(SimClock::new(EPOCH)));
+        let cache = cluster.node(0).cache();
+        let reconciled = reconcile(&cache, &cluster.addr(1), since, &context).await.unwrap();
+        let mut send = reconciled.send.clone();
+        send.sort();
+        assert_eq!(send, ["BROADCAST late=0".to_string(), "BROADCAST ours=1".to_string(), format!("REPLICATE EXPIREAT ours {}", EPOCH + 61_000)]);
+        assert_eq!((reconciled.taken, reconciled.conflicts), (2, 2));
+        assert_eq!(cluster.request(0, "GET both").await.unwrap().trim_end(), "1");
+        assert_eq!(cluster.request(0, "GET theirs").await.unwrap().trim_end(), "1");
+        assert_eq!(cluster.request(0, "TTL theirs").await.unwrap().trim_end(), "59");
+
+        let peer = cluster.addr(1);
+        let mut conflicts = context.partitions.conflicts();
+        conflicts.sort();
+        assert_eq!(
+            conflicts,
+            [
+                format!("{EPOCH} both with {peer}: ours both=0 (modified {}), theirs both=1 (modified {}), kept theirs", EPOCH + 1000, EPOCH + 2000),
+                format!("{EPOCH} late with {peer}: ours late=0 (modified {}), theirs late=1 (modified {}), kept ours", EPOCH + 3000, EPOCH + 2000),
+            ]
+        );
+        cluster.shutdown().await;
+    }
+}
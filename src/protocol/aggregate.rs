@@ -0,0 +1,246 @@
+//! COUNT/AGG evaluation, over the live cache or its retained past, and the SIZES report
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::path::Path;
+use crate::storage::archive::{cache_as_of, generations, load_generation};
+use crate::storage::json::{json_path_lookup, value_as_json, PathSegment};
+use crate::storage::persistence::BoxError;
+use crate::storage::Cache;
+use super::filter::Filter;
+
+#[derive(Clone, Copy)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+// The past a COUNT/AGG runs over instead of the live cache, from the append log and its archive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum History {
+    // The cache as it was at a time (Unix millis), as GET ... AS_OF reads it
+    AsOf(u64),
+    // Each retained snapshot written since a time, answered line by line after its write time
+    Snapshots { since: Option<u64> },
+}
+
+#[derive(Default)]
+pub(crate) struct Accumulator {
+    pub(crate) count: u64,
+    pub(crate) sum: f64,
+    pub(crate) min: Option<f64>,
+    pub(crate) max: Option<f64>,
+}
+
+impl Accumulator {
+    pub(crate) fn add(&mut self, x: f64) {
+        self.count += 1;
+        self.sum += x;
+        self.min = Some(self.min.map_or(x, |m| m.min(x)));
+        self.max = Some(self.max.map_or(x, |m| m.max(x)));
+    }
+
+    pub(crate) fn result(&self, aggregate: Aggregate) -> String {
+        let value = match aggregate {
+            Aggregate::Count => Some(self.count as f64),
+            Aggregate::Sum => Some(self.sum),
+            Aggregate::Avg => (self.count > 0).then(|| self.sum / self.count as f64),
+            Aggregate::Min => self.min,
+            Aggregate::Max => self.max,
+        };
+        value.map(|v| v.to_string()).unwrap_or_else(|| "Not Found".to_string())
+    }
+}
+
+// Extract `GROUP BY PREFIX <delimiter>` from a COUNT/AGG command, returning the remaining arguments
+pub(crate) fn split_group_by(args: &str) -> (String, Option<String>) {
+    let Some(at) = args.find("GROUP BY PREFIX") else {
+        return (args.to_string(), None);
+    };
+    let rest = args[at + "GROUP BY PREFIX".len()..].trim_start();
+    let (delimiter, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    if delimiter.is_empty() {
+        return (args.to_string(), None);
+    }
+    (format!("{} {}", &args[..at], tail), Some(delimiter.to_string()))
+}
+
+// Group key for GROUP BY PREFIX: the key up to and including the first delimiter
+pub(crate) fn key_group<'a>(key: &'a str, delimiter: &str) -> &'a str {
+    match key.find(delimiter) {
+        Some(i) => &key[..i + delimiter.len()],
+        None => key,
+    }
+}
+
+// Evaluate COUNT/AGG over the cache; `path` selects the numeric field (None counts keys)
+pub(crate) fn run_aggregation(
+    cache: &Cache,
+    aggregate: Aggregate,
+    path: Option<&[PathSegment]>,
+    prefix: &str,
+    filter: Option<&Filter>,
+    group_by: Option<&str>,
+) -> String {
+    let mut groups: BTreeMap<&str, Accumulator> = BTreeMap::new();
+    for (key, value) in cache.iter() {
+        if !key.starts_with(prefix) || !filter.is_none_or(|f| f.matches(key, value)) {
+            continue;
+        }
+        let number = match path {
+            None => Some(1.0),
+            Some(segments) => value_as_json(value)
+                .as_ref()
+                .and_then(|json| json_path_lookup(json, segments))
+                .and_then(|field| field.as_f64().or_else(|| field.as_str()?.parse().ok())),
+        };
+        if let Some(number) = number {
+            let group = group_by.map(|d| key_group(key, d)).unwrap_or("");
+            groups.entry(group).or_default().add(number);
+        }
+    }
+
+    match group_by {
+        None => groups.remove("").unwrap_or_default().result(aggregate),
+        Some(_) => groups
+            .iter()
+            .map(|(group, acc)| format!("{}={}", group, acc.result(aggregate)))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+// Evaluate COUNT/AGG over the retained past of the append log whose snapshot is `snapshot`
+pub(crate) fn run_history_aggregation(snapshot: &Path, history: History, run: impl Fn(&Cache) -> String) -> Result<String, BoxError> {
+    match history {
+        History::AsOf(at) => Ok(run(&cache_as_of(snapshot, at)?)),
+        History::Snapshots { since } => {
+            let generations = generations(snapshot, since.unwrap_or(0))?;
+            if generations.is_empty() {
+                return Err("no snapshots retained from then".into());
+            }
+            let mut lines = Vec::new();
+            for (written_at, path) in generations {
+                let result = run(&load_generation(&path, written_at)?);
+                lines.extend(result.lines().map(|line| format!("{} {}", written_at, line)));
+            }
+            Ok(lines.join("\n"))
+        }
+    }
+}
+
+// Power-of-two bucket label for a byte count: <=1, <=2, <=4, .. <=1K, .. <=1M
+fn size_bucket(bytes: usize) -> (u32, String) {
+    let exponent = bytes.max(1).next_power_of_two().trailing_zeros();
+    let label = match exponent {
+        0..=9 => format!("<={}", 1u64 << exponent),
+        10..=19 => format!("<={}K", 1u64 << (exponent - 10)),
+        _ => format!("<={}M", 1u64 << (exponent - 20)),
+    };
+    (exponent, label)
+}
+
+fn histogram_lines(name: &str, buckets: &BTreeMap<u32, (String, u64)>) -> Vec<String> {
+    buckets.values().map(|(label, count)| format!("{} {}: {}", name, label, count)).collect()
+}
+
+// Histograms of key lengths and encoded value sizes (as snapshots store them and BROADCAST
+// sends them) under `prefix`, then the `top` largest values
+pub(crate) fn run_size_report(cache: &Cache, prefix: &str, top: usize) -> String {
+    let (mut keys, mut key_total, mut value_total) = (0u64, 0usize, 0usize);
+    let mut key_buckets: BTreeMap<u32, (String, u64)> = BTreeMap::new();
+    let mut value_buckets: BTreeMap<u32, (String, u64)> = BTreeMap::new();
+    // Min-heap of the largest values seen so far
+    let mut largest: BinaryHeap<Reverse<(usize, &str)>> = BinaryHeap::new();
+    for (key, value) in cache.iter().filter(|(key, _)| key.starts_with(prefix)) {
+        let value_bytes = value.to_string().len();
+        keys += 1;
+        key_total += key.len();
+        value_total += value_bytes;
+        for (buckets, bytes) in [(&mut key_buckets, key.len()), (&mut value_buckets, value_bytes)] {
+            let (exponent, label) = size_bucket(bytes);
+            buckets.entry(exponent).or_insert((label, 0)).1 += 1;
+        }
+        largest.push(Reverse((value_bytes, key.as_str())));
+        if largest.len() > top {
+            largest.pop();
+        }
+    }
+
+    let mut lines = vec![format!("keys: {}", keys), format!("key_bytes: {}", key_total), format!("value_bytes: {}", value_total)];
+    lines.extend(histogram_lines("key_bytes", &key_buckets));
+    lines.extend(histogram_lines("value_bytes", &value_buckets));
+    let mut largest: Vec<(usize, &str)> = largest.into_iter().map(|Reverse(entry)| entry).collect();
+    largest.sort_by(|a, b| b.cmp(a));
+    for (value_bytes, key) in largest {
+        let value = cache.get(key).expect("listed from the cache");
+        let memory = cache.key_memory_usage(key).unwrap_or_default();
+        lines.push(format!("largest {} value_bytes={} memory_bytes={} type={}", key, value_bytes, memory, value.type_name()));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::json::parse_json_path;
+    use crate::storage::CacheValue;
+
+    fn orders() -> Cache {
+        let mut cache = Cache::new();
+        for (key, amount) in [("order:eu:1", 10), ("order:eu:2", 30), ("order:us:1", 5), ("user:1", 100)] {
+            cache.insert(key.to_string(), CacheValue::Str(format!(r#"{{"amount":{}}}"#, amount)));
+        }
+        cache
+    }
+
+    #[test]
+    fn splits_group_by() {
+        assert_eq!(split_group_by("PREFIX order: GROUP BY PREFIX :"), ("PREFIX order:  ".to_string(), Some(":".to_string())));
+        assert_eq!(split_group_by("PREFIX order:"), ("PREFIX order:".to_string(), None));
+        assert_eq!(split_group_by("GROUP BY PREFIX"), ("GROUP BY PREFIX".to_string(), None));
+    }
+
+    #[test]
+    fn aggregates_over_a_prefix() {
+        let cache = orders();
+        let amount = parse_json_path("$.amount").unwrap();
+        assert_eq!(run_aggregation(&cache, Aggregate::Count, None, "order:", None, None), "3");
+        assert_eq!(run_aggregation(&cache, Aggregate::Sum, Some(&amount), "order:", None, None), "45");
+        assert_eq!(run_aggregation(&cache, Aggregate::Max, Some(&amount), "", None, None), "100");
+        assert_eq!(run_aggregation(&cache, Aggregate::Avg, Some(&amount), "session:", None, None), "Not Found");
+
+        let filter = Filter::parse("$.amount >= 10").unwrap();
+        assert_eq!(run_aggregation(&cache, Aggregate::Min, Some(&amount), "order:", Some(&filter), None), "10");
+    }
+
+    #[test]
+    fn groups_by_key_prefix() {
+        let cache = orders();
+        assert_eq!(key_group("order:eu:1", ":"), "order:");
+        assert_eq!(key_group("plain", ":"), "plain");
+        assert_eq!(run_aggregation(&cache, Aggregate::Count, None, "", None, Some(":")), "order:=3\nuser:=1");
+    }
+
+    #[test]
+    fn size_report_buckets_the_prefix_and_lists_the_largest_values() {
+        let cache = orders();
+        assert_eq!(size_bucket(0).1, "<=1");
+        assert_eq!(size_bucket(1500).1, "<=2K");
+        assert_eq!(size_bucket(3 << 20).1, "<=4M");
+
+        let report = run_size_report(&cache, "order:", 2);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[..5], ["keys: 3", "key_bytes: 30", "value_bytes: 38", "key_bytes <=16: 3", "value_bytes <=16: 3"]);
+        // Ties on size list the later key first; the smallest value falls out of the top 2
+        assert_eq!(lines.len(), 7);
+        assert!(lines[5].starts_with("largest order:eu:2 value_bytes=13 memory_bytes="), "{}", lines[5]);
+        assert!(lines[6].starts_with("largest order:eu:1 value_bytes=13 "), "{}", lines[6]);
+        assert!(lines[6].ends_with(" type=string"), "{}", lines[6]);
+
+        assert_eq!(run_size_report(&cache, "session:", 5), "keys: 0\nkey_bytes: 0\nvalue_bytes: 0");
+    }
+}
@@ -0,0 +1,1209 @@
+//! Parsing of client and replicated commands.
+//!
+//! [`parse_command`] and [`parse_replicated`] are pure: they turn request
+//! text into a typed command without touching the cache, and return the
+//! client-facing error message for malformed input instead of panicking.
+
+use std::collections::HashSet;
+use std::fmt;
+use serde_json::Value;
+
+use crate::clock::parse_utc;
+use crate::gossip::{parse_buckets, MAX_BUCKETS};
+use crate::storage::json::{parse_json_path, PathSegment};
+use crate::storage::persistence::{parse_export_args, parse_import_args, ExportOptions, ImportOptions};
+use crate::storage::value::parse_score;
+use crate::replication::SyncAck;
+use crate::session::SessionToken;
+use crate::storage::CacheValue;
+use crate::transfer::SnapshotRequest;
+use super::aggregate::{split_group_by, Aggregate, History};
+use super::filter::{split_where, Filter};
+use super::parse_assignment;
+
+// LPUSH/RPUSH/LPOP/RPOP
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListOp {
+    Push { key: String, value: String, front: bool },
+    Pop { key: String, front: bool },
+}
+
+// HSET/HDEL
+#[derive(Clone, Debug, PartialEq)]
+pub enum HashOp {
+    Set { key: String, field: String, value: String },
+    Del { key: String, field: String },
+}
+
+// XADD_AT (replicated XADD carrying the origin's ID) and XCOMMIT
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamOp {
+    AddAt { key: String, id: u64, payload: String },
+    Commit { key: String, consumer: String, offset: u64 },
+}
+
+// ZADD/ZREM
+#[derive(Clone, Debug, PartialEq)]
+pub enum ZsetOp {
+    Add { key: String, score: f64, member: String },
+    Rem { key: String, member: String },
+}
+
+// DEL/DEL_PREFIX/DEL_MATCH
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeleteOp {
+    Key(String),
+    Prefix(String),
+    Match(String),
+}
+
+// EXPIREAT (as replicated, in Unix millis, also for GETEX: absolute, so every node expires the
+// key at once) and PERSIST
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpiryOp {
+    At { key: String, at: u64 },
+    Persist { key: String },
+}
+
+// An operation applied on a peer via REPLICATE
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplicatedOp {
+    List(ListOp),
+    Hash(HashOp),
+    Stream(StreamOp),
+    Zset(ZsetOp),
+    Delete(DeleteOp),
+    Expiry(ExpiryOp),
+    FlushAll { snapshot: bool },
+}
+
+pub enum Command {
+    // CLUSTER merges every peer's keys, keeping the most recently changed value
+    GetAll { filter: Option<Filter>, cluster: bool, arrow: bool },
+    GetLen { cluster: bool },
+    // A peer gathering a CLUSTER view: every local key with its modification time; gossip
+    // repair asks only for some digest buckets
+    Entries { buckets: Option<(usize, HashSet<usize>)>, since: Option<u64> },
+    // Key count and hash per bucket, for gossip (see gossip)
+    Digest { buckets: usize },
+    // With `lease`, the response says how long the value may be reused (see lease)
+    Get { key: String, lease: bool },
+    // The value a key had at `at` (Unix millis), from the append log and its archive
+    GetAsOf { key: String, at: u64 },
+    // Read a server-side snapshot, or a directory of snapshots and segments, and check every row
+    BackupVerify { path: String },
+    // What loading a server-side snapshot over the cache would change, without changing it
+    RestoreDryRun { path: String },
+    // Keys added, removed and changed from snapshot `from` to snapshot `to` (None: the live cache)
+    Diff { from: String, to: Option<String>, full: bool },
+    // GET from a peer's remote read fallback, answered from the local cache only
+    Lookup { key: String },
+    // With `sync`, answers once peers acknowledged the write
+    Set { key: String, value: CacheValue, sync: Option<SyncAck> },
+    Broadcast { key: String, value: CacheValue },
+    Scan { prefix: String, after: Option<String>, count: usize, filter: Option<Filter>, arrow: bool },
+    Aggregate {
+        aggregate: Aggregate,
+        path: Option<Vec<PathSegment>>,
+        prefix: String,
+        filter: Option<Filter>,
+        group_by: Option<String>,
+        history: Option<History>,
+    },
+    Import(ImportOptions),
+    Export { options: ExportOptions, filter: Option<Filter> },
+    JsonGet { key: String, path: Vec<PathSegment> },
+    JsonSet { key: String, path: Vec<PathSegment>, value: Value },
+    Incr { key: String, delta: i64 },
+    Append { key: String, suffix: String },
+    List(ListOp),
+    LRange { key: String, start: i64, stop: i64 },
+    Hash(HashOp),
+    HGetAll { key: String },
+    HGet { key: String, field: String },
+    XAdd { key: String, payload: String },
+    XRead { key: String, from: Option<u64>, consumer: Option<String>, count: usize },
+    // Only XCOMMIT; XADD_AT is a replication-only command
+    Stream(StreamOp),
+    XLen { key: String },
+    Zset(ZsetOp),
+    ZRangeByScore { key: String, min: f64, max: f64, with_scores: bool, limit: usize },
+    ZScore { key: String, member: String },
+    Delete(DeleteOp),
+    // FLUSHALL without arguments asks for a confirmation token
+    FlushRequest,
+    FlushConfirm { token: String, cluster: bool, snapshot: bool },
+    Replicate { command: String },
+    // Any other command under a correlation ID the client chose or the origin passed on,
+    // which names it in logs, the audit log, replication and SUBSCRIBE streams
+    Correlated { id: String, command: Box<Command> },
+    // A write answered with a read-your-writes token, or a read answered once this node has
+    // the writes the tokens name (see session.rs)
+    SessionWrite { command: Box<Command> },
+    SessionRead { tokens: Vec<SessionToken>, command: String },
+    // A replicated message or heartbeat stamped with its sender's cluster epoch (see membership.rs)
+    Epoch { epoch: u64, command: Box<Command> },
+    // A BROADCAST/REPLICATE numbered by its origin (see sequence.rs)
+    Sequenced { origin: String, boot: u64, seq: u64, message: String },
+    // Messages from..=to this node sent during run `boot`, for a peer that missed them
+    Resync { boot: u64, from: u64, to: u64 },
+    Type { key: String },
+    // Seconds left before a key expires (see storage::Cache::ttl)
+    Ttl { key: String },
+    // GET that also makes the key expire `ttl` seconds from now
+    GetEx { key: String, ttl: u64 },
+    // EXPIREAT key <unix seconds>, PERSIST key
+    Expiry(ExpiryOp),
+    // Approximate bytes held by a key, its value and metadata
+    MemoryUsage { key: String },
+    // Version, timestamp, size, origin and replicas of a key, for comparing nodes
+    DebugObject { key: String },
+    // The last `n` (default: all kept) changes to a key on this node, newest first
+    GetHist { key: String, n: Option<usize> },
+    // Key and value size histograms plus the largest values, for finding outsized keys
+    Sizes { prefix: String, top: usize },
+    CreateIndex { name: String, path: String },
+    DropIndex { name: String },
+    ListIndexes,
+    Find { index: String, value: String },
+    // Keeps the connection open and streams changes to keys under the prefix
+    Subscribe { prefix: String },
+    // Stream keys to a peer as Arrow IPC (see transfer)
+    Snapshot(SnapshotRequest),
+    // Copy a peer's keys into the local cache over SNAPSHOT
+    SyncFrom { peer: String, request: SnapshotRequest },
+    Peers,
+    // Put a peer in the peer list, or take one out, whatever discovery finds (see discovery::ManualPeers)
+    AddPeer { peer: String },
+    RemovePeer { peer: String },
+    // Replication health and circuit state per peer
+    PeerHealth,
+    // Peers banned so far and what is left of their bans (see health::PeerBans)
+    PeerBans,
+    // PING TIME, as heartbeats send it, also answers with this node's clock in Unix millis
+    Ping { time: bool },
+    // key:value lines about the node, e.g. outbox_dead:3
+    Stats,
+    // Per-operation latency histograms, in the Prometheus text format
+    Metrics,
+    // Outbox contents per peer (see outbox::Outboxes)
+    OutboxList,
+    OutboxDead { peer: String },
+    // Drop dead letters for one peer, or all of them
+    OutboxPurge { peer: Option<String> },
+    // Fold the append log into a fresh snapshot now, at most rows_per_sec snapshot rows a second
+    Compact { rows_per_sec: Option<u64> },
+    // This node and its peers in the order remote reads try them, with heartbeat round trips
+    ClusterStatus,
+    ClusterConflicts,
+    // Run a command on this node and every peer, reporting each node's result
+    ClusterExec { command: String },
+}
+
+impl Command {
+    // The protocol word a command is timed under (see metrics.rs)
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Command::GetAll { .. } => "GET_ALL",
+            Command::GetLen { .. } => "GET_LEN",
+            Command::Entries { .. } => "ENTRIES",
+            Command::Digest { .. } => "DIGEST",
+            Command::Get { .. } => "GET",
+            Command::GetAsOf { .. } => "GET_AS_OF",
+            Command::BackupVerify { .. } => "BACKUP",
+            Command::RestoreDryRun { .. } => "RESTORE",
+            Command::Diff { .. } => "DIFF",
+            Command::Lookup { .. } => "LOOKUP",
+            Command::Set { .. } => "SET",
+            Command::Broadcast { .. } => "BROADCAST",
+            Command::Scan { .. } => "SCAN",
+            Command::Aggregate { .. } => "AGG",
+            Command::Import(_) => "IMPORT",
+            Command::Export { .. } => "EXPORT",
+            Command::JsonGet { .. } => "JSON.GET",
+            Command::JsonSet { .. } => "JSON.SET",
+            Command::Incr { .. } => "INCR",
+            Command::Append { .. } => "APPEND",
+            Command::List(ListOp::Push { front: true, .. }) => "LPUSH",
+            Command::List(ListOp::Push { front: false, .. }) => "RPUSH",
+            Command::List(ListOp::Pop { front: true, .. }) => "LPOP",
+            Command::List(ListOp::Pop { front: false, .. }) => "RPOP",
+            Command::LRange { .. } => "LRANGE",
+            Command::Hash(HashOp::Set { .. }) => "HSET",
+            Command::Hash(HashOp::Del { .. }) => "HDEL",
+            Command::HGetAll { .. } => "HGETALL",
+            Command::HGet { .. } => "HGET",
+            Command::XAdd { .. } => "XADD",
+            Command::XRead { .. } => "XREAD",
+            Command::Stream(StreamOp::AddAt { .. }) => "XADD",
+            Command::Stream(StreamOp::Commit { .. }) => "XCOMMIT",
+            Command::XLen { .. } => "XLEN",
+            Command::Zset(ZsetOp::Add { .. }) => "ZADD",
+            Command::Zset(ZsetOp::Rem { .. }) => "ZREM",
+            Command::ZRangeByScore { .. } => "ZRANGEBYSCORE",
+            Command::ZScore { .. } => "ZSCORE",
+            Command::Delete(_) => "DEL",
+            Command::FlushRequest | Command::FlushConfirm { .. } => "FLUSHALL",
+            Command::Replicate { .. } => "REPLICATE",
+            Command::Correlated { command, .. } | Command::Epoch { command, .. } => command.name(),
+            Command::SessionWrite { command } => command.name(),
+            Command::SessionRead { .. } => "SESSION",
+            Command::Sequenced { .. } => "SEQ",
+            Command::Resync { .. } => "RESYNC",
+            Command::Type { .. } => "TYPE",
+            Command::Ttl { .. } => "TTL",
+            Command::GetEx { .. } => "GETEX",
+            Command::Expiry(ExpiryOp::At { .. }) => "EXPIREAT",
+            Command::Expiry(ExpiryOp::Persist { .. }) => "PERSIST",
+            Command::MemoryUsage { .. } => "MEMORY",
+            Command::DebugObject { .. } => "DEBUG",
+            Command::GetHist { .. } => "GETHIST",
+            Command::Sizes { .. } => "SIZES",
+            Command::CreateIndex { .. } => "CREATE_INDEX",
+            Command::DropIndex { .. } => "DROP_INDEX",
+            Command::ListIndexes => "LIST_INDEXES",
+            Command::Find { .. } => "FIND",
+            Command::Subscribe { .. } => "SUBSCRIBE",
+            Command::Snapshot(_) => "SNAPSHOT",
+            Command::SyncFrom { .. } => "SYNC",
+            Command::Peers | Command::PeerHealth | Command::PeerBans => "PEERS",
+            Command::AddPeer { .. } => "ADDPEER",
+            Command::RemovePeer { .. } => "REMOVEPEER",
+            Command::Ping { .. } => "PING",
+            Command::Stats => "STATS",
+            Command::Metrics => "METRICS",
+            Command::OutboxList | Command::OutboxDead { .. } | Command::OutboxPurge { .. } => "OUTBOX",
+            Command::Compact { .. } => "COMPACT",
+            Command::ClusterStatus | Command::ClusterConflicts | Command::ClusterExec { .. } => "CLUSTER",
+        }
+    }
+
+    // Client writes that replicate to peers when they succeed
+    pub(crate) fn replicates(&self) -> bool {
+        match self {
+            Command::Set { .. }
+            | Command::JsonSet { .. }
+            | Command::Incr { .. }
+            | Command::Append { .. }
+            | Command::List(_)
+            | Command::Hash(_)
+            | Command::XAdd { .. }
+            | Command::Stream(_)
+            | Command::Zset(_)
+            | Command::Delete(_)
+            | Command::GetEx { .. }
+            | Command::Expiry(_) => true,
+            Command::FlushConfirm { cluster, .. } => *cluster,
+            Command::SessionWrite { .. } => true,
+            Command::Import(options) => options.replicate,
+            _ => false,
+        }
+    }
+
+    // The key a single-key read answers from, which a tracking connection is told about
+    // when it changes (see serve_persistent)
+    pub(crate) fn read_key(&self) -> Option<String> {
+        match self {
+            Command::Get { key, .. }
+            | Command::JsonGet { key, .. }
+            | Command::LRange { key, .. }
+            | Command::HGetAll { key }
+            | Command::HGet { key, .. }
+            | Command::XLen { key }
+            | Command::XRead { key, .. }
+            | Command::ZRangeByScore { key, .. }
+            | Command::ZScore { key, .. }
+            | Command::GetEx { key, .. }
+            | Command::Type { key } => Some(key.clone()),
+            Command::Correlated { command, .. } => command.read_key(),
+            Command::SessionRead { command, .. } => parse_command(command).ok()?.read_key(),
+            _ => None,
+        }
+    }
+
+    // GET_ALL/SCAN .. FORMAT ARROW and EXPORT - answer with an Arrow IPC stream instead of text
+    pub(crate) fn streams_arrow(&self) -> bool {
+        match self {
+            Command::GetAll { arrow, .. } | Command::Scan { arrow, .. } => *arrow,
+            Command::Export { options, .. } => options.path == "-",
+            _ => false,
+        }
+    }
+}
+
+// What CLUSTER EXEC runs on each node: reads and node-local admin. Writes replicate on
+// their own and FLUSHALL has its own CLUSTER option. Arrow responses can't be merged into its report.
+fn runs_per_node(command: &Command) -> bool {
+    !command.streams_arrow() && matches!(
+        command,
+        Command::GetAll { cluster: false, .. }
+            | Command::GetLen { cluster: false }
+            | Command::Get { .. }
+            | Command::GetAsOf { .. }
+            | Command::BackupVerify { .. }
+            | Command::RestoreDryRun { .. }
+            | Command::Diff { .. }
+            | Command::Scan { .. }
+            | Command::Aggregate { .. }
+            | Command::Export { .. }
+            | Command::JsonGet { .. }
+            | Command::LRange { .. }
+            | Command::HGetAll { .. }
+            | Command::HGet { .. }
+            | Command::XLen { .. }
+            | Command::ZRangeByScore { .. }
+            | Command::ZScore { .. }
+            | Command::Type { .. }
+            | Command::Ttl { .. }
+            | Command::MemoryUsage { .. }
+            | Command::DebugObject { .. }
+            | Command::GetHist { .. }
+            | Command::Sizes { .. }
+            | Command::CreateIndex { .. }
+            | Command::DropIndex { .. }
+            | Command::ListIndexes
+            | Command::Find { .. }
+            | Command::Peers
+            | Command::AddPeer { .. }
+            | Command::RemovePeer { .. }
+            | Command::PeerHealth
+            | Command::PeerBans
+            | Command::ClusterStatus
+            | Command::ClusterConflicts
+            | Command::Ping { .. }
+            | Command::Stats
+            | Command::OutboxList
+            | Command::OutboxDead { .. }
+            | Command::OutboxPurge { .. }
+            | Command::Compact { .. }
+    )
+}
+
+// `FORMAT ARROW` or `FORMAT TEXT` (the default) among bulk read options; None if invalid
+fn arrow_format(options: &[&str]) -> Option<bool> {
+    let Some(at) = options.iter().position(|o| o.eq_ignore_ascii_case("FORMAT")) else {
+        return Some(false);
+    };
+    match options.get(at + 1).map(|f| f.to_uppercase()).as_deref() {
+        Some("ARROW") => Some(true),
+        Some("TEXT") => Some(false),
+        _ => None,
+    }
+}
+
+// Split a request into its command word and the (untrimmed) rest
+fn split_command(request: &str) -> (&str, &str) {
+    let request = request.trim();
+    match request.find(char::is_whitespace) {
+        Some(at) => (&request[..at], &request[at..]),
+        None => (request, ""),
+    }
+}
+
+// A trailing `SYNC [ALL|QUORUM] [TIMEOUT ms]` on SET. A string value whose last words read like
+// that needs an explicit TYPE=string after it.
+fn split_sync(args: &str) -> (&str, Option<SyncAck>) {
+    let args = args.trim_end();
+    for (at, _) in args.rmatch_indices(" SYNC") {
+        let options = &args[at + " SYNC".len()..];
+        if !options.is_empty() && !options.starts_with(char::is_whitespace) {
+            continue;
+        }
+        if let Ok(ack) = SyncAck::parse(options) {
+            return (&args[..at], Some(ack));
+        }
+    }
+    (args, None)
+}
+
+// A single non-empty key argument, e.g. GET key
+fn single_key(command: &str, args: &str) -> Result<String, String> {
+    let key = args.trim();
+    if key.is_empty() {
+        return Err(format!("Invalid {} command", command));
+    }
+    Ok(key.to_string())
+}
+
+// A single `<host>:<port>` peer address
+fn peer_addr(command: &str, args: &str) -> Result<String, String> {
+    let peer = args.trim();
+    match peer.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && !host.contains(char::is_whitespace) && port.parse::<u16>().is_ok_and(|p| p > 0) => {
+            Ok(peer.to_string())
+        }
+        _ => Err(format!("Invalid {} command", command)),
+    }
+}
+
+// `<key> <rest>` where rest is everything after the key, trimmed
+fn key_and_rest<'a>(command: &str, args: &'a str) -> Result<(&'a str, &'a str), String> {
+    args.trim()
+        .split_once(char::is_whitespace)
+        .map(|(key, rest)| (key, rest.trim()))
+        .ok_or_else(|| format!("Invalid {} command", command))
+}
+
+// Unix millis of a UTC time like 2024-05-01T12:00:00Z or of Unix seconds
+fn parse_time(time: &str) -> Option<u64> {
+    match time.parse::<u64>() {
+        Ok(seconds) => Some(seconds.saturating_mul(1000)),
+        Err(_) => parse_utc(time),
+    }
+}
+
+// Take `AS_OF <time>` or `OVER SNAPSHOTS [SINCE <time>]` out of COUNT/AGG arguments; None
+// for an invalid time
+fn split_history(args: &str) -> Option<(String, Option<History>)> {
+    let clause = |word: &str| match args.strip_prefix(word) {
+        Some(rest) => Some((0, rest)),
+        None => args.find(&format!(" {}", word)).map(|at| (at, &args[at + 1 + word.len()..])),
+    };
+    if let Some((at, rest)) = clause("AS_OF ") {
+        let (time, tail) = split_command(rest);
+        return Some((format!("{} {}", &args[..at], tail), Some(History::AsOf(parse_time(time)?))));
+    }
+    if let Some((at, rest)) = clause("OVER SNAPSHOTS") {
+        let (since, tail) = match rest.trim_start().strip_prefix("SINCE ") {
+            Some(rest) => {
+                let (time, tail) = split_command(rest);
+                (Some(parse_time(time)?), tail)
+            }
+            None => (None, rest),
+        };
+        return Some((format!("{} {}", &args[..at], tail), Some(History::Snapshots { since })));
+    }
+    Some((args.to_string(), None))
+}
+
+pub fn parse_command(request: &str) -> Result<Command, String> {
+    let (name, args) = split_command(request);
+    match name {
+        "GET_ALL" => {
+            // Optional server-side filter, e.g. GET_ALL WHERE $.category = 'books', and
+            // format, e.g. GET_ALL CLUSTER FORMAT ARROW
+            let (scope, filter) = split_where(args).map_err(|e| format!("Invalid filter: {}", e))?;
+            let scope: Vec<&str> = scope.split_whitespace().collect();
+            let arrow = arrow_format(&scope).ok_or("Invalid GET_ALL command")?;
+            Ok(Command::GetAll { filter, cluster: scope.first() == Some(&"CLUSTER"), arrow })
+        }
+        "GET_LEN" => Ok(Command::GetLen { cluster: args.trim() == "CLUSTER" }),
+        // ENTRIES, or ENTRIES BUCKETS <buckets> <i>.. for some digest buckets only
+        "ENTRIES" => match split_command(args) {
+            ("", _) => Ok(Command::Entries { buckets: None, since: None }),
+            ("BUCKETS", rest) => Ok(Command::Entries { buckets: Some(parse_buckets(rest).ok_or("Invalid ENTRIES command")?), since: None }),
+            ("SINCE", rest) => match rest.trim().parse() {
+                Ok(since) => Ok(Command::Entries { buckets: None, since: Some(since) }),
+                Err(_) => Err("Invalid ENTRIES command".to_string()),
+            },
+            _ => Err("Invalid ENTRIES command".to_string()),
+        },
+        "DIGEST" => match args.trim().parse() {
+            Ok(buckets) if (1..=MAX_BUCKETS).contains(&buckets) => Ok(Command::Digest { buckets }),
+            _ => Err("Invalid DIGEST command".to_string()),
+        },
+        "GET" => {
+            // e.g. GET order:1 AS_OF 2024-05-01T12:00:00Z, or a time in Unix seconds
+            if let Some((key, time)) = args.trim().split_once(" AS_OF ") {
+                let at = parse_time(time.trim()).ok_or("Invalid GET command")?;
+                return Ok(Command::GetAsOf { key: single_key(name, key)?, at });
+            }
+            match args.trim().strip_suffix(" LEASE") {
+                Some(key) => Ok(Command::Get { key: single_key(name, key)?, lease: true }),
+                None => Ok(Command::Get { key: single_key(name, args)?, lease: false }),
+            }
+        }
+        "LOOKUP" => Ok(Command::Lookup { key: single_key(name, args)? }),
+        "SET" => {
+            // e.g. SET order:1=paid SYNC QUORUM TIMEOUT 500
+            let (args, sync) = split_sync(args);
+            let (key, value) = parse_assignment(args).map_err(|e| format!("Invalid SET command: {}", e))?;
+            Ok(Command::Set { key, value, sync })
+        }
+        "BROADCAST" => {
+            let (key, value) = parse_assignment(args).map_err(|_| "Invalid BROADCAST command".to_string())?;
+            Ok(Command::Broadcast { key, value })
+        }
+        "SCAN" => {
+            // Page through keys in order, e.g. SCAN PREFIX user: AFTER user:42 COUNT 100 WHERE $.active = true,
+            // optionally as Arrow with FORMAT ARROW
+            let (options, filter) = split_where(args).map_err(|e| format!("Invalid filter: {}", e))?;
+            let options: Vec<&str> = options.split_whitespace().collect();
+            let option = |name: &str| {
+                options
+                    .iter()
+                    .position(|o| o.eq_ignore_ascii_case(name))
+                    .and_then(|i| options.get(i + 1).copied())
+            };
+            let count = option("COUNT")
+                .map(str::parse::<usize>)
+                .unwrap_or(Ok(usize::MAX))
+                .map_err(|_| "Invalid SCAN command".to_string())?;
+            Ok(Command::Scan {
+                prefix: option("PREFIX").unwrap_or("").to_string(),
+                after: option("AFTER").map(str::to_string),
+                count,
+                filter,
+                arrow: arrow_format(&options).ok_or("Invalid SCAN command")?,
+            })
+        }
+        "COUNT" | "AGG" => {
+            // Server-side aggregation, e.g. COUNT PREFIX user:, COUNT GROUP BY PREFIX :,
+            // AGG SUM $.amount PREFIX order: WHERE $.category = 'x', COUNT GROUP BY PREFIX : AS_OF <time>
+            let (args, history) = split_history(args).ok_or_else(|| format!("Invalid {} command", name))?;
+            let (args, group_by) = split_group_by(&args);
+            let (options, filter) = split_where(&args).map_err(|e| format!("Invalid filter: {}", e))?;
+            let mut options = options.split_whitespace();
+            let target = if name == "AGG" {
+                let function = match options.next().map(str::to_uppercase).as_deref() {
+                    Some("COUNT") => Some(Aggregate::Count),
+                    Some("SUM") => Some(Aggregate::Sum),
+                    Some("AVG") => Some(Aggregate::Avg),
+                    Some("MIN") => Some(Aggregate::Min),
+                    Some("MAX") => Some(Aggregate::Max),
+                    _ => None,
+                };
+                function.zip(options.next().and_then(parse_json_path).map(Some))
+            } else {
+                Some((Aggregate::Count, None))
+            };
+            let prefix = match (options.next(), options.next()) {
+                (Some("PREFIX"), Some(prefix)) => Some(prefix),
+                (None, _) => Some(""),
+                _ => None,
+            };
+            match (target, prefix) {
+                (Some((aggregate, path)), Some(prefix)) => Ok(Command::Aggregate {
+                    aggregate,
+                    path,
+                    prefix: prefix.to_string(),
+                    filter,
+                    group_by,
+                    history,
+                }),
+                _ => Err(format!("Invalid {} command", name)),
+            }
+        }
+        "IMPORT" => {
+            // Bulk load a server-side file, e.g. IMPORT /data/seed.csv --format csv --batch-size 5000
+            parse_import_args(args)
+                .map(Command::Import)
+                .map_err(|e| format!("Invalid IMPORT command: {}", e))
+        }
+        "BACKUP" => match args.trim().split_once(char::is_whitespace) {
+            // e.g. BACKUP VERIFY node_8080_cache.arrow, or a directory like node_8080_cache.archive
+            Some(("VERIFY", path)) if !path.trim().is_empty() => Ok(Command::BackupVerify { path: path.trim().to_string() }),
+            _ => Err("Invalid BACKUP command".to_string()),
+        },
+        // e.g. DIFF node_8080_cache.arrow LIVE FULL
+        "DIFF" => match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [from, to, rest @ ..] if matches!(rest, [] | ["FULL"]) => Ok(Command::Diff {
+                from: from.to_string(),
+                to: (*to != "LIVE").then(|| to.to_string()),
+                full: !rest.is_empty(),
+            }),
+            _ => Err("Invalid DIFF command".to_string()),
+        },
+        // Only a dry run for now: a backup is loaded with IMPORT <path> --format arrow
+        "RESTORE" => match args.trim().strip_suffix("--dry-run").map(str::trim) {
+            Some(path) if !path.is_empty() => Ok(Command::RestoreDryRun { path: path.to_string() }),
+            _ => Err("Invalid RESTORE command: only RESTORE <path> --dry-run is supported; load a backup with IMPORT <path> --format arrow".to_string()),
+        },
+        "EXPORT" => {
+            // Dump a subset of the cache to a server-side file, e.g.
+            // EXPORT /data/users.parquet --prefix user: WHERE $.active = true, or to the client with EXPORT -
+            split_where(args)
+                .and_then(|(args, filter)| Ok((parse_export_args(args)?, filter)))
+                .map(|(options, filter)| Command::Export { options, filter })
+                .map_err(|e| format!("Invalid EXPORT command: {}", e))
+        }
+        "JSON.GET" => {
+            // Read part of a JSON document, e.g. JSON.GET user:1 $.address.city
+            let mut args = args.split_whitespace();
+            let key = args.next().ok_or("Invalid JSON.GET command")?;
+            let path = args.next().unwrap_or("$");
+            let segments = parse_json_path(path).ok_or_else(|| format!("Invalid JSON path: {}", path))?;
+            Ok(Command::JsonGet { key: key.to_string(), path: segments })
+        }
+        "JSON.SET" => {
+            // Update part of a JSON document, e.g. JSON.SET user:1 $.address.city "Prague"
+            let mut args = args.trim().splitn(3, char::is_whitespace);
+            let (Some(key), Some(path), Some(raw_value)) = (args.next(), args.next(), args.next()) else {
+                return Err("Invalid JSON.SET command".to_string());
+            };
+            let segments = parse_json_path(path).ok_or_else(|| format!("Invalid JSON path: {}", path))?;
+            let value = serde_json::from_str::<Value>(raw_value.trim()).map_err(|_| "Invalid JSON value".to_string())?;
+            Ok(Command::JsonSet { key: key.to_string(), path: segments, value })
+        }
+        "INCR" => {
+            // Increment an integer value, e.g. INCR counter or INCR counter 5
+            let mut args = args.split_whitespace();
+            let key = args.next().ok_or("Invalid INCR command")?;
+            let delta = args
+                .next()
+                .map(str::parse::<i64>)
+                .unwrap_or(Ok(1))
+                .map_err(|_| "Invalid INCR command".to_string())?;
+            Ok(Command::Incr { key: key.to_string(), delta })
+        }
+        "APPEND" => {
+            // Append to a string (or hex-encoded bytes to a bytes value), e.g. APPEND log line
+            let (key, suffix) = args.trim().split_once(char::is_whitespace).ok_or("Invalid APPEND command")?;
+            Ok(Command::Append { key: key.to_string(), suffix: suffix.to_string() })
+        }
+        "LPUSH" | "RPUSH" | "LPOP" | "RPOP" => parse_list_op(name, args).map(Command::List),
+        "LRANGE" => {
+            // Read a slice of a list, e.g. LRANGE jobs 0 -1
+            let args: Vec<&str> = args.split_whitespace().collect();
+            match (args.as_slice(), args.get(1).and_then(|s| s.parse().ok()), args.get(2).and_then(|s| s.parse().ok())) {
+                ([key, _, _], Some(start), Some(stop)) => Ok(Command::LRange { key: key.to_string(), start, stop }),
+                _ => Err("Invalid LRANGE command".to_string()),
+            }
+        }
+        "HSET" | "HDEL" => parse_hash_op(name, args).map(Command::Hash),
+        "HGETALL" => Ok(Command::HGetAll { key: single_key(name, args)? }),
+        "HGET" => {
+            let mut args = args.split_whitespace();
+            match (args.next(), args.next()) {
+                (Some(key), Some(field)) => Ok(Command::HGet { key: key.to_string(), field: field.to_string() }),
+                _ => Err("Invalid HGET command".to_string()),
+            }
+        }
+        "XADD" => {
+            // Append to a stream, e.g. XADD orders {"id":7}; responds with the entry ID
+            let (key, payload) = key_and_rest(name, args)?;
+            Ok(Command::XAdd { key: key.to_string(), payload: payload.to_string() })
+        }
+        "XREAD" => {
+            // Read entries from an offset or a consumer's committed offset, e.g.
+            // XREAD orders from=10 count=100, XREAD orders consumer=billing
+            let mut args = args.split_whitespace();
+            let key = args.next().ok_or("Invalid XREAD command")?;
+            let (mut from, mut consumer, mut count) = (None, None, usize::MAX);
+            for option in args {
+                match option.split_once('=') {
+                    Some(("from", v)) if v.parse::<u64>().is_ok() => from = v.parse().ok(),
+                    Some(("count", v)) if v.parse::<usize>().is_ok() => count = v.parse().unwrap_or(count),
+                    Some(("consumer", v)) => consumer = Some(v.to_string()),
+                    _ => return Err("Invalid XREAD command".to_string()),
+                }
+            }
+            Ok(Command::XRead { key: key.to_string(), from, consumer, count })
+        }
+        "XCOMMIT" => {
+            // Record the next offset a consumer should read from, e.g. XCOMMIT orders billing 11
+            parse_stream_op(name, args).map(Command::Stream)
+        }
+        "XLEN" => Ok(Command::XLen { key: single_key(name, args)? }),
+        "ZADD" | "ZREM" => parse_zset_op(name, args).map(Command::Zset),
+        "ZRANGEBYSCORE" => {
+            // Members with min <= score <= max in score order, e.g. ZRANGEBYSCORE leaderboard 10 +inf WITHSCORES LIMIT 10
+            let args: Vec<&str> = args.split_whitespace().collect();
+            let with_scores = args.contains(&"WITHSCORES");
+            let limit = match args.iter().position(|a| *a == "LIMIT") {
+                Some(i) => args.get(i + 1).and_then(|n| n.parse::<usize>().ok()),
+                None => Some(usize::MAX),
+            };
+            match (args.first(), args.get(1).and_then(|s| parse_score(s)), args.get(2).and_then(|s| parse_score(s)), limit) {
+                (Some(key), Some(min), Some(max), Some(limit)) => Ok(Command::ZRangeByScore {
+                    key: key.to_string(),
+                    min,
+                    max,
+                    with_scores,
+                    limit,
+                }),
+                _ => Err("Invalid ZRANGEBYSCORE command".to_string()),
+            }
+        }
+        "ZSCORE" => {
+            let (key, member) = key_and_rest(name, args)?;
+            Ok(Command::ZScore { key: key.to_string(), member: member.to_string() })
+        }
+        "DEL" | "DEL_PREFIX" | "DEL_MATCH" => parse_delete_op(name, args).map(Command::Delete),
+        "FLUSHALL" => {
+            // Two-step flush: FLUSHALL issues a token, FLUSHALL <token> [CLUSTER] [SNAPSHOT] executes it
+            let mut args = args.split_whitespace();
+            let Some(token) = args.next() else {
+                return Ok(Command::FlushRequest);
+            };
+            let (mut cluster, mut snapshot) = (false, false);
+            for option in args {
+                match option {
+                    "CLUSTER" => cluster = true,
+                    "SNAPSHOT" => snapshot = true,
+                    _ => return Err("Invalid FLUSHALL command".to_string()),
+                }
+            }
+            Ok(Command::FlushConfirm { token: token.to_string(), cluster, snapshot })
+        }
+        "REPLICATE" => Ok(Command::Replicate { command: args.trim().to_string() }),
+        // CID <id> <command>
+        "CID" => {
+            let (id, command) = split_command(args);
+            if id.is_empty() {
+                return Err("Invalid CID command".to_string());
+            }
+            match parse_command(command)? {
+                Command::Correlated { .. } => Err("Invalid CID command".to_string()),
+                command => Ok(Command::Correlated { id: id.to_string(), command: Box::new(command) }),
+            }
+        }
+        // SESSION <write> or SESSION <token>[,<token>..] <read>
+        "SESSION" => {
+            let (first, rest) = split_command(args);
+            if !first.contains('/') {
+                return match parse_command(args)? {
+                    command if command.replicates() => Ok(Command::SessionWrite { command: Box::new(command) }),
+                    _ => Err("SESSION without tokens only runs writes".to_string()),
+                };
+            }
+            let tokens = first.split(',').map(SessionToken::parse).collect::<Option<Vec<_>>>().ok_or("Invalid SESSION command")?;
+            match parse_command(rest)? {
+                command if command.replicates() => Err("SESSION with tokens only runs reads".to_string()),
+                command if command.streams_arrow() => Err("Invalid SESSION command".to_string()),
+                Command::SessionWrite { .. } | Command::SessionRead { .. } | Command::Subscribe { .. } | Command::Snapshot(_) => {
+                    Err("Invalid SESSION command".to_string())
+                }
+                _ => Ok(Command::SessionRead { tokens, command: rest.trim().to_string() }),
+            }
+        }
+        // EPOCH <epoch> <command>
+        "EPOCH" => {
+            let (epoch, command) = split_command(args);
+            let epoch = epoch.parse().map_err(|_| "Invalid EPOCH command".to_string())?;
+            match parse_command(command)? {
+                Command::Epoch { .. } => Err("Invalid EPOCH command".to_string()),
+                command => Ok(Command::Epoch { epoch, command: Box::new(command) }),
+            }
+        }
+        // SEQ <origin> <boot> <n> <message>
+        "SEQ" => {
+            let invalid = || "Invalid SEQ command".to_string();
+            let (origin, rest) = split_command(args);
+            let (boot, rest) = split_command(rest);
+            let (seq, message) = split_command(rest);
+            if origin.is_empty() || message.trim().is_empty() {
+                return Err(invalid());
+            }
+            Ok(Command::Sequenced {
+                origin: origin.to_string(),
+                boot: boot.parse().map_err(|_| invalid())?,
+                seq: seq.parse().map_err(|_| invalid())?,
+                message: message.trim().to_string(),
+            })
+        }
+        "RESYNC" => match args.split_whitespace().map(str::parse).collect::<Result<Vec<u64>, _>>().as_deref() {
+            Ok([boot, from, to]) if from <= to => Ok(Command::Resync { boot: *boot, from: *from, to: *to }),
+            _ => Err("Invalid RESYNC command".to_string()),
+        },
+        "TYPE" => Ok(Command::Type { key: single_key(name, args)? }),
+        "TTL" => Ok(Command::Ttl { key: single_key(name, args)? }),
+        // e.g. EXPIREAT session:1 1798761600, in Unix seconds
+        "EXPIREAT" => {
+            let (key, at) = key_and_rest(name, args)?;
+            let at: u64 = at.parse().map_err(|_| "Invalid EXPIREAT command")?;
+            Ok(Command::Expiry(ExpiryOp::At { key: key.to_string(), at: at.saturating_mul(1000) }))
+        }
+        "GETEX" => {
+            // e.g. GETEX session:1 TTL=1800
+            let (key, rest) = key_and_rest(name, args)?;
+            match rest.strip_prefix("TTL=").map(str::parse) {
+                Some(Ok(ttl)) if ttl > 0 => Ok(Command::GetEx { key: key.to_string(), ttl }),
+                _ => Err("Invalid GETEX command".to_string()),
+            }
+        }
+        // As the first line of a connection, PERSIST [TRACKING] keeps it open instead (see handle_connection),
+        // so PERSIST TRACKING only reaches here on a connection that is already persistent
+        "PERSIST" => Ok(Command::Expiry(ExpiryOp::Persist { key: single_key(name, args)? })),
+        "SIZES" => {
+            // e.g. SIZES PREFIX user: TOP 20
+            let (mut prefix, mut top) = (String::new(), 10);
+            let mut words = args.split_whitespace();
+            while let Some(option) = words.next() {
+                match (option, words.next()) {
+                    ("PREFIX", Some(value)) => prefix = value.to_string(),
+                    ("TOP", Some(value)) => top = value.parse().map_err(|_| "Invalid SIZES command".to_string())?,
+                    _ => return Err("Invalid SIZES command".to_string()),
+                }
+            }
+            Ok(Command::Sizes { prefix, top })
+        }
+        "MEMORY" => match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["USAGE", key] => Ok(Command::MemoryUsage { key: key.to_string() }),
+            _ => Err("Invalid MEMORY command".to_string()),
+        },
+        "DEBUG" => match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["OBJECT", key] => Ok(Command::DebugObject { key: key.to_string() }),
+            _ => Err("Invalid DEBUG command".to_string()),
+        },
+        "GETHIST" => match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [key] => Ok(Command::GetHist { key: key.to_string(), n: None }),
+            [key, n] => match n.parse() {
+                Ok(n) if n > 0 => Ok(Command::GetHist { key: key.to_string(), n: Some(n) }),
+                _ => Err("Invalid GETHIST command".to_string()),
+            },
+            _ => Err("Invalid GETHIST command".to_string()),
+        },
+        "CREATE_INDEX" => {
+            // Declare a secondary index over a JSON path, e.g. CREATE_INDEX owner $.owner
+            match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [name, path] => Ok(Command::CreateIndex { name: name.to_string(), path: path.to_string() }),
+                _ => Err("Invalid CREATE_INDEX command".to_string()),
+            }
+        }
+        "DROP_INDEX" => Ok(Command::DropIndex { name: single_key(name, args)? }),
+        "LIST_INDEXES" => Ok(Command::ListIndexes),
+        "FIND" => {
+            // Look up keys through a secondary index, e.g. FIND owner=alice
+            let (index, value) = args.split_once('=').ok_or("Invalid FIND command")?;
+            Ok(Command::Find { index: index.trim().to_string(), value: value.trim().to_string() })
+        }
+        // Watch keys as they change, e.g. SUBSCRIBE user: (no prefix watches every key)
+        "SUBSCRIBE" => Ok(Command::Subscribe { prefix: args.trim().to_string() }),
+        "SNAPSHOT" => SnapshotRequest::parse(args)
+            .map(Command::Snapshot)
+            .map_err(|e| format!("Invalid SNAPSHOT command: {}", e)),
+        "SYNC" => {
+            // e.g. SYNC FROM 127.0.0.1:8081 PREFIX user: RATE 5000
+            let mut words = args.trim().splitn(3, char::is_whitespace);
+            let (Some("FROM"), Some(peer)) = (words.next(), words.next()) else {
+                return Err("Invalid SYNC command".to_string());
+            };
+            let request = SnapshotRequest::parse(words.next().unwrap_or("")).map_err(|e| format!("Invalid SYNC command: {}", e))?;
+            Ok(Command::SyncFrom { peer: peer.to_string(), request })
+        }
+        "PEERS" => match args.trim() {
+            "" => Ok(Command::Peers),
+            "HEALTH" => Ok(Command::PeerHealth),
+            "BANS" => Ok(Command::PeerBans),
+            _ => Err("Invalid PEERS command".to_string()),
+        },
+        "ADDPEER" => Ok(Command::AddPeer { peer: peer_addr(name, args)? }),
+        "REMOVEPEER" => Ok(Command::RemovePeer { peer: peer_addr(name, args)? }),
+        // Anything else after PING is ignored, as nodes before PING TIME did
+        "PING" => Ok(Command::Ping { time: args.trim() == "TIME" }),
+        "STATS" => Ok(Command::Stats),
+        "METRICS" => Ok(Command::Metrics),
+        // OUTBOX, OUTBOX DEAD <peer>, OUTBOX PURGE <peer>|ALL
+        "OUTBOX" => match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => Ok(Command::OutboxList),
+            ["DEAD", peer] => Ok(Command::OutboxDead { peer: peer.to_string() }),
+            ["PURGE", "ALL"] => Ok(Command::OutboxPurge { peer: None }),
+            ["PURGE", peer] => Ok(Command::OutboxPurge { peer: Some(peer.to_string()) }),
+            _ => Err("Invalid OUTBOX command".to_string()),
+        },
+        // COMPACT [RATE rows_per_s]
+        "COMPACT" => match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => Ok(Command::Compact { rows_per_sec: None }),
+            [rate, n] if rate.eq_ignore_ascii_case("RATE") => match n.parse() {
+                Ok(n) if n > 0 => Ok(Command::Compact { rows_per_sec: Some(n) }),
+                _ => Err("Invalid COMPACT command".to_string()),
+            },
+            _ => Err("Invalid COMPACT command".to_string()),
+        },
+        "CLUSTER" => {
+            let (sub, command) = split_command(args);
+            if sub == "STATUS" && command.trim().is_empty() {
+                return Ok(Command::ClusterStatus);
+            }
+            if sub == "CONFLICTS" && command.trim().is_empty() {
+                return Ok(Command::ClusterConflicts);
+            }
+            if sub != "EXEC" || command.trim().is_empty() {
+                return Err("Invalid CLUSTER command".to_string());
+            }
+            let (inner, _) = split_command(command);
+            if !runs_per_node(&parse_command(command)?) {
+                return Err(format!("CLUSTER EXEC does not run {}", inner));
+            }
+            Ok(Command::ClusterExec { command: command.trim().to_string() })
+        }
+        _ => Err("Unknown command".to_string()),
+    }
+}
+
+// Parse the operation carried by `REPLICATE <command>`
+pub fn parse_replicated(command: &str) -> Result<ReplicatedOp, String> {
+    let (name, args) = split_command(command);
+    match name {
+        "LPUSH" | "RPUSH" | "LPOP" | "RPOP" => parse_list_op(name, args).map(ReplicatedOp::List),
+        "HSET" | "HDEL" => parse_hash_op(name, args).map(ReplicatedOp::Hash),
+        "XADD_AT" | "XCOMMIT" => parse_stream_op(name, args).map(ReplicatedOp::Stream),
+        "ZADD" | "ZREM" => parse_zset_op(name, args).map(ReplicatedOp::Zset),
+        "DEL" | "DEL_PREFIX" | "DEL_MATCH" => parse_delete_op(name, args).map(ReplicatedOp::Delete),
+        "EXPIREAT" => {
+            let (key, at) = key_and_rest(name, args)?;
+            let at = at.parse().map_err(|_| "Invalid EXPIREAT command")?;
+            Ok(ReplicatedOp::Expiry(ExpiryOp::At { key: key.to_string(), at }))
+        }
+        "PERSIST" => Ok(ReplicatedOp::Expiry(ExpiryOp::Persist { key: single_key(name, args)? })),
+        "FLUSHALL" => match args.trim() {
+            "" => Ok(ReplicatedOp::FlushAll { snapshot: false }),
+            "SNAPSHOT" => Ok(ReplicatedOp::FlushAll { snapshot: true }),
+            _ => Err("Invalid FLUSHALL command".to_string()),
+        },
+        _ => Err(format!("Unsupported replicated command: {}", command.trim())),
+    }
+}
+
+fn parse_list_op(name: &str, args: &str) -> Result<ListOp, String> {
+    let front = name.starts_with('L');
+    match name {
+        "LPUSH" | "RPUSH" => {
+            // Push a single element, e.g. RPUSH jobs {"id":1}
+            let (key, value) = key_and_rest(name, args)?;
+            Ok(ListOp::Push { key: key.to_string(), value: value.to_string(), front })
+        }
+        _ => Ok(ListOp::Pop { key: single_key(name, args)?, front }),
+    }
+}
+
+fn parse_hash_op(name: &str, args: &str) -> Result<HashOp, String> {
+    // Update or remove a single field, e.g. HSET user:1 name=Alice, HDEL user:1 name
+    let (key, rest) = key_and_rest(name, args)?;
+    match name {
+        "HSET" => {
+            let (field, value) = rest.split_once('=').ok_or("Invalid HSET command")?;
+            Ok(HashOp::Set {
+                key: key.to_string(),
+                field: field.trim().to_string(),
+                value: value.trim().to_string(),
+            })
+        }
+        _ => Ok(HashOp::Del { key: key.to_string(), field: rest.to_string() }),
+    }
+}
+
+fn parse_stream_op(name: &str, args: &str) -> Result<StreamOp, String> {
+    let mut parts = args.trim().splitn(3, char::is_whitespace);
+    let (Some(key), Some(second), Some(third)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(format!("Invalid {} command", name));
+    };
+    match name {
+        "XADD_AT" => Ok(StreamOp::AddAt {
+            key: key.to_string(),
+            id: second.parse().map_err(|_| "Invalid XADD_AT id".to_string())?,
+            payload: third.trim().to_string(),
+        }),
+        _ => Ok(StreamOp::Commit {
+            key: key.to_string(),
+            consumer: second.to_string(),
+            offset: third.trim().parse().map_err(|_| "Invalid XCOMMIT offset".to_string())?,
+        }),
+    }
+}
+
+fn parse_zset_op(name: &str, args: &str) -> Result<ZsetOp, String> {
+    // Add/update or remove a member, e.g. ZADD leaderboard 42.5 alice, ZREM leaderboard alice
+    let (key, rest) = key_and_rest(name, args)?;
+    match name {
+        "ZADD" => {
+            let (score, member) = rest.split_once(char::is_whitespace).ok_or("Invalid ZADD command")?;
+            let score = parse_score(score).ok_or_else(|| format!("Invalid score: {}", score))?;
+            Ok(ZsetOp::Add { key: key.to_string(), score, member: member.trim().to_string() })
+        }
+        _ => Ok(ZsetOp::Rem { key: key.to_string(), member: rest.to_string() }),
+    }
+}
+
+fn parse_delete_op(name: &str, args: &str) -> Result<DeleteOp, String> {
+    // Delete one key, a prefix or a glob pattern, e.g. DEL user:1, DEL_PREFIX session:, DEL_MATCH user:*:tmp
+    let target = single_key(name, args)?;
+    match name {
+        "DEL" => Ok(DeleteOp::Key(target)),
+        "DEL_PREFIX" => Ok(DeleteOp::Prefix(target)),
+        _ => Ok(DeleteOp::Match(target)),
+    }
+}
+
+// Canonical command text, as sent to peers in REPLICATE
+impl fmt::Display for ListOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListOp::Push { key, value, front } => write!(f, "{} {} {}", if *front { "LPUSH" } else { "RPUSH" }, key, value),
+            ListOp::Pop { key, front } => write!(f, "{} {}", if *front { "LPOP" } else { "RPOP" }, key),
+        }
+    }
+}
+
+impl fmt::Display for HashOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashOp::Set { key, field, value } => write!(f, "HSET {} {}={}", key, field, value),
+            HashOp::Del { key, field } => write!(f, "HDEL {} {}", key, field),
+        }
+    }
+}
+
+impl fmt::Display for StreamOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamOp::AddAt { key, id, payload } => write!(f, "XADD_AT {} {} {}", key, id, payload),
+            StreamOp::Commit { key, consumer, offset } => write!(f, "XCOMMIT {} {} {}", key, consumer, offset),
+        }
+    }
+}
+
+impl fmt::Display for ZsetOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZsetOp::Add { key, score, member } => write!(f, "ZADD {} {} {}", key, score, member),
+            ZsetOp::Rem { key, member } => write!(f, "ZREM {} {}", key, member),
+        }
+    }
+}
+
+impl fmt::Display for DeleteOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeleteOp::Key(key) => write!(f, "DEL {}", key),
+            DeleteOp::Prefix(prefix) => write!(f, "DEL_PREFIX {}", prefix),
+            DeleteOp::Match(pattern) => write!(f, "DEL_MATCH {}", pattern),
+        }
+    }
+}
+
+impl fmt::Display for ExpiryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpiryOp::At { key, at } => write!(f, "EXPIREAT {} {}", key, at),
+            ExpiryOp::Persist { key } => write!(f, "PERSIST {}", key),
+        }
+    }
+}
+
+impl fmt::Display for ReplicatedOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicatedOp::List(op) => op.fmt(f),
+            ReplicatedOp::Hash(op) => op.fmt(f),
+            ReplicatedOp::Stream(op) => op.fmt(f),
+            ReplicatedOp::Zset(op) => op.fmt(f),
+            ReplicatedOp::Delete(op) => op.fmt(f),
+            ReplicatedOp::Expiry(op) => op.fmt(f),
+            ReplicatedOp::FlushAll { snapshot: false } => write!(f, "FLUSHALL"),
+            ReplicatedOp::FlushAll { snapshot: true } => write!(f, "FLUSHALL SNAPSHOT"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(request: &str) -> String {
+        match parse_command(request) {
+            Err(e) => e,
+            Ok(command) => panic!("{} parsed as {}", request, command.name()),
+        }
+    }
+
+    #[test]
+    fn parses_get_and_set() {
+        assert!(matches!(parse_command("GET user:1"), Ok(Command::Get { key, lease: false }) if key == "user:1"));
+        assert!(matches!(parse_command("GET user:1 LEASE"), Ok(Command::Get { key, lease: true }) if key == "user:1"));
+        assert!(matches!(
+            parse_command("GET order:1 AS_OF 1714564800"),
+            Ok(Command::GetAsOf { key, at: 1_714_564_800_000 }) if key == "order:1"
+        ));
+        assert!(matches!(
+            parse_command("SET counter=42 TYPE=int"),
+            Ok(Command::Set { key, value: CacheValue::Int(42), sync: None }) if key == "counter"
+        ));
+        assert!(matches!(parse_command("SET order:1=paid SYNC"), Ok(Command::Set { sync: Some(_), .. })));
+        assert_eq!(error("GET"), "Invalid GET command");
+        assert_eq!(error("NOT_A_COMMAND with some arguments"), "Unknown command");
+    }
+
+    #[test]
+    fn parses_scan_options() {
+        let Ok(Command::Scan { prefix, after, count, filter, arrow }) =
+            parse_command("SCAN PREFIX user: AFTER user:42 COUNT 100 WHERE $.active = true")
+        else {
+            panic!("SCAN did not parse");
+        };
+        assert_eq!((prefix.as_str(), after.as_deref(), count, arrow), ("user:", Some("user:42"), 100, false));
+        assert_eq!(filter.map(|f| f.conditions.len()), Some(1));
+        assert!(matches!(parse_command("SCAN"), Ok(Command::Scan { count: usize::MAX, .. })));
+        assert_eq!(error("SCAN COUNT many"), "Invalid SCAN command");
+    }
+
+    #[test]
+    fn parses_aggregations() {
+        assert!(matches!(
+            parse_command("COUNT PREFIX user:"),
+            Ok(Command::Aggregate { aggregate: Aggregate::Count, path: None, prefix, group_by: None, history: None, .. })
+                if prefix == "user:"
+        ));
+        assert!(matches!(
+            parse_command("AGG SUM $.amount PREFIX order: GROUP BY PREFIX :"),
+            Ok(Command::Aggregate { aggregate: Aggregate::Sum, path: Some(_), prefix, group_by: Some(d), .. })
+                if prefix == "order:" && d == ":"
+        ));
+        assert!(matches!(
+            parse_command("COUNT GROUP BY PREFIX : OVER SNAPSHOTS SINCE 1714564800"),
+            Ok(Command::Aggregate { history: Some(History::Snapshots { since: Some(1_714_564_800_000) }), group_by: Some(_), .. })
+        ));
+        assert!(matches!(
+            parse_command("COUNT AS_OF 1714564800 PREFIX user:"),
+            Ok(Command::Aggregate { history: Some(History::AsOf(1_714_564_800_000)), prefix, .. }) if prefix == "user:"
+        ));
+        assert_eq!(error("AGG MEDIAN $.amount"), "Invalid AGG command");
+        assert_eq!(error("COUNT AS_OF yesterday"), "Invalid COUNT command");
+    }
+
+    #[test]
+    fn parses_expiry_commands() {
+        assert!(matches!(
+            parse_command("EXPIREAT session:1 1798761600"),
+            Ok(Command::Expiry(ExpiryOp::At { key, at: 1_798_761_600_000 })) if key == "session:1"
+        ));
+        assert!(matches!(parse_command("GETEX session:1 TTL=1800"), Ok(Command::GetEx { ttl: 1800, .. })));
+        assert!(matches!(parse_command("PERSIST session:1"), Ok(Command::Expiry(ExpiryOp::Persist { key })) if key == "session:1"));
+        assert_eq!(error("GETEX session:1 TTL=0"), "Invalid GETEX command");
+        assert_eq!(error("GETEX session:1"), "Invalid GETEX command");
+        assert_eq!(error("EXPIREAT session:1 tomorrow"), "Invalid EXPIREAT command");
+    }
+
+    #[test]
+    fn parses_wrapped_commands() {
+        let Ok(Command::Correlated { id, command }) = parse_command("CID trace-42 SET key1=v") else {
+            panic!("CID did not parse");
+        };
+        assert_eq!((id.as_str(), command.name()), ("trace-42", "SET"));
+        assert!(matches!(
+            parse_command("SEQ 127.0.0.1:8080 7 42 BROADCAST key1=v"),
+            Ok(Command::Sequenced { origin, boot: 7, seq: 42, message }) if origin == "127.0.0.1:8080" && message == "BROADCAST key1=v"
+        ));
+        assert!(matches!(parse_command("RESYNC 7 1 5"), Ok(Command::Resync { boot: 7, from: 1, to: 5 })));
+        assert_eq!(error("RESYNC 7 5 1"), "Invalid RESYNC command");
+    }
+
+    #[test]
+    fn parses_replicated_ops() {
+        assert_eq!(
+            parse_replicated("RPUSH jobs {\"id\":1}"),
+            Ok(ReplicatedOp::List(ListOp::Push { key: "jobs".to_string(), value: "{\"id\":1}".to_string(), front: false }))
+        );
+        assert_eq!(parse_replicated("LPOP jobs"), Ok(ReplicatedOp::List(ListOp::Pop { key: "jobs".to_string(), front: true })));
+        assert_eq!(
+            parse_replicated("HSET user:1 name=Alice"),
+            Ok(ReplicatedOp::Hash(HashOp::Set { key: "user:1".to_string(), field: "name".to_string(), value: "Alice".to_string() }))
+        );
+        assert_eq!(parse_replicated("DEL_PREFIX session:"), Ok(ReplicatedOp::Delete(DeleteOp::Prefix("session:".to_string()))));
+        // Replicated expiry times are already in Unix millis
+        assert_eq!(
+            parse_replicated("EXPIREAT session:1 1798761600000"),
+            Ok(ReplicatedOp::Expiry(ExpiryOp::At { key: "session:1".to_string(), at: 1_798_761_600_000 }))
+        );
+        assert_eq!(parse_replicated("FLUSHALL SNAPSHOT"), Ok(ReplicatedOp::FlushAll { snapshot: true }));
+        assert_eq!(parse_replicated("INCR counter 1"), Err("Unsupported replicated command: INCR counter 1".to_string()));
+    }
+
+    #[test]
+    fn cluster_exec_runs_only_per_node_commands() {
+        assert!(matches!(parse_command("CLUSTER EXEC CREATE_INDEX owner $.owner"), Ok(Command::ClusterExec { .. })));
+        assert_eq!(error("CLUSTER EXEC SET key1=v"), "CLUSTER EXEC does not run SET");
+        assert_eq!(error("CLUSTER"), "Invalid CLUSTER command");
+    }
+}
@@ -0,0 +1,249 @@
+//! WHERE filters and key glob matching
+
+use serde_json::Value;
+
+use crate::storage::json::{json_path_lookup, parse_json_path, value_as_json, PathSegment};
+use crate::storage::CacheValue;
+
+pub(crate) enum Operand {
+    Key,
+    Value,
+    Path(Vec<PathSegment>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+pub(crate) struct Condition {
+    pub(crate) operand: Operand,
+    pub(crate) comparison: Comparison,
+    pub(crate) literal: String,
+}
+
+// Server-side filter such as `$.category = 'books' AND value CONTAINS sale`
+pub struct Filter {
+    pub(crate) conditions: Vec<Condition>,
+}
+
+// Split a filter expression into words, quoted literals and comparison operators
+pub(crate) fn tokenize_filter(expr: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let literal: String = chars.by_ref().take_while(|&ch| ch != c).collect();
+            tokens.push(literal);
+        } else if "=!<>".contains(c) {
+            let mut op = String::new();
+            while let Some(&ch) = chars.peek().filter(|ch| "=!<>".contains(**ch)) {
+                op.push(ch);
+                chars.next();
+            }
+            tokens.push(op);
+        } else {
+            let mut word = String::new();
+            while let Some(&ch) = chars.peek().filter(|ch| !ch.is_whitespace() && !"=!<>".contains(**ch)) {
+                word.push(ch);
+                chars.next();
+            }
+            tokens.push(word);
+        }
+    }
+    if tokens.is_empty() {
+        return Err("empty filter expression".to_string());
+    }
+    Ok(tokens)
+}
+
+impl Filter {
+    pub(crate) fn parse(expr: &str) -> Result<Filter, String> {
+        let tokens = tokenize_filter(expr)?;
+        let mut conditions = Vec::new();
+        for clause in tokens.split(|token| token.eq_ignore_ascii_case("AND")) {
+            let [operand, comparison, literal] = clause else {
+                return Err(format!("Invalid filter clause: {}", clause.join(" ")));
+            };
+            let operand = match operand.as_str() {
+                "key" => Operand::Key,
+                "value" => Operand::Value,
+                path => Operand::Path(parse_json_path(path).ok_or_else(|| format!("Invalid JSON path: {}", path))?),
+            };
+            let comparison = match comparison.to_uppercase().as_str() {
+                "=" | "==" => Comparison::Eq,
+                "!=" => Comparison::Ne,
+                "<" => Comparison::Lt,
+                "<=" => Comparison::Le,
+                ">" => Comparison::Gt,
+                ">=" => Comparison::Ge,
+                "CONTAINS" => Comparison::Contains,
+                other => return Err(format!("Unknown filter operator: {}", other)),
+            };
+            conditions.push(Condition {
+                operand,
+                comparison,
+                literal: literal.clone(),
+            });
+        }
+        Ok(Filter { conditions })
+    }
+
+    pub(crate) fn matches(&self, key: &str, value: &CacheValue) -> bool {
+        let json = LazyJson::new(value);
+        self.conditions.iter().all(|condition| {
+            let actual = match &condition.operand {
+                Operand::Key => Some(key.to_string()),
+                Operand::Value => Some(value.to_string()),
+                Operand::Path(segments) => json.get().and_then(|json| match json_path_lookup(json, segments)? {
+                    Value::String(s) => Some(s.clone()),
+                    other => Some(other.to_string()),
+                }),
+            };
+            actual.is_some_and(|actual| compare(&actual, condition.comparison, &condition.literal))
+        })
+    }
+}
+
+// Lazily parsed JSON view, so key/value-only filters never parse the value
+pub(crate) struct LazyJson<'a> {
+    pub(crate) value: &'a CacheValue,
+    pub(crate) json: std::cell::OnceCell<Option<Value>>,
+}
+
+impl<'a> LazyJson<'a> {
+    pub(crate) fn new(value: &'a CacheValue) -> Self {
+        LazyJson {
+            value,
+            json: std::cell::OnceCell::new(),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Option<&Value> {
+        self.json.get_or_init(|| value_as_json(self.value)).as_ref()
+    }
+}
+
+// Compare numerically when both sides are numbers, otherwise as strings
+pub(crate) fn compare(actual: &str, comparison: Comparison, literal: &str) -> bool {
+    use std::cmp::Ordering;
+    if comparison == Comparison::Contains {
+        return actual.contains(literal);
+    }
+    let ordering = match (actual.parse::<f64>(), literal.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b),
+        _ => Some(actual.cmp(literal)),
+    };
+    match (comparison, ordering) {
+        (Comparison::Eq, Some(o)) => o == Ordering::Equal,
+        (Comparison::Ne, o) => o != Some(Ordering::Equal),
+        (Comparison::Lt, Some(o)) => o == Ordering::Less,
+        (Comparison::Le, Some(o)) => o != Ordering::Greater,
+        (Comparison::Gt, Some(o)) => o == Ordering::Greater,
+        (Comparison::Ge, Some(o)) => o != Ordering::Less,
+        _ => false,
+    }
+}
+
+// Split `<args> WHERE <expr>` into the leading arguments and a parsed filter
+pub(crate) fn split_where(args: &str) -> Result<(&str, Option<Filter>), String> {
+    let at = args.match_indices("WHERE").map(|(i, _)| i).find(|&i| {
+        let before = args[..i].chars().next_back().is_none_or(char::is_whitespace);
+        let after = args[i + 5..].chars().next().is_some_and(char::is_whitespace);
+        before && after
+    });
+    match at {
+        Some(at) => Ok((&args[..at], Some(Filter::parse(&args[at + 5..])?))),
+        None => Ok((args, None)),
+    }
+}
+
+// Match a key against a glob pattern where `*` matches any run of characters and `?` any single one
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text index it is currently matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(text: &str) -> CacheValue {
+        CacheValue::Str(text.to_string())
+    }
+
+    #[test]
+    fn tokenizes_quotes_and_operators() {
+        assert_eq!(
+            tokenize_filter("$.category = 'science fiction' AND $.price<=20").unwrap(),
+            ["$.category", "=", "science fiction", "AND", "$.price", "<=", "20"]
+        );
+        assert!(tokenize_filter("   ").is_err());
+    }
+
+    #[test]
+    fn matches_json_paths_keys_and_values() {
+        let filter = Filter::parse("$.category = 'books' AND $.price < 20").unwrap();
+        assert!(filter.matches("item:1", &json(r#"{"category":"books","price":12.5}"#)));
+        assert!(!filter.matches("item:2", &json(r#"{"category":"books","price":25}"#)));
+        assert!(!filter.matches("item:3", &json("not json")));
+
+        assert!(Filter::parse("key CONTAINS :tmp").unwrap().matches("user:tmp:1", &json("v")));
+        assert!(Filter::parse("value != sale").unwrap().matches("k", &json("full price")));
+        // Numbers compare numerically, so 9 < 10 although "9" > "10"
+        assert!(Filter::parse("value < 10").unwrap().matches("k", &CacheValue::Int(9)));
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        assert_eq!(Filter::parse("$.price <").err().as_deref(), Some("Invalid filter clause: $.price <"));
+        assert_eq!(Filter::parse("$.price ~ 3").err().as_deref(), Some("Unknown filter operator: ~"));
+    }
+
+    #[test]
+    fn splits_where_only_as_a_word() {
+        let (args, filter) = split_where("PREFIX user: WHERE value = x").unwrap();
+        assert_eq!(args, "PREFIX user: ");
+        assert!(filter.is_some());
+        let (args, filter) = split_where("PREFIX WHEREABOUTS").unwrap();
+        assert_eq!(args, "PREFIX WHEREABOUTS");
+        assert!(filter.is_none());
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_match("user:*:tmp", "user:42:tmp"));
+        assert!(glob_match("user:?", "user:1"));
+        assert!(!glob_match("user:?", "user:12"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+    }
+}
@@ -0,0 +1,1857 @@
+//! Client command handling over the line-based TCP protocol
+
+pub mod aggregate;
+pub mod command;
+pub mod filter;
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use arrow::buffer::Buffer;
+use arrow::ipc::reader::StreamDecoder;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+
+use crate::cluster::{exec_on_cluster, gather_entries};
+use crate::discovery::PeerList;
+use crate::gossip::{bucket_of, digest, format_digest};
+use crate::lease;
+use crate::metrics;
+use crate::node::{NodeContext, SharedContext};
+use crate::replication::{apply_replicated, fetch_from_peers, replicate_acked, replicate_message, replicate_set};
+use crate::sequence::apply_sequenced;
+use crate::session::{self, await_tokens, forward_read};
+use crate::transfer::{pull_snapshot, serve_snapshot};
+use crate::storage::archive::value_as_of;
+use crate::storage::backup;
+use crate::storage::json::{json_path_lookup, json_path_set};
+use crate::storage::persistence::*;
+use crate::storage::value::{decode_hex, wrong_type};
+use crate::storage::{Cache, CacheValue, KeyChange, KeyVersion, SharedCache, WriteSource, WRITE_SOURCE};
+use aggregate::*;
+use command::*;
+use filter::*;
+
+// Largest request the node reads, per connection or per line on a persistent connection
+pub(crate) const MAX_REQUEST_SIZE: usize = 1024;
+
+// Largest framed request (see frame): a replicated write no longer fits in MAX_REQUEST_SIZE
+// with its replication prefix, nor does a value APPEND grew
+pub(crate) const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+// `message` as a request line, or as `FRAME <length>\n<message>` when the line would not fit in
+// MAX_REQUEST_SIZE or the message spans lines, which the node then reads to its end, on its own
+// connection or persistent
+pub(crate) fn frame(message: &str) -> String {
+    if message.len() < MAX_REQUEST_SIZE && !message.contains('\n') {
+        format!("{}\n", message)
+    } else {
+        format!("FRAME {}\n{}", message.len(), message)
+    }
+}
+
+// The length of a framed request starting `data`, and the part of it `data` already holds
+fn split_frame(data: &[u8]) -> Option<(usize, &[u8])> {
+    let rest = data.strip_prefix(b"FRAME ")?;
+    let end = rest.iter().position(|byte| *byte == b'\n')?;
+    let length = std::str::from_utf8(&rest[..end]).ok()?.trim_end().parse().ok()?;
+    Some((length, &rest[end + 1..]))
+}
+
+// Read the rest of a framed request of `length` bytes whose first bytes are `read`
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, length: usize, read: &[u8]) -> std::io::Result<Vec<u8>> {
+    if length > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("framed request over {} bytes", MAX_FRAME_SIZE)));
+    }
+    let mut request = read[..read.len().min(length)].to_vec();
+    let start = request.len();
+    request.resize(length, 0);
+    reader.read_exact(&mut request[start..]).await?;
+    Ok(request)
+}
+
+// OUTBOX answer on a node started without NodeBuilder::outbox
+const OUTBOX_DISABLED: &str = "Outbox is disabled";
+const KEY_HISTORY_DISABLED: &str = "Key history is disabled";
+
+// How long a FLUSHALL confirmation token stays valid
+pub(crate) const FLUSH_TOKEN_TTL: tokio::time::Duration = tokio::time::Duration::from_secs(30);
+
+// Audit trail for destructive admin commands (see the app::audit logger in log4rs.yaml)
+pub(crate) const AUDIT: &str = "app::audit";
+
+// Resolve an inclusive, possibly negative (from the end) index range against a length
+pub(crate) fn resolve_range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+// Parse `key=value [TYPE=<type>]` as used by SET and BROADCAST
+pub(crate) fn parse_assignment(args: &str) -> Result<(String, CacheValue), String> {
+    let (key, rest) = args.split_once('=').ok_or("missing '='")?;
+    let rest = rest.trim();
+    let (raw, type_name) = match rest.rsplit_once(char::is_whitespace) {
+        Some((raw, tag)) if tag.starts_with("TYPE=") => (raw.trim_end(), &tag[5..]),
+        _ => (rest, "string"),
+    };
+    Ok((key.trim().to_string(), CacheValue::parse(type_name, raw)?))
+}
+
+// Inverse of parse_assignment, omitting the tag for plain strings unless their last word looks like one
+pub(crate) fn format_assignment(key: &str, value: &CacheValue) -> String {
+    match value {
+        CacheValue::Str(s) if !s.rsplit(char::is_whitespace).next().unwrap_or("").starts_with("TYPE=") => {
+            format!("{}={}", key, s)
+        }
+        other => format!("{}={} TYPE={}", key, other, other.type_name()),
+    }
+}
+
+// Load a file batch by batch, releasing the cache lock between batches, replicating
+// each batch before reading the next one and reporting progress to the client
+pub(crate) async fn import_file<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    cache: &SharedCache,
+    peers: &PeerList,
+    context: &NodeContext,
+    options: ImportOptions,
+) -> Result<usize, BoxError> {
+    let mut imported = 0;
+    for batch in read_import_batches(&options)? {
+        imported += import_batch(cache, peers, context, &options, batch?).await;
+
+        info!("IMPORT {}: {} keys imported", options.path, imported);
+        let progress = format!("progress: {} keys imported\n", imported);
+        if let Err(e) = socket.write_all(progress.as_bytes()).await {
+            debug!("Client stopped listening to IMPORT progress: {}", e);
+        }
+    }
+    Ok(imported)
+}
+
+// Insert one batch of an import, then replicate it as the options say; returns its size
+async fn import_batch(cache: &SharedCache, peers: &PeerList, context: &NodeContext, options: &ImportOptions, batch: Vec<(String, CacheValue)>) -> usize {
+    {
+        let mut cache = cache.lock().await;
+        for (key, value) in batch.iter() {
+            cache.insert(key.clone(), value.clone());
+        }
+    }
+    let imported = batch.len();
+    if options.replicate {
+        for (key, value) in batch {
+            replicate_set(context, peers, key, value).await;
+        }
+        tokio::time::sleep(options.throttle).await;
+    }
+    imported
+}
+
+// IMPORT -: load the Arrow IPC stream of key/value (and type) batches the client sends after
+// the command, until it closes its side of the connection. Each batch is acknowledged with
+// `ack: batch <n>, <total> keys imported` once it is in the cache (and replicated), so a
+// pipeline knows how much landed if the stream breaks off; the last line is the outcome.
+async fn ingest_arrow<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    pending: Vec<u8>,
+    options: ImportOptions,
+    client: &str,
+    cache: &SharedCache,
+    peers: &PeerList,
+    context: &NodeContext,
+) {
+    let (mut batches, mut imported) = (0, 0);
+    let result: Result<(), BoxError> = async {
+        let mut decoder = StreamDecoder::new();
+        let mut buffer = Buffer::from_vec(pending);
+        let mut chunk = vec![0; 64 * 1024];
+        loop {
+            while !buffer.is_empty() {
+                let Some(batch) = decoder.decode(&mut buffer)? else {
+                    continue;
+                };
+                imported += import_batch(cache, peers, context, &options, batch_to_pairs(&batch)?).await;
+                batches += 1;
+                socket.write_all(format!("ack: batch {}, {} keys imported\n", batches, imported).as_bytes()).await?;
+            }
+            match socket.read(&mut chunk).await? {
+                0 => return Ok(decoder.finish()?),
+                read => buffer = Buffer::from_vec(chunk[..read].to_vec()),
+            }
+        }
+    }
+    .await;
+    let response = match result {
+        Ok(()) => {
+            info!("IMPORT - from {}: {} keys imported in {} batches", client, imported, batches);
+            format!("OK: imported {} keys in {} batches", imported, batches)
+        }
+        Err(e) => {
+            error!("IMPORT - from {} failed after {} batches: {}", client, batches, e);
+            format!("IMPORT failed after {} batches ({} keys imported): {}", batches, imported, e)
+        }
+    };
+    if let Err(e) = socket.write_all(response.as_bytes()).await {
+        debug!("Client stopped listening to IMPORT -: {}", e);
+    }
+}
+
+// Serve one request read from `socket`; `client` is the remote address, used in the audit log
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    client: String,
+    cache: SharedCache,
+    peers: PeerList,
+    context: SharedContext,
+) {
+    let mut buffer = [0; MAX_REQUEST_SIZE];
+
+    match socket.read(&mut buffer).await {
+        Ok(bytes_read) if bytes_read > 0 => {
+            // A request too long for one read, e.g. a replicated write, arrives framed
+            let framed = match split_frame(&buffer[..bytes_read]) {
+                Some((length, read)) => match read_frame(&mut socket, length, read).await {
+                    Ok(request) => Some(request),
+                    Err(e) => {
+                        warn!("Dropping framed request from {}: {}", client, e);
+                        let _ = socket.write_all(b"Request too large").await;
+                        return;
+                    }
+                },
+                None => None,
+            };
+            let data = framed.as_deref().unwrap_or(&buffer[..bytes_read]);
+
+            // Anything sent after the PERSIST line already belongs to the first framed request
+            if let Some(rest) = data.strip_prefix(b"PERSIST") {
+                let (tracking, rest) = match rest.strip_prefix(b" TRACKING") {
+                    Some(rest) => (true, rest),
+                    None => (false, rest),
+                };
+                let pending = rest.strip_prefix(b"\r\n").or_else(|| rest.strip_prefix(b"\n"));
+                if rest.is_empty() || pending.is_some() {
+                    let pending = pending.unwrap_or_default().to_vec();
+                    return serve_persistent(socket, pending, tracking, &client, &cache, &peers, &context).await;
+                }
+            }
+
+            // So a Prometheus scraper can be pointed at the node port
+            if data.starts_with(b"GET /metrics ") {
+                let response = metrics::http_response(&context.latencies.prometheus());
+                if let Err(e) = socket.write_all(response.as_bytes()).await {
+                    error!("Failed to send metrics: {}", e);
+                }
+                return;
+            }
+
+            // IMPORT - is followed on the same connection by the Arrow IPC stream to load
+            if let Some(end) = data.iter().position(|byte| *byte == b'\n') {
+                if let Ok(Command::Import(options)) = parse_command(&String::from_utf8_lossy(&data[..end])) {
+                    if options.path == "-" {
+                        let pending = data[end + 1..].to_vec();
+                        return ingest_arrow(socket, pending, options, &client, &cache, &peers, &context).await;
+                    }
+                }
+            }
+
+            let request = String::from_utf8_lossy(data);
+            debug!("Received: {}", request);
+
+            let response = match parse_command(&request) {
+                Ok(Command::Subscribe { prefix }) => return stream_changes(socket, &client, &cache, &prefix).await,
+                Ok(Command::Snapshot(request)) => return serve_snapshot(socket, &client, &cache, request).await,
+                Ok(command) if command.streams_arrow() => {
+                    let response = arrow_response(command, &cache, &peers, &context).await.unwrap_or_else(String::into_bytes);
+                    debug!("Sending Arrow response: {} bytes", response.len());
+                    if let Err(e) = socket.write_all(&response).await {
+                        error!("Failed to send response: {}", e);
+                    }
+                    return;
+                }
+                Ok(command) => execute(command, &mut socket, &client, &cache, &peers, &context).await,
+                Err(e) => e,
+            };
+
+            debug!("Sending response: {}", response);
+            if let Err(e) = socket.write_all(response.as_bytes()).await {
+                error!("Failed to send response: {}", e);
+            }
+        }
+        Ok(_) => debug!("Connection closed by client."),
+        Err(e) => error!("Failed to read from socket: {}", e),
+    }
+}
+
+// Serve newline-terminated (or framed) requests until the client disconnects, answering each with
+// `<length>\n<response>` so responses can span lines; IMPORT progress is not reported. With
+// `tracking` (PERSIST TRACKING), the keys of single-key reads are remembered and, when one
+// changes, the connection gets a line `INVALIDATE <key>` between responses and the key is no
+// longer tracked until read again; `INVALIDATE *` means changes were missed, so drop them all.
+async fn serve_persistent<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    pending: Vec<u8>,
+    tracking: bool,
+    client: &str,
+    cache: &SharedCache,
+    peers: &PeerList,
+    context: &NodeContext,
+) {
+    let (reader, mut writer) = tokio::io::split(socket);
+    let mut reader = BufReader::new(std::io::Cursor::new(pending).chain(reader));
+    debug!("{} switched to a persistent connection", client);
+    let mut changes = match tracking {
+        true => Some(cache.lock().await.subscribe()),
+        false => None,
+    };
+    let mut tracked = HashSet::new();
+    let greeting: &[u8] = if tracking { b"OK: PERSIST TRACKING\n" } else { b"OK: PERSIST\n" };
+    if writer.write_all(greeting).await.is_err() {
+        return;
+    }
+
+    let mut line = Vec::new();
+    loop {
+        // Reading a line is resumed after pushing an invalidation, so nothing read is lost
+        let mut limited = (&mut reader).take((MAX_REQUEST_SIZE + 1 - line.len()) as u64);
+        let read = tokio::select! {
+            read = limited.read_until(b'\n', &mut line) => read,
+            change = next_change(&mut changes) => {
+                let push = match change {
+                    Ok(change) if tracked.remove(&change.key) => format!("INVALIDATE {}\n", change.key),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(_)) => {
+                        tracked.clear();
+                        "INVALIDATE *\n".to_string()
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if let Err(e) = writer.write_all(push.as_bytes()).await {
+                    error!("Failed to send invalidation: {}", e);
+                    break;
+                }
+                continue;
+            }
+        };
+        match read {
+            Ok(0) if line.is_empty() => break,
+            Ok(_) if line.len() > MAX_REQUEST_SIZE => {
+                warn!("Closing persistent connection from {}: request over {} bytes", client, MAX_REQUEST_SIZE);
+                let response = "Request too large";
+                let _ = writer.write_all(format!("{}\n{}", response.len(), response).as_bytes()).await;
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Failed to read from socket: {}", e);
+                break;
+            }
+        }
+        let request = match split_frame(&line) {
+            Some((length, _)) => match read_frame(&mut reader, length, &[]).await {
+                Ok(request) => String::from_utf8_lossy(&request).into_owned(),
+                Err(e) => {
+                    warn!("Closing persistent connection from {}: {}", client, e);
+                    let response = "Request too large";
+                    let _ = writer.write_all(format!("{}\n{}", response.len(), response).as_bytes()).await;
+                    break;
+                }
+            },
+            None => String::from_utf8_lossy(&line).into_owned(),
+        };
+        line.clear();
+        debug!("Received: {}", request.trim_end());
+
+        let parsed = parse_command(&request);
+        if let (Some(key), true) = (parsed.as_ref().ok().and_then(Command::read_key), tracking) {
+            // Past the limit nothing is tracked reliably any more, so start over
+            if tracked.len() == MAX_TRACKED_KEYS && !tracked.contains(&key) {
+                tracked.clear();
+                if writer.write_all(b"INVALIDATE *\n").await.is_err() {
+                    break;
+                }
+            }
+            tracked.insert(key);
+        }
+        let response = match parsed {
+            Ok(command) if command.streams_arrow() => {
+                let response = arrow_response(command, cache, peers, context).await.unwrap_or_else(String::into_bytes);
+                debug!("Sending Arrow response: {} bytes", response.len());
+                response
+            }
+            parsed => {
+                let response = match parsed {
+                    Ok(command) => execute(command, &mut tokio::io::sink(), client, cache, peers, context).await,
+                    Err(e) => e,
+                };
+                debug!("Sending response: {}", response);
+                response.into_bytes()
+            }
+        };
+
+        if let Err(e) = writer.write_all(&[format!("{}\n", response.len()).into_bytes(), response].concat()).await {
+            error!("Failed to send response: {}", e);
+            break;
+        }
+    }
+    debug!("Persistent connection from {} closed", client);
+}
+
+// Keys a tracking connection is told about at most; reading more invalidates them all
+const MAX_TRACKED_KEYS: usize = 100_000;
+
+// The next change for a tracking connection; never for any other
+async fn next_change(changes: &mut Option<broadcast::Receiver<KeyChange>>) -> Result<KeyChange, RecvError> {
+    match changes {
+        Some(changes) => changes.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// The pairs GET_ALL/SCAN .. FORMAT ARROW or EXPORT - select, as an Arrow IPC stream of
+// key/value/type batches; errors are answered as text like any other response
+async fn arrow_response(command: Command, cache: &SharedCache, peers: &PeerList, context: &NodeContext) -> Result<Vec<u8>, String> {
+    let pairs: Vec<(String, CacheValue)> = match command {
+        Command::GetAll { filter, cluster: true, .. } => {
+            debug!("Processing GET_ALL CLUSTER FORMAT ARROW");
+
+            let mut entries: Vec<(String, CacheValue)> = gather_entries(cache, peers, context)
+                .await
+                .into_iter()
+                .filter(|(key, (_, value))| filter.as_ref().is_none_or(|f| f.matches(key, value)))
+                .map(|(key, (_, value))| (key, value))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries
+        }
+        Command::GetAll { filter, cluster: false, .. } => {
+            debug!("Processing GET_ALL FORMAT ARROW");
+
+            let cache = cache.lock().await;
+            cache
+                .iter()
+                .filter(|(key, value)| filter.as_ref().is_none_or(|f| f.matches(key, value)))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        }
+        Command::Scan { prefix, after, count, filter, .. } => {
+            debug!("Processing SCAN FORMAT ARROW prefix: {}, after: {:?}, count: {}", prefix, after, count);
+
+            let cache = cache.lock().await;
+            let mut matching: Vec<(&String, &CacheValue)> = cache
+                .iter()
+                .filter(|(key, _)| key.starts_with(&prefix) && after.as_ref().is_none_or(|a| *key > a))
+                .filter(|(key, value)| filter.as_ref().is_none_or(|f| f.matches(key, value)))
+                .collect();
+            matching.sort_by(|a, b| a.0.cmp(b.0));
+            matching.into_iter().take(count).map(|(key, value)| (key.clone(), value.clone())).collect()
+        }
+        Command::Export { options, filter } => {
+            debug!("Processing EXPORT - with prefix: {}", options.prefix);
+
+            let cache = cache.lock().await;
+            let mut pairs: Vec<(String, CacheValue)> = cache
+                .iter()
+                .filter(|(key, value)| key.starts_with(&options.prefix) && filter.as_ref().is_none_or(|f| f.matches(key, value)))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            pairs.sort_by(|a, b| a.0.cmp(&b.0));
+            pairs
+        }
+        _ => return Err("Only GET_ALL, SCAN and EXPORT answer in Arrow".to_string()),
+    };
+    let pairs: Vec<(&String, &CacheValue)> = pairs.iter().map(|(key, value)| (key, value)).collect();
+    pairs_to_arrow_stream(&pairs).map_err(|e| format!("Failed to encode Arrow response: {}", e))
+}
+
+// Stream changes to keys under `prefix` as `SET key=value` / `DEL key` lines, prefixed with
+// `CID <id>` of the request that made them, until the client disconnects; a subscriber that falls too far behind is told how many changes it missed
+async fn stream_changes<S: AsyncRead + AsyncWrite + Unpin>(mut socket: S, client: &str, cache: &SharedCache, prefix: &str) {
+    let mut changes = cache.lock().await.subscribe();
+    debug!("{} subscribed to changes under '{}'", client, prefix);
+    if socket.write_all(b"OK: SUBSCRIBED\n").await.is_err() {
+        return;
+    }
+
+    let mut buffer = [0; 64];
+    loop {
+        let line = tokio::select! {
+            read = socket.read(&mut buffer) => match read {
+                // Anything the client sends is ignored; EOF ends the subscription
+                Ok(bytes_read) if bytes_read > 0 => continue,
+                _ => break,
+            },
+            change = changes.recv() => match change {
+                Ok(KeyChange { key, .. }) if !key.starts_with(prefix) => continue,
+                Ok(KeyChange { key, value, correlation }) => {
+                    let change = match value {
+                        Some(value) => format!("SET {}={}\n", key, value),
+                        None => format!("DEL {}\n", key),
+                    };
+                    match correlation {
+                        Some(id) => format!("CID {} {}", id, change),
+                        None => change,
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => format!("LAGGED {}\n", missed),
+                Err(RecvError::Closed) => break,
+            },
+        };
+        if let Err(e) = socket.write_all(line.as_bytes()).await {
+            debug!("Subscriber {} went away: {}", client, e);
+            break;
+        }
+    }
+    debug!("{} unsubscribed from '{}'", client, prefix);
+}
+
+// Replicate an operation to all peers as `REPLICATE <op>`
+async fn replicate(context: &NodeContext, peers: &PeerList, op: impl std::fmt::Display) {
+    replicate_message(context, peers, None, format!("REPLICATE {}", op)).await;
+}
+
+// Replicate a whole value to all peers in the background, as SET does
+async fn replicate_value(context: &NodeContext, peers: &PeerList, key: String, value: CacheValue) {
+    let message = format!("BROADCAST {}", format_assignment(&key, &value));
+    replicate_message(context, peers, Some(&key), message).await;
+}
+
+// Run a parsed command against the cache and build the client response
+pub(crate) async fn execute<S: AsyncWrite + Unpin>(
+    command: Command,
+    socket: &mut S,
+    client: &str,
+    cache: &SharedCache,
+    peers: &PeerList,
+    context: &NodeContext,
+) -> String {
+    // A newer epoch means our view of the cluster is stale: refresh it before applying what came
+    // with it, except for heartbeats, which are answered at once
+    let command = match command {
+        Command::Epoch { epoch, command } if matches!(*command, Command::Ping { .. }) => {
+            context.membership.heard(epoch);
+            *command
+        }
+        Command::Epoch { epoch, command } => {
+            context.membership.observe(epoch, peers, context).await;
+            *command
+        }
+        command => command,
+    };
+    let (correlation, command): (Arc<str>, Command) = match command {
+        Command::Correlated { id, command } => (id.into(), *command),
+        // Messages applied from a SEQ or batch without an ID of their own share the carrier's
+        command => match WRITE_SOURCE.try_with(|source| Arc::clone(&source.correlation)) {
+            Ok(correlation) => (correlation, command),
+            Err(_) => (format!("{:016x}", rand::random::<u64>()).into(), command),
+        },
+    };
+    debug!("Request {} from {}", correlation, client);
+    if command.replicates() && context.replicator.as_ref().is_some_and(|replicator| replicator.refuse_write()) {
+        warn!("Refusing request {} from {}: replication queue full", correlation, client);
+        return "BUSY: replication queue full, try again later".to_string();
+    }
+    // Replicated writes come from their origin, anything else was written here
+    let origin = match &command {
+        Command::Broadcast { .. } | Command::Replicate { .. } => client,
+        _ => context.sequences.origin(),
+    };
+    // Client writes wait for the append log if its fsync policy says so, replicated ones never do
+    let logged_write = match &command {
+        Command::Broadcast { .. } | Command::Replicate { .. } => Some(false),
+        command if command.replicates() => Some(true),
+        _ => None,
+    };
+    let source = WriteSource { origin: origin.into(), writer: client.into(), correlation };
+    let op = command.name();
+    context
+        .latencies
+        .time(op, async {
+            let response = WRITE_SOURCE.scope(source, run_command(command, socket, client, cache, peers, context)).await;
+            if let (Some(wait), Some(commits)) = (logged_write, &context.log_commits) {
+                commits.committed(wait).await;
+            }
+            response
+        })
+        .await
+}
+
+// The correlation ID of the request being executed, for log lines
+pub(crate) fn request_id() -> String {
+    WRITE_SOURCE.try_with(|source| source.correlation.to_string()).unwrap_or_else(|_| "-".to_string())
+}
+
+async fn run_command<S: AsyncWrite + Unpin>(
+    command: Command,
+    socket: &mut S,
+    client: &str,
+    cache: &SharedCache,
+    peers: &PeerList,
+    context: &NodeContext,
+) -> String {
+    match command {
+        // Connections answer these with arrow_response instead
+        command if command.streams_arrow() => "Arrow responses need a client connection".to_string(),
+        Command::GetAll { filter, cluster: true, .. } => {
+            debug!("Processing GET_ALL CLUSTER");
+
+            let mut entries: Vec<(String, CacheValue)> = gather_entries(cache, peers, context)
+                .await
+                .into_iter()
+                .filter(|(key, (_, value))| filter.as_ref().is_none_or(|f| f.matches(key, value)))
+                .map(|(key, (_, value))| (key, value))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join("\n")
+        }
+        Command::GetAll { filter, cluster: false, .. } => {
+            debug!("Processing GET_ALL");
+
+            let cache = cache.lock().await;
+            cache
+                .iter()
+                .filter(|(key, value)| filter.as_ref().is_none_or(|f| f.matches(key, value)))
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        Command::GetLen { cluster: true } => {
+            debug!("Processing GET_LEN CLUSTER");
+
+            gather_entries(cache, peers, context).await.len().to_string()
+        }
+        Command::GetLen { cluster: false } => {
+            debug!("Processing GET_LEN");
+
+            let cache = cache.lock().await;
+            cache.len().to_string()
+        }
+        Command::Entries { buckets, since } => {
+            debug!("Processing ENTRIES");
+
+            let cache = cache.lock().await;
+            cache
+                .iter()
+                .filter(|(key, _)| buckets.as_ref().is_none_or(|(count, wanted)| wanted.contains(&bucket_of(key, *count))))
+                .filter(|(key, _)| since.is_none_or(|since| cache.modified(key).is_some_and(|modified| modified >= since)))
+                .map(|(key, value)| {
+                    json!({ "modified": cache.modified(key), "expires": cache.expires_at(key), "entry": format_assignment(key, value) }).to_string()
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        Command::Digest { buckets } => {
+            debug!("Processing DIGEST {}", buckets);
+
+            format_digest(&digest(&*cache.lock().await, buckets))
+        }
+        Command::Get { key, lease } => {
+            debug!("Processing GET for key: {}", key);
+
+            // A missing key gets no lease, so it is asked for again
+            let leased = |value: String, modified: Option<u64>, ttl: Option<u64>| match lease {
+                true => format!("{}\nMAX-AGE {}", value, lease::max_age(modified, ttl, context).as_millis()),
+                false => value,
+            };
+            {
+                let cache = cache.lock().await;
+                if let Some(value) = cache.get(&key) {
+                    return leased(value.to_string(), cache.modified(&key), cache.ttl(&key).flatten());
+                }
+            }
+            let not_found = || leased("Not Found".to_string(), None, None);
+            let Some(remote_reads) = &context.remote_reads else {
+                return not_found();
+            };
+            match fetch_from_peers(context, peers, &key, remote_reads).await {
+                Some((value, expires)) => {
+                    let mut cache = cache.lock().await;
+                    // A write that landed while the peers were asked wins
+                    if cache.get(&key).is_none() {
+                        cache.insert(key.clone(), value);
+                        cache.set_expiry(&key, expires);
+                    }
+                    match cache.get(&key) {
+                        Some(value) => leased(value.to_string(), cache.modified(&key), cache.ttl(&key).flatten()),
+                        // Expired on its way here
+                        None => not_found(),
+                    }
+                }
+                None => not_found(),
+            }
+        }
+        Command::GetAsOf { key, at } => {
+            debug!("Processing GET AS_OF {} for key: {}", at, key);
+
+            let Some(log) = &context.append_log else {
+                return "AS_OF needs the append log (P2P_PERSISTENCE=log)".to_string();
+            };
+            // Reading old segments can take a while, so it doesn't hold up the log
+            let snapshot = log.lock().await.snapshot_path().to_path_buf();
+            match tokio::task::spawn_blocking(move || value_as_of(&snapshot, &key, at)).await {
+                Ok(Ok(Some(value))) => value.to_string(),
+                Ok(Ok(None)) => "Not Found".to_string(),
+                Ok(Err(e)) => format!("AS_OF failed: {}", e),
+                Err(e) => format!("AS_OF failed: {}", e),
+            }
+        }
+        Command::BackupVerify { path } => {
+            debug!("Processing BACKUP VERIFY {}", path);
+
+            match tokio::task::spawn_blocking(move || backup::verify(Path::new(&path))).await {
+                Ok(Ok(report)) => report,
+                Ok(Err(e)) => format!("BACKUP VERIFY failed: {}", e),
+                Err(e) => format!("BACKUP VERIFY failed: {}", e),
+            }
+        }
+        Command::RestoreDryRun { path } => {
+            debug!("Processing RESTORE {} --dry-run", path);
+
+            // Read without the cache locked, then compared under one lock
+            let read = tokio::task::spawn_blocking(move || backup::read_backup(Path::new(&path))).await;
+            match read {
+                Ok(Ok(backup)) => backup::plan_restore(&backup, &*cache.lock().await),
+                Ok(Err(e)) => format!("RESTORE failed: {}", e),
+                Err(e) => format!("RESTORE failed: {}", e),
+            }
+        }
+        Command::Diff { from, to, full } => {
+            debug!("Processing DIFF {} {}", from, to.as_deref().unwrap_or("LIVE"));
+
+            let read = tokio::task::spawn_blocking(move || {
+                let to = to.map(|to| backup::read_backup(Path::new(&to))).transpose()?;
+                Ok::<_, BoxError>((backup::read_backup(Path::new(&from))?, to))
+            })
+            .await;
+            match read {
+                Ok(Ok((from, Some(to)))) => backup::diff_backups(&from, &to, full),
+                Ok(Ok((from, None))) => {
+                    let cache = cache.lock().await;
+                    let live = cache.iter().map(|(key, value)| (key.as_str(), value));
+                    backup::diff(&from, live, full)
+                }
+                Ok(Err(e)) => format!("DIFF failed: {}", e),
+                Err(e) => format!("DIFF failed: {}", e),
+            }
+        }
+        Command::Lookup { key } => {
+            debug!("Processing LOOKUP for key: {}", key);
+
+            let cache = cache.lock().await;
+            match (cache.get(&key), cache.expires_at(&key)) {
+                (Some(value), Some(at)) => format!("{}\nEXPIREAT {}", format_assignment(&key, value), at),
+                (Some(value), None) => format_assignment(&key, value),
+                (None, _) => "Not Found".to_string(),
+            }
+        }
+        Command::Set { key, value, sync } => {
+            // Local SET request
+            debug!("Processing local SET for key: {}, value: {}", key, value);
+
+            // Update local cache
+            {
+                let mut cache = cache.lock().await;
+                cache.insert(key.clone(), value.clone());
+            }
+
+            // Broadcast to peers
+            let Some(ack) = sync else {
+                replicate_value(context, peers, key, value).await;
+                return "OK: SET successful".to_string();
+            };
+            let message = format!("BROADCAST {}", format_assignment(&key, &value));
+            replicate_acked(context, peers, message, &ack).await.response("SET")
+        }
+        Command::Broadcast { key, value } => {
+            // Received broadcasted SET
+            debug!("Processing BROADCAST for key: {}, value: {}", key, value);
+
+            // Update local cache (no re-broadcast)
+            let mut cache = cache.lock().await;
+            cache.insert(key, value);
+
+            "OK: BROADCAST applied".to_string()
+        }
+        Command::Scan { prefix, after, count, filter, .. } => {
+            debug!("Processing SCAN prefix: {}, after: {:?}, count: {}", prefix, after, count);
+
+            let cache = cache.lock().await;
+            let mut matching: Vec<(&String, &CacheValue)> = cache
+                .iter()
+                .filter(|(key, _)| key.starts_with(&prefix) && after.as_ref().is_none_or(|a| *key > a))
+                .filter(|(key, value)| filter.as_ref().is_none_or(|f| f.matches(key, value)))
+                .collect();
+            matching.sort_by(|a, b| a.0.cmp(b.0));
+            matching
+                .into_iter()
+                .take(count)
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        Command::Aggregate { aggregate, path, prefix, filter, group_by, history: None } => {
+            debug!("Processing aggregation with prefix: {}", prefix);
+
+            let cache = cache.lock().await;
+            run_aggregation(&cache, aggregate, path.as_deref(), &prefix, filter.as_ref(), group_by.as_deref())
+        }
+        Command::Aggregate { aggregate, path, prefix, filter, group_by, history: Some(history) } => {
+            debug!("Processing aggregation with prefix: {} over {:?}", prefix, history);
+
+            let Some(log) = &context.append_log else {
+                return "AS_OF and OVER SNAPSHOTS need the append log (P2P_PERSISTENCE=log)".to_string();
+            };
+            // Like GET ... AS_OF, reading old files doesn't hold up the log
+            let snapshot = log.lock().await.snapshot_path().to_path_buf();
+            let run = move |cache: &Cache| run_aggregation(cache, aggregate, path.as_deref(), &prefix, filter.as_ref(), group_by.as_deref());
+            match tokio::task::spawn_blocking(move || run_history_aggregation(&snapshot, history, run)).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(e)) => format!("Aggregation over history failed: {}", e),
+                Err(e) => format!("Aggregation over history failed: {}", e),
+            }
+        }
+        Command::Import(options) if options.path == "-" => "IMPORT - needs a client connection".to_string(),
+        Command::Import(options) => {
+            let path = options.path.clone();
+            match import_file(socket, cache, peers, context, options).await {
+                Ok(imported) => format!("OK: imported {} keys from {}", imported, path),
+                Err(e) => {
+                    error!("IMPORT {} failed: {}", path, e);
+                    format!("IMPORT failed: {}", e)
+                }
+            }
+        }
+        Command::Export { options, filter } => {
+            debug!("Processing EXPORT to {} ({:?})", options.path, options.format);
+
+            let cache = cache.lock().await;
+            let mut pairs: Vec<(&String, &CacheValue)> = cache
+                .iter()
+                .filter(|(key, value)| key.starts_with(&options.prefix) && filter.as_ref().is_none_or(|f| f.matches(key, value)))
+                .collect();
+            pairs.sort_by(|a, b| a.0.cmp(b.0));
+            match export_pairs(&options, &pairs) {
+                Ok(()) => {
+                    info!("EXPORT {}: {} keys exported", options.path, pairs.len());
+                    format!("OK: exported {} keys to {}", pairs.len(), options.path)
+                }
+                Err(e) => {
+                    error!("EXPORT {} failed: {}", options.path, e);
+                    format!("EXPORT failed: {}", e)
+                }
+            }
+        }
+        Command::JsonGet { key, path } => {
+            debug!("Processing JSON.GET for key: {}", key);
+
+            let cache = cache.lock().await;
+            match cache.get(&key) {
+                None => "Not Found".to_string(),
+                Some(CacheValue::Str(raw)) => match serde_json::from_str::<Value>(raw) {
+                    Ok(json) => json_path_lookup(&json, &path)
+                        .map(Value::to_string)
+                        .unwrap_or_else(|| "Not Found".to_string()),
+                    Err(_) => "Value is not JSON".to_string(),
+                },
+                Some(other) => wrong_type(&key, other, "JSON.GET"),
+            }
+        }
+        Command::JsonSet { key, path, value: new_value } => {
+            debug!("Processing JSON.SET for key: {}", key);
+
+            let updated = {
+                let mut cache = cache.lock().await;
+                let document = match cache.get(&key) {
+                    Some(CacheValue::Str(raw)) => serde_json::from_str::<Value>(raw).map_err(|_| "Value is not JSON".to_string()),
+                    Some(other) => Err(wrong_type(&key, other, "JSON.SET")),
+                    None => Ok(Value::Null),
+                };
+                document.and_then(|mut document| {
+                    json_path_set(&mut document, &path, new_value)?;
+                    let value = CacheValue::Str(document.to_string());
+                    cache.insert(key.clone(), value.clone());
+                    Ok(value)
+                })
+            };
+
+            match updated {
+                Ok(value) => {
+                    // Replicate the whole updated document
+                    replicate_value(context, peers, key, value).await;
+                    "OK: JSON.SET successful".to_string()
+                }
+                Err(e) => e,
+            }
+        }
+        Command::Incr { key, delta } => {
+            debug!("Processing INCR for key: {} by {}", key, delta);
+
+            let updated = {
+                let mut cache = cache.lock().await;
+                match cache.get(&key) {
+                    None => Ok(delta),
+                    Some(CacheValue::Int(current)) => current.checked_add(delta).ok_or_else(|| "Integer overflow".to_string()),
+                    Some(other) => Err(wrong_type(&key, other, "INCR")),
+                }
+                .inspect(|value| {
+                    cache.insert(key.clone(), CacheValue::Int(*value));
+                })
+            };
+
+            match updated {
+                Ok(value) => {
+                    replicate_value(context, peers, key, CacheValue::Int(value)).await;
+                    value.to_string()
+                }
+                Err(e) => e,
+            }
+        }
+        Command::Append { key, suffix } => {
+            debug!("Processing APPEND for key: {}", key);
+
+            let updated = {
+                let mut cache = cache.lock().await;
+                match cache.get(&key) {
+                    None => Ok(CacheValue::Str(suffix)),
+                    Some(CacheValue::Str(current)) => Ok(CacheValue::Str(format!("{}{}", current, suffix))),
+                    Some(CacheValue::Bytes(current)) => decode_hex(&suffix)
+                        .map(|extra| CacheValue::Bytes([current.as_slice(), &extra].concat()))
+                        .ok_or_else(|| format!("Invalid hex bytes value: {}", suffix)),
+                    Some(other) => Err(wrong_type(&key, other, "APPEND")),
+                }
+                .inspect(|value| {
+                    cache.insert(key.clone(), value.clone());
+                })
+            };
+
+            match updated {
+                Ok(value) => {
+                    let length = match &value {
+                        CacheValue::Bytes(bytes) => bytes.len(),
+                        other => other.to_string().len(),
+                    };
+                    replicate_value(context, peers, key, value).await;
+                    length.to_string()
+                }
+                Err(e) => e,
+            }
+        }
+        Command::List(op) => match apply_list_command(cache, &op).await {
+            Ok(response) => {
+                // A pop from an empty or missing list changes nothing
+                if matches!(op, ListOp::Push { .. }) || response != "Not Found" {
+                    replicate(context, peers, &op).await;
+                }
+                response
+            }
+            Err(e) => e,
+        },
+        Command::LRange { key, start, stop } => {
+            debug!("Processing LRANGE for key: {} {}..{}", key, start, stop);
+
+            let cache = cache.lock().await;
+            match cache.get(&key) {
+                None => String::new(),
+                Some(CacheValue::List(list)) => match resolve_range(list.len(), start, stop) {
+                    Some((from, to)) => list.range(from..=to).cloned().collect::<Vec<_>>().join("\n"),
+                    None => String::new(),
+                },
+                Some(other) => wrong_type(&key, other, "LRANGE"),
+            }
+        }
+        Command::Hash(op) => match apply_hash_command(cache, &op).await {
+            Ok(response) => {
+                if response == "1" || matches!(op, HashOp::Set { .. }) {
+                    replicate(context, peers, &op).await;
+                }
+                response
+            }
+            Err(e) => e,
+        },
+        Command::HGetAll { key } => {
+            debug!("Processing HGETALL for key: {}", key);
+
+            let cache = cache.lock().await;
+            match cache.get(&key) {
+                None => String::new(),
+                Some(CacheValue::Hash(hash)) => hash
+                    .iter()
+                    .map(|(field, value)| format!("{}={}", field, value))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Some(other) => wrong_type(&key, other, "HGETALL"),
+            }
+        }
+        Command::HGet { key, field } => {
+            debug!("Processing HGET for key: {}, field: {}", key, field);
+
+            let cache = cache.lock().await;
+            match cache.get(&key) {
+                None => "Not Found".to_string(),
+                Some(CacheValue::Hash(hash)) => hash.get(&field).cloned().unwrap_or_else(|| "Not Found".to_string()),
+                Some(other) => wrong_type(&key, other, "HGET"),
+            }
+        }
+        Command::XAdd { key, payload } => {
+            debug!("Processing XADD for key: {}", key);
+
+            let added = {
+                let mut cache = cache.lock().await;
+                cache.stream_add(&key, None, payload.clone())
+            };
+            match added {
+                Ok(id) => {
+                    // Peers store the entry under the same ID
+                    replicate(context, peers, StreamOp::AddAt { key, id, payload }).await;
+                    id.to_string()
+                }
+                Err(e) => e,
+            }
+        }
+        Command::XRead { key, from, consumer, count } => {
+            debug!("Processing XREAD for key: {}", key);
+
+            let cache = cache.lock().await;
+            match cache.get(&key) {
+                None => String::new(),
+                Some(CacheValue::Stream(stream)) => {
+                    let committed = consumer.as_ref().and_then(|c| stream.consumers.get(c)).copied();
+                    let from = from.or(committed).unwrap_or(0);
+                    stream
+                        .entries
+                        .range(from..)
+                        .take(count)
+                        .map(|(id, payload)| format!("{} {}", id, payload))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+                Some(other) => wrong_type(&key, other, "XREAD"),
+            }
+        }
+        Command::Stream(op) => match apply_stream_command(cache, &op).await {
+            Ok(()) => {
+                replicate(context, peers, &op).await;
+                "OK: XCOMMIT successful".to_string()
+            }
+            Err(e) => e,
+        },
+        Command::XLen { key } => {
+            let cache = cache.lock().await;
+            match cache.get(&key) {
+                None => "0".to_string(),
+                Some(CacheValue::Stream(stream)) => stream.entries.len().to_string(),
+                Some(other) => wrong_type(&key, other, "XLEN"),
+            }
+        }
+        Command::Zset(op) => match apply_zset_command(cache, &op).await {
+            Ok(response) => {
+                if response == "1" || matches!(op, ZsetOp::Add { .. }) {
+                    replicate(context, peers, &op).await;
+                }
+                response
+            }
+            Err(e) => e,
+        },
+        Command::ZRangeByScore { key, min, max, with_scores, limit } => {
+            debug!("Processing ZRANGEBYSCORE for key: {} [{}, {}]", key, min, max);
+
+            let cache = cache.lock().await;
+            match cache.get(&key) {
+                None => String::new(),
+                Some(CacheValue::SortedSet(zset)) => zset
+                    .range_by_score(min, max)
+                    .take(limit)
+                    .map(|(member, score)| if with_scores { format!("{}={}", member, score) } else { member.clone() })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Some(other) => wrong_type(&key, other, "ZRANGEBYSCORE"),
+            }
+        }
+        Command::ZScore { key, member } => {
+            let cache = cache.lock().await;
+            match cache.get(&key) {
+                None => "Not Found".to_string(),
+                Some(CacheValue::SortedSet(zset)) => zset
+                    .scores
+                    .get(&member)
+                    .map(|score| score.to_string())
+                    .unwrap_or_else(|| "Not Found".to_string()),
+                Some(other) => wrong_type(&key, other, "ZSCORE"),
+            }
+        }
+        Command::Delete(op) => {
+            let removed = apply_delete_command(cache, &op).await;
+            // Deleting keys that aren't there changes nothing
+            if removed > 0 {
+                replicate(context, peers, &op).await;
+            }
+            removed.to_string()
+        }
+        Command::FlushRequest => {
+            let token = format!("{:016x}", rand::random::<u64>());
+            *context.pending_flush.lock().await = Some((token.clone(), context.clock.now()));
+            info!(target: AUDIT, "FLUSHALL requested by {}, confirmation token issued (request {})", client, request_id());
+            format!("CONFIRM: send FLUSHALL {} [CLUSTER] [SNAPSHOT] within {}s", token, FLUSH_TOKEN_TTL.as_secs())
+        }
+        Command::FlushConfirm { token, cluster, snapshot } => {
+            let confirmed = {
+                let mut pending = context.pending_flush.lock().await;
+                match pending.take() {
+                    Some((expected, issued)) if expected == token && context.clock.now() - issued < FLUSH_TOKEN_TTL => true,
+                    other => {
+                        // A wrong guess doesn't cancel the outstanding token
+                        *pending = other.filter(|(expected, _)| *expected != token);
+                        false
+                    }
+                }
+            };
+
+            if !confirmed {
+                warn!(target: AUDIT, "FLUSHALL rejected for {}: invalid or expired token (request {})", client, request_id());
+                return "Invalid or expired FLUSHALL token".to_string();
+            }
+            match flush_all(cache, context, snapshot, client).await {
+                Ok(removed) => {
+                    if cluster {
+                        replicate(context, peers, ReplicatedOp::FlushAll { snapshot }).await;
+                    }
+                    format!("OK: FLUSHALL removed {} keys", removed)
+                }
+                Err(e) => e,
+            }
+        }
+        Command::Replicate { command } => {
+            // Received a replicated operation (no re-broadcast)
+            debug!("Processing REPLICATE {}", command);
+
+            match apply_replicated(cache, context, &command).await {
+                Ok(_) => "OK: REPLICATE applied".to_string(),
+                Err(e) => {
+                    warn!("Failed to apply replicated command {}: {}", command, e);
+                    e
+                }
+            }
+        }
+        // Unwrapped by execute, and CID can't be nested
+        Command::Correlated { .. } => "Invalid CID command".to_string(),
+        Command::Epoch { .. } => "Invalid EPOCH command".to_string(),
+        Command::SessionWrite { command } => {
+            session::run_write(context, Box::pin(run_command(*command, socket, client, cache, peers, context))).await
+        }
+        Command::SessionRead { tokens, command } => match await_tokens(context, tokens).await.as_slice() {
+            [] => match parse_command(&command) {
+                Ok(command) => Box::pin(run_command(command, socket, client, cache, peers, context)).await,
+                Err(e) => e,
+            },
+            [first, rest @ ..] => forward_read(context, first, rest, &command).await,
+        },
+        Command::Sequenced { origin, boot, seq, message } => apply_sequenced(&origin, boot, seq, &message, cache, peers, context).await,
+        Command::Resync { boot, from, to } => {
+            debug!("Processing RESYNC {} {}..={} for {}", boot, from, to, client);
+
+            context.sequences.resync(boot, from, to).unwrap_or_else(|e| e)
+        }
+        Command::Type { key } => {
+            debug!("Processing TYPE for key: {}", key);
+
+            let cache = cache.lock().await;
+            cache.get(&key).map(CacheValue::type_name).unwrap_or("none").to_string()
+        }
+        Command::Ttl { key } => {
+            debug!("Processing TTL for key: {}", key);
+
+            format_ttl(cache.lock().await.ttl(&key))
+        }
+        Command::GetEx { key, ttl } => {
+            debug!("Processing GETEX for key: {} TTL={}", key, ttl);
+
+            // A TTL too long to count in Unix millis never expires in practice
+            let at = context.clock.unix_millis().saturating_add(ttl.saturating_mul(1000));
+            let op = ExpiryOp::At { key: key.clone(), at };
+            let value = {
+                let mut cache = cache.lock().await;
+                let Some(value) = cache.get(&key).map(CacheValue::to_string) else {
+                    return "Not Found".to_string();
+                };
+                apply_expiry(&mut cache, &op);
+                value
+            };
+            replicate(context, peers, &op).await;
+            value
+        }
+        Command::Expiry(op) => {
+            // EXPIREAT changes nothing on a missing key, PERSIST on a key without an expiry
+            let changed = apply_expiry_command(cache, &op).await;
+            if changed {
+                replicate(context, peers, &op).await;
+            }
+            if changed { "1" } else { "0" }.to_string()
+        }
+        Command::GetHist { key, n } => {
+            debug!("Processing GETHIST for key: {}", key);
+
+            let cache = cache.lock().await;
+            if !cache.keeps_history() {
+                return KEY_HISTORY_DISABLED.to_string();
+            }
+            let versions = cache.history(&key, n.unwrap_or(usize::MAX));
+            if versions.is_empty() {
+                return "Not Found".to_string();
+            }
+            versions.iter().map(format_version).collect::<Vec<_>>().join("\n")
+        }
+        Command::Sizes { prefix, top } => {
+            debug!("Processing SIZES prefix: {}, top: {}", prefix, top);
+
+            let cache = cache.lock().await;
+            run_size_report(&cache, &prefix, top)
+        }
+        Command::MemoryUsage { key } => {
+            debug!("Processing MEMORY USAGE for key: {}", key);
+
+            let cache = cache.lock().await;
+            cache.key_memory_usage(&key).map(|bytes| bytes.to_string()).unwrap_or_else(|| "Not Found".to_string())
+        }
+        Command::DebugObject { key } => {
+            debug!("Processing DEBUG OBJECT for key: {}", key);
+
+            let (kind, debug, ttl) = {
+                let cache = cache.lock().await;
+                match (cache.get(&key), cache.debug_object(&key)) {
+                    (Some(value), Some(debug)) => (value.type_name(), debug, format_ttl(cache.ttl(&key))),
+                    _ => return "Not Found".to_string(),
+                }
+            };
+            // Every peer holds a full copy
+            let mut replicas = vec![context.sequences.origin().to_string()];
+            replicas.extend(peers.lock().await.iter().cloned());
+            let (origin, writer, correlation) = match &debug.source {
+                Some(source) => (source.origin.to_string(), source.writer.to_string(), source.correlation.to_string()),
+                None => ("-".to_string(), "-".to_string(), "-".to_string()),
+            };
+            format!(
+                "type:{}\nversion:{}\nmodified:{}\nttl:{}\nmemory_bytes:{}\norigin:{}\nlast_writer:{}\ncorrelation_id:{}\nreplicas:{}",
+                kind,
+                debug.version,
+                debug.modified.map_or("-".to_string(), |modified| modified.to_string()),
+                ttl,
+                debug.memory_bytes,
+                origin,
+                writer,
+                correlation,
+                replicas.join(",")
+            )
+        }
+        Command::CreateIndex { name, path } => {
+            debug!("Processing CREATE_INDEX {} on {}", name, path);
+
+            let mut cache = cache.lock().await;
+            match cache.create_index(&name, &path) {
+                Ok(indexed) => format!("OK: index {} created ({} keys indexed)", name, indexed),
+                Err(e) => e,
+            }
+        }
+        Command::DropIndex { name } => {
+            debug!("Processing DROP_INDEX {}", name);
+
+            let mut cache = cache.lock().await;
+            if cache.drop_index(&name) {
+                format!("OK: index {} dropped", name)
+            } else {
+                format!("Unknown index {}", name)
+            }
+        }
+        Command::ListIndexes => {
+            debug!("Processing LIST_INDEXES");
+
+            let cache = cache.lock().await;
+            cache
+                .indexes
+                .iter()
+                .map(|(name, index)| format!("{}={}", name, index.path))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        Command::Find { index, value } => {
+            debug!("Processing FIND {}={}", index, value);
+
+            let cache = cache.lock().await;
+            match cache.find(&index, &value) {
+                Some(keys) if keys.is_empty() => "Not Found".to_string(),
+                Some(keys) => keys.join("\n"),
+                None => format!("Unknown index {}", index),
+            }
+        }
+        Command::Peers => {
+            debug!("Processing PEERS");
+
+            let mut peers: Vec<String> = peers.lock().await.iter().cloned().collect();
+            peers.sort();
+            peers.join("\n")
+        }
+        Command::AddPeer { peer } => {
+            if peer == context.sequences.origin() {
+                return format!("Invalid ADDPEER command: {} is this node", peer);
+            }
+            info!(target: AUDIT, "ADDPEER {} requested by {} (request {})", peer, client, request_id());
+            context.health.unban(&peer);
+            match context.manual_peers.add(peers, &peer).await {
+                true => format!("OK: added peer {}", peer),
+                false => format!("OK: {} was already a peer; discovery won't remove it", peer),
+            }
+        }
+        Command::RemovePeer { peer } => {
+            info!(target: AUDIT, "REMOVEPEER {} requested by {} (request {})", peer, client, request_id());
+            match context.manual_peers.remove(peers, &peer).await {
+                true => format!("OK: removed peer {}", peer),
+                false => format!("OK: {} was not a peer; discovery won't add it", peer),
+            }
+        }
+        Command::PeerHealth => {
+            debug!("Processing PEERS HEALTH");
+
+            context.health.report(context.clock.now()).join("\n")
+        }
+        Command::PeerBans => {
+            debug!("Processing PEERS BANS");
+
+            context.health.bans(context.clock.now()).join("\n")
+        }
+        Command::Ping { time: false } => "PONG".to_string(),
+        Command::Ping { time: true } => format!("PONG {}", context.clock.unix_millis()),
+        Command::Stats => {
+            debug!("Processing STATS");
+
+            let (keys, memory, index_memory) = {
+                let cache = cache.lock().await;
+                (cache.len(), cache.memory_usage(), cache.index_memory_usage())
+            };
+            let peer_count = peers.lock().await.len();
+            let (pending, dead) = match &context.missed.outbox {
+                Some(outbox) => outbox.counts().iter().fold((0, 0), |(p, d), (_, pending, dead)| (p + pending, d + dead)),
+                None => (0, 0),
+            };
+            let (queued, shed) = match &context.replicator {
+                Some(replicator) => (replicator.depth(), replicator.dropped()),
+                None => (0, 0),
+            };
+            let fsyncs = context.log_commits.as_ref().map(|commits| commits.fsync_stats()).unwrap_or_default();
+            let fsync_avg = fsyncs.total.checked_div(fsyncs.count as u32).unwrap_or_default();
+            format!(
+                "keys:{}\nmemory_bytes:{}\nindex_memory_bytes:{}\npeers:{}\noutbox_pending:{}\noutbox_dead:{}\nreplication_queue_depth:{}\nreplication_queue_shed:{}\nlog_fsyncs:{}\nlog_fsync_avg_ms:{:.3}\nlog_fsync_max_ms:{:.3}\ncluster_epoch:{}",
+                keys,
+                memory,
+                index_memory,
+                peer_count,
+                pending,
+                dead,
+                queued,
+                shed,
+                fsyncs.count,
+                fsync_avg.as_secs_f64() * 1000.0,
+                fsyncs.max.as_secs_f64() * 1000.0,
+                context.membership.epoch()
+            )
+        }
+        Command::Metrics => context.latencies.prometheus(),
+        Command::OutboxList => {
+            let Some(outbox) = &context.missed.outbox else {
+                return OUTBOX_DISABLED.to_string();
+            };
+            outbox
+                .counts()
+                .iter()
+                .map(|(peer, pending, dead)| format!("{} pending={} dead={}", peer, pending, dead))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        Command::OutboxDead { peer } => {
+            let Some(outbox) = &context.missed.outbox else {
+                return OUTBOX_DISABLED.to_string();
+            };
+            outbox
+                .dead(&peer)
+                .iter()
+                .map(|letter| json!({"queued": letter.queued, "reason": letter.reason, "message": letter.message}).to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        Command::OutboxPurge { peer } => {
+            let Some(outbox) = &context.missed.outbox else {
+                return OUTBOX_DISABLED.to_string();
+            };
+            info!(target: AUDIT, "OUTBOX PURGE {} requested by {} (request {})", peer.as_deref().unwrap_or("ALL"), client, request_id());
+            format!("OK: {} dead letters purged", outbox.purge(peer.as_deref()))
+        }
+        Command::Compact { rows_per_sec } => {
+            let Some(log) = &context.append_log else {
+                return "COMPACT needs the append log (P2P_PERSISTENCE=log)".to_string();
+            };
+            info!(target: AUDIT, "COMPACT requested by {} (request {})", client, request_id());
+            match log.lock().await.compact(cache, rows_per_sec).await {
+                Ok(compacted) => format!(
+                    "OK: compacted {} segments into the snapshot ({} keys, {} tombstones dropped), reclaimed {} bytes",
+                    compacted.segments, compacted.keys, compacted.tombstones, compacted.reclaimed_bytes
+                ),
+                Err(e) => {
+                    error!("COMPACT failed: {}", e);
+                    format!("COMPACT failed: {}", e)
+                }
+            }
+        }
+        Command::ClusterStatus => {
+            debug!("Processing CLUSTER STATUS");
+
+            let keys = cache.lock().await.len();
+            let peers_snapshot = peers.lock().await.iter().cloned().collect();
+            let mut lines = vec![format!("{} self keys={}", context.sequences.origin(), keys)];
+            lines.extend(context.health.status(peers_snapshot, context.clock.now()));
+            lines.extend(context.partitions.status());
+            lines.join("\n")
+        }
+        Command::ClusterConflicts => {
+            debug!("Processing CLUSTER CONFLICTS");
+
+            context.partitions.conflicts().join("\n")
+        }
+        Command::ClusterExec { command } => {
+            info!(target: AUDIT, "CLUSTER EXEC {} requested by {} (request {})", command, client, request_id());
+            exec_on_cluster(&command, client, cache, peers, context).await
+        }
+        // Served by handle_connection, which hands the whole connection to stream_changes
+        Command::Subscribe { .. } => "SUBSCRIBE must be the first command on its connection".to_string(),
+        // Likewise handed to serve_snapshot
+        Command::Snapshot(_) => "SNAPSHOT must be the first command on its connection".to_string(),
+        Command::SyncFrom { peer, request } => {
+            info!(target: AUDIT, "SYNC FROM {} requested by {} (request {})", peer, client, request_id());
+            match pull_snapshot(&peer, request, cache, context).await {
+                Ok(received) => format!("OK: synced {} keys from {}", received, peer),
+                Err(e) => format!("SYNC failed: {}", e),
+            }
+        }
+    }
+}
+
+// Apply LPUSH/RPUSH/LPOP/RPOP to the local cache, returning the client response
+pub(crate) async fn apply_list_command(cache: &SharedCache, op: &ListOp) -> Result<String, String> {
+    debug!("Processing {}", op);
+
+    let mut cache = cache.lock().await;
+    match op {
+        ListOp::Push { key, value, front } => cache.list_push(key, value.clone(), *front).map(|len| len.to_string()),
+        ListOp::Pop { key, front } => Ok(cache.list_pop(key, *front)?.unwrap_or_else(|| "Not Found".to_string())),
+    }
+}
+
+// Apply HSET/HDEL to the local cache, returning "1" if a field was added/removed, "0" otherwise
+pub(crate) async fn apply_hash_command(cache: &SharedCache, op: &HashOp) -> Result<String, String> {
+    debug!("Processing {}", op);
+
+    let mut cache = cache.lock().await;
+    let changed = match op {
+        HashOp::Set { key, field, value } => cache.hash_set(key, field.clone(), value.clone())?,
+        HashOp::Del { key, field } => cache.hash_del(key, field)?,
+    };
+    Ok(if changed { "1" } else { "0" }.to_string())
+}
+
+// Apply XADD_AT (replicated XADD carrying the origin's ID) and XCOMMIT to the local cache
+pub(crate) async fn apply_stream_command(cache: &SharedCache, op: &StreamOp) -> Result<(), String> {
+    let mut cache = cache.lock().await;
+    match op {
+        StreamOp::AddAt { key, id, payload } => cache.stream_add(key, Some(*id), payload.clone()).map(|_| ()),
+        StreamOp::Commit { key, consumer, offset } => cache.stream_commit(key, consumer, *offset),
+    }
+}
+
+// Apply ZADD/ZREM to the local cache, returning "1" if a member was added/removed, "0" otherwise
+pub(crate) async fn apply_zset_command(cache: &SharedCache, op: &ZsetOp) -> Result<String, String> {
+    debug!("Processing {}", op);
+
+    let mut cache = cache.lock().await;
+    let changed = match op {
+        ZsetOp::Add { key, score, member } => cache.zset_add(key, *score, member.clone())?,
+        ZsetOp::Rem { key, member } => cache.zset_remove(key, member)?,
+    };
+    Ok(if changed { "1" } else { "0" }.to_string())
+}
+
+// Apply EXPIREAT/PERSIST, returning whether the key exists (EXPIREAT) or had an expiry (PERSIST)
+pub(crate) async fn apply_expiry_command(cache: &SharedCache, op: &ExpiryOp) -> bool {
+    apply_expiry(&mut *cache.lock().await, op)
+}
+
+fn apply_expiry(cache: &mut Cache, op: &ExpiryOp) -> bool {
+    match op {
+        ExpiryOp::At { key, at } => cache.set_expiry(key, Some(*at)),
+        ExpiryOp::Persist { key } => cache.expires_at(key).is_some() && cache.set_expiry(key, None),
+    }
+}
+
+// TTL's answer: seconds left, rounded up, -1 for a key without an expiry and -2 for a missing one
+fn format_ttl(ttl: Option<Option<u64>>) -> String {
+    match ttl {
+        None => "-2".to_string(),
+        Some(None) => "-1".to_string(),
+        Some(Some(ms)) => ms.div_ceil(1000).to_string(),
+    }
+}
+
+// Apply DEL/DEL_PREFIX/DEL_MATCH atomically to the local cache, returning the number of keys removed
+pub(crate) async fn apply_delete_command(cache: &SharedCache, op: &DeleteOp) -> usize {
+    debug!("Processing {}", op);
+
+    let mut cache = cache.lock().await;
+    match op {
+        DeleteOp::Key(key) => cache.remove(key).map_or(0, |_| 1),
+        DeleteOp::Prefix(prefix) => cache.remove_where(|key| key.starts_with(prefix.as_str())),
+        DeleteOp::Match(pattern) => cache.remove_where(|key| glob_match(pattern, key)),
+    }
+}
+
+// One GETHIST line; the value comes last since it may contain spaces
+fn format_version(version: &KeyVersion) -> String {
+    let (origin, writer) = match &version.source {
+        Some(source) => (source.origin.to_string(), source.writer.to_string()),
+        None => ("-".to_string(), "-".to_string()),
+    };
+    let value = match &version.value {
+        Some(value) => format!("type={} value={}", value.type_name(), value),
+        None => "deleted".to_string(),
+    };
+    format!(
+        "version={} modified={} origin={} writer={} {}",
+        version.version,
+        version.modified.map_or("-".to_string(), |modified| modified.to_string()),
+        origin,
+        writer,
+        value
+    )
+}
+
+// Clear the cache, optionally snapshotting its current state first, and record it in the audit log
+pub(crate) async fn flush_all(cache: &SharedCache, context: &NodeContext, snapshot: bool, requested_by: &str) -> Result<usize, String> {
+    if snapshot {
+        let timestamp = context.clock.unix_millis() / 1000;
+        let snapshot_path = format!("node_{}_preflush_{}.arrow", context.node_port, timestamp);
+        if let Err(e) = write_cache_to_arrow(Arc::clone(cache), &snapshot_path).await {
+            error!(target: AUDIT, "FLUSHALL by {} aborted, pre-flush snapshot failed: {} (request {})", requested_by, e, request_id());
+            return Err(format!("FLUSHALL aborted: snapshot failed: {}", e));
+        }
+        info!(target: AUDIT, "Pre-flush snapshot written to {} (request {})", snapshot_path, request_id());
+    }
+
+    let removed = cache.lock().await.clear();
+    warn!(target: AUDIT, "FLUSHALL by {} removed {} keys (request {})", requested_by, removed, request_id());
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{Duration, Instant};
+    use crate::node::RemoteReads;
+    use crate::testing::TestCluster;
+
+    // Poll `command` on node `i` until it answers `expected`, as a peer may not have the write yet
+    async fn answers(cluster: &TestCluster, i: usize, command: &str, expected: &str) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let response = cluster.request(i, command).await.unwrap();
+            if response.trim_end() == expected {
+                return;
+            }
+            assert!(Instant::now() < deadline, "node {} answered {:?} to {}, expected {:?}", i, response, command, expected);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    // Run each command on node 0, checking its answer, then check node 1 reads the same
+    async fn check(cluster: &TestCluster, steps: &[(&str, &str)], reads: &[(&str, &str)]) {
+        for (command, expected) in steps {
+            assert_eq!(cluster.request(0, command).await.unwrap().trim_end(), *expected, "{}", command);
+        }
+        for (command, expected) in reads {
+            answers(cluster, 1, command, expected).await;
+        }
+    }
+
+    #[test]
+    fn frames_only_messages_that_do_not_fit_a_request_line() {
+        assert_eq!(frame("SET a=1"), "SET a=1\n");
+        let long = "x".repeat(MAX_REQUEST_SIZE);
+        assert_eq!(frame(&long), format!("FRAME {}\n{}", MAX_REQUEST_SIZE, long));
+        assert_eq!(frame("SET note=a\nb"), "FRAME 12\nSET note=a\nb");
+    }
+
+    #[test]
+    fn splits_a_frame_header() {
+        assert_eq!(split_frame(b"FRAME 5\nabc"), Some((5, &b"abc"[..])));
+        assert_eq!(split_frame(b"FRAME 5\r\n"), Some((5, &b""[..])));
+        assert_eq!(split_frame(b"SET a=1\n"), None);
+        assert_eq!(split_frame(b"FRAME x\nabc"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_the_rest_of_a_frame() {
+        let message = format!("SET big={}", "y".repeat(2 * MAX_REQUEST_SIZE));
+        let framed = frame(&message);
+        let (head, tail) = framed.as_bytes().split_at(MAX_REQUEST_SIZE);
+        let (length, read) = split_frame(head).unwrap();
+        let mut rest = tail;
+        assert_eq!(read_frame(&mut rest, length, read).await.unwrap(), message.as_bytes());
+
+        // A following request on the same stream is left unread
+        let mut stream = &b"SET a=1\nGET a\n"[..];
+        assert_eq!(read_frame(&mut stream, 8, &[]).await.unwrap(), b"SET a=1\n");
+        assert_eq!(stream, b"GET a\n");
+
+        let mut empty = &b""[..];
+        assert!(read_frame(&mut empty, MAX_FRAME_SIZE + 1, &[]).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn typed_values_keep_their_type() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        check(
+            &cluster,
+            &[
+                ("SET counter=40 TYPE=int", "OK: SET successful"),
+                ("INCR counter 2", "42"),
+                ("SET ratio=0.5 TYPE=float", "OK: SET successful"),
+                ("INCR ratio 1", "Wrong type: ratio holds a value of type float, INCR not supported"),
+                ("SET raw=cafe TYPE=bytes", "OK: SET successful"),
+                ("APPEND raw babe", "4"),
+                ("APPEND raw xyz", "Invalid hex bytes value: xyz"),
+                ("SET name=al", "OK: SET successful"),
+                ("APPEND name ice", "5"),
+                ("APPEND counter 1", "Wrong type: counter holds a value of type int, APPEND not supported"),
+                ("SET bad=x TYPE=int", "Invalid SET command: Invalid int value: x"),
+            ],
+            &[("TYPE counter", "int"), ("GET counter", "42"), ("TYPE ratio", "float"), ("GET raw", "cafebabe"), ("TYPE raw", "bytes"), ("GET name", "alice")],
+        )
+        .await;
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn list_operations_replicate() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        check(
+            &cluster,
+            &[
+                ("RPUSH jobs b", "1"),
+                ("RPUSH jobs c", "2"),
+                ("LPUSH jobs a", "3"),
+                ("LRANGE jobs 0 -1", "a\nb\nc"),
+                ("RPOP jobs", "c"),
+                ("LPOP missing", "Not Found"),
+                ("SET name=x", "OK: SET successful"),
+                ("LPUSH name a", "Wrong type: name holds a value of type string, LPUSH not supported"),
+            ],
+            &[("LRANGE jobs 0 -1", "a\nb"), ("TYPE jobs", "list")],
+        )
+        .await;
+        // Popping the last element removes the key everywhere
+        check(&cluster, &[("LPOP jobs", "a"), ("LPOP jobs", "b")], &[("TYPE jobs", "none")]).await;
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hash_operations_replicate() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        check(
+            &cluster,
+            &[
+                ("HSET user:1 name=Alice", "1"),
+                ("HSET user:1 name=Alicia", "0"),
+                ("HSET user:1 city=Oslo", "1"),
+                ("HGET user:1 name", "Alicia"),
+                ("HDEL user:1 city", "1"),
+                ("HDEL user:1 city", "0"),
+                ("HGET user:1 city", "Not Found"),
+            ],
+            &[("HGETALL user:1", "name=Alicia"), ("TYPE user:1", "hash")],
+        )
+        .await;
+        check(&cluster, &[("HDEL user:1 name", "1")], &[("TYPE user:1", "none")]).await;
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_entries_and_offsets_replicate() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        check(
+            &cluster,
+            &[
+                ("XADD events first", "1"),
+                ("XADD events second", "2"),
+                ("XADD events third", "3"),
+                ("XREAD events from=2 count=1", "2 second"),
+                ("XCOMMIT events billing 2", "OK: XCOMMIT successful"),
+                ("XCOMMIT missing billing 1", "Not Found"),
+                ("XLEN missing", "0"),
+            ],
+            &[("XLEN events", "3"), ("XREAD events consumer=billing", "2 second\n3 third")],
+        )
+        .await;
+        // An entry added on the peer continues from the IDs it was sent
+        assert_eq!(cluster.request(1, "XADD events fourth").await.unwrap().trim_end(), "4");
+        answers(&cluster, 0, "XREAD events from=4", "4 fourth").await;
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sorted_set_operations_replicate() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        check(
+            &cluster,
+            &[
+                ("ZADD board 42 alice", "1"),
+                ("ZADD board 7 bob", "1"),
+                ("ZADD board 99 carol", "1"),
+                ("ZADD board 50 alice", "0"),
+                ("ZSCORE board alice", "50"),
+                ("ZRANGEBYSCORE board 10 +inf WITHSCORES LIMIT 1", "alice=50"),
+                ("ZREM board bob", "1"),
+                ("ZREM board bob", "0"),
+            ],
+            &[("ZRANGEBYSCORE board -inf +inf", "alice\ncarol"), ("ZSCORE board bob", "Not Found")],
+        )
+        .await;
+        check(&cluster, &[("ZREM board alice", "1"), ("ZREM board carol", "1")], &[("TYPE board", "none")]).await;
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ttl_commands_answer_and_replicate() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        check(
+            &cluster,
+            &[
+                ("TTL session:1", "-2"),
+                ("GETEX session:1 TTL=30", "Not Found"),
+                ("SET session:1=token", "OK: SET successful"),
+                ("TTL session:1", "-1"),
+                ("PERSIST session:1", "0"),
+                ("GETEX session:1 TTL=30", "token"),
+                ("TTL session:1", "30"),
+            ],
+            &[("TTL session:1", "30")],
+        )
+        .await;
+        // Seconds left are rounded up
+        cluster.advance(Duration::from_millis(500)).await;
+        check(&cluster, &[("TTL session:1", "30"), ("PERSIST session:1", "1")], &[("TTL session:1", "-1")]).await;
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn json_fields_are_read_and_updated_in_place() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        check(
+            &cluster,
+            &[
+                (r#"SET user:1={"name":"Alice","tags":["a","b"]}"#, "OK: SET successful"),
+                ("JSON.GET user:1 $.name", r#""Alice""#),
+                ("JSON.GET user:1 $.tags[1]", r#""b""#),
+                ("JSON.GET user:1 $.age", "Not Found"),
+                (r#"JSON.SET user:1 $.address.city "Prague""#, "OK: JSON.SET successful"),
+                ("JSON.SET user:1 $.tags[5] 1", "Array index 5 out of bounds"),
+                ("SET plain=text", "OK: SET successful"),
+                ("JSON.GET plain $.name", "Value is not JSON"),
+                ("JSON.SET fresh $.count 1", "OK: JSON.SET successful"),
+            ],
+            &[("JSON.GET user:1 $.address", r#"{"city":"Prague"}"#), ("GET fresh", r#"{"count":1}"#)],
+        )
+        .await;
+        cluster.shutdown().await;
+    }
+
+    // The token out of a FLUSHALL confirmation prompt
+    async fn flush_token(cluster: &TestCluster, i: usize) -> String {
+        let prompt = cluster.request(i, "FLUSHALL").await.unwrap();
+        prompt.split_whitespace().nth(3).unwrap().to_string()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flushall_needs_a_fresh_token_and_clears_peers_only_with_cluster() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        check(&cluster, &[("SET a=1", "OK: SET successful"), ("SET b=2", "OK: SET successful")], &[("GET_LEN", "2")]).await;
+
+        // A wrong guess leaves the outstanding token usable
+        let token = flush_token(&cluster, 0).await;
+        assert_eq!(cluster.request(0, "FLUSHALL 0000000000000000").await.unwrap().trim_end(), "Invalid or expired FLUSHALL token");
+        let flush = format!("FLUSHALL {}", token);
+        check(&cluster, &[(&flush, "OK: FLUSHALL removed 2 keys")], &[]).await;
+        // Each token is used once, and a node-local flush leaves peers alone
+        assert_eq!(cluster.request(0, &flush).await.unwrap().trim_end(), "Invalid or expired FLUSHALL token");
+        assert_eq!(cluster.request(1, "GET_LEN").await.unwrap().trim_end(), "2");
+
+        let token = flush_token(&cluster, 1).await;
+        cluster.advance(FLUSH_TOKEN_TTL).await;
+        let late = format!("FLUSHALL {} CLUSTER", token);
+        assert_eq!(cluster.request(1, &late).await.unwrap().trim_end(), "Invalid or expired FLUSHALL token");
+
+        check(&cluster, &[("SET c=3", "OK: SET successful")], &[("GET_LEN", "3")]).await;
+        let token = flush_token(&cluster, 1).await;
+        assert_eq!(cluster.request(1, &format!("FLUSHALL {} CLUSTER", token)).await.unwrap().trim_end(), "OK: FLUSHALL removed 3 keys");
+        answers(&cluster, 0, "GET_LEN", "0").await;
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn gets_that_miss_ask_the_peers_only_with_remote_reads() {
+        let cluster = TestCluster::start_configured(2, |builder| builder.remote_reads(RemoteReads::default())).await.unwrap();
+        // A value only node 1 holds, as if its replication hadn't arrived yet
+        cluster.request(1, "BROADCAST late=v").await.unwrap();
+        assert_eq!(cluster.request(0, "LOOKUP late").await.unwrap().trim_end(), "Not Found");
+        assert_eq!(cluster.request(0, "GET late").await.unwrap().trim_end(), "v");
+        // The value found is cached, so the peer isn't needed any more
+        assert_eq!(cluster.request(0, "LOOKUP late").await.unwrap().trim_end(), "late=v");
+        assert_eq!(cluster.request(0, "GET missing").await.unwrap().trim_end(), "Not Found");
+        // So is when it expires
+        cluster.request(1, "BROADCAST session:1=token").await.unwrap();
+        cluster.request(1, "GETEX session:1 TTL=30").await.unwrap();
+        assert!(cluster.request(1, "LOOKUP session:1").await.unwrap().starts_with("session:1=token\nEXPIREAT "));
+        assert_eq!(cluster.request(0, "GET session:1").await.unwrap().trim_end(), "token");
+        assert_eq!(cluster.request(0, "TTL session:1").await.unwrap().trim_end(), "30");
+        cluster.shutdown().await;
+
+        let cluster = TestCluster::start(2).await.unwrap();
+        cluster.request(1, "BROADCAST late=v").await.unwrap();
+        assert_eq!(cluster.request(0, "GET late").await.unwrap().trim_end(), "Not Found");
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debug_object_reports_where_a_key_was_written() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        check(&cluster, &[("SET key1=v", "OK: SET successful"), ("CID req-2 SET key1=w", "OK: SET successful")], &[("GET key1", "w")]).await;
+        let (a, b) = (cluster.addr(0), cluster.addr(1));
+        // A peer sees the origin as the writer, and the correlation ID the client chose
+        let on_peer = cluster.request(1, "DEBUG OBJECT key1").await.unwrap();
+        let expected = [
+            "type:string".to_string(),
+            "version:2".to_string(),
+            format!("origin:{a}"),
+            format!("last_writer:{a}"),
+            "correlation_id:req-2".to_string(),
+            format!("replicas:{b},{a}"),
+        ];
+        for line in expected {
+            assert!(on_peer.lines().any(|l| l == line), "{} not in {}", line, on_peer);
+        }
+        let here = cluster.request(0, "DEBUG OBJECT key1").await.unwrap();
+        assert!(here.lines().any(|l| l == format!("origin:{a}")) && !here.contains(&format!("last_writer:{a}")), "{}", here);
+        assert_eq!(cluster.request(0, "DEBUG OBJECT missing").await.unwrap().trim_end(), "Not Found");
+        cluster.shutdown().await;
+    }
+}
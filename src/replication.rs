@@ -0,0 +1,1113 @@
+//! Propagation of writes to peers (BROADCAST for values, REPLICATE for operations)
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use futures::future::join_all;
+use futures::stream::{self, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Duration;
+use log::{debug, error, info, warn};
+
+use crate::clock::SharedClock;
+use crate::discovery::PeerList;
+use crate::node::{NodeContext, RemoteReads, SharedContext};
+use crate::health::PeerHealth;
+use crate::membership::Membership;
+use crate::metrics::Latencies;
+use crate::outbox::Outboxes;
+use crate::partition::reconcile;
+use crate::sequence::Sequences;
+use crate::session;
+use crate::protocol::command::{parse_replicated, ExpiryOp, ReplicatedOp};
+use crate::protocol::{apply_delete_command, apply_expiry_command, apply_hash_command, apply_list_command, apply_stream_command, apply_zset_command, flush_all, format_assignment, frame, parse_assignment};
+use crate::storage::{Cache, CacheValue, SharedCache, WRITE_SOURCE};
+use crate::transport::SharedTransport;
+
+// Apply an operation received from a peer via REPLICATE
+pub async fn apply_replicated(cache: &SharedCache, context: &NodeContext, command: &str) -> Result<(), String> {
+    match parse_replicated(command)? {
+        ReplicatedOp::List(op) => apply_list_command(cache, &op).await.map(|_| ()),
+        ReplicatedOp::Hash(op) => apply_hash_command(cache, &op).await.map(|_| ()),
+        ReplicatedOp::Stream(op) => apply_stream_command(cache, &op).await,
+        ReplicatedOp::Zset(op) => apply_zset_command(cache, &op).await.map(|_| ()),
+        ReplicatedOp::Delete(op) => {
+            apply_delete_command(cache, &op).await;
+            Ok(())
+        }
+        ReplicatedOp::Expiry(op) => {
+            apply_expiry_command(cache, &local_expiry(context, op)).await;
+            Ok(())
+        }
+        ReplicatedOp::FlushAll { snapshot } => flush_all(cache, context, snapshot, "peer").await.map(|_| ()),
+    }
+}
+
+// An EXPIREAT is in the origin's clock: move it by how far that is ahead of ours, so the key
+// expires at the same moment everywhere. Unchanged while the offset isn't known yet.
+fn local_expiry(context: &NodeContext, op: ExpiryOp) -> ExpiryOp {
+    let ExpiryOp::At { key, at } = op else {
+        return op;
+    };
+    match WRITE_SOURCE.try_with(|source| Arc::clone(&source.origin)) {
+        Ok(origin) => ExpiryOp::At { key, at: from_peer_clock(context, &origin, at) },
+        Err(_) => ExpiryOp::At { key, at },
+    }
+}
+
+// Unix millis `at` in `peer`'s clock moved to ours, unchanged while the offset isn't known yet
+pub(crate) fn from_peer_clock(context: &NodeContext, peer: &str, at: u64) -> u64 {
+    let offset = context.health.clock_offset(peer);
+    offset.map_or(at, |offset| at.saturating_add_signed(offset.saturating_neg()))
+}
+
+pub async fn broadcast_set(transport: SharedTransport, peers: PeerList, key: String, value: CacheValue) {
+    let message = format!("BROADCAST {}", format_assignment(&key, &value)); // Use BROADCAST prefix
+    send_to_peers(transport, peers, message).await;
+}
+
+// Replicate an operation to all peers in the background
+pub fn broadcast_command(transport: SharedTransport, peers: PeerList, message: String) {
+    tokio::spawn(send_to_peers(transport, peers, message));
+}
+
+// `message` under the correlation ID of the request replicating it, if there is one
+fn correlated(message: String) -> String {
+    WRITE_SOURCE
+        .try_with(|source| format!("CID {} {}", source.correlation, message))
+        .unwrap_or(message)
+}
+
+// Number `message` for its peers, noting the number for a SESSION write's token
+fn number(context: &NodeContext, message: String) -> String {
+    let (seq, numbered) = context.sequences.number(message);
+    session::replicated(Some(seq));
+    numbered
+}
+
+// Replicate a value to all peers, queued on the node's batcher or replication queue if it has one
+pub(crate) async fn replicate_set(context: &NodeContext, peers: &PeerList, key: String, value: CacheValue) {
+    let message = correlated(format!("BROADCAST {}", format_assignment(&key, &value)));
+    match (&context.batcher, &context.replicator) {
+        (Some(batcher), _) => {
+            session::replicated(None);
+            batcher.queue(context, peers, Some(&key), message)
+        }
+        (None, Some(replicator)) => replicator.push(context, peers, message).await,
+        (None, None) => {
+            let peers = peers.lock().await.clone();
+            delivered(context.lanes.send_numbered(context, &peers, message)).await;
+        }
+    }
+}
+
+// Send a BROADCAST (for `key`) or REPLICATE message to all peers in the background; with a
+// replication queue this waits while the queue is full
+pub(crate) async fn replicate_message(context: &NodeContext, peers: &PeerList, key: Option<&str>, message: String) {
+    let message = correlated(message);
+    match (&context.batcher, &context.replicator) {
+        (Some(batcher), _) => {
+            session::replicated(None);
+            batcher.queue(context, peers, key, message)
+        }
+        (None, Some(replicator)) => replicator.push(context, peers, message).await,
+        (None, None) => {
+            let peers = peers.lock().await.clone();
+            context.lanes.send_numbered(context, &peers, message);
+        }
+    }
+}
+
+// Bounds the writes waiting to be sent to peers, which `workers` tasks send in turn, instead
+// of a task per write
+#[derive(Clone, Debug)]
+pub struct ReplicationQueue {
+    pub capacity: usize,
+    pub workers: usize,
+    // When full, refuse client writes with BUSY instead of making them wait
+    pub shed: bool,
+}
+
+impl Default for ReplicationQueue {
+    fn default() -> Self {
+        ReplicationQueue { capacity: 10_000, workers: 4, shed: false }
+    }
+}
+
+impl ReplicationQueue {
+    // "on" for the defaults, or e.g. "capacity=50000,workers=8,when_full=shed"
+    pub fn parse(spec: &str) -> Result<ReplicationQueue, String> {
+        let mut queue = ReplicationQueue::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty() && *s != "on") {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid replication queue setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "capacity" => queue.capacity = value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?,
+                "workers" => queue.workers = value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?,
+                "when_full" => match value {
+                    "block" => queue.shed = false,
+                    "shed" => queue.shed = true,
+                    _ => return Err(invalid()),
+                },
+                _ => return Err(format!("Unknown replication queue setting: {}", name)),
+            }
+        }
+        Ok(queue)
+    }
+}
+
+type QueuedWrite = (PeerList, String);
+
+// The running queue of a node started with NodeBuilder::replication_queue
+pub(crate) struct Replicator {
+    pub(crate) workers: usize,
+    shed: bool,
+    // Held from numbering a write until it is queued, so the queue is in number order
+    numbering: tokio::sync::Mutex<()>,
+    sender: mpsc::Sender<QueuedWrite>,
+    receiver: tokio::sync::Mutex<mpsc::Receiver<QueuedWrite>>,
+    // Client writes refused while the queue was full, plus writes left to catch-up because it
+    // filled up after they were let in
+    dropped: AtomicU64,
+}
+
+impl Replicator {
+    pub(crate) fn new(queue: &ReplicationQueue) -> Self {
+        let (sender, receiver) = mpsc::channel(queue.capacity);
+        Replicator {
+            workers: queue.workers,
+            shed: queue.shed,
+            numbering: tokio::sync::Mutex::new(()),
+            sender,
+            receiver: tokio::sync::Mutex::new(receiver),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // Writes waiting for a worker
+    pub(crate) fn depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Whether to refuse a client write now: the queue sheds and has no room
+    pub(crate) fn refuse_write(&self) -> bool {
+        let refused = self.shed && self.sender.capacity() == 0;
+        if refused {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        refused
+    }
+
+    async fn push(&self, context: &NodeContext, peers: &PeerList, message: String) {
+        let _numbering = self.numbering.lock().await;
+        let message = number(context, message);
+        if !self.shed {
+            let _ = self.sender.send((Arc::clone(peers), message)).await;
+            return;
+        }
+        // Filled up since the write was let in: every peer misses it until catch-up
+        if let Err(mpsc::error::TrySendError::Full((peers, message))) = self.sender.try_send((Arc::clone(peers), message)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            warn!("Replication queue full, leaving a write to catch-up");
+            let since = context.clock.unix_millis();
+            for peer in peers.lock().await.iter() {
+                context.missed.record(peer, since, std::slice::from_ref(&message));
+            }
+        }
+    }
+}
+
+// Send writes from the node's replication queue, one at a time; Node::start runs `workers` of these
+pub async fn replication_worker(context: SharedContext) {
+    let Some(replicator) = &context.replicator else {
+        return;
+    };
+    loop {
+        let sent = {
+            let mut receiver = replicator.receiver.lock().await;
+            let Some((peers, message)) = receiver.recv().await else {
+                return;
+            };
+            // Queued for the peers before another worker takes the next write, keeping the order
+            let peers = peers.lock().await.clone();
+            context.lanes.send(&context, &peers, message)
+        };
+        delivered(sent).await;
+    }
+}
+
+// How long SET .. SYNC waits for acknowledgments unless it names a TIMEOUT
+const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Which acknowledgments a SYNC write waits for
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncAck {
+    // A majority of the cluster, counting this node, instead of every peer
+    pub quorum: bool,
+    pub timeout: Duration,
+}
+
+impl SyncAck {
+    // What follows SYNC: `[ALL|QUORUM] [TIMEOUT ms]`
+    pub(crate) fn parse(options: &str) -> Result<SyncAck, String> {
+        let mut ack = SyncAck { quorum: false, timeout: DEFAULT_SYNC_TIMEOUT };
+        let mut words = options.split_whitespace();
+        while let Some(option) = words.next() {
+            match option {
+                "ALL" => ack.quorum = false,
+                "QUORUM" => ack.quorum = true,
+                "TIMEOUT" => {
+                    let ms = words.next().and_then(|ms| ms.parse().ok()).ok_or("invalid SYNC TIMEOUT")?;
+                    ack.timeout = Duration::from_millis(ms);
+                }
+                _ => return Err(format!("unknown SYNC option {}", option)),
+            }
+        }
+        Ok(ack)
+    }
+}
+
+// How a SYNC write's replication went
+pub(crate) struct Acks {
+    pub(crate) acked: usize,
+    pub(crate) needed: usize,
+    pub(crate) peers: usize,
+    // `<peer> (<why>)` for each peer that refused, failed or did not answer in time
+    pub(crate) failed: Vec<String>,
+}
+
+impl Acks {
+    pub(crate) fn response(&self, command: &str) -> String {
+        let failed = match self.failed.is_empty() {
+            true => String::new(),
+            false => format!("; failed: {}", self.failed.join(", ")),
+        };
+        if self.acked >= self.needed {
+            format!("OK: {} successful (acked by {}/{} peers){}", command, self.acked, self.peers, failed)
+        } else {
+            format!(
+                "SYNC failed: {} applied locally but acked by {}/{} peers, {} needed{}",
+                command, self.acked, self.peers, self.needed, failed
+            )
+        }
+    }
+}
+
+// Send a BROADCAST/REPLICATE message to every peer and wait until enough of them applied it,
+// at most `ack.timeout`. Peers that don't acknowledge are caught up later like any missed write.
+pub(crate) async fn replicate_acked(context: &NodeContext, peers: &PeerList, message: String, ack: &SyncAck) -> Acks {
+    if let Some(batcher) = &context.batcher {
+        batcher.flush(&Delivery::new(context), peers).await;
+    }
+    let peers_snapshot = peers.lock().await.clone();
+    let needed = if ack.quorum { peers_snapshot.len().div_ceil(2) } else { peers_snapshot.len() };
+
+    // Lanes send on in the background once enough peers answered, so the rest still get the write
+    let sent = context.lanes.send_numbered(context, &peers_snapshot, correlated(message));
+    let mut results: stream::FuturesUnordered<_> = sent
+        .into_iter()
+        .map(|(peer, sent)| async move { (peer, sent.await.unwrap_or_else(|_| Err("not sent".to_string()))) })
+        .collect();
+
+    let deadline = tokio::time::Instant::now() + ack.timeout;
+    let mut acks = Acks { acked: 0, needed, peers: peers_snapshot.len(), failed: Vec::new() };
+    let mut answered = HashSet::new();
+    while acks.acked < needed {
+        match tokio::time::timeout_at(deadline, results.next()).await {
+            Ok(Some((peer, result))) => {
+                match result {
+                    Ok(()) => acks.acked += 1,
+                    Err(e) => acks.failed.push(format!("{} ({})", peer, e)),
+                }
+                answered.insert(peer);
+            }
+            Ok(None) => break,
+            Err(_) => {
+                let late = peers_snapshot.iter().filter(|peer| !answered.contains(*peer));
+                acks.failed.extend(late.map(|peer| format!("{} (timed out)", peer)));
+                break;
+            }
+        }
+    }
+    acks
+}
+
+// What SYNC writes and batches a peer answered are timed under (see metrics.rs)
+const REPLICATION_ROUND_TRIP: &str = "replication_round_trip";
+
+// The parts of the node context a background send needs
+struct Delivery {
+    transport: SharedTransport,
+    clock: SharedClock,
+    missed: Arc<MissedWrites>,
+    sequences: Arc<Sequences>,
+    health: Arc<PeerHealth>,
+    membership: Arc<Membership>,
+    latencies: Arc<Latencies>,
+}
+
+impl Delivery {
+    fn new(context: &NodeContext) -> Self {
+        Delivery {
+            transport: Arc::clone(&context.transport),
+            clock: Arc::clone(&context.clock),
+            missed: Arc::clone(&context.missed),
+            sequences: Arc::clone(&context.sequences),
+            health: Arc::clone(&context.health),
+            membership: Arc::clone(&context.membership),
+            latencies: Arc::clone(&context.latencies),
+        }
+    }
+}
+
+// How sending a message to one peer went: Err(why) unless the peer applied it
+type Sent = oneshot::Receiver<Result<(), String>>;
+
+// A numbered message waiting in a peer's lane
+struct Letter {
+    message: String,
+    // When it was queued (unix ms), the point the peer is behind from if it never gets there
+    since: u64,
+    sent: oneshot::Sender<Result<(), String>>,
+}
+
+// Most messages a lane sends in one connection
+const LANE_BATCH: usize = 256;
+
+// A queue per peer for unbatched and SYNC writes. One task per peer sends them, whatever has
+// queued up as one PERSIST connection, and starts the next connection only once the peer
+// applied the last, so a message never overtakes an earlier one on its way to a peer.
+#[derive(Default)]
+pub(crate) struct PeerLanes {
+    lanes: std::sync::Mutex<HashMap<String, mpsc::UnboundedSender<Letter>>>,
+}
+
+impl PeerLanes {
+    // Number `message` and queue it for `peers`; numbering it under the lanes' lock keeps every
+    // lane in number order
+    fn send_numbered(&self, context: &NodeContext, peers: &HashSet<String>, message: String) -> Vec<(String, Sent)> {
+        let mut lanes = self.lanes.lock().unwrap();
+        let message = number(context, message);
+        queue_letters(&mut lanes, context, peers, message)
+    }
+
+    // Queue an already numbered message for `peers`
+    fn send(&self, context: &NodeContext, peers: &HashSet<String>, message: String) -> Vec<(String, Sent)> {
+        queue_letters(&mut self.lanes.lock().unwrap(), context, peers, message)
+    }
+}
+
+fn queue_letters(
+    lanes: &mut HashMap<String, mpsc::UnboundedSender<Letter>>,
+    context: &NodeContext,
+    peers: &HashSet<String>,
+    message: String,
+) -> Vec<(String, Sent)> {
+    let since = context.clock.unix_millis();
+    peers
+        .iter()
+        .map(|peer| {
+            let (sent, result) = oneshot::channel();
+            let letter = Letter { message: message.clone(), since, sent };
+            let lane = lanes.entry(peer.clone()).or_insert_with(|| {
+                let (lane, letters) = mpsc::unbounded_channel();
+                tokio::spawn(run_lane(Delivery::new(context), peer.clone(), letters));
+                lane
+            });
+            // The lane's task only ends with the runtime
+            let _ = lane.send(letter);
+            (peer.clone(), result)
+        })
+        .collect()
+}
+
+// Send the letters queued for `peer`, in order, remembering the ones it did not get
+async fn run_lane(delivery: Delivery, peer: String, mut letters: mpsc::UnboundedReceiver<Letter>) {
+    while let Some(letter) = letters.recv().await {
+        let mut batch = vec![letter];
+        batch.extend(std::iter::from_fn(|| letters.try_recv().ok()).take(LANE_BATCH - 1));
+        let messages: Vec<String> = batch.iter().map(|letter| letter.message.clone()).collect();
+        let result = send_batch_to(&delivery, &peer, &messages).await.map_err(|e| e.to_string());
+        match &result {
+            Ok(()) => debug!("Replicated {} writes to {}", messages.len(), peer),
+            Err(e) => {
+                error!("Failed to replicate {} writes to {}: {}", messages.len(), peer, e);
+                delivery.missed.record(&peer, batch[0].since, &messages);
+            }
+        }
+        for letter in batch {
+            let _ = letter.sent.send(result.clone());
+        }
+    }
+}
+
+// Wait until each peer applied its copy of a message, or failed to
+async fn delivered(sent: Vec<(String, Sent)>) {
+    for (_, sent) in sent {
+        let _ = sent.await;
+    }
+}
+
+// Time allowed for a peer to apply a whole batch
+const BATCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Messages waiting for the next batch
+struct Batch {
+    // When the first message was queued (unix ms), the point a peer missing the batch is behind from
+    since: u64,
+    messages: Vec<String>,
+    // Position of each key's BROADCAST since the last REPLICATE, which a newer value overwrites;
+    // a REPLICATE may depend on the value before it, so nothing is coalesced across one
+    latest: HashMap<String, usize>,
+}
+
+// Collects writes for `window` and sends each peer one batch over a PERSIST connection
+pub(crate) struct ReplicationBatcher {
+    window: Duration,
+    // Some while a flush is scheduled
+    batch: std::sync::Mutex<Option<Batch>>,
+    // Held while a batch is sent, so batches reach each peer in order
+    sending: tokio::sync::Mutex<()>,
+}
+
+impl ReplicationBatcher {
+    pub(crate) fn new(window: Duration) -> Arc<Self> {
+        Arc::new(ReplicationBatcher {
+            window,
+            batch: std::sync::Mutex::new(None),
+            sending: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    fn queue(self: &Arc<Self>, context: &NodeContext, peers: &PeerList, key: Option<&str>, message: String) {
+        let mut batch = self.batch.lock().unwrap();
+        let scheduled = batch.is_some();
+        let pending = batch.get_or_insert_with(|| Batch {
+            since: context.clock.unix_millis(),
+            messages: Vec::new(),
+            latest: HashMap::new(),
+        });
+        match key {
+            Some(key) => match pending.latest.get(key) {
+                Some(&index) => pending.messages[index] = message,
+                None => {
+                    pending.latest.insert(key.to_string(), pending.messages.len());
+                    pending.messages.push(message);
+                }
+            },
+            None => {
+                pending.latest.clear();
+                pending.messages.push(message);
+            }
+        }
+        drop(batch);
+
+        if !scheduled {
+            tokio::spawn(Arc::clone(self).flush_after_window(Delivery::new(context), Arc::clone(peers)));
+        }
+    }
+
+    async fn flush_after_window(self: Arc<Self>, delivery: Delivery, peers: PeerList) {
+        tokio::time::sleep(self.window).await;
+        self.flush(&delivery, &peers).await;
+    }
+
+    // Send whatever is queued now, e.g. ahead of a SYNC write that must not be overtaken by an
+    // older value still waiting for the window
+    async fn flush(&self, delivery: &Delivery, peers: &PeerList) {
+        let _sending = self.sending.lock().await;
+        // Writes queued while the previous batch was sending join this one
+        let Some(batch) = self.batch.lock().unwrap().take() else {
+            return;
+        };
+        let peers_snapshot = peers.lock().await.clone();
+        // Numbered only now, so a coalesced write leaves no gap
+        let since = batch.since;
+        let batch: Vec<String> = batch.messages.into_iter().map(|message| delivery.sequences.number(message).1).collect();
+        let batch = &batch;
+        join_all(peers_snapshot.into_iter().map(|peer| async move {
+            match send_batch_to(delivery, &peer, batch).await {
+                Ok(()) => debug!("Replicated a batch of {} writes to {}", batch.len(), peer),
+                Err(e) => {
+                    error!("Failed to replicate a batch of {} writes to {}: {}", batch.len(), peer, e);
+                    delivery.missed.record(&peer, since, batch);
+                }
+            }
+        }))
+        .await;
+    }
+}
+
+// send_batch within BATCH_TIMEOUT, skipped while the peer's circuit is open; the outcome counts
+// towards the peer's health
+async fn send_batch_to(delivery: &Delivery, peer: &str, batch: &[String]) -> std::io::Result<()> {
+    let started = delivery.clock.now();
+    if !delivery.health.allow(peer, started) {
+        return Err(std::io::Error::other("circuit open"));
+    }
+    let batch: Vec<String> = batch.iter().map(|message| delivery.membership.stamp(message)).collect();
+    let result = match tokio::time::timeout(BATCH_TIMEOUT, send_batch(&delivery.transport, peer, &batch)).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "peer did not apply the batch in time")),
+    };
+    let now = delivery.clock.now();
+    if result.is_ok() {
+        delivery.latencies.record(REPLICATION_ROUND_TRIP, now - started);
+    }
+    delivery.health.record(peer, now, result.as_ref().ok().map(|_| now - started));
+    result
+}
+
+// Write the batch as persistent-connection requests and wait until the peer has applied them
+// all; an error unless it answered every one of them with OK
+async fn send_batch(transport: &SharedTransport, peer: &str, batch: &[String]) -> std::io::Result<()> {
+    let mut stream = transport.connect(peer.to_string()).await?;
+    let mut request = String::from("PERSIST\n");
+    for message in batch {
+        request.push_str(&frame(message));
+    }
+    stream.write_all(request.as_bytes()).await?;
+    stream.shutdown().await?;
+    // The peer closes once it has read and answered every line
+    let mut responses = Vec::new();
+    stream.read_to_end(&mut responses).await?;
+    check_batch_responses(&responses, batch.len())
+}
+
+// The peer's answers to a batch of `sent` messages: the PERSIST greeting, then one
+// `<length>\n<response>` per message
+fn check_batch_responses(responses: &[u8], sent: usize) -> std::io::Result<()> {
+    let invalid = |reason: String| std::io::Error::new(std::io::ErrorKind::InvalidData, reason);
+    let Some(mut rest) = responses.strip_prefix(b"OK: PERSIST\n") else {
+        return Err(invalid(format!("peer refused the batch: {}", String::from_utf8_lossy(responses).trim())));
+    };
+    let mut answered = 0;
+    while !rest.is_empty() {
+        let frame = rest.iter().position(|byte| *byte == b'\n').and_then(|end| {
+            let length: usize = std::str::from_utf8(&rest[..end]).ok()?.parse().ok()?;
+            Some((end + 1, end + 1 + length)).filter(|(_, next)| *next <= rest.len())
+        });
+        let Some((start, next)) = frame else {
+            return Err(invalid(format!("peer answered {} of {} messages, then sent no complete response", answered, sent)));
+        };
+        let response = &rest[start..next];
+        if !response.starts_with(b"OK") {
+            return Err(invalid(format!("message {} of {} failed: {}", answered + 1, sent, String::from_utf8_lossy(response))));
+        }
+        rest = &rest[next..];
+        answered += 1;
+    }
+    match answered == sent {
+        true => Ok(()),
+        false => Err(invalid(format!("peer answered {} of {} messages", answered, sent))),
+    }
+}
+
+// Returns the peers the message could not be sent to
+pub(crate) async fn send_to_peers(transport: SharedTransport, peers: PeerList, message: String) -> Vec<String> {
+    let peers_snapshot = peers.lock().await.clone(); // Clone to avoid holding the lock for too long
+    let mut failed = Vec::new();
+    for peer in peers_snapshot.iter() {
+        if !send_to_peer(&transport, peer, &message).await {
+            failed.push(peer.clone());
+        }
+    }
+    failed
+}
+
+async fn send_to_peer(transport: &SharedTransport, peer: &str, message: &str) -> bool {
+    let Ok(mut stream) = transport.connect(peer.to_string()).await else {
+        warn!("Failed to connect to peer: {}", peer);
+        return false;
+    };
+    if let Err(e) = stream.write_all(frame(message).as_bytes()).await {
+        error!("Failed to send {} to {}: {}", message, peer, e);
+        return false;
+    }
+    debug!("Broadcasted {} to {}", message, peer);
+    true
+}
+
+// A LOOKUP answer: `key=value`, then `EXPIREAT <unix ms>` on a line of its own if the key expires
+fn parse_lookup(response: &str) -> Result<(CacheValue, Option<u64>), String> {
+    match response.rsplit_once('\n') {
+        Some((entry, last)) if last.starts_with("EXPIREAT ") => {
+            let at = last["EXPIREAT ".len()..].parse().map_err(|_| format!("invalid expiry: {}", last))?;
+            Ok((parse_assignment(entry)?.1, Some(at)))
+        }
+        _ => Ok((parse_assignment(response)?.1, None)),
+    }
+}
+
+// LOOKUP `key` on up to `concurrency` peers at a time, returning the first value found with
+// when it expires in our clock; healthy peers with the lowest heartbeat round trip are asked first
+pub(crate) async fn fetch_from_peers(
+    context: &NodeContext,
+    peers: &PeerList,
+    key: &str,
+    config: &RemoteReads,
+) -> Option<(CacheValue, Option<u64>)> {
+    let peers_snapshot = peers.lock().await.iter().cloned().collect();
+    let ordered = context.health.read_order(peers_snapshot, context.clock.now());
+    let mut lookups = stream::iter(ordered)
+        .map(|peer| {
+            let transport = Arc::clone(&context.transport);
+            async move {
+                let lookup = async {
+                    let mut stream = transport.connect(peer.clone()).await?;
+                    stream.write_all(format!("LOOKUP {}\n", key).as_bytes()).await?;
+                    let mut response = String::new();
+                    stream.read_to_string(&mut response).await?;
+                    Ok::<_, std::io::Error>(response)
+                };
+                match tokio::time::timeout(config.timeout, lookup).await {
+                    Ok(Ok(response)) if response != "Not Found" => match parse_lookup(&response) {
+                        Ok((value, expires)) => Some((value, expires.map(|at| from_peer_clock(context, &peer, at)))),
+                        Err(e) => {
+                            warn!("Invalid LOOKUP response from {}: {}", peer, e);
+                            None
+                        }
+                    },
+                    Ok(Ok(_)) => None,
+                    Ok(Err(e)) => {
+                        debug!("LOOKUP {} on {} failed: {}", key, peer, e);
+                        None
+                    }
+                    Err(_) => {
+                        debug!("LOOKUP {} on {} timed out", key, peer);
+                        None
+                    }
+                }
+            }
+        })
+        .buffer_unordered(config.concurrency);
+    while let Some(found) = lookups.next().await {
+        if found.is_some() {
+            debug!("Fetched missing key {} from a peer", key);
+            return found;
+        }
+    }
+    None
+}
+
+// The messages that set `key` to `value` on a peer: a BROADCAST, and since that clears any
+// expiry, the one the key has after it
+pub(crate) fn value_messages(cache: &Cache, key: &str, value: &CacheValue) -> Vec<String> {
+    let expiry = cache.expires_at(key).map(|at| ExpiryOp::At { key: key.to_string(), at });
+    std::iter::once(format!("BROADCAST {}", format_assignment(key, value)))
+        .chain(expiry.map(|op| format!("REPLICATE {}", op)))
+        .collect()
+}
+
+// How often peers that missed writes are checked and caught up
+const CATCH_UP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Peers that missed writes while unreachable or out of the peer list. A key's modified time in
+// the cache serves as its sequence number: a peer behind since T is sent every key written at or
+// after T. Deletes leave no modified time, so without an outbox a missed delete is not repeated.
+#[derive(Default)]
+pub(crate) struct MissedWrites {
+    // Peer -> (unix ms of the first write it may lack, failed deliveries recorded so far)
+    peers: std::sync::Mutex<HashMap<String, (u64, u64)>>,
+    // The undelivered messages themselves, replayed before the catch-up values
+    pub(crate) outbox: Option<Outboxes>,
+}
+
+impl MissedWrites {
+    pub(crate) fn new(outbox: Option<Outboxes>) -> Self {
+        let missed = MissedWrites { peers: Default::default(), outbox };
+        // Whatever an earlier run could not deliver is still owed
+        for (peer, since) in missed.outbox.iter().flat_map(Outboxes::behind) {
+            missed.record(&peer, since, &[]);
+        }
+        missed
+    }
+
+    fn record(&self, peer: &str, since: u64, messages: &[String]) {
+        let mut peers = self.peers.lock().unwrap();
+        let behind = peers.entry(peer.to_string()).or_insert((since, 0));
+        behind.0 = behind.0.min(since);
+        behind.1 += 1;
+        if let (Some(outbox), false) = (&self.outbox, messages.is_empty()) {
+            outbox.push(peer, since, messages.iter().cloned());
+        }
+    }
+
+    // Forget the peer unless another delivery failed since `failures` was read
+    fn caught_up(&self, peer: &str, failures: u64) {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.get(peer).is_some_and(|behind| behind.1 == failures) {
+            peers.remove(peer);
+        }
+    }
+}
+
+// Send peers that are back in the peer list the writes they missed, as one batch each
+pub async fn catch_up_peers(cache: SharedCache, peers: PeerList, context: SharedContext) {
+    let delivery = Delivery::new(&context);
+    let mut present = peers.lock().await.clone();
+    let mut last_seen = context.clock.unix_millis();
+    loop {
+        tokio::time::sleep(CATCH_UP_INTERVAL).await;
+        let now = context.clock.unix_millis();
+        let current = peers.lock().await.clone();
+        // Nothing is sent to a peer outside the list, so it misses everything after it was last seen.
+        // One removed with REMOVEPEER was ejected, not split off.
+        for peer in present.difference(&current) {
+            context.missed.record(peer, last_seen, &[]);
+            if !context.manual_peers.removed(peer) {
+                context.partitions.lost(peer, last_seen);
+            }
+        }
+        if let Some(outbox) = &context.missed.outbox {
+            outbox.expire(now);
+        }
+
+        let behind: Vec<(String, u64, u64)> = {
+            let missed = context.missed.peers.lock().unwrap();
+            missed
+                .iter()
+                .filter(|(peer, _)| current.contains(*peer))
+                .map(|(peer, &(since, failures))| (peer.clone(), since, failures))
+                .collect()
+        };
+        for (peer, since, failures) in behind {
+            // A peer back from a partition may have taken writes of its own, so merge instead
+            if let Some(split) = context.partitions.split_since(&peer) {
+                // The merged values supersede what was queued for it until now, but not what
+                // gets queued while they are sent
+                let queued = context.missed.outbox.as_ref().map_or(0, |outbox| outbox.pending(&peer).len());
+                match reconcile(&cache, &peer, since.min(split), &context).await {
+                    Ok(reconciled) => match send_batch_to(&delivery, &peer, &reconciled.send).await {
+                        Ok(()) => {
+                            context.partitions.healed(&peer);
+                            context.missed.caught_up(&peer, failures);
+                            if let Some(outbox) = &context.missed.outbox {
+                                outbox.delivered(&peer, queued);
+                            }
+                        }
+                        Err(e) => debug!("Could not send {} our side of the partition: {}", peer, e),
+                    },
+                    Err(e) => debug!("Could not reconcile with {}: {}", peer, e),
+                }
+                continue;
+            }
+
+            // Replay undelivered messages in order first; the values below overwrite what they set
+            if let Some(outbox) = &context.missed.outbox {
+                let pending = outbox.pending(&peer);
+                if !pending.is_empty() {
+                    if let Err(e) = send_batch_to(&delivery, &peer, &pending).await {
+                        debug!("Could not replay {} undelivered messages to {}: {}", pending.len(), peer, e);
+                        continue;
+                    }
+                    info!("Replayed {} undelivered messages to {}", pending.len(), peer);
+                    outbox.delivered(&peer, pending.len());
+                }
+            }
+
+            let delta: Vec<String> = {
+                let cache = cache.lock().await;
+                cache
+                    .iter()
+                    .filter(|(key, _)| cache.modified(key).is_some_and(|modified| modified >= since))
+                    .flat_map(|(key, value)| value_messages(&cache, key, value))
+                    .collect()
+            };
+            match send_batch_to(&delivery, &peer, &delta).await {
+                Ok(()) => {
+                    info!("Caught {} up with {} writes it missed", peer, delta.len());
+                    context.missed.caught_up(&peer, failures);
+                }
+                Err(e) => debug!("Could not catch {} up with {} missed writes: {}", peer, delta.len(), e),
+            }
+        }
+
+        present = current;
+        last_seen = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use futures::future::BoxFuture;
+    use tokio::sync::Mutex;
+    use crate::clock::SystemClock;
+    use crate::health::heartbeat_peers;
+    use crate::sim::{SimClock, SimNetwork};
+    use crate::storage::{Cache, WriteSource};
+    use crate::transport::{BoxConnection, Listener, Transport};
+    use super::*;
+
+    const PEER: &str = "127.0.0.1:2";
+
+    // Peers that take longer to answer the connections opened first, recording the SEQ numbers
+    // of the messages they apply in the order they apply them, and each message's line; the
+    // ones in `down` refuse connections
+    #[derive(Default)]
+    struct SlowPeer {
+        connections: AtomicU64,
+        applied: Arc<std::sync::Mutex<Vec<u64>>>,
+        received: Arc<std::sync::Mutex<Vec<String>>>,
+        down: HashSet<String>,
+    }
+
+    impl Transport for SlowPeer {
+        fn bind(&self, _port: u16) -> io::Result<Box<dyn Listener>> {
+            Err(io::Error::other("SlowPeer only connects"))
+        }
+
+        fn connect(&self, addr: String) -> BoxFuture<'static, io::Result<BoxConnection>> {
+            if self.down.contains(&addr) {
+                return Box::pin(async move { Err(io::Error::new(io::ErrorKind::ConnectionRefused, "down")) });
+            }
+            let (client, mut server) = tokio::io::duplex(64 * 1024);
+            let delay = Duration::from_millis(100 / (self.connections.fetch_add(1, Ordering::Relaxed) + 1));
+            let (applied, received) = (Arc::clone(&self.applied), Arc::clone(&self.received));
+            tokio::spawn(async move {
+                let mut request = String::new();
+                let _ = server.read_to_string(&mut request).await;
+                tokio::time::sleep(delay).await;
+                let (mut response, messages) = match request.strip_prefix("PERSIST\n") {
+                    Some(messages) => ("OK: PERSIST\n".to_string(), messages),
+                    None => (String::new(), request.as_str()),
+                };
+                for message in messages.lines() {
+                    let words: Vec<&str> = message.split_whitespace().collect();
+                    let seq: Option<u64> = words.iter().position(|word| *word == "SEQ").map(|at| words[at + 3].parse().unwrap());
+                    applied.lock().unwrap().extend(seq);
+                    received.lock().unwrap().push(message.to_string());
+                    response.push_str("2\nOK");
+                }
+                let _ = server.write_all(response.as_bytes()).await;
+            });
+            Box::pin(async move { Ok(Box::new(client) as BoxConnection) })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unbatched_writes_reach_a_peer_in_number_order() {
+        let peer = Arc::new(SlowPeer::default());
+        let context = NodeContext::new(1, peer.clone(), Arc::new(SystemClock));
+        let peers: PeerList = Arc::new(Mutex::new(HashSet::from([PEER.to_string()])));
+        for i in 0..20 {
+            replicate_message(&context, &peers, Some(&format!("k{}", i)), format!("BROADCAST k{}={}", i, i)).await;
+        }
+
+        while peer.applied.lock().unwrap().len() < 20 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*peer.applied.lock().unwrap(), (1..=20).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queued_writes_reach_a_peer_in_number_order() {
+        let peer = Arc::new(SlowPeer::default());
+        let context = Arc::new(NodeContext {
+            replicator: Some(Replicator::new(&ReplicationQueue { workers: 4, ..ReplicationQueue::default() })),
+            ..NodeContext::new(1, peer.clone(), Arc::new(SystemClock))
+        });
+        for _ in 0..4 {
+            tokio::spawn(replication_worker(Arc::clone(&context)));
+        }
+        let peers: PeerList = Arc::new(Mutex::new(HashSet::from([PEER.to_string()])));
+        for i in 0..20 {
+            replicate_message(&context, &peers, Some(&format!("k{}", i)), format!("BROADCAST k{}={}", i, i)).await;
+        }
+
+        while peer.applied.lock().unwrap().len() < 20 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*peer.applied.lock().unwrap(), (1..=20).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replicated_expiries_move_by_the_origins_clock_offset() {
+        const EPOCH: u64 = 1_735_689_600_000;
+        let network = SimNetwork::new();
+        let clock: SharedClock = Arc::new(SimClock::new(EPOCH));
+        // An origin whose clock is 5s ahead of ours
+        let mut listener = network.bind(0).unwrap();
+        let origin = format!("127.0.0.1:{}", listener.local_port());
+        let origin_clock = Arc::clone(&clock);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.read(&mut [0; 64]).await;
+                let _ = stream.write_all(format!("PONG {}", origin_clock.unix_millis() + 5_000).as_bytes()).await;
+            }
+        });
+        let context = Arc::new(NodeContext::new(1, Arc::new(network.clone()), Arc::clone(&clock)));
+        let peers: PeerList = Arc::new(Mutex::new(HashSet::from([origin.clone()])));
+        let heartbeat = tokio::spawn(heartbeat_peers(peers, Arc::clone(&context)));
+        while context.health.clock_offset(&origin).is_none() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        heartbeat.abort();
+
+        let cache: SharedCache = Arc::new(Mutex::new(Cache::new()));
+        cache.lock().await.insert("session".to_string(), CacheValue::Str("token".to_string()));
+        let source = WriteSource { origin: origin.into(), writer: "peer".into(), correlation: "c".into() };
+        let at = clock.unix_millis() + 65_000;
+        WRITE_SOURCE
+            .scope(source, apply_replicated(&cache, &context, &format!("EXPIREAT session {}", at)))
+            .await
+            .unwrap();
+        assert_eq!(cache.lock().await.expires_at("session"), Some(at - 5_000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_batch_keeps_the_last_value_of_each_key_but_never_coalesces_across_an_operation() {
+        let peer = Arc::new(SlowPeer::default());
+        let context = NodeContext {
+            batcher: Some(ReplicationBatcher::new(Duration::from_millis(10))),
+            ..NodeContext::new(1, peer.clone(), Arc::new(SystemClock))
+        };
+        let peers: PeerList = Arc::new(Mutex::new(HashSet::from([PEER.to_string()])));
+        for (key, message) in [
+            (Some("a"), "BROADCAST a=1"),
+            (Some("a"), "BROADCAST a=2"),
+            (None, "REPLICATE LPUSH a x"),
+            (Some("a"), "BROADCAST a=3"),
+            (Some("b"), "BROADCAST b=1"),
+            (Some("a"), "BROADCAST a=4"),
+        ] {
+            replicate_message(&context, &peers, key, message.to_string()).await;
+        }
+
+        while peer.received.lock().unwrap().len() < 4 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let received = peer.received.lock().unwrap().clone();
+        let expected = ["BROADCAST a=2", "REPLICATE LPUSH a x", "BROADCAST a=4", "BROADCAST b=1"];
+        assert!(received.iter().zip(expected).all(|(line, message)| line.ends_with(message)), "{:?}", received);
+        // One connection for the whole window, numbered without gaps
+        assert_eq!(peer.connections.load(Ordering::Relaxed), 1);
+        assert_eq!(*peer.applied.lock().unwrap(), [1, 2, 3, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_batch_a_peer_missed_is_left_to_catch_up() {
+        let peer = Arc::new(SlowPeer { down: HashSet::from([PEER.to_string()]), ..SlowPeer::default() });
+        let context = NodeContext {
+            batcher: Some(ReplicationBatcher::new(Duration::from_millis(10))),
+            ..NodeContext::new(1, peer, Arc::new(SystemClock))
+        };
+        let peers: PeerList = Arc::new(Mutex::new(HashSet::from([PEER.to_string()])));
+        replicate_message(&context, &peers, Some("a"), "BROADCAST a=1".to_string()).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(context.missed.peers.lock().unwrap().contains_key(PEER));
+    }
+
+    #[test]
+    fn parses_replication_queue_settings() {
+        let queue = ReplicationQueue::parse("capacity=500, workers=2,when_full=shed").unwrap();
+        assert_eq!((queue.capacity, queue.workers, queue.shed), (500, 2, true));
+        assert!(!ReplicationQueue::parse("on").unwrap().shed);
+        assert_eq!(ReplicationQueue::parse("workers=0").unwrap_err(), "Invalid value for workers: 0");
+        assert_eq!(ReplicationQueue::parse("when_full=drop").unwrap_err(), "Invalid value for when_full: drop");
+        assert_eq!(ReplicationQueue::parse("depth=1").unwrap_err(), "Unknown replication queue setting: depth");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_shedding_queue_refuses_writes_and_leaves_late_ones_to_catch_up() {
+        let peer = Arc::new(SlowPeer::default());
+        let context = NodeContext {
+            replicator: Some(Replicator::new(&ReplicationQueue { capacity: 1, workers: 1, shed: true })),
+            ..NodeContext::new(1, peer, Arc::new(SystemClock))
+        };
+        let replicator = context.replicator.as_ref().unwrap();
+        let peers: PeerList = Arc::new(Mutex::new(HashSet::from([PEER.to_string()])));
+
+        // No worker runs, so the first write fills the queue
+        assert!(!replicator.refuse_write());
+        replicate_message(&context, &peers, Some("a"), "BROADCAST a=1".to_string()).await;
+        assert_eq!(replicator.depth(), 1);
+        assert!(replicator.refuse_write());
+
+        // A write let in before the queue filled up goes to catch-up instead
+        replicate_message(&context, &peers, Some("b"), "BROADCAST b=1".to_string()).await;
+        assert_eq!(replicator.dropped(), 2);
+        assert!(context.missed.peers.lock().unwrap().contains_key(PEER));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_blocking_queue_holds_writes_until_a_worker_frees_room() {
+        let peer = Arc::new(SlowPeer::default());
+        let context = Arc::new(NodeContext {
+            replicator: Some(Replicator::new(&ReplicationQueue { capacity: 1, workers: 1, shed: false })),
+            ..NodeContext::new(1, peer.clone(), Arc::new(SystemClock))
+        });
+        let peers: PeerList = Arc::new(Mutex::new(HashSet::from([PEER.to_string()])));
+        replicate_message(&context, &peers, Some("a"), "BROADCAST a=1".to_string()).await;
+        let blocked = {
+            let (context, peers) = (Arc::clone(&context), Arc::clone(&peers));
+            tokio::spawn(async move { replicate_message(&context, &peers, Some("b"), "BROADCAST b=1".to_string()).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+        assert!(!context.replicator.as_ref().unwrap().refuse_write());
+
+        tokio::spawn(replication_worker(Arc::clone(&context)));
+        blocked.await.unwrap();
+        while peer.applied.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(context.replicator.as_ref().unwrap().dropped(), 0);
+    }
+
+    #[test]
+    fn parses_sync_options() {
+        assert_eq!(SyncAck::parse(""), Ok(SyncAck { quorum: false, timeout: DEFAULT_SYNC_TIMEOUT }));
+        assert_eq!(SyncAck::parse("QUORUM TIMEOUT 500"), Ok(SyncAck { quorum: true, timeout: Duration::from_millis(500) }));
+        assert_eq!(SyncAck::parse("QUORUM ALL").map(|ack| ack.quorum), Ok(false));
+        assert_eq!(SyncAck::parse("TIMEOUT soon"), Err("invalid SYNC TIMEOUT".to_string()));
+        assert_eq!(SyncAck::parse("MAJORITY"), Err("unknown SYNC option MAJORITY".to_string()));
+    }
+
+    // Three peers, the last of which is down
+    fn three_peers() -> (NodeContext, PeerList) {
+        let peer = Arc::new(SlowPeer { down: HashSet::from(["127.0.0.1:4".to_string()]), ..SlowPeer::default() });
+        let context = NodeContext::new(1, peer, Arc::new(SystemClock));
+        let peers = ["127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:4"].map(str::to_string);
+        (context, Arc::new(Mutex::new(HashSet::from(peers))))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_quorum_write_succeeds_without_every_peer() {
+        let (context, peers) = three_peers();
+        let ack = SyncAck::parse("QUORUM").unwrap();
+        let acks = replicate_acked(&context, &peers, "BROADCAST a=1".to_string(), &ack).await;
+        assert_eq!((acks.acked, acks.needed, acks.peers), (2, 2, 3));
+        assert!(acks.response("SET").starts_with("OK: SET successful (acked by 2/3 peers)"), "{}", acks.response("SET"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_write_synced_to_all_fails_naming_the_peer_that_missed_it() {
+        let (context, peers) = three_peers();
+        let acks = replicate_acked(&context, &peers, "BROADCAST a=1".to_string(), &SyncAck::parse("ALL").unwrap()).await;
+        let response = acks.response("SET");
+        assert!(response.starts_with("SYNC failed: SET applied locally but acked by 2/3 peers, 3 needed; failed: 127.0.0.1:4 ("), "{}", response);
+        // It is still caught up later
+        assert!(context.missed.peers.lock().unwrap().contains_key("127.0.0.1:4"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_that_answer_after_the_timeout_fail_the_write() {
+        let (context, peers) = three_peers();
+        let ack = SyncAck::parse("QUORUM TIMEOUT 10").unwrap();
+        let acks = replicate_acked(&context, &peers, "BROADCAST a=1".to_string(), &ack).await;
+        assert_eq!(acks.acked, 0);
+        let mut failed = acks.failed.clone();
+        failed.sort();
+        assert_eq!(failed[..2], ["127.0.0.1:2 (timed out)", "127.0.0.1:3 (timed out)"]);
+        assert!(acks.response("SET").starts_with("SYNC failed: SET applied locally but acked by 0/3 peers, 2 needed"));
+    }
+}
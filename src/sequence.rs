@@ -0,0 +1,330 @@
+//! Per-origin numbering of replicated messages, so receivers notice what they missed.
+//!
+//! Every BROADCAST/REPLICATE a node sends goes out as `SEQ <origin> <boot> <n> <message>`,
+//! numbered from 1 each time the node starts (`boot` tells the runs apart). A receiver that
+//! sees `n` jump past the next number it expects asks the origin for the messages in between
+//! with `RESYNC <boot> <from> <to>` and applies them first. Origins keep the last
+//! RESYNC_LOG_SIZE messages for this; older gaps are logged and left to peer catch-up.
+//! Senders hand each peer its messages in order (see PeerLanes), so a gap is a message that
+//! was lost rather than one still on its way.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+use log::{debug, info, warn};
+
+use crate::cluster::CLUSTER_QUERY_TIMEOUT;
+use crate::discovery::PeerList;
+use crate::node::NodeContext;
+use crate::protocol::command::{parse_command, Command};
+use crate::protocol::execute;
+use crate::storage::SharedCache;
+
+// Sent messages kept for answering RESYNC
+pub(crate) const RESYNC_LOG_SIZE: usize = 10_000;
+
+struct Sent {
+    next: u64,
+    log: VecDeque<(u64, String)>,
+}
+
+// Numbers applied from one run of an origin, held while a gap is repaired
+#[derive(Default)]
+struct Applied {
+    // The first number seen, where this node started following the run; 0 until then
+    first: u64,
+    last: u64,
+}
+
+type LastApplied = Arc<tokio::sync::Mutex<Applied>>;
+
+pub(crate) struct Sequences {
+    // How peers reach this node, as in discovery announcements
+    origin: String,
+    boot: u64,
+    sent: std::sync::Mutex<Sent>,
+    // Keyed by (origin, boot)
+    received: std::sync::Mutex<HashMap<(String, u64), LastApplied>>,
+}
+
+impl Sequences {
+    pub(crate) fn new(origin: String, boot: u64) -> Self {
+        Sequences {
+            origin,
+            boot,
+            sent: std::sync::Mutex::new(Sent { next: 1, log: VecDeque::new() }),
+            received: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn boot(&self) -> u64 {
+        self.boot
+    }
+
+    pub(crate) fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    // Give `message` the next number, remembering it for RESYNC
+    pub(crate) fn number(&self, message: String) -> (u64, String) {
+        let mut sent = self.sent.lock().unwrap();
+        let seq = sent.next;
+        sent.next += 1;
+        let numbered = format!("SEQ {} {} {} {}", self.origin, self.boot, seq, message);
+        sent.log.push_back((seq, message));
+        if sent.log.len() > RESYNC_LOG_SIZE {
+            sent.log.pop_front();
+        }
+        (seq, numbered)
+    }
+
+    // Messages `from..=to` of run `boot`, or as many of the last of them as are still kept, each
+    // as `<length>\n<message>` so values spanning lines come back whole
+    pub(crate) fn resync(&self, boot: u64, from: u64, to: u64) -> Result<String, String> {
+        if boot != self.boot {
+            return Err(format!("Invalid RESYNC boot {}, this node started at {}", boot, self.boot));
+        }
+        let sent = self.sent.lock().unwrap();
+        Ok(sent
+            .log
+            .iter()
+            .filter(|(seq, _)| (from..=to).contains(seq))
+            .map(|(_, message)| format!("{}\n{}", message.len(), message))
+            .collect())
+    }
+
+    // Whether message `seq` of run `boot` of `origin` has been applied here, waiting for one
+    // being applied to finish. Messages from before this node started following the run may
+    // never have reached it, so they count as not applied.
+    pub(crate) async fn applied(&self, origin: &str, boot: u64, seq: u64) -> bool {
+        let applied = self.received.lock().unwrap().get(&(origin.to_string(), boot)).cloned();
+        match applied {
+            Some(applied) => {
+                let applied = applied.lock().await;
+                (applied.first..=applied.last).contains(&seq)
+            }
+            None => false,
+        }
+    }
+
+    fn received(&self, origin: &str, boot: u64) -> LastApplied {
+        let mut received = self.received.lock().unwrap();
+        Arc::clone(received.entry((origin.to_string(), boot)).or_default())
+    }
+}
+
+// Apply message `seq` from `origin` in order: duplicates are dropped and a gap is filled
+// from the origin first
+pub(crate) async fn apply_sequenced(
+    origin: &str,
+    boot: u64,
+    seq: u64,
+    message: &str,
+    cache: &SharedCache,
+    peers: &PeerList,
+    context: &NodeContext,
+) -> String {
+    let received = context.sequences.received(origin, boot);
+    let mut applied = received.lock().await;
+    // The first message seen from a run is where this node starts following it
+    if applied.first == 0 {
+        applied.first = seq;
+    } else if seq <= applied.last {
+        debug!("Dropping message {} from {}, already applied", seq, origin);
+        return "OK: already applied".to_string();
+    } else if seq > applied.last + 1 {
+        let (from, to) = (applied.last + 1, seq - 1);
+        // Not held over the round trip, so the origin's other messages are not held up by it
+        drop(applied);
+        warn!("Missed messages {}..={} from {}, asking it to resend them", from, to, origin);
+        let missed = match request_resync(context, origin, boot, from, to).await {
+            Ok(missed) => {
+                let expected = (to - from + 1) as usize;
+                if missed.len() < expected {
+                    warn!("{} only had {} of the {} missed messages", origin, missed.len(), expected);
+                } else {
+                    info!("Recovered {} missed messages from {}", missed.len(), origin);
+                }
+                missed
+            }
+            Err(e) => {
+                warn!("Failed to resync {}..={} from {}: {}", from, to, origin, e);
+                Vec::new()
+            }
+        };
+
+        applied = received.lock().await;
+        // The origin answers with the last of the range it still has; another message may
+        // have filled some of the gap meanwhile
+        let first_missed = (to + 1).saturating_sub(missed.len() as u64).max(from);
+        for (missed_seq, missed) in (first_missed..=to).zip(missed) {
+            if missed_seq > applied.last {
+                apply_message(&missed, origin, cache, peers, context).await;
+                applied.last = missed_seq;
+            }
+        }
+        if seq <= applied.last {
+            debug!("Dropping message {} from {}, applied while resyncing", seq, origin);
+            return "OK: already applied".to_string();
+        }
+    }
+    applied.last = seq;
+    apply_message(message, origin, cache, peers, context).await
+}
+
+async fn apply_message(message: &str, origin: &str, cache: &SharedCache, peers: &PeerList, context: &NodeContext) -> String {
+    match parse_command(message) {
+        // Boxed because execute is what called us
+        Ok(command) if is_replication(&command) => Box::pin(execute(command, &mut tokio::io::sink(), origin, cache, peers, context)).await,
+        Ok(_) => "SEQ only carries BROADCAST and REPLICATE".to_string(),
+        Err(e) => e,
+    }
+}
+
+// BROADCAST or REPLICATE, possibly under the correlation ID of the origin's request
+fn is_replication(command: &Command) -> bool {
+    match command {
+        Command::Broadcast { .. } | Command::Replicate { .. } => true,
+        Command::Correlated { command, .. } => is_replication(command),
+        _ => false,
+    }
+}
+
+async fn request_resync(context: &NodeContext, origin: &str, boot: u64, from: u64, to: u64) -> Result<Vec<String>, String> {
+    let request = async {
+        let mut stream = context.transport.connect(origin.to_string()).await?;
+        stream.write_all(format!("RESYNC {} {} {}\n", boot, from, to).as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = match timeout(CLUSTER_QUERY_TIMEOUT, request).await {
+        Ok(response) => response.map_err(|e| e.to_string())?,
+        Err(_) => return Err("origin did not answer in time".to_string()),
+    };
+    if response.starts_with("Invalid") {
+        return Err(response);
+    }
+    parse_resync(&response)
+}
+
+// The messages of a RESYNC answer, each sent as `<length>\n<message>`
+fn parse_resync(mut response: &str) -> Result<Vec<String>, String> {
+    let mut messages = Vec::new();
+    while !response.is_empty() {
+        let message = response.split_once('\n').and_then(|(length, rest)| {
+            let length: usize = length.parse().ok()?;
+            Some((rest.get(..length)?, rest.get(length..)?))
+        });
+        let Some((message, rest)) = message else {
+            return Err(format!("incomplete answer after {} messages", messages.len()));
+        };
+        messages.push(message.to_string());
+        response = rest;
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+    use tokio::sync::{Mutex, Notify};
+    use crate::clock::SystemClock;
+    use crate::sim::SimNetwork;
+    use crate::storage::{Cache, CacheValue};
+    use crate::transport::Transport;
+    use super::*;
+
+    const BOOT: u64 = 7;
+
+    // An origin on `network` answering RESYNC from `sent` once `answer` is notified
+    fn serve_origin(network: &SimNetwork, sent: Arc<Sequences>, answer: Arc<Notify>) -> String {
+        let mut listener = network.bind(0).unwrap();
+        let origin = format!("127.0.0.1:{}", listener.local_port());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0; 64];
+                let read = stream.read(&mut request).await.unwrap();
+                let Ok(Command::Resync { boot, from, to }) = parse_command(&String::from_utf8_lossy(&request[..read])) else {
+                    panic!("expected RESYNC");
+                };
+                answer.notified().await;
+                let response = sent.resync(boot, from, to).unwrap_or_else(|e| e);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        origin
+    }
+
+    fn receiver(network: &SimNetwork) -> (NodeContext, SharedCache, PeerList) {
+        let context = NodeContext::new(1, Arc::new(network.clone()), Arc::new(SystemClock));
+        (context, Arc::new(Mutex::new(Cache::new())), Arc::new(Mutex::new(HashSet::new())))
+    }
+
+    #[test]
+    fn resync_answers_keep_multi_line_messages_whole() {
+        let sequences = Sequences::new("127.0.0.1:8080".to_string(), BOOT);
+        for message in ["BROADCAST a=1", "BROADCAST note=first\nsecond", "BROADCAST b=2"] {
+            sequences.number(message.to_string());
+        }
+        let answer = sequences.resync(BOOT, 2, 3).unwrap();
+        assert_eq!(parse_resync(&answer).unwrap(), ["BROADCAST note=first\nsecond", "BROADCAST b=2"]);
+        assert_eq!(parse_resync(""), Ok(Vec::new()));
+        assert!(parse_resync("40\nBROADCAST a=1").is_err());
+        assert!(sequences.resync(BOOT + 1, 1, 1).unwrap_err().starts_with("Invalid RESYNC boot"));
+    }
+
+    #[tokio::test]
+    async fn gaps_are_filled_with_multi_line_values_intact() {
+        let network = SimNetwork::new();
+        let sent = Arc::new(Sequences::new(String::new(), BOOT));
+        for message in ["BROADCAST a=1", "BROADCAST note=first\nsecond", "BROADCAST b=2"] {
+            sent.number(message.to_string());
+        }
+        let answer = Arc::new(Notify::new());
+        answer.notify_one();
+        let origin = serve_origin(&network, Arc::clone(&sent), answer);
+        let (context, cache, peers) = receiver(&network);
+
+        apply_sequenced(&origin, BOOT, 1, "BROADCAST a=1", &cache, &peers, &context).await;
+        apply_sequenced(&origin, BOOT, 3, "BROADCAST b=2", &cache, &peers, &context).await;
+
+        let cache = cache.lock().await;
+        assert_eq!(cache.get("note"), Some(&CacheValue::Str("first\nsecond".to_string())));
+        assert!(cache.get("second").is_none());
+        assert_eq!(cache.get("b"), Some(&CacheValue::Str("2".to_string())));
+        assert!(context.sequences.applied(&origin, BOOT, 2).await);
+    }
+
+    #[tokio::test]
+    async fn resyncing_does_not_hold_up_reads_of_the_origin() {
+        let network = SimNetwork::new();
+        let sent = Arc::new(Sequences::new(String::new(), BOOT));
+        for message in ["BROADCAST a=1", "BROADCAST b=2", "BROADCAST c=3"] {
+            sent.number(message.to_string());
+        }
+        let answer = Arc::new(Notify::new());
+        let origin = serve_origin(&network, Arc::clone(&sent), Arc::clone(&answer));
+        let (context, cache, peers) = receiver(&network);
+        let context = Arc::new(context);
+        apply_sequenced(&origin, BOOT, 1, "BROADCAST a=1", &cache, &peers, &context).await;
+
+        // Message 3 waits for the origin to resend 2
+        let gap = {
+            let (origin, cache, peers, context) = (origin.clone(), Arc::clone(&cache), Arc::clone(&peers), Arc::clone(&context));
+            tokio::spawn(async move { apply_sequenced(&origin, BOOT, 3, "BROADCAST c=3", &cache, &peers, &context).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let applied = tokio::time::timeout(Duration::from_secs(1), context.sequences.applied(&origin, BOOT, 1)).await;
+        assert_eq!(applied, Ok(true));
+
+        answer.notify_one();
+        assert!(gap.await.unwrap().starts_with("OK"));
+        let cache = cache.lock().await;
+        for key in ["a", "b", "c"] {
+            assert!(cache.get(key).is_some(), "{} missing", key);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Read-your-writes session tokens
+
+use std::cell::Cell;
+use std::fmt;
+use std::future::Future;
+use tokio::time::{Duration, Instant};
+use log::debug;
+
+use crate::cluster::ask;
+use crate::node::NodeContext;
+
+// How long a read waits for this node to apply the writes its tokens name
+const SESSION_WAIT: Duration = Duration::from_millis(500);
+const SESSION_POLL: Duration = Duration::from_millis(5);
+
+// A write made on `origin` during its run `boot`, replicated as message `seq`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionToken {
+    pub origin: String,
+    pub boot: u64,
+    // None if the message's number wasn't known when the write was answered
+    pub seq: Option<u64>,
+}
+
+impl SessionToken {
+    pub fn parse(token: &str) -> Option<SessionToken> {
+        let mut parts = token.split('/');
+        let (origin, boot, seq) = (parts.next()?, parts.next()?, parts.next()?);
+        if origin.is_empty() || parts.next().is_some() {
+            return None;
+        }
+        let seq = match seq {
+            "-" => None,
+            seq => Some(seq.parse().ok()?),
+        };
+        Some(SessionToken { origin: origin.to_string(), boot: boot.parse().ok()?, seq })
+    }
+}
+
+impl fmt::Display for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.seq {
+            Some(seq) => write!(f, "{}/{}/{}", self.origin, self.boot, seq),
+            None => write!(f, "{}/{}/-", self.origin, self.boot),
+        }
+    }
+}
+
+tokio::task_local! {
+    // The message a SESSION write was replicated as so far: Some(None) once it had one
+    // without a number yet
+    static SESSION_WRITE: Cell<Option<Option<u64>>>;
+}
+
+// The write being run was replicated as message `seq` (None: numbered later by the batcher)
+pub(crate) fn replicated(seq: Option<u64>) {
+    let _ = SESSION_WRITE.try_with(|write| {
+        // A write sent as several messages is applied once the last of them is
+        write.set(Some(match (write.get(), seq) {
+            (None, seq) => seq,
+            (Some(Some(earlier)), Some(seq)) => Some(earlier.max(seq)),
+            _ => None,
+        }));
+    });
+}
+
+// Run a SESSION write, adding the token of its replicated message to the response
+pub(crate) async fn run_write(context: &NodeContext, write: impl Future<Output = String>) -> String {
+    let (response, seq) = SESSION_WRITE
+        .scope(Cell::new(None), async {
+            let response = write.await;
+            (response, SESSION_WRITE.with(Cell::get))
+        })
+        .await;
+    let Some(seq) = seq else {
+        return response;
+    };
+    let token = SessionToken { origin: context.sequences.origin().to_string(), boot: context.sequences.boot(), seq };
+    format!("{}\nTOKEN {}", response, token)
+}
+
+// Wait for this node to apply what the tokens name. Returns the tokens still outstanding after
+// SESSION_WAIT, unnumbered ones first; a token of this node's own run never is.
+pub(crate) async fn await_tokens(context: &NodeContext, mut tokens: Vec<SessionToken>) -> Vec<SessionToken> {
+    let sequences = &context.sequences;
+    tokens.retain(|token| token.origin != sequences.origin() || token.boot != sequences.boot());
+    let deadline = Instant::now() + SESSION_WAIT;
+    loop {
+        let mut outstanding = Vec::new();
+        for token in tokens {
+            let applied = match token.seq {
+                Some(seq) => sequences.applied(&token.origin, token.boot, seq).await,
+                None => false,
+            };
+            if !applied {
+                outstanding.push(token);
+            }
+        }
+        // Nothing makes an unnumbered token applied here, so the read goes to its origin anyway
+        if outstanding.iter().any(|token| token.seq.is_none()) || outstanding.is_empty() || Instant::now() >= deadline {
+            outstanding.sort_by_key(|token| token.seq.is_some());
+            return outstanding;
+        }
+        tokens = outstanding;
+        tokio::time::sleep(SESSION_POLL).await;
+    }
+}
+
+// Forward `read` to the origin of the first outstanding token, passing the others on
+pub(crate) async fn forward_read(context: &NodeContext, first: &SessionToken, rest: &[SessionToken], read: &str) -> String {
+    debug!("Session read not applied here yet, forwarding it to {}", first.origin);
+    let request = match rest {
+        [] => read.to_string(),
+        rest => format!("SESSION {} {}", rest.iter().map(SessionToken::to_string).collect::<Vec<_>>().join(","), read),
+    };
+    match ask(context, &first.origin, &request).await {
+        Ok(response) => response,
+        Err(e) => format!("SESSION read failed: could not reach {}: {}", first.origin, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCluster;
+
+    #[test]
+    fn tokens_round_trip() {
+        for token in ["127.0.0.1:8080/17/42", "127.0.0.1:8080/17/-"] {
+            assert_eq!(SessionToken::parse(token).unwrap().to_string(), token);
+        }
+        for token in ["/17/42", "a:1/17", "a:1/17/42/1", "a:1/x/42", "a:1/17/x"] {
+            assert_eq!(SessionToken::parse(token), None, "{}", token);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_reads_see_their_writes_or_go_to_the_origin() {
+        let cluster = TestCluster::start_simulated(2).await.unwrap();
+        let written = cluster.request(0, "SESSION SET key1=v").await.unwrap();
+        let (answer, token) = written.trim_end().split_once("\nTOKEN ").unwrap();
+        assert_eq!(answer, "OK: SET successful");
+        let token = SessionToken::parse(token).unwrap();
+        assert_eq!(token.origin, cluster.addr(0));
+        assert_eq!(cluster.request(1, &format!("SESSION {} GET key1", token)).await.unwrap().trim_end(), "v");
+
+        // A write node 1 hasn't applied by the deadline is read from its origin instead
+        cluster.request(1, "BROADCAST key1=stale").await.unwrap();
+        let ahead = SessionToken { seq: token.seq.map(|seq| seq + 100), ..token };
+        let started = Instant::now();
+        assert_eq!(cluster.request(1, &format!("SESSION {} GET key1", ahead)).await.unwrap().trim_end(), "v");
+        assert!(started.elapsed() >= SESSION_WAIT);
+        assert_eq!(cluster.request(1, "GET key1").await.unwrap().trim_end(), "stale");
+        cluster.shutdown().await;
+    }
+}
@@ -0,0 +1,156 @@
+//! Deterministic simulation: an in-memory network and a virtual clock.
+//!
+//! Run nodes built with [`SimNetwork`] and [`SimClock`] on a current-thread
+//! runtime with paused time (`#[tokio::test(start_paused = true)]`). tokio
+//! then advances the clock only when every task is idle, so periodic
+//! tasks, deadlines and replication interleave the same way on every run.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use futures::future::BoxFuture;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::clock::Clock;
+use crate::transport::{BoxConnection, Listener, Transport};
+
+// Per-direction buffer of an in-memory connection
+const SIM_BUFFER_SIZE: usize = 64 * 1024;
+
+// First port handed out for port 0 binds and client connections
+const SIM_FIRST_PORT: u16 = 20000;
+
+type Incoming = mpsc::UnboundedSender<(DuplexStream, String)>;
+
+struct SimState {
+    next_port: u16,
+    listeners: HashMap<u16, Incoming>,
+}
+
+impl SimState {
+    fn allocate_port(&mut self) -> u16 {
+        while self.listeners.contains_key(&self.next_port) {
+            self.next_port = self.next_port.wrapping_add(1).max(SIM_FIRST_PORT);
+        }
+        let port = self.next_port;
+        self.next_port = self.next_port.wrapping_add(1).max(SIM_FIRST_PORT);
+        port
+    }
+}
+
+// In-memory network keyed by port; every address resolves to the listener on its port
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl Default for SimNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimNetwork {
+    pub fn new() -> Self {
+        SimNetwork {
+            state: Arc::new(Mutex::new(SimState {
+                next_port: SIM_FIRST_PORT,
+                listeners: HashMap::new(),
+            })),
+        }
+    }
+}
+
+fn port_of(addr: &str) -> io::Result<u16> {
+    addr.rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address: {}", addr)))
+}
+
+impl Transport for SimNetwork {
+    fn bind(&self, port: u16) -> io::Result<Box<dyn Listener>> {
+        let mut state = self.state.lock().unwrap();
+        let port = if port == 0 { state.allocate_port() } else { port };
+        if state.listeners.contains_key(&port) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("sim port {} already bound", port)));
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        state.listeners.insert(port, sender);
+        Ok(Box::new(SimListener {
+            port,
+            receiver,
+            state: Arc::clone(&self.state),
+        }))
+    }
+
+    fn connect(&self, addr: String) -> BoxFuture<'static, io::Result<BoxConnection>> {
+        let result = port_of(&addr).and_then(|port| {
+            let mut state = self.state.lock().unwrap();
+            let from = format!("127.0.0.1:{}", state.allocate_port());
+            let incoming = state
+                .listeners
+                .get(&port)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionRefused, format!("nothing listening on {}", addr)))?;
+            let (client, server) = tokio::io::duplex(SIM_BUFFER_SIZE);
+            incoming
+                .send((server, from))
+                .map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, format!("listener on {} closed", addr)))?;
+            Ok(Box::new(client) as BoxConnection)
+        });
+        Box::pin(async move { result })
+    }
+}
+
+struct SimListener {
+    port: u16,
+    receiver: mpsc::UnboundedReceiver<(DuplexStream, String)>,
+    state: Arc<Mutex<SimState>>,
+}
+
+impl Listener for SimListener {
+    fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxConnection, String)>> {
+        Box::pin(async move {
+            match self.receiver.recv().await {
+                Some((stream, from)) => Ok((Box::new(stream) as BoxConnection, from)),
+                None => Err(io::Error::new(io::ErrorKind::NotConnected, "sim listener closed")),
+            }
+        })
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().listeners.remove(&self.port);
+    }
+}
+
+// Wall clock pinned to a fixed epoch and advanced by tokio's (possibly paused) clock
+pub struct SimClock {
+    epoch_millis: u64,
+    start: Instant,
+}
+
+impl SimClock {
+    pub fn new(epoch_millis: u64) -> Self {
+        SimClock {
+            epoch_millis,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_millis(&self) -> u64 {
+        self.epoch_millis + self.start.elapsed().as_millis() as u64
+    }
+}
@@ -0,0 +1,216 @@
+//! Log archive and point-in-time recovery for the append log
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use log::{debug, info, warn};
+
+use super::persistence::{list_segments, read_segment, read_snapshot, snapshot_covers, written_at, BoxError};
+use super::{Cache, CacheValue};
+
+// How long archived snapshots and segments are kept for recovery
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogArchive {
+    pub keep: Duration,
+}
+
+impl Default for LogArchive {
+    fn default() -> Self {
+        LogArchive { keep: Duration::from_secs(7 * 24 * 3600) }
+    }
+}
+
+impl LogArchive {
+    // "on" for the defaults, or e.g. "keep_s=86400"
+    pub fn parse(spec: &str) -> Result<LogArchive, String> {
+        let mut archive = LogArchive::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty() && *s != "on") {
+            let (name, value) = setting.split_once('=').ok_or_else(|| format!("Invalid archive setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "keep_s" => archive.keep = Duration::from_secs(value.parse().ok().filter(|s| *s > 0).ok_or_else(invalid)?),
+                _ => return Err(format!("Unknown archive setting: {}", name)),
+            }
+        }
+        Ok(archive)
+    }
+}
+
+pub(crate) fn archive_dir(snapshot: &Path) -> PathBuf {
+    snapshot.with_extension("archive")
+}
+
+// Archived snapshots by the last segment they contain, oldest first; those containing the same
+// segments by when they were written
+pub(crate) fn list_snapshots(dir: &Path) -> std::io::Result<Vec<(u64, PathBuf)>> {
+    let mut snapshots = Vec::new();
+    if !dir.exists() {
+        return Ok(snapshots);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        // Archives from before the write time was in the name have `snapshot_<n>.arrow`
+        let n = name.strip_prefix("snapshot_").and_then(|n| n.strip_suffix(".arrow")).map(|n| n.split_once('_').map_or(n, |(n, _)| n));
+        if let Some(n) = n.and_then(|n| n.parse().ok()) {
+            snapshots.push((n, path));
+        }
+    }
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+// Move the segments a compaction folded in into the archive, with a copy of the new snapshot
+pub(super) fn store(snapshot: &Path, covered: u64, written_at: u64, segments: &[(u64, PathBuf)]) -> std::io::Result<()> {
+    let dir = archive_dir(snapshot);
+    std::fs::create_dir_all(&dir)?;
+    for (_, path) in segments {
+        if let Some(name) = path.file_name() {
+            std::fs::rename(path, dir.join(name))?;
+        }
+    }
+    std::fs::copy(snapshot, dir.join(format!("snapshot_{:08}_{}.arrow", covered, written_at)))?;
+    Ok(())
+}
+
+// Remove archived files no recovery within `keep` of `now` needs; returns how many were removed
+pub(super) fn prune(snapshot: &Path, keep: Duration, now: u64) -> Result<usize, BoxError> {
+    let dir = archive_dir(snapshot);
+    let cutoff = now.saturating_sub(keep.as_millis() as u64);
+    let snapshots = list_snapshots(&dir)?;
+    // The newest snapshot from before the window is where recovering to its start begins
+    let Some(oldest_needed) = snapshots.iter().rev().find(|(_, path)| written_at(path).is_some_and(|at| at <= cutoff)).map(|(n, _)| *n) else {
+        return Ok(0);
+    };
+    let mut removed = 0;
+    for (_, path) in snapshots.iter().filter(|(n, _)| *n < oldest_needed) {
+        std::fs::remove_file(path)?;
+        removed += 1;
+    }
+    for (_, path) in list_segments(&dir)?.into_iter().filter(|(n, _)| *n <= oldest_needed) {
+        std::fs::remove_file(path)?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+// A key's value and expiry as a snapshot or segment stores it, None once deleted
+type Entry = Option<(CacheValue, Option<u64>)>;
+
+// Walk the cache as it was at `target` (Unix millis): every key of the newest snapshot written
+// by then, live or archived, then the changes of the segments after it written by then, in
+// order. Returns how many segments were replayed.
+fn replay(snapshot: &Path, target: u64, mut apply: impl FnMut(String, Entry)) -> Result<usize, BoxError> {
+    let dir = archive_dir(snapshot);
+    let mut snapshots = list_snapshots(&dir)?;
+    if snapshot.exists() {
+        snapshots.push((snapshot_covers(snapshot)?, snapshot.to_path_buf()));
+    }
+    let base = snapshots
+        .into_iter()
+        .filter(|(_, path)| written_at(path).is_some_and(|at| at <= target))
+        .max_by_key(|(n, _)| *n);
+
+    let mut segments = list_segments(&dir)?;
+    segments.extend(list_segments(&snapshot.with_extension("log"))?);
+    segments.sort();
+    segments.dedup_by_key(|(n, _)| *n);
+
+    let covered = match base {
+        Some((covered, path)) => {
+            debug!("Replaying from snapshot {} (segments up to {})", path.display(), covered);
+            read_snapshot(&path, |key, value, expires| apply(key, Some((value, expires))))?;
+            covered
+        }
+        // Without a snapshot the log must go back to the very first segment
+        None if segments.first().is_some_and(|(n, _)| *n == 1) => 0,
+        None => return Err("nothing retained from before that time".into()),
+    };
+    let mut last = covered;
+    for (segment, path) in segments.into_iter().filter(|(n, _)| *n > covered) {
+        if segment != last + 1 {
+            warn!("Stopped replaying at segment {}: segment {} is missing", segment, last + 1);
+            break;
+        }
+        match written_at(&path) {
+            Some(at) if at <= target => {}
+            Some(_) => break,
+            None => {
+                warn!("Stopped replaying at segment {}: it has no write time", path.display());
+                break;
+            }
+        }
+        for (key, value) in read_segment(&path)? {
+            apply(key, value);
+        }
+        last = segment;
+    }
+    Ok((last - covered) as usize)
+}
+
+// The cache as it was at `target` (Unix millis)
+pub(super) fn recover(snapshot: &Path, target: u64) -> Result<Cache, BoxError> {
+    let mut cache = Cache::new();
+    let replayed = replay(snapshot, target, |key, value| match value {
+        Some((value, expires)) => {
+            cache.insert(key.clone(), value);
+            cache.set_expiry(&key, expires);
+        }
+        None => drop(cache.remove(&key)),
+    })?;
+    info!("Recovered {} keys as of {} after replaying {} segments", cache.len(), target, replayed);
+    Ok(cache)
+}
+
+// The value `key` had at `target` (Unix millis), None if it was missing or expired by then
+pub(crate) fn value_as_of(snapshot: &Path, key: &str, target: u64) -> Result<Option<CacheValue>, BoxError> {
+    let mut found = None;
+    replay(snapshot, target, |changed, value| {
+        if changed == key {
+            found = value;
+        }
+    })?;
+    Ok(found.and_then(|(value, expires)| match expires {
+        Some(at) if at <= target => None,
+        _ => Some(value),
+    }))
+}
+
+// The cache as it was at `target` (Unix millis) for COUNT/AGG ... AS_OF, without the keys
+// expired by then
+pub(crate) fn cache_as_of(snapshot: &Path, target: u64) -> Result<Cache, BoxError> {
+    let mut cache = Cache::new();
+    replay(snapshot, target, |key, value| match value {
+        Some((value, expires)) if expires.is_none_or(|at| at > target) => drop(cache.insert(key, value)),
+        _ => drop(cache.remove(&key)),
+    })?;
+    Ok(cache)
+}
+
+// Retained snapshots, live or archived, written at or after `since`, oldest first with when
+// each was written
+pub(crate) fn generations(snapshot: &Path, since: u64) -> Result<Vec<(u64, PathBuf)>, BoxError> {
+    let mut snapshots = list_snapshots(&archive_dir(snapshot))?;
+    if snapshot.exists() {
+        snapshots.push((snapshot_covers(snapshot)?, snapshot.to_path_buf()));
+    }
+    let mut generations: Vec<(u64, PathBuf)> = snapshots
+        .into_iter()
+        .filter_map(|(_, path)| Some((written_at(&path)?, path)))
+        .filter(|(at, _)| *at >= since)
+        .collect();
+    // The live snapshot has an archived copy once the archive is on
+    generations.sort_by_key(|(at, _)| *at);
+    generations.dedup_by_key(|(at, _)| *at);
+    Ok(generations)
+}
+
+// A snapshot's keys, without those already expired when it was written
+pub(crate) fn load_generation(path: &Path, written_at: u64) -> Result<Cache, BoxError> {
+    let mut cache = Cache::new();
+    read_snapshot(path, |key, value, expires| {
+        if expires.is_none_or(|at| at > written_at) {
+            cache.insert(key, value);
+        }
+    })?;
+    Ok(cache)
+}
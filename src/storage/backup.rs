@@ -0,0 +1,354 @@
+//! BACKUP VERIFY, RESTORE --dry-run and DIFF over snapshots and log segments
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+use arrow::array::{Array, StringArray};
+use arrow::datatypes::DataType;
+use arrow::ipc::reader::FileReader;
+
+use super::archive::list_snapshots;
+use super::persistence::{list_segments, read_snapshot, BoxError, COVERED_SEGMENT, WRITTEN_AT};
+use super::{Cache, CacheValue};
+
+// Problems listed per file; the rest are only counted
+const MAX_PROBLEMS: usize = 10;
+
+// Keys a dry run names of those whose newer value a restore would lose
+const MAX_CONFLICTS_LISTED: usize = 10;
+
+// What BACKUP VERIFY found in one file
+#[derive(Debug, Default)]
+pub(crate) struct FileReport {
+    pub(crate) rows: usize,
+    pub(crate) keys: usize,
+    // Deleted keys, in a log segment
+    pub(crate) tombstones: usize,
+    pub(crate) columns: Vec<String>,
+    pub(crate) written_at: Option<u64>,
+    pub(crate) covered_segment: Option<u64>,
+    pub(crate) problems: Vec<String>,
+    pub(crate) problem_count: usize,
+}
+
+impl FileReport {
+    fn problem(&mut self, problem: String) {
+        if self.problems.len() < MAX_PROBLEMS {
+            self.problems.push(problem);
+        }
+        self.problem_count += 1;
+    }
+}
+
+// Read every row of a snapshot, or of a log segment (which may hold tombstones), and check it
+pub(crate) fn verify_file(path: &Path, segment: bool) -> FileReport {
+    let mut report = FileReport::default();
+    let reader = match File::open(path).map_err(BoxError::from).and_then(|file| Ok(FileReader::try_new(file, None)?)) {
+        Ok(reader) => reader,
+        Err(e) => {
+            report.problem(format!("not a readable Arrow IPC file: {}", e));
+            return report;
+        }
+    };
+    let schema = reader.schema();
+    report.columns = schema.fields().iter().map(|field| field.name().clone()).collect();
+    report.written_at = schema.metadata().get(WRITTEN_AT).and_then(|at| at.parse().ok());
+    report.covered_segment = schema.metadata().get(COVERED_SEGMENT).and_then(|n| n.parse().ok());
+
+    // The columns a restart reads; snapshots from before typed values or TTLs lack the last two
+    for (name, expected, required) in [
+        ("key", DataType::Utf8, true),
+        ("value", DataType::Utf8, true),
+        ("type", DataType::Utf8, false),
+        ("expires", DataType::UInt64, false),
+    ] {
+        match schema.field_with_name(name) {
+            Ok(field) if *field.data_type() != expected => {
+                report.problem(format!("column {} is {}, expected {}", name, field.data_type(), expected))
+            }
+            Err(_) if required => report.problem(format!("missing {} column", name)),
+            _ => {}
+        }
+    }
+    if report.problem_count > 0 {
+        return report;
+    }
+
+    let mut keys = HashSet::new();
+    for (number, batch) in reader.enumerate() {
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                report.problem(format!("record batch {} is unreadable: {}", number, e));
+                break;
+            }
+        };
+        let column = |name: &str| batch.column_by_name(name).and_then(|c| c.as_any().downcast_ref::<StringArray>().cloned());
+        let (Some(key_column), Some(values)) = (column("key"), column("value")) else {
+            report.problem(format!("record batch {} lacks the key and value columns", number));
+            break;
+        };
+        let types = column("type");
+        for i in 0..batch.num_rows() {
+            report.rows += 1;
+            if key_column.is_null(i) {
+                report.problem(format!("row {} has no key", report.rows));
+                continue;
+            }
+            let key = key_column.value(i);
+            if !keys.insert(key.to_string()) && !segment {
+                report.problem(format!("row {}: key {} appears twice", report.rows, key));
+            }
+            if values.is_null(i) {
+                match segment {
+                    true => report.tombstones += 1,
+                    false => report.problem(format!("row {}: {} has no value", report.rows, key)),
+                }
+                continue;
+            }
+            let type_name = types.as_ref().filter(|types| !types.is_null(i)).map_or("string", |types| types.value(i));
+            if let Err(e) = CacheValue::parse(type_name, values.value(i)) {
+                report.problem(format!("row {}: {} does not parse as {}: {}", report.rows, key, type_name, e));
+            }
+        }
+    }
+    report.keys = keys.len();
+    report
+}
+
+// BACKUP VERIFY's answer for a snapshot file or a directory of snapshots and segments
+pub(crate) fn verify(path: &Path) -> Result<String, BoxError> {
+    if !path.is_dir() {
+        if !path.exists() {
+            return Err(format!("{} does not exist", path.display()).into());
+        }
+        let report = verify_file(path, false);
+        let status = match report.problem_count {
+            0 => format!("OK: {} verified", path.display()),
+            n => format!("FAILED: {} has {} problems", path.display(), n),
+        };
+        let mut lines = vec![
+            status,
+            format!("rows:{}", report.rows),
+            format!("keys:{}", report.keys),
+            format!("columns:{}", report.columns.join(",")),
+            format!("written_at:{}", report.written_at.map_or("-".to_string(), |at| at.to_string())),
+            format!("covered_segment:{}", report.covered_segment.map_or("-".to_string(), |n| n.to_string())),
+        ];
+        lines.extend(report.problems.iter().map(|problem| format!("problem:{}", problem)));
+        return Ok(lines.join("\n"));
+    }
+
+    let segments = list_segments(path)?;
+    let mut files: Vec<(String, FileReport)> = list_snapshots(path)?
+        .into_iter()
+        .map(|(_, file)| (file_name(&file), verify_file(&file, false)))
+        .collect();
+    files.extend(segments.iter().map(|(_, file)| (file_name(file), verify_file(file, true))));
+    if files.is_empty() {
+        return Err(format!("no snapshots or log segments in {}", path.display()).into());
+    }
+    // A segment missing from the middle loses its changes for good on replay
+    let gaps: Vec<String> = segments
+        .windows(2)
+        .filter(|pair| pair[1].0 != pair[0].0 + 1)
+        .map(|pair| match (pair[0].0 + 1, pair[1].0 - 1) {
+            (first, last) if first == last => format!("problem:segment {} is missing", first),
+            (first, last) => format!("problem:segments {} to {} are missing", first, last),
+        })
+        .collect();
+    let failed = files.iter().filter(|(_, report)| report.problem_count > 0).count();
+    let rows: usize = files.iter().map(|(_, report)| report.rows).sum();
+    let mut lines = vec![match (failed, gaps.len()) {
+        (0, 0) => format!("OK: {} files in {} verified, {} rows", files.len(), path.display(), rows),
+        _ => format!("FAILED: {} of {} files in {} have problems, {} segment gaps", failed, files.len(), path.display(), gaps.len()),
+    }];
+    for (name, report) in &files {
+        let status = report.problems.first().map_or("ok".to_string(), |problem| format!("problem={}", problem));
+        lines.push(format!("{} rows={} keys={} tombstones={} {}", name, report.rows, report.keys, report.tombstones, status));
+    }
+    lines.extend(gaps);
+    Ok(lines.join("\n"))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+// Every key of a snapshot with its value, and when the snapshot was written
+pub(crate) struct Backup {
+    entries: Vec<(String, CacheValue)>,
+    written_at: Option<u64>,
+}
+
+pub(crate) fn read_backup(path: &Path) -> Result<Backup, BoxError> {
+    let written_at = FileReader::try_new(File::open(path)?, None)?.schema().metadata().get(WRITTEN_AT).and_then(|at| at.parse().ok());
+    let mut entries = Vec::new();
+    read_snapshot(path, |key, value, _| entries.push((key, value)))?;
+    Ok(Backup { entries, written_at })
+}
+
+// RESTORE --dry-run's answer: what loading the backup over the live cache would change
+pub(crate) fn plan_restore(backup: &Backup, cache: &Cache) -> String {
+    let Backup { entries, written_at } = backup;
+    let written_at = *written_at;
+    let (mut added, mut overwritten, mut unchanged) = (0, 0, 0);
+    let mut conflicts = Vec::new();
+    for (key, value) in entries {
+        match cache.get(key) {
+            None => added += 1,
+            Some(live) if live == value => unchanged += 1,
+            // Changed here since the backup was taken, so that change would be lost
+            Some(_) if written_at.is_some_and(|at| cache.modified(key).is_some_and(|modified| modified > at)) => conflicts.push(key.as_str()),
+            Some(_) => overwritten += 1,
+        }
+    }
+    let restored: HashSet<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+    let untouched = cache.iter().filter(|(key, _)| !restored.contains(key.as_str())).count();
+    conflicts.sort_unstable();
+    let listed = conflicts.iter().take(MAX_CONFLICTS_LISTED).copied().collect::<Vec<_>>().join(",");
+    format!(
+        "DRY RUN: nothing was changed\nadded:{}\noverwritten:{}\nconflicting:{}\nunchanged:{}\nuntouched:{}\nwritten_at:{}\nconflicts:{}",
+        added,
+        overwritten,
+        match written_at {
+            Some(_) => conflicts.len().to_string(),
+            // Without the backup's time a newer change can't be told from an older one
+            None => "unknown".to_string(),
+        },
+        unchanged,
+        untouched,
+        written_at.map_or("-".to_string(), |at| at.to_string()),
+        if listed.is_empty() { "-" } else { &listed }
+    )
+}
+
+// DIFF's answer: keys only in `to` (added), only in `from` (removed), and in both with different
+// values (changed), each listed with `full`
+pub(crate) fn diff<'a>(from: &'a Backup, to: impl Iterator<Item = (&'a str, &'a CacheValue)>, full: bool) -> String {
+    let mut before: HashMap<&str, &CacheValue> = from.entries.iter().map(|(key, value)| (key.as_str(), value)).collect();
+    let (mut added, mut changed, mut unchanged) = (Vec::new(), Vec::new(), 0);
+    for (key, value) in to {
+        match before.remove(key) {
+            Some(old) if old == value => unchanged += 1,
+            Some(_) => changed.push(key),
+            None => added.push(key),
+        }
+    }
+    let mut removed: Vec<&str> = before.into_keys().collect();
+    let mut lines = vec![
+        format!("added:{}", added.len()),
+        format!("removed:{}", removed.len()),
+        format!("changed:{}", changed.len()),
+        format!("unchanged:{}", unchanged),
+    ];
+    if full {
+        for (sign, keys) in [("+", &mut added), ("-", &mut removed), ("~", &mut changed)] {
+            keys.sort_unstable();
+            lines.extend(keys.iter().map(|key| format!("{} {}", sign, key)));
+        }
+    }
+    lines.join("\n")
+}
+
+pub(crate) fn diff_backups(from: &Backup, to: &Backup, full: bool) -> String {
+    diff(from, to.entries.iter().map(|(key, value)| (key.as_str(), value)), full)
+}
+
+// Diff two snapshot files, as `p2p-rust diff` does without a node
+pub fn diff_files(from: &Path, to: &Path, full: bool) -> Result<String, BoxError> {
+    Ok(diff_backups(&read_backup(from)?, &read_backup(to)?, full))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use tokio::time::Duration;
+    use super::*;
+    use crate::sim::SimClock;
+    use crate::storage::persistence::write_cache_to_arrow;
+
+    const EPOCH: u64 = 1_735_689_600_000;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("p2p-rust-backup-{}-{}", std::process::id(), rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A cache on `clock` holding `pairs`
+    fn cache(clock: &Arc<SimClock>, pairs: &[(&str, i64)]) -> Cache {
+        let mut cache = Cache::new();
+        cache.set_clock(Arc::clone(clock) as _);
+        for (key, value) in pairs {
+            cache.insert(key.to_string(), CacheValue::Int(*value));
+        }
+        cache
+    }
+
+    async fn write(cache: Cache, path: &Path) -> Cache {
+        let shared = Arc::new(Mutex::new(cache));
+        write_cache_to_arrow(Arc::clone(&shared), path.to_str().unwrap()).await.unwrap();
+        Arc::into_inner(shared).unwrap().into_inner()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn verifies_a_snapshot_and_finds_a_truncated_one() {
+        let dir = temp_dir();
+        let snapshot = dir.join("cache.arrow");
+        write(cache(&Arc::new(SimClock::new(EPOCH)), &[("a", 1), ("b", 2)]), &snapshot).await;
+        let report = verify(&snapshot).unwrap();
+        assert!(report.starts_with(&format!("OK: {} verified\nrows:2\nkeys:2\n", snapshot.display())), "{}", report);
+        assert!(report.contains(&format!("written_at:{}", EPOCH)), "{}", report);
+
+        let bytes = std::fs::read(&snapshot).unwrap();
+        std::fs::write(&snapshot, &bytes[..bytes.len() / 2]).unwrap();
+        let report = verify(&snapshot).unwrap();
+        assert!(report.starts_with("FAILED: "), "{}", report);
+        assert!(report.contains("problem:not a readable Arrow IPC file"), "{}", report);
+        assert!(verify(&dir.join("missing.arrow")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_dry_run_restore_names_the_changes_it_would_lose() {
+        let dir = temp_dir();
+        let snapshot = dir.join("cache.arrow");
+        // The live cache's values from before the backup was written, one of them different
+        let clock = Arc::new(SimClock::new(EPOCH));
+        let mut live = cache(&clock, &[("same", 1), ("old", 3), ("newer", 1), ("gone", 1)]);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        write(cache(&clock, &[("same", 1), ("old", 1), ("newer", 1), ("gone", 1)]), &snapshot).await;
+        tokio::time::advance(Duration::from_secs(1)).await;
+        live.insert("newer".to_string(), CacheValue::Int(2));
+        live.insert("extra".to_string(), CacheValue::Int(1));
+        live.remove("gone");
+
+        let plan = plan_restore(&read_backup(&snapshot).unwrap(), &live);
+        let expected = format!(
+            "DRY RUN: nothing was changed\nadded:1\noverwritten:1\nconflicting:1\nunchanged:1\nuntouched:1\nwritten_at:{}\nconflicts:newer",
+            EPOCH + 1000
+        );
+        assert_eq!(plan, expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn diffs_two_snapshots_key_by_key() {
+        let dir = temp_dir();
+        let clock = Arc::new(SimClock::new(EPOCH));
+        let (from, to) = (dir.join("from.arrow"), dir.join("to.arrow"));
+        write(cache(&clock, &[("same", 1), ("changed", 1), ("removed", 1)]), &from).await;
+        let live = write(cache(&clock, &[("same", 1), ("changed", 2), ("added", 1), ("also", 1)]), &to).await;
+
+        assert_eq!(diff_files(&from, &to, false).unwrap(), "added:2\nremoved:1\nchanged:1\nunchanged:1");
+        let full = "added:2\nremoved:1\nchanged:1\nunchanged:1\n+ added\n+ also\n- removed\n~ changed";
+        assert_eq!(diff_files(&from, &to, true).unwrap(), full);
+        // Against the live cache as against a snapshot of it
+        let backup = read_backup(&from).unwrap();
+        assert_eq!(diff(&backup, live.iter().map(|(key, value)| (key.as_str(), value)), true), full);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+//! JSON path parsing and access (`$.owner`, `$.items[0].id`)
+
+use serde_json::Value;
+
+use super::value::CacheValue;
+
+pub enum PathSegment {
+    Field(String),
+    Index(usize),
+}
+
+// Parse a JSON path such as `$.owner` or `$.items[0].id` into its segments
+pub(crate) fn parse_json_path(path: &str) -> Option<Vec<PathSegment>> {
+    let mut rest = path.trim().strip_prefix('$')?;
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(after_dot) = rest.strip_prefix('.') {
+            let end = after_dot.find(['.', '[']).unwrap_or(after_dot.len());
+            if end == 0 {
+                return None;
+            }
+            segments.push(PathSegment::Field(after_dot[..end].to_string()));
+            rest = &after_dot[end..];
+        } else if let Some(after_bracket) = rest.strip_prefix('[') {
+            let end = after_bracket.find(']')?;
+            segments.push(PathSegment::Index(after_bracket[..end].parse().ok()?));
+            rest = &after_bracket[end + 1..];
+        } else {
+            return None;
+        }
+    }
+    Some(segments)
+}
+
+pub(crate) fn json_path_lookup<'a>(value: &'a Value, segments: &[PathSegment]) -> Option<&'a Value> {
+    segments.iter().try_fold(value, |current, segment| match segment {
+        PathSegment::Field(name) => current.get(name.as_str()),
+        PathSegment::Index(i) => current.get(*i),
+    })
+}
+
+// Replace the value at `segments` inside `root`, creating intermediate objects as needed
+pub(crate) fn json_path_set(root: &mut Value, segments: &[PathSegment], new_value: Value) -> Result<(), String> {
+    let Some((last, parents)) = segments.split_last() else {
+        *root = new_value;
+        return Ok(());
+    };
+
+    let mut current = root;
+    for segment in parents {
+        current = match segment {
+            PathSegment::Field(name) => {
+                if current.is_null() {
+                    *current = Value::Object(Default::default());
+                }
+                current
+                    .as_object_mut()
+                    .ok_or_else(|| format!("Cannot index non-object with .{}", name))?
+                    .entry(name.clone())
+                    .or_insert(Value::Null)
+            }
+            PathSegment::Index(i) => current
+                .as_array_mut()
+                .and_then(|array| array.get_mut(*i))
+                .ok_or_else(|| format!("Array index {} out of bounds", i))?,
+        };
+    }
+
+    match last {
+        PathSegment::Field(name) => {
+            if current.is_null() {
+                *current = Value::Object(Default::default());
+            }
+            current
+                .as_object_mut()
+                .ok_or_else(|| format!("Cannot index non-object with .{}", name))?
+                .insert(name.clone(), new_value);
+        }
+        PathSegment::Index(i) => {
+            let array = current
+                .as_array_mut()
+                .ok_or_else(|| format!("Cannot index non-array with [{}]", i))?;
+            match i.cmp(&array.len()) {
+                std::cmp::Ordering::Less => array[*i] = new_value,
+                std::cmp::Ordering::Equal => array.push(new_value),
+                std::cmp::Ordering::Greater => return Err(format!("Array index {} out of bounds", i)),
+            }
+        }
+    }
+    Ok(())
+}
+
+// JSON view of a value for path-based filters: JSON strings, hashes (as objects) and numbers
+pub(crate) fn value_as_json(value: &CacheValue) -> Option<Value> {
+    match value {
+        CacheValue::Str(raw) => serde_json::from_str(raw).ok(),
+        CacheValue::Hash(hash) => serde_json::to_value(hash).ok(),
+        CacheValue::Int(i) => Some(Value::from(*i)),
+        CacheValue::Float(x) => Some(Value::from(*x)),
+        _ => None,
+    }
+}
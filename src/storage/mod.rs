@@ -0,0 +1,763 @@
+//! In-memory cache, typed values, secondary indexes and persistence
+
+pub mod archive;
+pub mod backup;
+pub mod json;
+pub mod persistence;
+pub mod value;
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+use log::debug;
+use serde_json::Value;
+
+use crate::clock::SharedClock;
+use json::{json_path_lookup, parse_json_path, PathSegment};
+pub use value::{CacheValue, EventStream, Score, SortedSet};
+use value::{hash_field_size, stream_entry_size, string_size, wrong_type, zset_member_size};
+
+pub type SharedCache = Arc<Mutex<Cache>>;
+
+// Changes buffered per subscriber before it starts missing them
+const CHANGE_BUFFER: usize = 1024;
+
+// How often keys past their expiry are removed; reads don't see them even before that
+const EXPIRY_SWEEP_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(1);
+
+// A key written (with its new value) or removed (None), as streamed to SUBSCRIBE clients
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyChange {
+    pub key: String,
+    pub value: Option<CacheValue>,
+    // Correlation ID of the request that made the change
+    pub correlation: Option<Arc<str>>,
+}
+
+pub(crate) struct SecondaryIndex {
+    pub(crate) path: String,
+    pub(crate) segments: Vec<PathSegment>,
+    // Indexed field value -> keys whose JSON value has it
+    pub(crate) entries: HashMap<String, HashSet<String>>,
+}
+
+impl SecondaryIndex {
+    // Extract the indexed field from a cache value, if it is a JSON string and has the field
+    pub(crate) fn extract(&self, value: &CacheValue) -> Option<String> {
+        let CacheValue::Str(raw) = value else {
+            return None;
+        };
+        let json: Value = serde_json::from_str(raw).ok()?;
+        match json_path_lookup(&json, &self.segments)? {
+            Value::String(s) => Some(s.clone()),
+            other => Some(other.to_string()),
+        }
+    }
+
+    pub(crate) fn add(&mut self, key: &str, value: &CacheValue) {
+        if let Some(field) = self.extract(value) {
+            self.entries.entry(field).or_default().insert(key.to_string());
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str, value: &CacheValue) {
+        if let Some(field) = self.extract(value) {
+            if let Some(keys) = self.entries.get_mut(&field) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.entries.remove(&field);
+                }
+            }
+        }
+    }
+}
+
+// Where the writes being applied come from; protocol::execute sets it around each command
+#[derive(Clone, Debug)]
+pub struct WriteSource {
+    // Node the write was first made on
+    pub origin: Arc<str>,
+    // Client or peer that sent it to this node
+    pub writer: Arc<str>,
+    // Correlation ID of the request, the same on every node it reaches
+    pub correlation: Arc<str>,
+}
+
+tokio::task_local! {
+    pub static WRITE_SOURCE: WriteSource;
+}
+
+// What the cache knows about each key besides its value
+struct EntryMeta {
+    // Unix millis of the key's last change on this node, tracked once a clock is set
+    modified: Option<u64>,
+    // Approximate bytes of key, value and this metadata (see entry_size)
+    size: usize,
+    // Changes applied to the key on this node since it was created, or first created while
+    // history is kept
+    version: u64,
+    // None for writes made outside a command, e.g. restoring a snapshot
+    source: Option<WriteSource>,
+}
+
+// The metadata DEBUG OBJECT reports
+#[derive(Clone, Debug)]
+pub struct KeyDebug {
+    pub version: u64,
+    pub modified: Option<u64>,
+    pub memory_bytes: usize,
+    pub source: Option<WriteSource>,
+}
+
+// An earlier (or the current) state of a key, as GETHIST reports it
+#[derive(Clone, Debug)]
+pub struct KeyVersion {
+    pub version: u64,
+    pub modified: Option<u64>,
+    // None for a delete
+    pub value: Option<CacheValue>,
+    pub source: Option<WriteSource>,
+}
+
+// Approximate bytes `key` and `value` take in the cache, counting the key's copy in the metadata
+fn entry_size(key: &str, value: &CacheValue) -> usize {
+    2 * string_size(key) + std::mem::size_of::<EntryMeta>() + value.memory_usage()
+}
+
+// In-memory key/value store with secondary indexes maintained on every write
+pub struct Cache {
+    pub(crate) entries: HashMap<String, CacheValue>,
+    pub(crate) indexes: HashMap<String, SecondaryIndex>,
+    changes: broadcast::Sender<KeyChange>,
+    meta: HashMap<String, EntryMeta>,
+    // Sum of every entry's size
+    memory: usize,
+    clock: Option<SharedClock>,
+    // Keys changed since the last take_changed, once track_changes was called
+    changed: Option<HashSet<String>>,
+    // Unix millis each key with a TTL expires at; writing a whole value clears it
+    expires: HashMap<String, u64>,
+    // Versions kept per key once keep_history was called, newest last (deletes included)
+    history_depth: usize,
+    history: HashMap<String, VecDeque<KeyVersion>>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Cache {
+            entries: HashMap::new(),
+            indexes: HashMap::new(),
+            changes: broadcast::channel(CHANGE_BUFFER).0,
+            meta: HashMap::new(),
+            memory: 0,
+            clock: None,
+            changed: None,
+            expires: HashMap::new(),
+            history_depth: 0,
+            history: HashMap::new(),
+        }
+    }
+
+    // Start recording modification times; keys already present (e.g. restored from a
+    // snapshot) have none and count as older than any change made from now on
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = Some(clock);
+    }
+
+    // When `key` last changed on this node, in Unix millis
+    pub fn modified(&self, key: &str) -> Option<u64> {
+        self.meta.get(key).and_then(|meta| meta.modified)
+    }
+
+    // Version, size and source of `key`'s last change on this node
+    pub fn debug_object(&self, key: &str) -> Option<KeyDebug> {
+        self.meta.get(key).map(|meta| KeyDebug {
+            version: meta.version,
+            modified: meta.modified,
+            memory_bytes: meta.size,
+            source: meta.source.clone(),
+        })
+    }
+
+    // Approximate bytes held by all entries, their keys and metadata
+    pub fn memory_usage(&self) -> usize {
+        self.memory
+    }
+
+    // Approximate bytes held by `key`, its value and metadata, as MEMORY USAGE reports
+    pub fn key_memory_usage(&self, key: &str) -> Option<usize> {
+        self.meta.get(key).map(|meta| meta.size)
+    }
+
+    // Approximate bytes held by the secondary indexes, computed on demand
+    pub fn index_memory_usage(&self) -> usize {
+        self.indexes
+            .values()
+            .flat_map(|index| index.entries.iter())
+            .map(|(field, keys)| string_size(field) + keys.iter().map(|key| string_size(key)).sum::<usize>())
+            .sum()
+    }
+
+    // Keep the last `versions` changes of every key from now on, for GETHIST
+    pub fn keep_history(&mut self, versions: usize) {
+        self.history_depth = versions;
+    }
+
+    pub fn keeps_history(&self) -> bool {
+        self.history_depth > 0
+    }
+
+    // Up to `n` of the versions kept for `key`, newest first
+    pub fn history(&self, key: &str, n: usize) -> Vec<KeyVersion> {
+        self.history.get(key).map(|versions| versions.iter().rev().take(n).cloned().collect()).unwrap_or_default()
+    }
+
+    // Start collecting changed keys for take_changed, e.g. for append-log persistence
+    pub fn track_changes(&mut self) {
+        self.changed.get_or_insert_with(HashSet::new);
+    }
+
+    // Keys changed since the last call, each now either present or deleted
+    pub fn take_changed(&mut self) -> Vec<String> {
+        self.changed.as_mut().map(|changed| changed.drain().collect()).unwrap_or_default()
+    }
+
+    // Receive every subsequent change; a receiver that falls behind gets RecvError::Lagged
+    pub fn subscribe(&self) -> broadcast::Receiver<KeyChange> {
+        self.changes.subscribe()
+    }
+
+    // Record that `key` changed, sizing its value from scratch
+    fn notify(&mut self, key: &str) {
+        let size = self.entries.get(key).map(|value| entry_size(key, value));
+        self.record_change(key, size);
+    }
+
+    // Like notify, for a collection changed in place by adding and removing elements of the
+    // given sizes, so long collections aren't walked on every change
+    fn notify_resized(&mut self, key: &str, added: usize, removed: usize) {
+        let size = match (self.entries.get(key), self.meta.get(key)) {
+            (Some(_), Some(meta)) => Some((meta.size + added).saturating_sub(removed)),
+            (Some(value), None) => Some(entry_size(key, value)),
+            (None, _) => None,
+        };
+        self.record_change(key, size);
+    }
+
+    // Update the metadata of `key` (None once it is gone) and publish its current state to
+    // subscribers, if there are any
+    fn record_change(&mut self, key: &str, size: Option<usize>) {
+        let source = WRITE_SOURCE.try_with(WriteSource::clone).ok();
+        let correlation = source.as_ref().map(|source| Arc::clone(&source.correlation));
+        let old = self.meta.remove(key);
+        if let Some(old) = &old {
+            self.memory -= old.size;
+        }
+        let modified = self.clock.as_ref().map(|clock| clock.unix_millis());
+        // With history on, a deleted key goes on counting from its last version
+        let last = old.map(|old| old.version).or_else(|| self.history.get(key).and_then(VecDeque::back).map(|last| last.version));
+        let version = last.map_or(1, |last| last + 1);
+        if self.history_depth > 0 {
+            let versions = self.history.entry(key.to_string()).or_default();
+            if versions.len() == self.history_depth {
+                versions.pop_front();
+            }
+            versions.push_back(KeyVersion { version, modified, value: self.entries.get(key).cloned(), source: source.clone() });
+        }
+        if let Some(size) = size {
+            self.meta.insert(key.to_string(), EntryMeta { modified, size, version, source });
+            self.memory += size;
+        }
+        if let Some(changed) = &mut self.changed {
+            changed.insert(key.to_string());
+        }
+        if self.changes.receiver_count() > 0 {
+            let _ = self.changes.send(KeyChange {
+                key: key.to_string(),
+                value: self.entries.get(key).cloned(),
+                correlation,
+            });
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&CacheValue> {
+        self.entries.get(key).filter(|_| !self.expired(key))
+    }
+
+    // Whether `key` is past its expiry but not removed yet
+    fn expired(&self, key: &str) -> bool {
+        match (self.expires.get(key), &self.clock) {
+            (Some(at), Some(clock)) => *at <= clock.unix_millis(),
+            _ => false,
+        }
+    }
+
+    // When `key` expires, in Unix millis; None without a TTL
+    pub fn expires_at(&self, key: &str) -> Option<u64> {
+        self.expires.get(key).copied().filter(|_| self.get(key).is_some())
+    }
+
+    // Milliseconds left before `key` expires: None if the key is missing, Some(None) without a TTL
+    pub fn ttl(&self, key: &str) -> Option<Option<u64>> {
+        self.get(key)?;
+        let now = self.clock.as_ref().map_or(0, |clock| clock.unix_millis());
+        Some(self.expires.get(key).map(|at| at.saturating_sub(now)))
+    }
+
+    // Expire `key` at `at` (Unix millis), or never with None; false if there is no such key
+    pub fn set_expiry(&mut self, key: &str, at: Option<u64>) -> bool {
+        if self.get(key).is_none() {
+            return false;
+        }
+        match at {
+            Some(at) => self.expires.insert(key.to_string(), at),
+            None => self.expires.remove(key),
+        };
+        // The append log stores expiries with the values
+        if let Some(changed) = &mut self.changed {
+            changed.insert(key.to_string());
+        }
+        true
+    }
+
+    // Drop `key` if it is past its expiry, so a change in place starts from a missing key
+    fn remove_if_expired(&mut self, key: &str) {
+        if self.expired(key) {
+            self.remove(key);
+        }
+    }
+
+    // Remove every key past its expiry; returns how many were removed
+    pub fn remove_expired(&mut self) -> usize {
+        let Some(now) = self.clock.as_ref().map(|clock| clock.unix_millis()) else {
+            return 0;
+        };
+        let expired: Vec<String> = self.expires.iter().filter(|(_, at)| **at <= now).map(|(key, _)| key.clone()).collect();
+        for key in &expired {
+            self.remove(key);
+        }
+        expired.len()
+    }
+
+    // Keys not past their expiry, as get sees them, until remove_expired drops the rest
+    pub fn len(&self) -> usize {
+        let now = self.clock.as_ref().map(|clock| clock.unix_millis());
+        self.entries.len() - now.map_or(0, |now| self.expires.values().filter(|at| **at <= now).count())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &CacheValue)> {
+        let now = self.clock.as_ref().map(|clock| clock.unix_millis());
+        self.entries
+            .iter()
+            .filter(move |(key, _)| now.is_none_or(|now| self.expires.get(*key).is_none_or(|at| *at > now)))
+    }
+
+    pub fn insert(&mut self, key: String, value: CacheValue) -> Option<CacheValue> {
+        self.expires.remove(&key);
+        let old = self.entries.insert(key.clone(), value);
+        let new = &self.entries[&key];
+        for index in self.indexes.values_mut() {
+            if let Some(old) = &old {
+                index.remove(&key, old);
+            }
+            index.add(&key, new);
+        }
+        self.notify(&key);
+        old
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<CacheValue> {
+        self.expires.remove(key);
+        let old = self.entries.remove(key)?;
+        for index in self.indexes.values_mut() {
+            index.remove(key, &old);
+        }
+        self.notify(key);
+        Some(old)
+    }
+
+    // Drop all entries (index definitions are kept); returns how many were removed
+    pub fn clear(&mut self) -> usize {
+        let removed = self.entries.len();
+        let keys: Vec<String> = if self.changes.receiver_count() > 0 || self.changed.is_some() || self.history_depth > 0 {
+            self.entries.keys().cloned().collect()
+        } else {
+            Vec::new()
+        };
+        self.entries.clear();
+        self.expires.clear();
+        self.meta.clear();
+        self.memory = 0;
+        for index in self.indexes.values_mut() {
+            index.entries.clear();
+        }
+        for key in keys {
+            self.notify(&key);
+        }
+        removed
+    }
+
+    // Remove every key matching the predicate under a single lock; returns how many were removed
+    pub fn remove_where(&mut self, predicate: impl Fn(&str) -> bool) -> usize {
+        let doomed: Vec<String> = self.entries.keys().filter(|key| predicate(key)).cloned().collect();
+        for key in doomed.iter() {
+            self.remove(key);
+        }
+        doomed.len()
+    }
+
+    // Push onto the front or back of a list, creating it if missing; returns the new length
+    pub fn list_push(&mut self, key: &str, value: String, front: bool) -> Result<usize, String> {
+        self.remove_if_expired(key);
+        let entry = self
+            .entries
+            .entry(key.to_string())
+            .or_insert_with(|| CacheValue::List(VecDeque::new()));
+        let CacheValue::List(list) = entry else {
+            return Err(wrong_type(key, entry, if front { "LPUSH" } else { "RPUSH" }));
+        };
+        let added = string_size(&value);
+        if front {
+            list.push_front(value);
+        } else {
+            list.push_back(value);
+        }
+        let len = list.len();
+        self.notify_resized(key, added, 0);
+        Ok(len)
+    }
+
+    // Pop from the front or back of a list, removing the key once the list is empty
+    pub fn list_pop(&mut self, key: &str, front: bool) -> Result<Option<String>, String> {
+        self.remove_if_expired(key);
+        let Some(entry) = self.entries.get_mut(key) else {
+            return Ok(None);
+        };
+        let CacheValue::List(list) = entry else {
+            return Err(wrong_type(key, entry, if front { "LPOP" } else { "RPOP" }));
+        };
+        let popped = if front { list.pop_front() } else { list.pop_back() };
+        if list.is_empty() {
+            self.remove(key);
+        } else {
+            self.notify_resized(key, 0, popped.as_deref().map_or(0, string_size));
+        }
+        Ok(popped)
+    }
+
+    // Set a hash field, creating the hash if missing; returns whether the field is new
+    pub fn hash_set(&mut self, key: &str, field: String, value: String) -> Result<bool, String> {
+        self.remove_if_expired(key);
+        let entry = self
+            .entries
+            .entry(key.to_string())
+            .or_insert_with(|| CacheValue::Hash(BTreeMap::new()));
+        let CacheValue::Hash(hash) = entry else {
+            return Err(wrong_type(key, entry, "HSET"));
+        };
+        let size = hash_field_size(&field, &value);
+        let old = hash.insert(field.clone(), value);
+        let replaced = old.as_deref().map_or(0, |old| hash_field_size(&field, old));
+        self.notify_resized(key, size, replaced);
+        Ok(old.is_none())
+    }
+
+    // Delete a hash field, removing the key once the hash is empty; returns whether it existed
+    pub fn hash_del(&mut self, key: &str, field: &str) -> Result<bool, String> {
+        self.remove_if_expired(key);
+        let Some(entry) = self.entries.get_mut(key) else {
+            return Ok(false);
+        };
+        let CacheValue::Hash(hash) = entry else {
+            return Err(wrong_type(key, entry, "HDEL"));
+        };
+        let removed = hash.remove(field);
+        if hash.is_empty() {
+            self.remove(key);
+        } else if let Some(value) = &removed {
+            self.notify_resized(key, 0, hash_field_size(field, value));
+        }
+        Ok(removed.is_some())
+    }
+
+    // Append a stream entry; replicas pass the origin's ID so every node agrees on it
+    pub fn stream_add(&mut self, key: &str, id: Option<u64>, payload: String) -> Result<u64, String> {
+        self.remove_if_expired(key);
+        let entry = self
+            .entries
+            .entry(key.to_string())
+            .or_insert_with(|| CacheValue::Stream(EventStream::default()));
+        let CacheValue::Stream(stream) = entry else {
+            return Err(wrong_type(key, entry, "XADD"));
+        };
+        let id = id.unwrap_or(stream.last_id + 1);
+        stream.last_id = stream.last_id.max(id);
+        let added = stream_entry_size(&payload);
+        let replaced = stream.entries.insert(id, payload).as_deref().map_or(0, stream_entry_size);
+        self.notify_resized(key, added, replaced);
+        Ok(id)
+    }
+
+    pub fn stream_commit(&mut self, key: &str, consumer: &str, offset: u64) -> Result<(), String> {
+        self.remove_if_expired(key);
+        match self.entries.get_mut(key) {
+            Some(CacheValue::Stream(stream)) => {
+                let added = match stream.consumers.insert(consumer.to_string(), offset) {
+                    Some(_) => 0,
+                    None => string_size(consumer) + std::mem::size_of::<u64>(),
+                };
+                self.notify_resized(key, added, 0);
+                Ok(())
+            }
+            Some(other) => Err(wrong_type(key, other, "XCOMMIT")),
+            None => Err("Not Found".to_string()),
+        }
+    }
+
+    pub fn zset_add(&mut self, key: &str, score: f64, member: String) -> Result<bool, String> {
+        self.remove_if_expired(key);
+        let entry = self
+            .entries
+            .entry(key.to_string())
+            .or_insert_with(|| CacheValue::SortedSet(SortedSet::default()));
+        let CacheValue::SortedSet(zset) = entry else {
+            return Err(wrong_type(key, entry, "ZADD"));
+        };
+        let size = zset_member_size(&member);
+        let added = zset.insert(member, score);
+        self.notify_resized(key, if added { size } else { 0 }, 0);
+        Ok(added)
+    }
+
+    // Removes the key once the set is empty
+    pub fn zset_remove(&mut self, key: &str, member: &str) -> Result<bool, String> {
+        self.remove_if_expired(key);
+        let Some(entry) = self.entries.get_mut(key) else {
+            return Ok(false);
+        };
+        let CacheValue::SortedSet(zset) = entry else {
+            return Err(wrong_type(key, entry, "ZREM"));
+        };
+        let removed = zset.remove(member);
+        if zset.scores.is_empty() {
+            self.remove(key);
+        } else if removed {
+            self.notify_resized(key, 0, zset_member_size(member));
+        }
+        Ok(removed)
+    }
+
+    pub fn create_index(&mut self, name: &str, path: &str) -> Result<usize, String> {
+        if self.indexes.contains_key(name) {
+            return Err(format!("Index {} already exists", name));
+        }
+        let segments = parse_json_path(path).ok_or_else(|| format!("Invalid JSON path: {}", path))?;
+        let mut index = SecondaryIndex {
+            path: path.to_string(),
+            segments,
+            entries: HashMap::new(),
+        };
+        for (key, value) in self.entries.iter() {
+            index.add(key, value);
+        }
+        let indexed = index.entries.values().map(HashSet::len).sum();
+        self.indexes.insert(name.to_string(), index);
+        Ok(indexed)
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> bool {
+        self.indexes.remove(name).is_some()
+    }
+
+    pub fn find(&self, name: &str, field: &str) -> Option<Vec<String>> {
+        let index = self.indexes.get(name)?;
+        let mut keys: Vec<String> = index
+            .entries
+            .get(field)
+            .map(|keys| keys.iter().filter(|key| !self.expired(key)).cloned().collect())
+            .unwrap_or_default();
+        keys.sort();
+        Some(keys)
+    }
+}
+
+// Remove keys past their expiry every EXPIRY_SWEEP_INTERVAL
+pub async fn expire_periodically(cache: SharedCache) {
+    loop {
+        tokio::time::sleep(EXPIRY_SWEEP_INTERVAL).await;
+        let removed = cache.lock().await.remove_expired();
+        if removed > 0 {
+            debug!("Removed {} expired keys", removed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+    use crate::sim::SimClock;
+
+    const EPOCH: u64 = 1_735_689_600_000;
+
+    fn cache() -> Cache {
+        let mut cache = Cache::new();
+        cache.set_clock(Arc::new(SimClock::new(EPOCH)));
+        cache
+    }
+
+    fn value(s: &str) -> CacheValue {
+        CacheValue::Str(s.to_string())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_keys_are_hidden_until_swept() {
+        let mut cache = cache();
+        cache.insert("session".to_string(), value("token"));
+        cache.insert("plain".to_string(), value("v"));
+        assert!(cache.set_expiry("session", Some(EPOCH + 1_500)));
+        assert!(!cache.set_expiry("missing", Some(EPOCH + 1_500)));
+        assert_eq!(cache.ttl("session"), Some(Some(1_500)));
+        assert_eq!(cache.ttl("plain"), Some(None));
+        assert_eq!(cache.ttl("missing"), None);
+
+        tokio::time::advance(Duration::from_millis(1_500)).await;
+        assert_eq!(cache.get("session"), None);
+        assert_eq!(cache.expires_at("session"), None);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(), ["plain"]);
+        assert_eq!(cache.remove_expired(), 1);
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn whole_writes_clear_an_expiry_and_changes_in_place_keep_it() {
+        let mut cache = cache();
+        cache.insert("name".to_string(), value("a"));
+        cache.set_expiry("name", Some(EPOCH + 60_000));
+        cache.insert("name".to_string(), value("b"));
+        assert_eq!(cache.expires_at("name"), None);
+
+        cache.list_push("jobs", "a".to_string(), false).unwrap();
+        cache.set_expiry("jobs", Some(EPOCH + 60_000));
+        cache.list_push("jobs", "b".to_string(), false).unwrap();
+        assert_eq!(cache.expires_at("jobs"), Some(EPOCH + 60_000));
+        assert!(cache.set_expiry("jobs", None));
+        assert_eq!(cache.ttl("jobs"), Some(None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_change_to_an_expired_key_starts_from_nothing() {
+        let mut cache = cache();
+        cache.hash_set("user", "name".to_string(), "Alice".to_string()).unwrap();
+        cache.set_expiry("user", Some(EPOCH + 10));
+        tokio::time::advance(Duration::from_millis(10)).await;
+
+        assert_eq!(cache.hash_set("user", "city".to_string(), "Oslo".to_string()), Ok(true));
+        assert_eq!(cache.get("user"), Some(&CacheValue::Hash(BTreeMap::from([("city".to_string(), "Oslo".to_string())]))));
+        assert_eq!(cache.expires_at("user"), None);
+    }
+
+    #[test]
+    fn indexes_follow_writes_and_deletes() {
+        let mut cache = cache();
+        cache.insert("order:1".to_string(), value(r#"{"owner":"alice","qty":2}"#));
+        cache.insert("order:2".to_string(), value(r#"{"owner":"bob"}"#));
+        cache.insert("note".to_string(), value("not json"));
+        assert_eq!(cache.create_index("owner", "$.owner"), Ok(2));
+        assert_eq!(cache.create_index("owner", "$.owner"), Err("Index owner already exists".to_string()));
+        assert_eq!(cache.create_index("qty", "$.qty"), Ok(1));
+        assert_eq!(cache.find("qty", "2"), Some(vec!["order:1".to_string()]));
+
+        cache.insert("order:3".to_string(), value(r#"{"owner":"alice"}"#));
+        cache.insert("order:1".to_string(), value(r#"{"owner":"bob"}"#));
+        assert_eq!(cache.find("owner", "alice"), Some(vec!["order:3".to_string()]));
+        assert_eq!(cache.find("owner", "bob"), Some(vec!["order:1".to_string(), "order:2".to_string()]));
+
+        cache.remove("order:2");
+        assert_eq!(cache.find("owner", "bob"), Some(vec!["order:1".to_string()]));
+        assert_eq!(cache.find("owner", "carol"), Some(vec![]));
+        assert!(cache.drop_index("owner"));
+        assert_eq!(cache.find("owner", "bob"), None);
+    }
+
+    #[test]
+    fn memory_usage_follows_every_change() {
+        let mut cache = cache();
+        cache.insert("name".to_string(), value("alice"));
+        let name = cache.key_memory_usage("name").unwrap();
+        assert_eq!(name, entry_size("name", &value("alice")));
+        assert_eq!(cache.memory_usage(), name);
+
+        cache.list_push("jobs", "a".repeat(100), false).unwrap();
+        let one = cache.key_memory_usage("jobs").unwrap();
+        cache.list_push("jobs", "b".repeat(100), false).unwrap();
+        let two = cache.key_memory_usage("jobs").unwrap();
+        assert_eq!(two, entry_size("jobs", cache.get("jobs").unwrap()));
+        assert!(two > one);
+        cache.list_pop("jobs", true).unwrap();
+        assert_eq!(cache.key_memory_usage("jobs"), Some(one));
+        assert_eq!(cache.memory_usage(), name + one);
+
+        cache.remove("jobs");
+        cache.remove("name");
+        assert_eq!(cache.key_memory_usage("name"), None);
+        assert_eq!(cache.memory_usage(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn history_keeps_the_last_versions_of_each_key_deletes_included() {
+        let mut cache = cache();
+        cache.insert("early".to_string(), value("untracked"));
+        cache.keep_history(3);
+        let source = WriteSource { origin: "a:1".into(), writer: "client:1".into(), correlation: "req-1".into() };
+        WRITE_SOURCE.sync_scope(source, || {
+            for v in ["1", "2", "3"] {
+                cache.insert("key".to_string(), value(v));
+            }
+        });
+        tokio::time::advance(Duration::from_millis(5)).await;
+        cache.remove("key");
+
+        let versions = cache.history("key", usize::MAX);
+        let summary: Vec<_> = versions.iter().map(|v| (v.version, v.value.as_ref().map(CacheValue::to_string))).collect();
+        assert_eq!(summary, [(4, None), (3, Some("3".to_string())), (2, Some("2".to_string()))]);
+        assert_eq!(versions[0].modified, Some(EPOCH + 5));
+        assert!(versions[0].source.is_none());
+        assert_eq!(versions[1].source.as_ref().map(|s| &*s.writer), Some("client:1"));
+        assert_eq!(cache.history("key", 1).len(), 1);
+        assert!(cache.history("early", usize::MAX).is_empty());
+
+        // A key created again goes on from the version it was deleted at
+        cache.insert("key".to_string(), value("again"));
+        assert_eq!(cache.history("key", 1)[0].version, 5);
+        assert_eq!(cache.debug_object("key").map(|debug| debug.version), Some(5));
+    }
+
+    #[test]
+    fn flushall_records_each_delete_as_the_next_version() {
+        let mut cache = Cache::new();
+        cache.keep_history(10);
+        for v in ["1", "2"] {
+            cache.insert("a".to_string(), value(v));
+        }
+        cache.insert("b".to_string(), value("1"));
+        assert_eq!(cache.clear(), 2);
+
+        let latest = |cache: &Cache, key: &str| cache.history(key, 1).first().map(|v| (v.version, v.value.is_none()));
+        assert_eq!(latest(&cache, "a"), Some((3, true)));
+        assert_eq!(latest(&cache, "b"), Some((2, true)));
+        cache.insert("a".to_string(), value("3"));
+        assert_eq!(latest(&cache, "a"), Some((4, false)));
+    }
+}
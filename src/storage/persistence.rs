@@ -0,0 +1,1162 @@
+//! Arrow snapshots, the append log and bulk import/export (csv, jsonl, arrow, parquet)
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use arrow::array::{Array, StringArray, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::{FileWriter, StreamWriter};
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use tokio::sync::{watch, Notify};
+
+use super::archive::{self, LogArchive};
+use super::{Cache, CacheValue, SharedCache};
+use crate::clock::SharedClock;
+use crate::metrics::Latencies;
+
+// Where a node keeps its cache between restarts
+#[derive(Clone, Debug)]
+pub enum Persistence {
+    // Arrow IPC snapshot, restored on start and rewritten every 10s
+    Arrow(std::path::PathBuf),
+    // Arrow IPC snapshot plus segments of the keys changed since, in `<snapshot>.log/`;
+    // changes are appended every second and folded into the snapshot once the log outgrows the cache
+    ArrowLog(std::path::PathBuf),
+    // In-memory only
+    None,
+}
+
+// Rows per record batch in an Arrow IPC stream response
+const STREAM_BATCH_ROWS: usize = 8192;
+
+// Key/value/type schema shared by EXPORT and Arrow responses, and extended by snapshots
+pub(crate) fn pairs_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("key", DataType::Utf8, false),
+        Field::new("value", DataType::Utf8, false),
+        Field::new("type", DataType::Utf8, false),
+    ])
+}
+
+// Snapshots and log segments add when each key expires, in Unix millis (null without a TTL)
+pub(crate) fn snapshot_schema() -> Schema {
+    let mut fields: Vec<Field> = pairs_schema().fields().iter().map(|f| f.as_ref().clone()).collect();
+    fields.push(Field::new("expires", DataType::UInt64, true));
+    Schema::new(fields)
+}
+
+// A key, its value and when it expires, as a snapshot stores them
+pub(crate) type SnapshotEntry = (String, CacheValue, Option<u64>);
+
+// Every key with its expiry, copied out so the lock isn't held while writing
+fn snapshot_entries(cache: &Cache) -> Vec<SnapshotEntry> {
+    cache.iter().map(|(key, value)| (key.clone(), value.clone(), cache.expires_at(key))).collect()
+}
+
+// Build a key/value/type/expires record batch for a snapshot
+pub(crate) fn entries_to_record_batch(entries: &[SnapshotEntry]) -> Result<RecordBatch, arrow::error::ArrowError> {
+    let keys_array = StringArray::from(entries.iter().map(|(k, _, _)| k.as_str()).collect::<Vec<&str>>());
+    let values_array = StringArray::from(entries.iter().map(|(_, v, _)| v.to_string()).collect::<Vec<String>>());
+    let types_array = StringArray::from(entries.iter().map(|(_, v, _)| v.type_name()).collect::<Vec<&str>>());
+    let expires_array = UInt64Array::from(entries.iter().map(|(_, _, at)| *at).collect::<Vec<Option<u64>>>());
+
+    RecordBatch::try_new(
+        Arc::new(snapshot_schema()),
+        vec![Arc::new(keys_array), Arc::new(values_array), Arc::new(types_array), Arc::new(expires_array)],
+    )
+}
+
+// The expires column of a snapshot or segment batch; files written before TTLs have none
+pub(crate) fn batch_expiries(batch: &RecordBatch) -> Vec<Option<u64>> {
+    match batch.column_by_name("expires").and_then(|c| c.as_any().downcast_ref::<UInt64Array>()) {
+        Some(expires) => (0..expires.len()).map(|i| (!expires.is_null(i)).then(|| expires.value(i))).collect(),
+        None => vec![None; batch.num_rows()],
+    }
+}
+
+// Build a key/value/type record batch, the layout shared by EXPORT and Arrow responses
+pub(crate) fn pairs_to_record_batch(pairs: &[(&String, &CacheValue)]) -> Result<RecordBatch, arrow::error::ArrowError> {
+    // Create Arrow arrays for keys, values and their type tags
+    let keys_array = StringArray::from(pairs.iter().map(|(k, _)| k.as_str()).collect::<Vec<&str>>());
+    let values_array = StringArray::from(pairs.iter().map(|(_, v)| v.to_string()).collect::<Vec<String>>());
+    let types_array = StringArray::from(pairs.iter().map(|(_, v)| v.type_name()).collect::<Vec<&str>>());
+
+    // Create a RecordBatch
+    RecordBatch::try_new(
+        Arc::new(pairs_schema()),
+        vec![Arc::new(keys_array), Arc::new(values_array), Arc::new(types_array)],
+    )
+}
+
+// A changed key with its value and expiry, or None once deleted
+type Change = (String, Option<(CacheValue, Option<u64>)>);
+
+// Build a key/value/type/expires record batch of changed keys, with null value and type for deleted ones
+fn changes_to_record_batch(changes: &[Change]) -> Result<RecordBatch, arrow::error::ArrowError> {
+    let keys_array = StringArray::from(changes.iter().map(|(k, _)| k.as_str()).collect::<Vec<&str>>());
+    let values_array = StringArray::from(changes.iter().map(|(_, v)| v.as_ref().map(|(v, _)| v.to_string())).collect::<Vec<Option<String>>>());
+    let types_array = StringArray::from(changes.iter().map(|(_, v)| v.as_ref().map(|(v, _)| v.type_name())).collect::<Vec<Option<&str>>>());
+    let expires_array = UInt64Array::from(changes.iter().map(|(_, v)| v.as_ref().and_then(|(_, at)| *at)).collect::<Vec<Option<u64>>>());
+
+    let schema = Schema::new(vec![
+        Field::new("key", DataType::Utf8, false),
+        Field::new("value", DataType::Utf8, true),
+        Field::new("type", DataType::Utf8, true),
+        Field::new("expires", DataType::UInt64, true),
+    ]);
+
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![Arc::new(keys_array), Arc::new(values_array), Arc::new(types_array), Arc::new(expires_array)],
+    )
+}
+
+// Encode pairs as an Arrow IPC stream of key/value/type batches, for FORMAT ARROW and EXPORT -
+pub(crate) fn pairs_to_arrow_stream(pairs: &[(&String, &CacheValue)]) -> Result<Vec<u8>, arrow::error::ArrowError> {
+    let mut writer = StreamWriter::try_new(Vec::new(), &pairs_schema())?;
+    for chunk in pairs.chunks(STREAM_BATCH_ROWS) {
+        writer.write(&pairs_to_record_batch(chunk)?)?;
+    }
+    writer.finish()?;
+    writer.into_inner()
+}
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// The cache lock is only held to copy the pairs; building the batch and writing the file
+// happen on a blocking thread so requests keep being served meanwhile
+pub async fn write_cache_to_arrow(cache: SharedCache, file_path: &str) -> Result<(), BoxError> {
+    let (entries, written_at) = {
+        let cache = cache.lock().await;
+        (snapshot_entries(&cache), cache.clock.as_ref().map(|clock| clock.unix_millis()))
+    };
+
+    let file_path = file_path.to_string();
+    tokio::task::spawn_blocking(move || {
+        let record_batch = entries_to_record_batch(&entries)?;
+        // Dated once the cache has a clock, for BACKUP VERIFY and RESTORE --dry-run
+        let metadata = written_at.map(|at| HashMap::from([(WRITTEN_AT.to_string(), at.to_string())])).unwrap_or_default();
+        let record_batch = record_batch.with_schema(Arc::new(snapshot_schema().with_metadata(metadata)))?;
+
+        // Write to Arrow file
+        let file = File::create(&file_path)?;
+        let mut writer = FileWriter::try_new(file, &record_batch.schema())?;
+        writer.write(&record_batch)?;
+        writer.finish()?;
+        Ok(())
+    })
+    .await?
+}
+
+pub(crate) type ImportBatch = Result<Vec<(String, CacheValue)>, BoxError>;
+
+// Decode a record batch with key/value (and optional type) Utf8 columns
+pub(crate) fn batch_to_pairs(batch: &RecordBatch) -> ImportBatch {
+    let column = |name: &str| {
+        batch
+            .column_by_name(name)
+            .and_then(|c| c.as_any().downcast_ref::<StringArray>().cloned())
+    };
+    let (Some(keys), Some(values)) = (column("key"), column("value")) else {
+        return Err("missing key/value columns".into());
+    };
+    // Snapshots written before typed values have no type column and hold strings only
+    let types = column("type");
+
+    (0..keys.len())
+        .map(|i| {
+            let type_name = types.as_ref().map(|t| t.value(i)).unwrap_or("string");
+            Ok((keys.value(i).to_string(), CacheValue::parse(type_name, values.value(i))?))
+        })
+        .collect()
+}
+
+// Restore the cache from a snapshot written by write_cache_to_arrow. Keys keep their expiry
+// even if it has passed; the node removes those once its clock is set.
+pub fn load_cache_from_arrow(file_path: &str) -> Result<Cache, BoxError> {
+    let mut cache = Cache::new();
+    read_snapshot(Path::new(file_path), |key, value, expires| {
+        cache.insert(key.clone(), value);
+        cache.set_expiry(&key, expires);
+    })?;
+    Ok(cache)
+}
+
+// Hand every key of a snapshot to `entry` with its value and expiry, a batch at a time
+pub(crate) fn read_snapshot(path: &Path, mut entry: impl FnMut(String, CacheValue, Option<u64>)) -> Result<(), BoxError> {
+    let reader = FileReader::try_new(File::open(path)?, None)?;
+    for batch in reader {
+        let batch = batch?;
+        for ((key, value), expires) in batch_to_pairs(&batch)?.into_iter().zip(batch_expiries(&batch)) {
+            entry(key, value, expires);
+        }
+    }
+    Ok(())
+}
+
+// Snapshot schema metadata naming the last log segment the snapshot already contains
+pub(crate) const COVERED_SEGMENT: &str = "p2p.log_segment";
+
+// Snapshot and segment schema metadata: when the file's contents were taken from the cache, in Unix millis
+pub(crate) const WRITTEN_AT: &str = "p2p.written_at";
+
+// When a snapshot or segment was written, if it says
+pub(crate) fn written_at(path: &Path) -> Option<u64> {
+    let reader = FileReader::try_new(File::open(path).ok()?, None).ok()?;
+    reader.schema().metadata().get(WRITTEN_AT)?.parse().ok()
+}
+
+// The last log segment a snapshot contains, 0 for none
+pub(crate) fn snapshot_covers(snapshot: &Path) -> Result<u64, BoxError> {
+    Ok(FileReader::try_new(File::open(snapshot)?, None)?
+        .schema()
+        .metadata()
+        .get(COVERED_SEGMENT)
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0))
+}
+
+// The log is compacted once it holds more rows than the cache has keys, but never below this
+const MIN_COMPACTION_ROWS: usize = 1024;
+
+// Rows per record batch in a compacted snapshot, which is also how often throttling pauses
+const COMPACTION_BATCH_ROWS: usize = 1000;
+
+// How often changed keys are appended to the log, unless the fsync policy says otherwise
+const LOG_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// When the append log writes (and fsyncs) a segment
+#[derive(Clone, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    // Client writes are answered once they are on disk; writes waiting at the same time share
+    // one segment and one fsync
+    Always,
+    // A segment of whatever changed, every interval
+    Interval(Duration),
+    // A segment as soon as this many writes are pending, and at least every second
+    Batch(usize),
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::Interval(LOG_FLUSH_INTERVAL)
+    }
+}
+
+impl FsyncPolicy {
+    // "always", "interval_ms=200" or "batch=500"
+    pub fn parse(spec: &str) -> Result<FsyncPolicy, String> {
+        let invalid = || format!("Invalid fsync policy: {}", spec);
+        match spec.split_once('=') {
+            None if spec == "always" => Ok(FsyncPolicy::Always),
+            Some(("interval_ms", ms)) => ms.parse().map(|ms| FsyncPolicy::Interval(Duration::from_millis(ms))).map_err(|_| invalid()),
+            Some(("batch", n)) => n.parse().ok().filter(|n| *n > 0).map(FsyncPolicy::Batch).ok_or_else(invalid),
+            _ => Err(invalid()),
+        }
+    }
+
+    // Longest the log waits between segments
+    fn interval(&self) -> Duration {
+        match self {
+            FsyncPolicy::Interval(interval) => *interval,
+            _ => LOG_FLUSH_INTERVAL,
+        }
+    }
+}
+
+// Compacting the append log on a timer, on top of compacting whenever it outgrows the cache
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionSchedule {
+    pub interval: Duration,
+    // Rows the log must have gained since the last compaction for a scheduled one to run
+    pub min_rows: usize,
+    // Snapshot rows written per second, None for as fast as the disk goes
+    pub rows_per_sec: Option<u64>,
+}
+
+impl Default for CompactionSchedule {
+    fn default() -> Self {
+        CompactionSchedule { interval: Duration::from_secs(300), min_rows: 1, rows_per_sec: None }
+    }
+}
+
+impl CompactionSchedule {
+    // "on" for the defaults, or e.g. "interval_s=60,min_rows=1000,rate=50000"
+    pub fn parse(spec: &str) -> Result<CompactionSchedule, String> {
+        let mut schedule = CompactionSchedule::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty() && *s != "on") {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid compaction setting: {}", setting))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "interval_s" => schedule.interval = Duration::from_secs(value.parse().ok().filter(|s| *s > 0).ok_or_else(invalid)?),
+                "min_rows" => schedule.min_rows = value.parse().map_err(|_| invalid())?,
+                "rate" => schedule.rows_per_sec = Some(value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?),
+                _ => return Err(format!("Unknown compaction setting: {}", name)),
+            }
+        }
+        Ok(schedule)
+    }
+}
+
+// What a compaction folded into the snapshot and how much disk it freed
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Compacted {
+    pub(crate) segments: usize,
+    pub(crate) tombstones: usize,
+    pub(crate) keys: usize,
+    pub(crate) reclaimed_bytes: u64,
+}
+
+// Group commit between writers and the log task: writers say what they changed, the log task
+// says how far its segments reach
+pub(crate) struct GroupCommit {
+    policy: FsyncPolicy,
+    // Flushes that have taken the cache's changes so far, and the last one on disk
+    taken: AtomicU64,
+    durable: watch::Sender<u64>,
+    // Writes since the last flush, for FsyncPolicy::Batch
+    pending: AtomicUsize,
+    wake: Notify,
+    syncs: std::sync::Mutex<FsyncStats>,
+}
+
+#[derive(Clone, Copy, Default)]
+pub(crate) struct FsyncStats {
+    pub(crate) count: u64,
+    pub(crate) total: Duration,
+    pub(crate) max: Duration,
+}
+
+impl GroupCommit {
+    fn new(policy: FsyncPolicy) -> Self {
+        GroupCommit {
+            policy,
+            taken: AtomicU64::new(0),
+            durable: watch::Sender::new(0),
+            pending: AtomicUsize::new(0),
+            wake: Notify::new(),
+            syncs: Default::default(),
+        }
+    }
+
+    // Called after a request changed the cache. Under FsyncPolicy::Always and with `wait`,
+    // returns once a segment holding the change is on disk.
+    pub(crate) async fn committed(&self, wait: bool) {
+        match self.policy {
+            FsyncPolicy::Always if wait => {
+                // A flush taking changes now might have missed this one, so wait for the next
+                let target = self.taken.load(Ordering::SeqCst) + 1;
+                self.wake.notify_one();
+                let _ = self.durable.subscribe().wait_for(|durable| *durable >= target).await;
+            }
+            FsyncPolicy::Batch(batch) if self.pending.fetch_add(1, Ordering::Relaxed) + 1 >= batch => self.wake.notify_one(),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn fsync_stats(&self) -> FsyncStats {
+        *self.syncs.lock().unwrap()
+    }
+
+    // Number the flush taking the cache's changes now; call with the cache locked
+    fn take(&self) -> u64 {
+        self.pending.store(0, Ordering::Relaxed);
+        self.taken.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // Flush `flush` is on disk, after an fsync that took `took` (None if there was nothing to write)
+    fn synced(&self, flush: u64, took: Option<Duration>) {
+        if let Some(took) = took {
+            let mut syncs = self.syncs.lock().unwrap();
+            syncs.count += 1;
+            syncs.total += took;
+            syncs.max = syncs.max.max(took);
+        }
+        self.durable.send_if_modified(|durable| {
+            let advanced = flush > *durable;
+            *durable = (*durable).max(flush);
+            advanced
+        });
+    }
+}
+
+// Snapshot plus numbered segments of changes since, for Persistence::ArrowLog
+pub(crate) struct AppendLog {
+    snapshot: PathBuf,
+    dir: PathBuf,
+    commits: Arc<GroupCommit>,
+    clock: SharedClock,
+    // Keep compacted segments and snapshots for point-in-time recovery (see archive.rs)
+    archive: Option<LogArchive>,
+    // Last segment contained in the snapshot, and the number for the next one
+    covered: u64,
+    next: u64,
+    // Rows written to segments since the last compaction, and how many of them were deletes
+    logged: usize,
+    tombstones: usize,
+    // Set when a segment could not be written, so its changes end up in a snapshot instead
+    needs_compaction: bool,
+}
+
+impl AppendLog {
+    // Create the segment directory if needed and find where the log left off
+    pub(crate) fn open(snapshot: &Path, policy: FsyncPolicy, clock: SharedClock, archive: Option<LogArchive>) -> Result<AppendLog, BoxError> {
+        let dir = snapshot.with_extension("log");
+        std::fs::create_dir_all(&dir)?;
+        let covered = match snapshot.exists() {
+            true => snapshot_covers(snapshot)?,
+            false => 0,
+        };
+        let segments = list_segments(&dir)?;
+        let last = segments.last().map(|(n, _)| *n).unwrap_or(0).max(covered);
+        let leftover = segments.iter().any(|(n, _)| *n > covered);
+        Ok(AppendLog {
+            snapshot: snapshot.to_path_buf(),
+            dir,
+            commits: Arc::new(GroupCommit::new(policy)),
+            clock,
+            archive,
+            covered,
+            next: last + 1,
+            logged: 0,
+            tombstones: 0,
+            // Segments left over from the last run are folded in on the first flush
+            needs_compaction: leftover,
+        })
+    }
+
+    pub(crate) fn snapshot_path(&self) -> &Path {
+        &self.snapshot
+    }
+
+    pub(crate) fn commits(&self) -> Arc<GroupCommit> {
+        Arc::clone(&self.commits)
+    }
+
+    // The snapshot with every later segment applied in order
+    pub(crate) fn load(&self) -> Result<Cache, BoxError> {
+        let mut cache = match self.snapshot.exists() {
+            true => load_cache_from_arrow(&self.snapshot.to_string_lossy())?,
+            false => Cache::new(),
+        };
+        for (segment, path) in list_segments(&self.dir)?.into_iter().filter(|(n, _)| *n > self.covered) {
+            // A segment cut short by a crash is left out, along with anything after it
+            let changes = match read_segment(&path) {
+                Ok(changes) => changes,
+                Err(e) => {
+                    warn!("Stopped replaying the log at segment {}: {}", path.display(), e);
+                    break;
+                }
+            };
+            debug!("Replaying {} changes from log segment {}", changes.len(), segment);
+            for (key, value) in changes {
+                match value {
+                    Some((value, expires)) => {
+                        cache.insert(key.clone(), value);
+                        cache.set_expiry(&key, expires);
+                    }
+                    None => drop(cache.remove(&key)),
+                }
+            }
+        }
+        Ok(cache)
+    }
+
+    // The cache as it was at `target` (Unix millis) instead of now; the first flush then makes it
+    // the snapshot (see archive.rs)
+    pub(crate) fn recover(&mut self, target: u64) -> Result<Cache, BoxError> {
+        let cache = archive::recover(&self.snapshot, target)?;
+        self.needs_compaction = true;
+        Ok(cache)
+    }
+
+    // Append the keys changed since the last flush as a new segment, compacting if the log
+    // has grown past the cache; returns what it wrote, if anything
+    pub(crate) async fn flush(&mut self, cache: &SharedCache) -> Result<Option<&'static str>, BoxError> {
+        let (changes, keys, flush, written_at) = {
+            let mut cache = cache.lock().await;
+            let changes: Vec<Change> = cache
+                .take_changed()
+                .into_iter()
+                .map(|key| {
+                    let value = cache.get(&key).cloned().map(|value| (value, cache.expires_at(&key)));
+                    (key, value)
+                })
+                .collect();
+            (changes, cache.len(), self.commits.take(), self.clock.unix_millis())
+        };
+        if self.needs_compaction || self.logged + changes.len() > keys.max(MIN_COMPACTION_ROWS) {
+            // The snapshot takes in these changes as well, since they are still in the cache
+            return self.compact(cache, None).await.map(|_| Some("log_compaction"));
+        }
+        if changes.is_empty() {
+            self.commits.synced(flush, None);
+            return Ok(None);
+        }
+
+        // Numbered before writing, so a snapshot taken meanwhile counts it as covered
+        let segment = self.next;
+        self.next += 1;
+        let path = self.dir.join(format!("{:08}.arrow", segment));
+        let rows = changes.len();
+        let tombstones = changes.iter().filter(|(_, value)| value.is_none()).count();
+        let written = tokio::task::spawn_blocking(move || write_segment(&path, &changes, written_at)).await?;
+        let took = match written {
+            Ok(took) => took,
+            Err(e) => {
+                self.needs_compaction = true;
+                return Err(e);
+            }
+        };
+        self.commits.synced(flush, Some(took));
+        self.logged += rows;
+        self.tombstones += tombstones;
+        debug!("Appended {} changes to log segment {}", rows, segment);
+        Ok(Some("log_append"))
+    }
+
+    // Rows appended since the last compaction
+    pub(crate) fn logged(&self) -> usize {
+        self.logged
+    }
+
+    // Write a full snapshot containing every segment so far, at most `rows_per_sec` rows a second,
+    // then delete those segments and any temporary files a crash left behind
+    pub(crate) async fn compact(&mut self, cache: &SharedCache, rows_per_sec: Option<u64>) -> Result<Compacted, BoxError> {
+        let (entries, flush, written_at) = {
+            let mut cache = cache.lock().await;
+            // The snapshot holds these changes, so they need no segment
+            cache.take_changed();
+            (snapshot_entries(&cache), self.commits.take(), self.clock.unix_millis())
+        };
+        let covered = self.next - 1;
+
+        let (snapshot, dir, archive) = (self.snapshot.clone(), self.dir.clone(), self.archive.clone());
+        let keys = entries.len();
+        let compacted = tokio::task::spawn_blocking(move || -> Result<(Duration, usize, u64), BoxError> {
+            let metadata = HashMap::from([
+                (COVERED_SEGMENT.to_string(), covered.to_string()),
+                (WRITTEN_AT.to_string(), written_at.to_string()),
+            ]);
+            let schema = Arc::new(snapshot_schema().with_metadata(metadata));
+            let before = file_size(&snapshot);
+
+            // Replace the snapshot in one step, so a crash leaves either the old or the new one
+            let tmp = snapshot.with_extension("arrow.tmp");
+            let mut writer = FileWriter::try_new(File::create(&tmp)?, &schema)?;
+            for chunk in entries.chunks(COMPACTION_BATCH_ROWS) {
+                writer.write(&entries_to_record_batch(chunk)?.with_schema(Arc::clone(&schema))?)?;
+                if let Some(rate) = rows_per_sec {
+                    std::thread::sleep(Duration::from_secs_f64(chunk.len() as f64 / rate as f64));
+                }
+            }
+            writer.finish()?;
+            let took = sync_file(writer.into_inner()?)?;
+            std::fs::rename(&tmp, &snapshot)?;
+
+            let mut freed = before;
+            let compacted: Vec<(u64, PathBuf)> = list_segments(&dir)?.into_iter().filter(|(n, _)| *n <= covered).collect();
+            let segments = compacted.len();
+            match &archive {
+                // Kept for recovery instead, so nothing is freed but the old snapshot
+                Some(archive) => {
+                    archive::store(&snapshot, covered, written_at, &compacted)?;
+                    let pruned = archive::prune(&snapshot, archive.keep, written_at)?;
+                    if pruned > 0 {
+                        debug!("Pruned {} archived files older than {:?}", pruned, archive.keep);
+                    }
+                }
+                None => {
+                    for (_, path) in compacted {
+                        freed += file_size(&path);
+                        std::fs::remove_file(path)?;
+                    }
+                }
+            }
+            // A segment cut short by a crash never got renamed into place
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.to_string_lossy().ends_with(".arrow.tmp") {
+                    freed += file_size(&path);
+                    std::fs::remove_file(path)?;
+                }
+            }
+            Ok((took, segments, freed.saturating_sub(file_size(&snapshot))))
+        })
+        .await?;
+        let (segments, reclaimed_bytes) = match compacted {
+            Ok((took, segments, reclaimed)) => {
+                self.commits.synced(flush, Some(took));
+                (segments, reclaimed)
+            }
+            Err(e) => {
+                self.needs_compaction = true;
+                return Err(e);
+            }
+        };
+
+        let compacted = Compacted { segments, tombstones: self.tombstones, keys, reclaimed_bytes };
+        self.covered = covered;
+        self.logged = 0;
+        self.tombstones = 0;
+        self.needs_compaction = false;
+        info!(
+            "Compacted {} log segments into {} ({} keys, {} tombstones dropped, {} bytes reclaimed)",
+            segments,
+            self.snapshot.display(),
+            keys,
+            compacted.tombstones,
+            reclaimed_bytes
+        );
+        Ok(compacted)
+    }
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+// Segment files in `dir` by number, oldest first
+pub(crate) fn list_segments(dir: &Path) -> std::io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "arrow") {
+            if let Some(n) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                segments.push((n, path));
+            }
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+// fsync `file`, returning how long it took
+fn sync_file(file: File) -> std::io::Result<Duration> {
+    let started = std::time::Instant::now();
+    file.sync_all()?;
+    Ok(started.elapsed())
+}
+
+// Written to a temporary file first, so a segment is either complete or missing; returns how
+// long the fsync took
+fn write_segment(path: &Path, changes: &[Change], written_at: u64) -> Result<Duration, BoxError> {
+    let record_batch = changes_to_record_batch(changes)?;
+    let schema = Arc::new(record_batch.schema().as_ref().clone().with_metadata(HashMap::from([(WRITTEN_AT.to_string(), written_at.to_string())])));
+    let record_batch = record_batch.with_schema(schema)?;
+    let tmp = path.with_extension("arrow.tmp");
+    let mut writer = FileWriter::try_new(File::create(&tmp)?, &record_batch.schema())?;
+    writer.write(&record_batch)?;
+    writer.finish()?;
+    let took = sync_file(writer.into_inner()?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(took)
+}
+
+pub(crate) fn read_segment(path: &Path) -> Result<Vec<Change>, BoxError> {
+    let reader = FileReader::try_new(File::open(path)?, None)?;
+    let mut changes = Vec::new();
+    for batch in reader {
+        let batch = batch?;
+        let column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .and_then(|c| c.as_any().downcast_ref::<StringArray>().cloned())
+                .ok_or_else(|| format!("missing {} column", name))
+        };
+        let (keys, values, types) = (column("key")?, column("value")?, column("type")?);
+        for (i, expires) in batch_expiries(&batch).into_iter().enumerate() {
+            let value = match values.is_null(i) {
+                true => None,
+                false => Some((CacheValue::parse(types.value(i), values.value(i))?, expires)),
+            };
+            changes.push((keys.value(i).to_string(), value));
+        }
+    }
+    Ok(changes)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileFormat {
+    Csv,
+    Jsonl,
+    Arrow,
+    Parquet,
+}
+
+impl FileFormat {
+    pub(crate) fn parse(name: &str) -> Option<FileFormat> {
+        match name {
+            "csv" => Some(FileFormat::Csv),
+            "jsonl" | "json" => Some(FileFormat::Jsonl),
+            "arrow" | "ipc" => Some(FileFormat::Arrow),
+            "parquet" => Some(FileFormat::Parquet),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn from_extension(path: &str) -> Option<FileFormat> {
+        FileFormat::parse(std::path::Path::new(path).extension()?.to_str()?)
+    }
+}
+
+pub struct ImportOptions {
+    pub(crate) path: String,
+    pub(crate) format: FileFormat,
+    pub(crate) batch_size: usize,
+    pub(crate) throttle: tokio::time::Duration,
+    pub(crate) replicate: bool,
+}
+
+// Parse `<path> [--format csv|jsonl|arrow] [--batch-size n] [--throttle-ms n] [--no-replicate]`;
+// a path of `-` is an Arrow IPC stream sent after the command on the same connection
+pub(crate) fn parse_import_args(args: &str) -> Result<ImportOptions, String> {
+    let mut words = args.split_whitespace();
+    let path = words.next().ok_or("missing path")?.to_string();
+    let mut format = match path.as_str() {
+        "-" => Some(FileFormat::Arrow),
+        path => FileFormat::from_extension(path),
+    };
+    let mut options = ImportOptions {
+        path,
+        format: FileFormat::Csv,
+        batch_size: 1000,
+        throttle: tokio::time::Duration::from_millis(10),
+        replicate: true,
+    };
+    while let Some(flag) = words.next() {
+        match flag {
+            "--format" => format = Some(words.next().and_then(FileFormat::parse).ok_or("invalid --format")?),
+            "--batch-size" => {
+                options.batch_size = words
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or("invalid --batch-size")?
+            }
+            "--throttle-ms" => {
+                options.throttle = tokio::time::Duration::from_millis(
+                    words.next().and_then(|n| n.parse().ok()).ok_or("invalid --throttle-ms")?,
+                )
+            }
+            "--no-replicate" => options.replicate = false,
+            other => return Err(format!("unknown option {}", other)),
+        }
+    }
+    options.format = format.ok_or("cannot infer format, use --format csv|jsonl|arrow|parquet")?;
+    if options.path == "-" && options.format != FileFormat::Arrow {
+        return Err("IMPORT - reads an Arrow IPC stream".to_string());
+    }
+    Ok(options)
+}
+
+// Open an import file as an iterator over batches of key/value pairs
+pub(crate) fn read_import_batches(options: &ImportOptions) -> Result<Box<dyn Iterator<Item = ImportBatch> + Send>, BoxError> {
+    let file = File::open(&options.path)?;
+    match options.format {
+        FileFormat::Arrow => {
+            let reader = FileReader::try_new(file, None)?;
+            Ok(Box::new(reader.map(|batch| batch_to_pairs(&batch?))))
+        }
+        FileFormat::Parquet => {
+            let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(file)?
+                .with_batch_size(options.batch_size)
+                .build()?;
+            Ok(Box::new(reader.map(|batch| batch_to_pairs(&batch?))))
+        }
+        FileFormat::Csv => {
+            // Header row names the key/value/type columns; read every column as a string
+            let (inferred, _) = arrow::csv::reader::Format::default()
+                .with_header(true)
+                .infer_schema(File::open(&options.path)?, Some(1))?;
+            let schema = Schema::new(
+                inferred
+                    .fields()
+                    .iter()
+                    .map(|f| Field::new(f.name(), DataType::Utf8, true))
+                    .collect::<Vec<_>>(),
+            );
+            let reader = arrow::csv::ReaderBuilder::new(Arc::new(schema))
+                .with_header(true)
+                .with_batch_size(options.batch_size)
+                .build(file)?;
+            Ok(Box::new(reader.map(|batch| batch_to_pairs(&batch?))))
+        }
+        FileFormat::Jsonl => {
+            // One {"key": ..., "value": ..., "type": ...} object per line; non-string values are stored as JSON
+            let mut lines = std::io::BufReader::new(file).lines();
+            let batch_size = options.batch_size;
+            Ok(Box::new(std::iter::from_fn(move || {
+                let mut pairs = Vec::new();
+                for line in lines.by_ref() {
+                    let line = match line {
+                        Ok(line) if line.trim().is_empty() => continue,
+                        Ok(line) => line,
+                        Err(e) => return Some(Err(e.into())),
+                    };
+                    let record: Value = match serde_json::from_str(&line) {
+                        Ok(record) => record,
+                        Err(e) => return Some(Err(e.into())),
+                    };
+                    let (Some(key), Some(value)) = (record.get("key").and_then(Value::as_str), record.get("value")) else {
+                        return Some(Err(format!("line without key/value: {}", line).into()));
+                    };
+                    let raw = value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string());
+                    let type_name = record.get("type").and_then(Value::as_str).unwrap_or("string");
+                    match CacheValue::parse(type_name, &raw) {
+                        Ok(value) => pairs.push((key.to_string(), value)),
+                        Err(e) => return Some(Err(e.into())),
+                    }
+                    if pairs.len() == batch_size {
+                        break;
+                    }
+                }
+                (!pairs.is_empty()).then_some(Ok(pairs))
+            })))
+        }
+    }
+}
+
+pub struct ExportOptions {
+    pub(crate) path: String,
+    pub(crate) format: FileFormat,
+    pub(crate) prefix: String,
+}
+
+// Parse `<path> [--format csv|jsonl|arrow|parquet] [--prefix p]` (format defaults to the extension, then arrow);
+// path `-` streams to the client instead
+pub(crate) fn parse_export_args(args: &str) -> Result<ExportOptions, String> {
+    let mut words = args.split_whitespace();
+    let path = words.next().ok_or("missing path")?.to_string();
+    let mut options = ExportOptions {
+        format: FileFormat::from_extension(&path).unwrap_or(FileFormat::Arrow),
+        path,
+        prefix: String::new(),
+    };
+    while let Some(flag) = words.next() {
+        match flag {
+            "--format" => options.format = words.next().and_then(FileFormat::parse).ok_or("invalid --format")?,
+            "--prefix" => options.prefix = words.next().ok_or("missing --prefix value")?.to_string(),
+            other => return Err(format!("unknown option {}", other)),
+        }
+    }
+    if options.path == "-" && options.format != FileFormat::Arrow {
+        return Err("EXPORT - only streams arrow".into());
+    }
+    Ok(options)
+}
+
+// Write the selected pairs to a file in the requested format
+pub(crate) fn export_pairs(options: &ExportOptions, pairs: &[(&String, &CacheValue)]) -> Result<(), BoxError> {
+    let file = File::create(&options.path)?;
+    match options.format {
+        FileFormat::Arrow => {
+            let batch = pairs_to_record_batch(pairs)?;
+            let mut writer = FileWriter::try_new(file, &batch.schema())?;
+            writer.write(&batch)?;
+            writer.finish()?;
+        }
+        FileFormat::Parquet => {
+            let batch = pairs_to_record_batch(pairs)?;
+            let mut writer = parquet::arrow::ArrowWriter::try_new(file, batch.schema(), None)?;
+            writer.write(&batch)?;
+            writer.close()?;
+        }
+        FileFormat::Csv => {
+            let batch = pairs_to_record_batch(pairs)?;
+            let mut writer = arrow::csv::WriterBuilder::new().with_header(true).build(file);
+            writer.write(&batch)?;
+        }
+        FileFormat::Jsonl => {
+            // Same shape IMPORT reads back
+            let mut writer = std::io::BufWriter::new(file);
+            for (key, value) in pairs {
+                let record = serde_json::json!({ "key": key, "value": value.to_string(), "type": value.type_name() });
+                std::io::Write::write_all(&mut writer, format!("{}\n", record).as_bytes())?;
+            }
+            std::io::Write::flush(&mut writer)?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn save_cache_periodically(cache: SharedCache, file_path: String, latencies: Arc<Latencies>) {
+    loop {
+        if let Err(e) = latencies.time("snapshot_write", write_cache_to_arrow(Arc::clone(&cache), &file_path)).await {
+            error!("Failed to save cache to Arrow file: {}", e);
+        } else {
+            debug!("Cache saved to Arrow file: {}", file_path);
+        }
+
+        // Sleep for 10 seconds before saving again
+        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+    }
+}
+
+// Append changes to the log as its fsync policy asks, compacting it as it grows
+pub(crate) async fn save_cache_incrementally(cache: SharedCache, log: Arc<tokio::sync::Mutex<AppendLog>>, latencies: Arc<Latencies>) {
+    let commits = log.lock().await.commits();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(commits.policy.interval()) => {}
+            _ = commits.wake.notified() => {}
+        }
+        let mut log = log.lock().await;
+        let started = std::time::Instant::now();
+        match log.flush(&cache).await {
+            Ok(Some(op)) => latencies.record(op, started.elapsed()),
+            Ok(None) => {}
+            Err(e) => error!("Failed to append cache changes to the log: {}", e),
+        }
+    }
+}
+
+// Compact the log every `schedule.interval` once it has gained `schedule.min_rows` rows
+pub(crate) async fn compact_periodically(
+    cache: SharedCache,
+    log: Arc<tokio::sync::Mutex<AppendLog>>,
+    schedule: CompactionSchedule,
+    latencies: Arc<Latencies>,
+) {
+    loop {
+        tokio::time::sleep(schedule.interval).await;
+        let mut log = log.lock().await;
+        if log.logged() < schedule.min_rows.max(1) {
+            continue;
+        }
+        if let Err(e) = latencies.time("log_compaction", log.compact(&cache, schedule.rows_per_sec)).await {
+            error!("Scheduled compaction of the log failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimClock;
+
+    #[test]
+    fn parses_fsync_policies() {
+        assert_eq!(FsyncPolicy::parse("always"), Ok(FsyncPolicy::Always));
+        assert_eq!(FsyncPolicy::parse("interval_ms=200"), Ok(FsyncPolicy::Interval(Duration::from_millis(200))));
+        assert_eq!(FsyncPolicy::parse("batch=500"), Ok(FsyncPolicy::Batch(500)));
+        for spec in ["batch=0", "interval_ms=soon", "always=1", "never", ""] {
+            assert_eq!(FsyncPolicy::parse(spec), Err(format!("Invalid fsync policy: {}", spec)));
+        }
+    }
+
+    #[test]
+    fn parses_compaction_schedules() {
+        assert_eq!(CompactionSchedule::parse("on"), Ok(CompactionSchedule::default()));
+        assert_eq!(
+            CompactionSchedule::parse("interval_s=60, min_rows=1000,rate=50000"),
+            Ok(CompactionSchedule { interval: Duration::from_secs(60), min_rows: 1000, rows_per_sec: Some(50000) })
+        );
+        assert_eq!(CompactionSchedule::parse("interval_s=0"), Err("Invalid value for interval_s: 0".to_string()));
+        assert_eq!(CompactionSchedule::parse("rate=0"), Err("Invalid value for rate: 0".to_string()));
+        assert_eq!(CompactionSchedule::parse("min_rows"), Err("Invalid compaction setting: min_rows".to_string()));
+        assert_eq!(CompactionSchedule::parse("every=5"), Err("Unknown compaction setting: every".to_string()));
+    }
+
+    const EPOCH: u64 = 1_735_689_600_000;
+
+    // A log for a snapshot in a fresh temp dir, on a SimClock, with the cache it persists
+    fn log(archive: Option<LogArchive>) -> (AppendLog, SharedCache, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("p2p-rust-log-{}-{}", std::process::id(), rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let snapshot = dir.join("cache.arrow");
+        let log = AppendLog::open(&snapshot, FsyncPolicy::default(), Arc::new(SimClock::new(EPOCH)), archive).unwrap();
+        let mut cache = Cache::new();
+        cache.set_clock(Arc::new(SimClock::new(EPOCH)));
+        cache.track_changes();
+        (log, Arc::new(tokio::sync::Mutex::new(cache)), dir)
+    }
+
+    fn reopen(dir: &Path) -> Cache {
+        AppendLog::open(&dir.join("cache.arrow"), FsyncPolicy::default(), Arc::new(SimClock::new(EPOCH)), None)
+            .unwrap()
+            .load()
+            .unwrap()
+    }
+
+    fn keys(cache: &Cache) -> Vec<(String, String)> {
+        let mut keys: Vec<_> = cache.iter().map(|(key, value)| (key.clone(), value.to_string())).collect();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_log_replays_changes_and_deletes_after_a_restart() {
+        let (mut log, cache, dir) = log(None);
+        {
+            let mut cache = cache.lock().await;
+            cache.insert("a".to_string(), CacheValue::Int(1));
+            cache.insert("b".to_string(), CacheValue::Str("x".to_string()));
+        }
+        assert_eq!(log.flush(&cache).await.unwrap(), Some("log_append"));
+        {
+            let mut cache = cache.lock().await;
+            cache.remove("a");
+            cache.list_push("jobs", "job1".to_string(), false).unwrap();
+        }
+        assert_eq!(log.flush(&cache).await.unwrap(), Some("log_append"));
+        assert_eq!(log.flush(&cache).await.unwrap(), None);
+        assert_eq!(log.logged(), 4);
+
+        let restored = reopen(&dir);
+        assert_eq!(keys(&restored), keys(&*cache.lock().await));
+        assert_eq!(restored.get("jobs"), Some(&CacheValue::List(["job1".to_string()].into())));
+
+        // Compacting folds the segments into the snapshot, which restores the same cache
+        let compacted = log.compact(&cache, None).await.unwrap();
+        assert_eq!((compacted.segments, compacted.tombstones, compacted.keys), (2, 1, 2));
+        assert!(list_segments(&dir.join("cache.log")).unwrap().is_empty());
+        assert_eq!(keys(&reopen(&dir)), keys(&*cache.lock().await));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_torn_segment_ends_the_replay() {
+        let (mut log, cache, dir) = log(None);
+        cache.lock().await.insert("a".to_string(), CacheValue::Int(1));
+        log.flush(&cache).await.unwrap();
+        std::fs::write(dir.join("cache.log").join(format!("{:08}.arrow", 2)), b"ARROW1 cut short").unwrap();
+
+        assert_eq!(keys(&reopen(&dir)), [("a".to_string(), "1".to_string())]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expiries_survive_the_log_and_the_snapshot() {
+        let (mut log, cache, dir) = log(None);
+        {
+            let mut cache = cache.lock().await;
+            cache.insert("session".to_string(), CacheValue::Str("token".to_string()));
+            cache.insert("plain".to_string(), CacheValue::Str("v".to_string()));
+            cache.set_expiry("session", Some(EPOCH + 60_000));
+        }
+        log.flush(&cache).await.unwrap();
+        assert_eq!(reopen(&dir).expires_at("session"), Some(EPOCH + 60_000));
+
+        log.compact(&cache, None).await.unwrap();
+        let restored = reopen(&dir);
+        assert_eq!(restored.expires_at("session"), Some(EPOCH + 60_000));
+        assert_eq!(restored.expires_at("plain"), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recovers_the_cache_as_it_was_at_a_time() {
+        let (mut log, cache, dir) = log(Some(LogArchive::default()));
+        let set = |value: i64| {
+            let cache = Arc::clone(&cache);
+            async move { cache.lock().await.insert("a".to_string(), CacheValue::Int(value)) }
+        };
+        set(1).await;
+        log.flush(&cache).await.unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        set(2).await;
+        log.compact(&cache, None).await.unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        set(3).await;
+        log.flush(&cache).await.unwrap();
+
+        let at = |seconds: u64| EPOCH + seconds * 1000;
+        assert_eq!(log.recover(at(5)).unwrap().get("a"), Some(&CacheValue::Int(1)));
+        assert_eq!(log.recover(at(15)).unwrap().get("a"), Some(&CacheValue::Int(2)));
+        assert_eq!(log.recover(at(25)).unwrap().get("a"), Some(&CacheValue::Int(3)));
+        // The recovered cache becomes the snapshot on the next flush
+        let recovered = Arc::new(tokio::sync::Mutex::new(log.recover(at(5)).unwrap()));
+        assert_eq!(log.flush(&recovered).await.unwrap(), Some("log_compaction"));
+        assert_eq!(reopen(&dir).get("a"), Some(&CacheValue::Int(1)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recovering_to_before_anything_retained_fails() {
+        let (mut log, cache, dir) = log(Some(LogArchive::default()));
+        tokio::time::advance(Duration::from_secs(10)).await;
+        cache.lock().await.insert("a".to_string(), CacheValue::Int(1));
+        log.compact(&cache, None).await.unwrap();
+        assert!(log.recover(EPOCH).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_a_key_as_it_was_at_a_time() {
+        let (mut log, cache, dir) = log(Some(LogArchive::default()));
+        {
+            let mut cache = cache.lock().await;
+            cache.insert("a".to_string(), CacheValue::Int(1));
+            cache.insert("session".to_string(), CacheValue::Str("token".to_string()));
+            cache.set_expiry("session", Some(EPOCH + 15_000));
+        }
+        log.flush(&cache).await.unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        cache.lock().await.remove("a");
+        log.compact(&cache, None).await.unwrap();
+
+        let snapshot = log.snapshot_path().to_path_buf();
+        let at = |seconds: u64| EPOCH + seconds * 1000;
+        assert_eq!(archive::value_as_of(&snapshot, "a", at(5)).unwrap(), Some(CacheValue::Int(1)));
+        assert_eq!(archive::value_as_of(&snapshot, "a", at(20)).unwrap(), None);
+        assert_eq!(archive::value_as_of(&snapshot, "session", at(5)).unwrap(), Some(CacheValue::Str("token".to_string())));
+        // Gone once it expired, though no write removed it
+        assert_eq!(archive::value_as_of(&snapshot, "session", at(15)).unwrap(), None);
+        assert_eq!(archive::value_as_of(&snapshot, "missing", at(5)).unwrap(), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn compacting_again_before_a_segment_keeps_both_snapshots() {
+        let (mut log, cache, dir) = log(Some(LogArchive::default()));
+        cache.lock().await.insert("a".to_string(), CacheValue::Int(1));
+        log.compact(&cache, None).await.unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        cache.lock().await.insert("a".to_string(), CacheValue::Int(2));
+        log.compact(&cache, None).await.unwrap();
+
+        let at = |seconds: u64| EPOCH + seconds * 1000;
+        assert_eq!(log.recover(at(5)).unwrap().get("a"), Some(&CacheValue::Int(1)));
+        assert_eq!(log.recover(at(10)).unwrap().get("a"), Some(&CacheValue::Int(2)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn aggregates_see_the_cache_of_a_time_and_each_retained_snapshot() {
+        let (mut log, cache, dir) = log(Some(LogArchive::default()));
+        {
+            let mut cache = cache.lock().await;
+            cache.insert("a".to_string(), CacheValue::Int(1));
+            cache.insert("session".to_string(), CacheValue::Int(2));
+            cache.set_expiry("session", Some(EPOCH + 5_000));
+        }
+        log.compact(&cache, None).await.unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        cache.lock().await.insert("b".to_string(), CacheValue::Int(3));
+        log.compact(&cache, None).await.unwrap();
+
+        let snapshot = log.snapshot_path().to_path_buf();
+        let at = |seconds: u64| EPOCH + seconds * 1000;
+        assert_eq!(keys(&archive::cache_as_of(&snapshot, at(1)).unwrap()).len(), 2);
+        // Expired keys are left out, as a live read would
+        let later = keys(&archive::cache_as_of(&snapshot, at(10)).unwrap());
+        assert_eq!(later, [("a".to_string(), "1".to_string()), ("b".to_string(), "3".to_string())]);
+
+        let generations = archive::generations(&snapshot, EPOCH).unwrap();
+        let written: Vec<u64> = generations.iter().map(|(at, _)| *at).collect();
+        assert_eq!(written, [at(0), at(10)]);
+        assert_eq!(archive::load_generation(&generations[0].1, at(0)).unwrap().len(), 2);
+        assert_eq!(archive::generations(&snapshot, at(1)).unwrap().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+//! Typed cache values and their string encodings
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+
+// A typed cache value; the type tag travels with SET/BROADCAST (`TYPE=int`) and snapshots
+#[derive(Clone, Debug, PartialEq)]
+pub enum CacheValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bytes(Vec<u8>),
+    List(VecDeque<String>),
+    Hash(BTreeMap<String, String>),
+    Stream(EventStream),
+    SortedSet(SortedSet),
+}
+
+// Append-only log with monotonically increasing entry IDs and committed consumer offsets
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EventStream {
+    pub last_id: u64,
+    pub entries: BTreeMap<u64, String>,
+    pub consumers: BTreeMap<String, u64>,
+}
+
+// Approximate bytes a string takes inside a collection: its header plus its contents
+pub(crate) fn string_size(s: &str) -> usize {
+    std::mem::size_of::<String>() + s.len()
+}
+
+// Approximate bytes of one element of each collection type, as counted by memory_usage
+pub(crate) fn hash_field_size(field: &str, value: &str) -> usize {
+    string_size(field) + string_size(value)
+}
+
+pub(crate) fn stream_entry_size(payload: &str) -> usize {
+    std::mem::size_of::<u64>() + string_size(payload)
+}
+
+pub(crate) fn zset_member_size(member: &str) -> usize {
+    // Kept in both the score map and the ordered set
+    2 * (string_size(member) + std::mem::size_of::<f64>())
+}
+
+impl CacheValue {
+    // Approximate bytes the value takes, inline and on the heap; allocator and collection
+    // node overhead is not counted
+    pub fn memory_usage(&self) -> usize {
+        let heap = match self {
+            CacheValue::Str(s) => s.len(),
+            CacheValue::Int(_) | CacheValue::Float(_) => 0,
+            CacheValue::Bytes(bytes) => bytes.len(),
+            CacheValue::List(list) => list.iter().map(|item| string_size(item)).sum(),
+            CacheValue::Hash(hash) => hash.iter().map(|(field, value)| hash_field_size(field, value)).sum(),
+            CacheValue::Stream(stream) => {
+                stream.entries.values().map(|payload| stream_entry_size(payload)).sum::<usize>()
+                    + stream.consumers.keys().map(|consumer| string_size(consumer) + std::mem::size_of::<u64>()).sum::<usize>()
+            }
+            CacheValue::SortedSet(zset) => zset.scores.keys().map(|member| zset_member_size(member)).sum(),
+        };
+        std::mem::size_of::<CacheValue>() + heap
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            CacheValue::Str(_) => "string",
+            CacheValue::Int(_) => "int",
+            CacheValue::Float(_) => "float",
+            CacheValue::Bytes(_) => "bytes",
+            CacheValue::List(_) => "list",
+            CacheValue::Hash(_) => "hash",
+            CacheValue::Stream(_) => "stream",
+            CacheValue::SortedSet(_) => "zset",
+        }
+    }
+
+    // Parse the wire representation of a value of the given type (bytes are hex encoded)
+    pub fn parse(type_name: &str, raw: &str) -> Result<CacheValue, String> {
+        match type_name {
+            "string" => Ok(CacheValue::Str(raw.to_string())),
+            "int" => raw
+                .parse()
+                .map(CacheValue::Int)
+                .map_err(|_| format!("Invalid int value: {}", raw)),
+            "float" => raw
+                .parse()
+                .map(CacheValue::Float)
+                .map_err(|_| format!("Invalid float value: {}", raw)),
+            "bytes" => decode_hex(raw)
+                .map(CacheValue::Bytes)
+                .ok_or_else(|| format!("Invalid hex bytes value: {}", raw)),
+            "list" => serde_json::from_str(raw)
+                .map(CacheValue::List)
+                .map_err(|_| format!("Invalid list value: {}", raw)),
+            "hash" => serde_json::from_str(raw)
+                .map(CacheValue::Hash)
+                .map_err(|_| format!("Invalid hash value: {}", raw)),
+            "stream" => serde_json::from_str(raw)
+                .map(CacheValue::Stream)
+                .map_err(|_| format!("Invalid stream value: {}", raw)),
+            "zset" => serde_json::from_str(raw)
+                .map(CacheValue::SortedSet)
+                .map_err(|_| format!("Invalid zset value: {}", raw)),
+            other => Err(format!("Unknown type: {}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for CacheValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheValue::Str(s) => write!(f, "{}", s),
+            CacheValue::Int(i) => write!(f, "{}", i),
+            CacheValue::Float(x) => write!(f, "{}", x),
+            CacheValue::Bytes(bytes) => write!(f, "{}", encode_hex(bytes)),
+            CacheValue::List(list) => write!(f, "{}", serde_json::to_string(list).map_err(|_| std::fmt::Error)?),
+            CacheValue::Hash(hash) => write!(f, "{}", serde_json::to_string(hash).map_err(|_| std::fmt::Error)?),
+            CacheValue::Stream(stream) => write!(f, "{}", serde_json::to_string(stream).map_err(|_| std::fmt::Error)?),
+            CacheValue::SortedSet(zset) => write!(f, "{}", serde_json::to_string(zset).map_err(|_| std::fmt::Error)?),
+        }
+    }
+}
+
+// f64 wrapper with a total order so scores can key a BTreeSet
+#[derive(Clone, Copy, Debug)]
+pub struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// Members ordered by score (ties broken by member), serialized as a member -> score map
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "BTreeMap<String, f64>", into = "BTreeMap<String, f64>")]
+pub struct SortedSet {
+    pub(crate) scores: HashMap<String, f64>,
+    pub(crate) ordered: BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    // Returns whether the member is new
+    pub(crate) fn insert(&mut self, member: String, score: f64) -> bool {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(Score(previous), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        previous.is_none()
+    }
+
+    pub(crate) fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.ordered.remove(&(Score(score), member.to_string())),
+            None => false,
+        }
+    }
+
+    pub(crate) fn range_by_score(&self, min: f64, max: f64) -> impl Iterator<Item = (&String, f64)> {
+        self.ordered
+            .iter()
+            .skip_while(move |(score, _)| score.0 < min)
+            .take_while(move |(score, _)| score.0 <= max)
+            .map(|(score, member)| (member, score.0))
+    }
+}
+
+impl From<BTreeMap<String, f64>> for SortedSet {
+    fn from(members: BTreeMap<String, f64>) -> Self {
+        let mut zset = SortedSet::default();
+        for (member, score) in members {
+            zset.insert(member, score);
+        }
+        zset
+    }
+}
+
+impl From<SortedSet> for BTreeMap<String, f64> {
+    fn from(zset: SortedSet) -> Self {
+        zset.scores.into_iter().collect()
+    }
+}
+
+// Parse a score bound, accepting -inf/+inf
+pub(crate) fn parse_score(raw: &str) -> Option<f64> {
+    match raw {
+        "-inf" => Some(f64::NEG_INFINITY),
+        "+inf" | "inf" => Some(f64::INFINITY),
+        other => other.parse().ok().filter(|score: &f64| !score.is_nan()),
+    }
+}
+
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn decode_hex(raw: &str) -> Option<Vec<u8>> {
+    if !raw.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..raw.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(raw.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub(crate) fn wrong_type(key: &str, value: &CacheValue, command: &str) -> String {
+    format!("Wrong type: {} holds a value of type {}, {} not supported", key, value.type_name(), command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip_through_their_encoding() {
+        let mut stream = EventStream::default();
+        stream.entries.insert(1, "{\"a\":1}".to_string());
+        stream.consumers.insert("billing".to_string(), 1);
+        let values = [
+            CacheValue::Str("a b=c".to_string()),
+            CacheValue::Int(-7),
+            CacheValue::Float(0.25),
+            CacheValue::Bytes(vec![0, 0xca, 0xfe]),
+            CacheValue::List(VecDeque::from(["x".to_string(), "y".to_string()])),
+            CacheValue::Hash(BTreeMap::from([("name".to_string(), "Alice".to_string())])),
+            CacheValue::Stream(stream),
+            CacheValue::SortedSet(SortedSet::from(BTreeMap::from([("alice".to_string(), 42.0)]))),
+        ];
+        for value in values {
+            assert_eq!(CacheValue::parse(value.type_name(), &value.to_string()), Ok(value));
+        }
+    }
+
+    #[test]
+    fn rejects_values_that_do_not_match_their_type() {
+        assert_eq!(CacheValue::parse("int", "1.5"), Err("Invalid int value: 1.5".to_string()));
+        assert_eq!(CacheValue::parse("bytes", "abc"), Err("Invalid hex bytes value: abc".to_string()));
+        assert_eq!(CacheValue::parse("bytes", "zz"), Err("Invalid hex bytes value: zz".to_string()));
+        assert_eq!(CacheValue::parse("list", "x"), Err("Invalid list value: x".to_string()));
+        assert_eq!(CacheValue::parse("uuid", "x"), Err("Unknown type: uuid".to_string()));
+    }
+}
@@ -0,0 +1,109 @@
+//! Public address detection over STUN (RFC 5389 Binding requests), for nodes behind NAT.
+//!
+//! The node asks a STUN server which address its UDP packets arrive from and advertises that
+//! IP with its own TCP port (see NodeBuilder::advertise), so the NAT has to forward that port.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+use log::{debug, info};
+
+// Used by P2P_STUN=on
+pub const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+// Requests sent before giving up, each waiting this long for the answer (it's UDP)
+const STUN_ATTEMPTS: u32 = 3;
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The address `server` sees this host's packets coming from
+pub async fn public_address(server: &str) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    socket.connect(server).await?;
+    let transaction: [u8; 12] = rand::random();
+
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction);
+
+    let mut buf = [0u8; 512];
+    for attempt in 1..=STUN_ATTEMPTS {
+        socket.send(&request).await?;
+        match timeout(STUN_TIMEOUT, socket.recv(&mut buf)).await {
+            Ok(received) => {
+                let len = received?;
+                if let Some(addr) = parse_response(&buf[..len], &transaction) {
+                    info!("STUN server {} sees this node as {}", server, addr);
+                    return Ok(addr);
+                }
+                debug!("Ignoring an unexpected STUN answer from {}", server);
+            }
+            Err(_) => debug!("STUN request {} to {} timed out", attempt, server),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, format!("no STUN answer from {}", server)))
+}
+
+// The mapped address in a Binding success response to `transaction`
+fn parse_response(response: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    let header = response.get(..20)?;
+    if u16::from_be_bytes([header[0], header[1]]) != BINDING_SUCCESS
+        || header[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || header[8..20] != transaction[..]
+    {
+        return None;
+    }
+
+    let mut mapped = None;
+    let mut attributes = &response[20..];
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + len)?;
+        match kind {
+            // Preferred; NATs that rewrite addresses inside packets leave it alone
+            XOR_MAPPED_ADDRESS => return decode_address(value, Some(transaction)),
+            MAPPED_ADDRESS => mapped = decode_address(value, None),
+            _ => {}
+        }
+        // Values are padded to four bytes
+        attributes = attributes.get(4 + len.div_ceil(4) * 4..).unwrap_or_default();
+    }
+    mapped
+}
+
+// A (XOR-)MAPPED-ADDRESS value: reserved byte, family, port, address
+fn decode_address(value: &[u8], xor: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let mut port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    if xor.is_some() {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+    let ip = match value[1] {
+        0x01 => {
+            let mut octets: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            if xor.is_some() {
+                octets.iter_mut().zip(cookie).for_each(|(octet, key)| *octet ^= key);
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            if let Some(transaction) = xor {
+                let key = cookie.iter().chain(transaction.iter());
+                octets.iter_mut().zip(key).for_each(|(octet, key)| *octet ^= key);
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
@@ -0,0 +1,192 @@
+//! Test support: an in-process cluster of nodes on ephemeral ports.
+//!
+//! Nodes are wired into a full mesh up front instead of waiting for UDP
+//! broadcast discovery, so replication can be exercised as soon as the
+//! cluster is up. [`TestCluster::start_simulated`] runs the same cluster on
+//! the in-memory network and virtual clock from `sim`. Enabled for
+//! `cfg(test)` and the `test-support` feature.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{Duration, Instant};
+
+use crate::clock::{SharedClock, SystemClock};
+use crate::discovery::Discovery;
+use crate::node::{Node, NodeBuilder};
+use crate::sim::{SimClock, SimNetwork};
+use crate::storage::persistence::Persistence;
+use crate::storage::CacheValue;
+use crate::transport::{SharedTransport, TcpTransport};
+
+// How often await_* helpers re-check the nodes
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Wall-clock epoch of simulated clusters (2025-01-01T00:00:00Z)
+const SIM_EPOCH_MILLIS: u64 = 1_735_689_600_000;
+
+pub struct TestCluster {
+    nodes: Vec<Arc<Node>>,
+    dir: Option<PathBuf>,
+    transport: SharedTransport,
+}
+
+impl TestCluster {
+    // Start `n` in-memory nodes
+    pub async fn start(n: usize) -> std::io::Result<TestCluster> {
+        Self::start_configured(n, |builder| builder).await
+    }
+
+    // Start `n` in-memory nodes with `configure` applied to each node's builder, e.g. to batch
+    // replication or keep an outbox
+    pub async fn start_configured(n: usize, configure: impl Fn(NodeBuilder) -> NodeBuilder) -> std::io::Result<TestCluster> {
+        Self::start_with(n, None, Arc::new(TcpTransport), Arc::new(SystemClock), configure).await
+    }
+
+    // Start `n` in-memory nodes on a SimNetwork with a SimClock; run under a paused
+    // current-thread runtime (`#[tokio::test(start_paused = true)]`) for deterministic time
+    pub async fn start_simulated(n: usize) -> std::io::Result<TestCluster> {
+        let clock = Arc::new(SimClock::new(SIM_EPOCH_MILLIS));
+        Self::start_with(n, None, Arc::new(SimNetwork::new()), clock, |builder| builder).await
+    }
+
+    // Start `n` nodes snapshotting to node_<index>_cache.arrow in a fresh temp dir, removed on shutdown
+    pub async fn start_persistent(n: usize) -> std::io::Result<TestCluster> {
+        let dir = std::env::temp_dir().join(format!("p2p-rust-{}-{}", std::process::id(), rand::random::<u32>()));
+        std::fs::create_dir_all(&dir)?;
+        Self::start_with(n, Some(dir), Arc::new(TcpTransport), Arc::new(SystemClock), |builder| builder).await
+    }
+
+    async fn start_with(
+        n: usize,
+        dir: Option<PathBuf>,
+        transport: SharedTransport,
+        clock: SharedClock,
+        configure: impl Fn(NodeBuilder) -> NodeBuilder,
+    ) -> std::io::Result<TestCluster> {
+        let mut nodes = Vec::with_capacity(n);
+        for i in 0..n {
+            let persistence = match &dir {
+                Some(dir) => Persistence::Arrow(dir.join(format!("node_{}_cache.arrow", i))),
+                None => Persistence::None,
+            };
+            let node = configure(NodeBuilder::new())
+                .port(0)
+                .discovery(Discovery::None)
+                .persistence(persistence)
+                .transport(Arc::clone(&transport))
+                .clock(Arc::clone(&clock))
+                .build()?;
+            nodes.push(Arc::new(node));
+        }
+
+        // Full mesh: every node knows every other node before it starts serving
+        let addrs: Vec<String> = nodes.iter().map(|node| format!("127.0.0.1:{}", node.port())).collect();
+        for node in &nodes {
+            let own = format!("127.0.0.1:{}", node.port());
+            let peers = node.peers();
+            let mut peers = peers.lock().await;
+            peers.extend(addrs.iter().filter(|addr| **addr != own).cloned());
+        }
+
+        for node in &nodes {
+            node.start()?;
+        }
+        Ok(TestCluster { nodes, dir, transport })
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, i: usize) -> Arc<Node> {
+        Arc::clone(&self.nodes[i])
+    }
+
+    pub fn addr(&self, i: usize) -> String {
+        format!("127.0.0.1:{}", self.nodes[i].port())
+    }
+
+    // Send one protocol command to node `i` over the cluster's transport and return the full response
+    pub async fn request(&self, i: usize, command: &str) -> std::io::Result<String> {
+        let mut stream = self.transport.connect(self.addr(i)).await?;
+        stream.write_all(command.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    // Let `duration` pass; with a paused runtime this advances virtual time instantly
+    pub async fn advance(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    // Write directly on node `i`, replicating to the rest of the cluster
+    pub async fn set(&self, i: usize, key: &str, value: CacheValue) {
+        self.nodes[i].set(key, value).await;
+    }
+
+    // Wait until `predicate` holds for the value of `key` on node `i`
+    pub async fn await_value<F>(&self, i: usize, key: &str, timeout: Duration, predicate: F) -> Result<(), String>
+    where
+        F: Fn(Option<&CacheValue>) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let value = self.nodes[i].get(key).await;
+            if predicate(value.as_ref()) {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "node {} did not converge on {} within {:?}, last value: {:?}",
+                    i,
+                    key,
+                    timeout,
+                    value.map(|v| v.to_string())
+                ));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    // Wait until node `i` holds `expected` for `key`
+    pub async fn await_key(&self, i: usize, key: &str, expected: &CacheValue, timeout: Duration) -> Result<(), String> {
+        let expected = expected.to_string();
+        self.await_value(i, key, timeout, |value| value.map(|v| v.to_string()).as_deref() == Some(expected.as_str()))
+            .await
+    }
+
+    // Wait until every node holds the same value (or no value) for `key`
+    pub async fn await_convergence(&self, key: &str, timeout: Duration) -> Result<Option<CacheValue>, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut values = Vec::with_capacity(self.nodes.len());
+            for node in &self.nodes {
+                values.push(node.get(key).await);
+            }
+            let rendered: Vec<Option<String>> = values.iter().map(|v| v.as_ref().map(|v| v.to_string())).collect();
+            if rendered.windows(2).all(|pair| pair[0] == pair[1]) {
+                return Ok(values.into_iter().next().flatten());
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("nodes did not converge on {} within {:?}: {:?}", key, timeout, rendered));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    // Stop every node and remove the temp dir, if any
+    pub async fn shutdown(self) {
+        for node in &self.nodes {
+            node.shutdown().await;
+        }
+        if let Some(dir) = self.dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
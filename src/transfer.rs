@@ -0,0 +1,358 @@
+//! Node-to-node bulk transfer of the cache as a streamed Arrow IPC exchange.
+//!
+//! On a connection of its own, `SNAPSHOT [PREFIX p] [AFTER key] [BATCH rows] [RATE rows_per_s]`
+//! answers with the node's keys in order as an Arrow IPC stream of key/value/type/expires batches
+//! (the snapshot layout), BATCH rows at a time and at most RATE rows a second. `SYNC FROM <peer>`
+//! pulls such a stream into the local cache for a full sync; when the connection breaks it
+//! reconnects and resumes after the last key it applied. Keys written locally while a pull runs
+//! keep their newer values.
+//!
+//! With a [`WarmUp`] configured, a starting node pulls its keys (or those under a prefix) from
+//! the first peer that answers before it listens or announces itself, so it doesn't answer
+//! "Not Found" for keys the cluster has. Writes peers send meanwhile are caught up afterwards
+//! like any others a peer missed.
+
+use arrow::buffer::Buffer;
+use arrow::ipc::reader::StreamDecoder;
+use arrow::ipc::writer::StreamWriter;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
+use log::{debug, error, info, warn};
+
+use crate::cluster::CLUSTER_QUERY_TIMEOUT;
+use crate::discovery::PeerList;
+use crate::node::NodeContext;
+use crate::storage::persistence::{batch_expiries, batch_to_pairs, entries_to_record_batch, snapshot_schema, SnapshotEntry};
+use crate::storage::SharedCache;
+
+// Rows per batch unless SNAPSHOT asks otherwise
+const DEFAULT_BATCH_ROWS: usize = 1000;
+
+// Reconnects a pull makes without getting any further before giving up
+const RESUME_ATTEMPTS: u32 = 5;
+const RESUME_DELAY: Duration = Duration::from_millis(500);
+
+// How long a pull waits for the next bytes of the stream
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What the stream ends with, so a connection closed between batches isn't taken for the end
+const END_OF_STREAM: [u8; 8] = [0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0];
+
+// How often a warm-up checks whether a peer has been discovered yet
+const WARM_UP_POLL: Duration = Duration::from_millis(500);
+
+// Loading the cache from a peer before a node starts serving
+#[derive(Clone, Debug, PartialEq)]
+pub struct WarmUp {
+    // Only keys starting with this
+    pub prefix: String,
+    // How long to wait for a peer to be discovered before starting cold
+    pub wait: Duration,
+}
+
+impl Default for WarmUp {
+    fn default() -> Self {
+        WarmUp { prefix: String::new(), wait: Duration::from_secs(15) }
+    }
+}
+
+impl WarmUp {
+    // "on" for the defaults, or e.g. "prefix=user:,wait_s=30"
+    pub fn parse(spec: &str) -> Result<WarmUp, String> {
+        let mut warm_up = WarmUp::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty() && *s != "on") {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid warm-up setting: {}", setting))?;
+            match name {
+                "prefix" => warm_up.prefix = value.to_string(),
+                "wait_s" => warm_up.wait = Duration::from_secs(value.parse().map_err(|_| format!("Invalid value for {}: {}", name, value))?),
+                _ => return Err(format!("Unknown warm-up setting: {}", name)),
+            }
+        }
+        Ok(warm_up)
+    }
+}
+
+// Which keys a SNAPSHOT stream carries and how fast
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotRequest {
+    pub prefix: String,
+    // Resume point: only keys after this one
+    pub after: Option<String>,
+    pub batch_rows: usize,
+    // None sends as fast as the connection takes it
+    pub rows_per_sec: Option<u64>,
+}
+
+impl Default for SnapshotRequest {
+    fn default() -> Self {
+        SnapshotRequest {
+            prefix: String::new(),
+            after: None,
+            batch_rows: DEFAULT_BATCH_ROWS,
+            rows_per_sec: None,
+        }
+    }
+}
+
+impl SnapshotRequest {
+    // `[PREFIX p] [AFTER key] [BATCH rows] [RATE rows_per_s]`, as SNAPSHOT and SYNC FROM take them
+    pub(crate) fn parse(args: &str) -> Result<SnapshotRequest, String> {
+        let mut request = SnapshotRequest::default();
+        let mut words = args.split_whitespace();
+        while let Some(option) = words.next() {
+            let value = words.next().ok_or_else(|| format!("missing {} value", option))?;
+            let invalid = || format!("invalid {} value: {}", option, value);
+            match option.to_uppercase().as_str() {
+                "PREFIX" => request.prefix = value.to_string(),
+                "AFTER" => request.after = Some(value.to_string()),
+                "BATCH" => request.batch_rows = value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?,
+                "RATE" => request.rows_per_sec = Some(value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?),
+                _ => return Err(format!("unknown option {}", option)),
+            }
+        }
+        Ok(request)
+    }
+
+    fn to_command(&self) -> String {
+        let mut command = format!("SNAPSHOT BATCH {}", self.batch_rows);
+        if !self.prefix.is_empty() {
+            command.push_str(&format!(" PREFIX {}", self.prefix));
+        }
+        if let Some(after) = &self.after {
+            command.push_str(&format!(" AFTER {}", after));
+        }
+        if let Some(rate) = self.rows_per_sec {
+            command.push_str(&format!(" RATE {}", rate));
+        }
+        command
+    }
+}
+
+// Answer SNAPSHOT: the matching keys as they are now with their expiries, streamed in key
+// order; keys already past their expiry are left out
+pub(crate) async fn serve_snapshot<S: AsyncRead + AsyncWrite + Unpin>(mut socket: S, client: &str, cache: &SharedCache, request: SnapshotRequest) {
+    let mut entries: Vec<SnapshotEntry> = {
+        let cache = cache.lock().await;
+        cache
+            .iter()
+            .filter(|(key, _)| key.starts_with(&request.prefix) && request.after.as_ref().is_none_or(|after| *key > after))
+            .map(|(key, value)| (key.clone(), value.clone(), cache.expires_at(key)))
+            .collect()
+    };
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    info!("Streaming {} keys to {} ({})", entries.len(), client, request.to_command());
+
+    let streamed = async {
+        let mut writer = StreamWriter::try_new(Vec::new(), &snapshot_schema())?;
+        for chunk in entries.chunks(request.batch_rows) {
+            writer.write(&entries_to_record_batch(chunk)?)?;
+            let bytes: Vec<u8> = writer.get_mut().drain(..).collect();
+            socket.write_all(&bytes).await?;
+            if let Some(rate) = request.rows_per_sec {
+                tokio::time::sleep(Duration::from_secs_f64(chunk.len() as f64 / rate as f64)).await;
+            }
+        }
+        writer.finish()?;
+        socket.write_all(&writer.into_inner()?).await?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+    };
+    match streamed.await {
+        Ok(()) => debug!("Finished streaming keys to {}", client),
+        Err(e) => warn!("Streaming keys to {} stopped: {}", client, e),
+    }
+}
+
+enum PullError {
+    // The peer answered with an error instead of a stream; retrying won't help
+    Rejected(String),
+    // The connection failed or broke off; the pull can resume
+    Broken(String),
+}
+
+// Copy `peer`'s keys into the local cache, resuming where a broken connection left off;
+// returns how many keys were received
+pub(crate) async fn pull_snapshot(peer: &str, mut request: SnapshotRequest, cache: &SharedCache, context: &NodeContext) -> Result<usize, String> {
+    let started = context.clock.unix_millis();
+    let mut received = 0;
+    let mut attempts = 0;
+    loop {
+        let before = received;
+        match receive_snapshot(peer, &mut request, started, &mut received, cache, context).await {
+            Ok(()) => {
+                info!("Received {} keys from {}", received, peer);
+                return Ok(received);
+            }
+            Err(PullError::Rejected(e)) => return Err(e),
+            Err(PullError::Broken(e)) => {
+                attempts = if received > before { 1 } else { attempts + 1 };
+                if attempts > RESUME_ATTEMPTS {
+                    error!("Giving up on the snapshot from {} after {} keys: {}", peer, received, e);
+                    return Err(format!("snapshot from {} failed after {} keys: {}", peer, received, e));
+                }
+                let after = request.after.as_deref().unwrap_or("the start");
+                warn!("Snapshot from {} broke off after {} keys ({}), resuming after {}", peer, received, e, after);
+                tokio::time::sleep(RESUME_DELAY).await;
+            }
+        }
+    }
+}
+
+// One connection's worth of a pull; moves `request.after` past every key applied
+async fn receive_snapshot(
+    peer: &str,
+    request: &mut SnapshotRequest,
+    started: u64,
+    received: &mut usize,
+    cache: &SharedCache,
+    context: &NodeContext,
+) -> Result<(), PullError> {
+    let broken = |e: std::io::Error| PullError::Broken(e.to_string());
+    let mut stream = match timeout(CLUSTER_QUERY_TIMEOUT, context.transport.connect(peer.to_string())).await {
+        Ok(stream) => stream.map_err(broken)?,
+        Err(_) => return Err(PullError::Broken("connect timed out".to_string())),
+    };
+    stream.write_all(format!("{}\n", request.to_command()).as_bytes()).await.map_err(broken)?;
+
+    let mut decoder = StreamDecoder::new();
+    let mut tail: Vec<u8> = Vec::new();
+    let mut chunk = vec![0; 64 * 1024];
+    let mut first = true;
+    loop {
+        let read = match timeout(READ_TIMEOUT, stream.read(&mut chunk)).await {
+            Ok(read) => read.map_err(broken)?,
+            Err(_) => return Err(PullError::Broken("no data within the read timeout".to_string())),
+        };
+        if read == 0 {
+            break;
+        }
+        let mut bytes = chunk[..read].to_vec();
+        // Errors come back as text, while an Arrow stream starts with the 0xFFFFFFFF continuation marker
+        if first && !bytes.starts_with(&[0xff; 4]) {
+            let _ = timeout(READ_TIMEOUT, stream.read_to_end(&mut bytes)).await;
+            return Err(PullError::Rejected(String::from_utf8_lossy(&bytes).into_owned()));
+        }
+        first = false;
+        tail.extend_from_slice(&bytes);
+        tail.drain(..tail.len().saturating_sub(END_OF_STREAM.len()));
+
+        let mut buffer = Buffer::from_vec(bytes);
+        while !buffer.is_empty() {
+            let batch = decoder.decode(&mut buffer).map_err(|e| PullError::Broken(e.to_string()))?;
+            let Some(batch) = batch else {
+                continue;
+            };
+            let pairs = batch_to_pairs(&batch).map_err(|e| PullError::Rejected(e.to_string()))?;
+            let Some(last) = pairs.last().map(|(key, _)| key.clone()) else {
+                continue;
+            };
+            *received += pairs.len();
+            let mut cache = cache.lock().await;
+            for ((key, value), expires) in pairs.into_iter().zip(batch_expiries(&batch)) {
+                if cache.modified(&key).is_none_or(|modified| modified < started) {
+                    cache.insert(key.clone(), value);
+                    cache.set_expiry(&key, expires);
+                }
+            }
+            request.after = Some(last);
+        }
+    }
+    match decoder.finish() {
+        Ok(()) if tail == END_OF_STREAM => Ok(()),
+        _ => Err(PullError::Broken("stream ended early".to_string())),
+    }
+}
+
+// Pull the keys `warm_up` asks for from the first known peer that sends them, waiting up to
+// `warm_up.wait` for one to be discovered; a node that finds none starts with what it has
+pub(crate) async fn warm_up(warm_up: &WarmUp, peers: &PeerList, cache: &SharedCache, context: &NodeContext) {
+    let deadline = context.clock.now() + warm_up.wait;
+    let mut candidates = loop {
+        let known: Vec<String> = peers.lock().await.iter().cloned().collect();
+        if !known.is_empty() || context.clock.now() >= deadline {
+            break known;
+        }
+        tokio::time::sleep(WARM_UP_POLL).await;
+    };
+    candidates.sort();
+    if candidates.is_empty() {
+        warn!("No peer to warm the cache up from within {:?}, starting cold", warm_up.wait);
+        return;
+    }
+
+    let request = SnapshotRequest { prefix: warm_up.prefix.clone(), ..SnapshotRequest::default() };
+    for peer in candidates {
+        info!("Warming the cache up from {}", peer);
+        match pull_snapshot(&peer, request.clone(), cache, context).await {
+            Ok(received) => {
+                info!("Warmed the cache up with {} keys from {}", received, peer);
+                return;
+            }
+            Err(e) => warn!("Could not warm the cache up from {}: {}", peer, e),
+        }
+    }
+    warn!("No peer could warm the cache up, starting cold");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use crate::sim::SimClock;
+    use crate::storage::Cache;
+    use crate::testing::TestCluster;
+
+    const EPOCH: u64 = 1_735_689_600_000;
+
+    #[test]
+    fn parses_warm_ups() {
+        assert_eq!(WarmUp::parse("on"), Ok(WarmUp::default()));
+        assert_eq!(
+            WarmUp::parse("prefix=user:,wait_s=30"),
+            Ok(WarmUp { prefix: "user:".to_string(), wait: Duration::from_secs(30) })
+        );
+        assert_eq!(WarmUp::parse("wait_s=long"), Err("Invalid value for wait_s: long".to_string()));
+        assert_eq!(WarmUp::parse("prefix"), Err("Invalid warm-up setting: prefix".to_string()));
+        assert_eq!(WarmUp::parse("rate=5"), Err("Unknown warm-up setting: rate".to_string()));
+    }
+
+    #[test]
+    fn snapshot_requests_round_trip() {
+        let request = SnapshotRequest::parse("PREFIX user: AFTER user:42 BATCH 500 RATE 1000").unwrap();
+        assert_eq!(
+            request,
+            SnapshotRequest { prefix: "user:".to_string(), after: Some("user:42".to_string()), batch_rows: 500, rows_per_sec: Some(1000) }
+        );
+        assert_eq!(SnapshotRequest::parse(request.to_command().strip_prefix("SNAPSHOT ").unwrap()), Ok(request));
+        assert_eq!(SnapshotRequest::parse("BATCH 0"), Err("invalid BATCH value: 0".to_string()));
+        assert_eq!(SnapshotRequest::parse("PREFIX"), Err("missing PREFIX value".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn warming_up_keeps_the_peers_expiries() {
+        let clock = Arc::new(SimClock::new(EPOCH));
+        let cluster = TestCluster::start_simulated(1).await.unwrap();
+        for command in ["SET plain=v", "SET session:1=token", "GETEX session:1 TTL=30", "SET gone=x", "GETEX gone TTL=1"] {
+            cluster.request(0, command).await.unwrap();
+        }
+        cluster.advance(Duration::from_secs(2)).await;
+
+        let context = NodeContext::new(1, cluster.node(0).transport(), clock.clone());
+        let mut cache = Cache::new();
+        cache.set_clock(clock);
+        let cache = Arc::new(Mutex::new(cache));
+        let peers = Arc::new(Mutex::new(HashSet::from([cluster.addr(0)])));
+        warm_up(&WarmUp::default(), &peers, &cache, &context).await;
+
+        let cache = cache.lock().await;
+        assert_eq!(cache.ttl("session:1"), Some(Some(28_000)));
+        assert_eq!(cache.ttl("plain"), Some(None));
+        // Keys that expired on the peer before the stream was sent don't come back
+        assert_eq!(cache.len(), 2);
+        drop(cache);
+        cluster.shutdown().await;
+    }
+}
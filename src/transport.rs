@@ -0,0 +1,82 @@
+//! Network transport between clients, nodes and peers.
+//!
+//! Nodes only talk to the network through a [`Transport`], so the TCP
+//! implementation can be swapped for an in-memory one (see `sim`) in tests.
+
+use std::io;
+use std::sync::Arc;
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+// A bidirectional byte stream, e.g. a TcpStream
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+pub type BoxConnection = Box<dyn Connection>;
+
+pub trait Listener: Send {
+    // The port actually bound, useful after binding port 0
+    fn local_port(&self) -> u16;
+
+    // Wait for the next connection and the address it came from
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxConnection, String)>>;
+}
+
+pub trait Transport: Send + Sync {
+    // Bind a listener on `port` (0 picks a free port); does not need a runtime
+    fn bind(&self, port: u16) -> io::Result<Box<dyn Listener>>;
+
+    // Open a connection to `addr`, e.g. "127.0.0.1:8081"
+    fn connect(&self, addr: String) -> BoxFuture<'static, io::Result<BoxConnection>>;
+}
+
+pub type SharedTransport = Arc<dyn Transport>;
+
+// Plain TCP on 0.0.0.0
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn bind(&self, port: u16) -> io::Result<Box<dyn Listener>> {
+        let listener = std::net::TcpListener::bind(("0.0.0.0", port))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        Ok(Box::new(TcpIncoming {
+            port,
+            std: Some(listener),
+            tokio: None,
+        }))
+    }
+
+    fn connect(&self, addr: String) -> BoxFuture<'static, io::Result<BoxConnection>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(addr).await?;
+            Ok(Box::new(stream) as BoxConnection)
+        })
+    }
+}
+
+// Bound std listener, registered with tokio on first accept so binding works outside a runtime
+struct TcpIncoming {
+    port: u16,
+    std: Option<std::net::TcpListener>,
+    tokio: Option<TcpListener>,
+}
+
+impl Listener for TcpIncoming {
+    fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxConnection, String)>> {
+        Box::pin(async move {
+            if let Some(listener) = self.std.take() {
+                self.tokio = Some(TcpListener::from_std(listener)?);
+            }
+            let listener = self.tokio.as_ref().expect("listener registered above");
+            let (stream, addr) = listener.accept().await?;
+            Ok((Box::new(stream) as BoxConnection, addr.to_string()))
+        })
+    }
+}
@@ -0,0 +1,297 @@
+// Replication across in-process clusters: writes made on one node converge on the others.
+
+use std::time::Duration;
+use p2p_rust::testing::TestCluster;
+use p2p_rust::CacheValue;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Unix seconds of the simulated clusters' epoch (2025-01-01T00:00:00Z)
+const SIM_EPOCH_SECS: u64 = 1_735_689_600;
+
+// Poll `command` on node `i` until it answers `expected`
+async fn await_response(cluster: &TestCluster, i: usize, command: &str, expected: &str) {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    loop {
+        let response = cluster.request(i, command).await.unwrap();
+        if response.trim_end() == expected {
+            return;
+        }
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "node {} answered {:?} to {}, expected {:?}",
+            i,
+            response,
+            command,
+            expected
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn set_converges_on_every_node() {
+    let cluster = TestCluster::start(3).await.unwrap();
+    cluster.request(0, "SET user:1=alice").await.unwrap();
+
+    let value = cluster.await_convergence("user:1", TIMEOUT).await.unwrap();
+    assert_eq!(value.map(|v| v.to_string()).as_deref(), Some("alice"));
+    cluster.shutdown().await;
+}
+
+#[tokio::test]
+async fn set_from_any_node_converges() {
+    let cluster = TestCluster::start(3).await.unwrap();
+    cluster.set(2, "counter", CacheValue::Int(42)).await;
+
+    for i in 0..2 {
+        cluster.await_key(i, "counter", &CacheValue::Int(42), TIMEOUT).await.unwrap();
+    }
+    cluster.shutdown().await;
+}
+
+#[tokio::test]
+async fn list_and_hash_changes_replicate() {
+    let cluster = TestCluster::start(2).await.unwrap();
+    cluster.request(0, "RPUSH jobs job1").await.unwrap();
+    cluster.request(0, "RPUSH jobs job2").await.unwrap();
+    cluster.request(0, "LPUSH jobs job0").await.unwrap();
+    cluster.request(0, "HSET user:1 name=Alice").await.unwrap();
+    cluster.request(0, "HSET user:1 city=Prague").await.unwrap();
+
+    await_response(&cluster, 1, "LRANGE jobs 0 -1", &cluster.request(0, "LRANGE jobs 0 -1").await.unwrap()).await;
+    await_response(&cluster, 1, "HGET user:1 city", "Prague").await;
+    cluster.await_convergence("jobs", TIMEOUT).await.unwrap();
+    cluster.await_convergence("user:1", TIMEOUT).await.unwrap();
+
+    cluster.request(1, "LPOP jobs").await.unwrap();
+    cluster.request(1, "HDEL user:1 name").await.unwrap();
+    await_response(&cluster, 0, "HGETALL user:1", &cluster.request(1, "HGETALL user:1").await.unwrap()).await;
+    await_response(&cluster, 0, "LRANGE jobs 0 -1", &cluster.request(1, "LRANGE jobs 0 -1").await.unwrap()).await;
+    cluster.shutdown().await;
+}
+
+#[tokio::test]
+async fn del_prefix_replicates() {
+    let cluster = TestCluster::start(3).await.unwrap();
+    for key in ["session:1", "session:2", "user:1"] {
+        cluster.request(0, &format!("SET {}=v", key)).await.unwrap();
+    }
+    for i in 1..3 {
+        await_response(&cluster, i, "GET_LEN", &cluster.request(0, "GET_LEN").await.unwrap()).await;
+    }
+
+    cluster.request(1, "DEL_PREFIX session:").await.unwrap();
+    for i in 0..3 {
+        cluster.await_value(i, "session:1", TIMEOUT, |value| value.is_none()).await.unwrap();
+        cluster.await_value(i, "session:2", TIMEOUT, |value| value.is_none()).await.unwrap();
+    }
+    assert_eq!(cluster.await_convergence("user:1", TIMEOUT).await.unwrap(), Some(CacheValue::Str("v".to_string())));
+    cluster.shutdown().await;
+}
+
+#[tokio::test]
+async fn deletes_that_remove_nothing_are_not_replicated() {
+    let cluster = TestCluster::start(2).await.unwrap();
+    cluster.request(0, "SET user:1=v").await.unwrap();
+    // A SESSION write's token names the message it was replicated as, so none means it wasn't
+    for delete in ["DEL missing", "DEL_PREFIX session:", "DEL_MATCH *:9"] {
+        assert_eq!(cluster.request(0, &format!("SESSION {}", delete)).await.unwrap().trim_end(), "0");
+    }
+    let response = cluster.request(0, "SESSION DEL user:1").await.unwrap();
+    assert!(response.starts_with("1\nTOKEN "), "{:?}", response);
+    cluster.await_value(1, "user:1", TIMEOUT, |value| value.is_none()).await.unwrap();
+    cluster.shutdown().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn expiry_replicates_and_expires_everywhere() {
+    let cluster = TestCluster::start_simulated(3).await.unwrap();
+    cluster.request(0, "SET session:1=token").await.unwrap();
+    cluster.request(0, &format!("EXPIREAT session:1 {}", SIM_EPOCH_SECS + 60)).await.unwrap();
+
+    for i in 1..3 {
+        cluster.await_key(i, "session:1", &CacheValue::Str("token".to_string()), TIMEOUT).await.unwrap();
+        await_response(&cluster, i, "TTL session:1", "60").await;
+    }
+
+    cluster.advance(Duration::from_secs(61)).await;
+    for i in 0..3 {
+        assert_eq!(cluster.request(i, "TTL session:1").await.unwrap().trim_end(), "-2");
+    }
+    cluster.shutdown().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn getex_expiry_replicates() {
+    let cluster = TestCluster::start_simulated(2).await.unwrap();
+    cluster.request(0, "SET session:1=token").await.unwrap();
+    cluster.await_key(1, "session:1", &CacheValue::Str("token".to_string()), TIMEOUT).await.unwrap();
+
+    cluster.request(0, "GETEX session:1 TTL=30").await.unwrap();
+    await_response(&cluster, 1, "TTL session:1", "30").await;
+
+    cluster.request(1, "PERSIST session:1").await.unwrap();
+    await_response(&cluster, 0, "TTL session:1", "-1").await;
+    cluster.shutdown().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn catch_up_keeps_expiries() {
+    let cluster = TestCluster::start_simulated(2).await.unwrap();
+    let peer = cluster.addr(1);
+    cluster.request(0, &format!("REMOVEPEER {}", peer)).await.unwrap();
+    // Let the catch-up loop see the peer go before writing what it will miss
+    cluster.advance(Duration::from_secs(2)).await;
+    cluster.request(0, "SET session:1=token").await.unwrap();
+    cluster.request(0, &format!("EXPIREAT session:1 {}", SIM_EPOCH_SECS + 60)).await.unwrap();
+    cluster.request(0, "SET plain=value").await.unwrap();
+
+    cluster.request(0, &format!("ADDPEER {}", peer)).await.unwrap();
+    cluster.advance(Duration::from_secs(2)).await;
+    cluster.await_key(1, "plain", &CacheValue::Str("value".to_string()), TIMEOUT).await.unwrap();
+    let ttl: u64 = cluster.request(1, "TTL session:1").await.unwrap().trim_end().parse().unwrap();
+    assert!((1..=60).contains(&ttl), "TTL {} after catch-up", ttl);
+    assert_eq!(cluster.request(1, "TTL plain").await.unwrap().trim_end(), "-1");
+    cluster.shutdown().await;
+}
+
+// A SET taking the whole of a 1024-byte request, newline included
+fn largest_set(key: &str) -> (String, String) {
+    let value = "x".repeat(1024 - format!("SET {}=\n", key).len());
+    (format!("SET {}={}\n", key, value), value)
+}
+
+#[tokio::test]
+async fn largest_write_replicates_intact() {
+    let cluster = TestCluster::start(3).await.unwrap();
+    let (request, value) = largest_set("big");
+    assert_eq!(request.len(), 1024);
+    cluster.request(0, &request).await.unwrap();
+
+    for i in 1..3 {
+        cluster.await_key(i, "big", &CacheValue::Str(value.clone()), TIMEOUT).await.unwrap();
+    }
+    cluster.shutdown().await;
+}
+
+#[tokio::test]
+async fn largest_write_replicates_intact_in_batches() {
+    let cluster = TestCluster::start_configured(2, |builder| builder.replication_batch(Duration::from_millis(10))).await.unwrap();
+    let (request, value) = largest_set("big");
+    cluster.request(0, &request).await.unwrap();
+    cluster.request(0, "SET after=1").await.unwrap();
+
+    cluster.await_key(1, "big", &CacheValue::Str(value), TIMEOUT).await.unwrap();
+    // The line after a framed one is read as its own request
+    cluster.await_key(1, "after", &CacheValue::Str("1".to_string()), TIMEOUT).await.unwrap();
+    cluster.shutdown().await;
+}
+
+#[tokio::test]
+async fn values_grown_past_a_request_replicate() {
+    let cluster = TestCluster::start(2).await.unwrap();
+    let chunk = "y".repeat(900);
+    for _ in 0..4 {
+        cluster.request(0, &format!("APPEND log {}", chunk)).await.unwrap();
+    }
+
+    cluster.await_key(1, "log", &CacheValue::Str(chunk.repeat(4)), TIMEOUT).await.unwrap();
+    cluster.shutdown().await;
+}
+
+#[tokio::test]
+async fn multi_line_values_replicate_whole() {
+    for batch in [None, Some(Duration::from_millis(10))] {
+        let cluster = TestCluster::start_configured(2, |builder| match batch {
+            Some(window) => builder.replication_batch(window),
+            None => builder,
+        })
+        .await
+        .unwrap();
+        cluster.request(0, "SET note=first\nsecond").await.unwrap();
+        cluster.request(0, "SET after=1").await.unwrap();
+
+        cluster.await_key(1, "note", &CacheValue::Str("first\nsecond".to_string()), TIMEOUT).await.unwrap();
+        cluster.await_key(1, "after", &CacheValue::Str("1".to_string()), TIMEOUT).await.unwrap();
+        cluster.shutdown().await;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn longest_ttls_never_expire() {
+    let cluster = TestCluster::start_simulated(2).await.unwrap();
+    cluster.request(0, "SET session:1=token").await.unwrap();
+    cluster.request(0, "SET session:2=token").await.unwrap();
+    cluster.await_key(1, "session:2", &CacheValue::Str("token".to_string()), TIMEOUT).await.unwrap();
+
+    assert_eq!(cluster.request(0, &format!("GETEX session:1 TTL={}", u64::MAX)).await.unwrap(), "token");
+    cluster.request(0, &format!("EXPIREAT session:2 {}", u64::MAX)).await.unwrap();
+    // Let heartbeats learn the clock offset the peer moves expiry times by
+    cluster.advance(Duration::from_secs(10)).await;
+    for i in 0..2 {
+        for key in ["session:1", "session:2"] {
+            let ttl: u64 = cluster.request(i, &format!("TTL {}", key)).await.unwrap().trim_end().parse().unwrap();
+            assert!(ttl > u64::MAX / 1000 - SIM_EPOCH_SECS - 3600, "node {} answered TTL {} for {}", i, ttl, key);
+        }
+    }
+    cluster.shutdown().await;
+}
+
+// A peer that answers every PERSIST connection with `reply` once the sender is done, and
+// drops any other connection
+async fn fake_peer(reply: &'static str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut first = [0; 7];
+                if socket.read_exact(&mut first).await.is_err() || &first != b"PERSIST" {
+                    return;
+                }
+                let mut rest = Vec::new();
+                let _ = socket.read_to_end(&mut rest).await;
+                let _ = socket.write_all(reply.as_bytes()).await;
+            });
+        }
+    });
+    addr
+}
+
+// The `failed=` count PEERS HEALTH reports for `peer` once it has been sent something
+async fn failed_batches(cluster: &TestCluster, peer: &str) -> String {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    loop {
+        let report = cluster.request(0, "PEERS HEALTH").await.unwrap();
+        let line = report.lines().find(|line| line.starts_with(peer) && !line.contains(" sent=0 "));
+        if let Some(line) = line {
+            return line.split_whitespace().find_map(|field| field.strip_prefix("failed=")).unwrap().to_string();
+        }
+        assert!(tokio::time::Instant::now() < deadline, "{} was never sent a batch: {}", peer, report);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn batches_count_as_sent_only_once_every_message_is_applied() {
+    let batched = |builder: p2p_rust::NodeBuilder| builder.replication_batch(Duration::from_millis(10));
+    let cluster = TestCluster::start_configured(2, batched).await.unwrap();
+    cluster.request(0, "SET key1=v").await.unwrap();
+    cluster.request(0, "SET key2=v").await.unwrap();
+    assert_eq!(failed_batches(&cluster, &cluster.addr(1)).await, "0");
+
+    // Answers nothing for the messages, or fails the second one
+    for reply in ["OK: PERSIST\n", "OK: PERSIST\n20\nOK: BROADCAST applied45\nBUSY: replication queue full, try again later"] {
+        let cluster = TestCluster::start_configured(1, batched).await.unwrap();
+        let peer = fake_peer(reply).await;
+        cluster.request(0, &format!("ADDPEER {}", peer)).await.unwrap();
+        cluster.request(0, "SET key1=v").await.unwrap();
+        cluster.request(0, "SET key2=v").await.unwrap();
+        assert_ne!(failed_batches(&cluster, &peer).await, "0", "after {:?}", reply);
+        cluster.shutdown().await;
+    }
+    cluster.shutdown().await;
+}